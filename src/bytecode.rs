@@ -0,0 +1,2050 @@
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{io::*, object::*, value::*};
+
+/// Checks the code of every function among `objects`, warning about failures unless
+/// `options.strict` makes them fatal. Every object read lands in the pool, so a loader
+/// passes the objects it added to reach functions nested at any depth.
+pub(crate) fn verify_functions(
+    objects: &[Rc<Object>],
+    options: &LoadOptions,
+) -> Result<(), ConstantsError> {
+    for object in objects {
+        let Object::Function {
+            identifier, code, ..
+        } = &**object
+        else {
+            continue;
+        };
+        if let Err(e) = verify_function(code) {
+            if options.strict {
+                let identifier = identifier.clone();
+                return Err(ConstantsError::FailedVerification {
+                    identifier,
+                    error: e,
+                });
+            }
+            options.warn(Warning::FailedVerification {
+                identifier: identifier.clone(),
+                error: e,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Opcodes are stored in files as their discriminant, so existing values must never change
+/// and new opcodes are only ever appended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ByteCode {
+    ConstantByte = 0,
+    Return = 1,
+    Add = 2,
+    Sub = 3,
+    Mul = 4,
+    Div = 5,
+    Negate = 6,
+    Equal = 7,
+    Less = 8,
+    Greater = 9,
+    Jump = 10,
+    JumpIfFalse = 11,
+    Loop = 12,
+    ConstantLong = 13,
+    GetLocal = 14,
+    SetLocal = 15,
+    Call = 16,
+    TailCall = 17,
+    Pop = 18,
+}
+
+/// How an opcode's operand is interpreted, which decides how it's disassembled and checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OperandKind {
+    None,
+    /// Index into the function's constant table.
+    Constant,
+    /// Local variable slot.
+    Local,
+    /// Number of arguments passed to a call.
+    ArgCount,
+    /// Offset past the end of the instruction.
+    ForwardJump,
+    /// Offset back from the end of the instruction.
+    BackwardJump,
+}
+
+pub(crate) struct OpInfo {
+    op: ByteCode,
+    pub(crate) name: &'static str,
+    pub(crate) operand: OperandKind,
+    /// Number of big-endian operand bytes following the opcode.
+    pub(crate) width: usize,
+}
+
+const fn op_info(op: ByteCode, name: &'static str, operand: OperandKind, width: usize) -> OpInfo {
+    OpInfo {
+        op,
+        name,
+        operand,
+        width,
+    }
+}
+
+/// Metadata for every opcode, indexed by its byte value.
+const OPCODES: &[OpInfo] = &[
+    op_info(
+        ByteCode::ConstantByte,
+        "CONSTANT_BYTE",
+        OperandKind::Constant,
+        1,
+    ),
+    op_info(ByteCode::Return, "RETURN", OperandKind::None, 0),
+    op_info(ByteCode::Add, "ADD", OperandKind::None, 0),
+    op_info(ByteCode::Sub, "SUB", OperandKind::None, 0),
+    op_info(ByteCode::Mul, "MUL", OperandKind::None, 0),
+    op_info(ByteCode::Div, "DIV", OperandKind::None, 0),
+    op_info(ByteCode::Negate, "NEGATE", OperandKind::None, 0),
+    op_info(ByteCode::Equal, "EQUAL", OperandKind::None, 0),
+    op_info(ByteCode::Less, "LESS", OperandKind::None, 0),
+    op_info(ByteCode::Greater, "GREATER", OperandKind::None, 0),
+    op_info(ByteCode::Jump, "JUMP", OperandKind::ForwardJump, 2),
+    op_info(
+        ByteCode::JumpIfFalse,
+        "JUMP_IF_FALSE",
+        OperandKind::ForwardJump,
+        2,
+    ),
+    op_info(ByteCode::Loop, "LOOP", OperandKind::BackwardJump, 2),
+    op_info(
+        ByteCode::ConstantLong,
+        "CONSTANT_LONG",
+        OperandKind::Constant,
+        2,
+    ),
+    op_info(ByteCode::GetLocal, "GET_LOCAL", OperandKind::Local, 1),
+    op_info(ByteCode::SetLocal, "SET_LOCAL", OperandKind::Local, 1),
+    op_info(ByteCode::Call, "CALL", OperandKind::ArgCount, 1),
+    op_info(ByteCode::TailCall, "TAIL_CALL", OperandKind::ArgCount, 1),
+    op_info(ByteCode::Pop, "POP", OperandKind::None, 0),
+];
+
+// A row out of place would decode every later opcode as its neighbour
+const _: () = {
+    let mut i = 0;
+    while i < OPCODES.len() {
+        assert!(
+            OPCODES[i].op as usize == i,
+            "OPCODES must be indexed by opcode"
+        );
+        i += 1;
+    }
+};
+
+impl TryFrom<u8> for ByteCode {
+    /// The byte that didn't match any opcode.
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<ByteCode, u8> {
+        OPCODES.get(byte as usize).map(|info| info.op).ok_or(byte)
+    }
+}
+
+impl ByteCode {
+    pub(crate) fn info(self) -> &'static OpInfo {
+        &OPCODES[self as usize]
+    }
+
+    pub(crate) fn mnemonic(self) -> &'static str {
+        self.info().name
+    }
+
+    fn from_mnemonic(name: &str) -> Option<ByteCode> {
+        OPCODES
+            .iter()
+            .find(|info| info.name == name)
+            .map(|info| info.op)
+    }
+
+    /// Direction of a jump's offset relative to the end of the instruction, or `None` for
+    /// opcodes that don't jump.
+    fn jump_sign(self) -> Option<isize> {
+        match self.info().operand {
+            OperandKind::ForwardJump => Some(1),
+            OperandKind::BackwardJump => Some(-1),
+            _ => None,
+        }
+    }
+
+    fn operand_width(self) -> usize {
+        self.info().width
+    }
+}
+
+/// A `ByteCode` with its operand decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    ConstantByte(u8),
+    Return,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Negate,
+    Equal,
+    Less,
+    Greater,
+    Jump(u16),
+    JumpIfFalse(u16),
+    Loop(u16),
+    ConstantLong(u16),
+    GetLocal(u8),
+    SetLocal(u8),
+    Call(u8),
+    TailCall(u8),
+    Pop,
+}
+
+impl Instruction {
+    /// Builds the instruction for `op` from exactly `op.operand_width()` operand bytes.
+    fn decode(op: ByteCode, operand: &[u8]) -> Instruction {
+        let byte = || operand[0];
+        let word = || u16::from_be_bytes([operand[0], operand[1]]);
+        match op {
+            ByteCode::ConstantByte => Instruction::ConstantByte(byte()),
+            ByteCode::Return => Instruction::Return,
+            ByteCode::Add => Instruction::Add,
+            ByteCode::Sub => Instruction::Sub,
+            ByteCode::Mul => Instruction::Mul,
+            ByteCode::Div => Instruction::Div,
+            ByteCode::Negate => Instruction::Negate,
+            ByteCode::Equal => Instruction::Equal,
+            ByteCode::Less => Instruction::Less,
+            ByteCode::Greater => Instruction::Greater,
+            ByteCode::Jump => Instruction::Jump(word()),
+            ByteCode::JumpIfFalse => Instruction::JumpIfFalse(word()),
+            ByteCode::Loop => Instruction::Loop(word()),
+            ByteCode::ConstantLong => Instruction::ConstantLong(word()),
+            ByteCode::GetLocal => Instruction::GetLocal(byte()),
+            ByteCode::SetLocal => Instruction::SetLocal(byte()),
+            ByteCode::Call => Instruction::Call(byte()),
+            ByteCode::TailCall => Instruction::TailCall(byte()),
+            ByteCode::Pop => Instruction::Pop,
+        }
+    }
+
+    pub(crate) fn op(self) -> ByteCode {
+        match self {
+            Instruction::ConstantByte(_) => ByteCode::ConstantByte,
+            Instruction::Return => ByteCode::Return,
+            Instruction::Add => ByteCode::Add,
+            Instruction::Sub => ByteCode::Sub,
+            Instruction::Mul => ByteCode::Mul,
+            Instruction::Div => ByteCode::Div,
+            Instruction::Negate => ByteCode::Negate,
+            Instruction::Equal => ByteCode::Equal,
+            Instruction::Less => ByteCode::Less,
+            Instruction::Greater => ByteCode::Greater,
+            Instruction::Jump(_) => ByteCode::Jump,
+            Instruction::JumpIfFalse(_) => ByteCode::JumpIfFalse,
+            Instruction::Loop(_) => ByteCode::Loop,
+            Instruction::ConstantLong(_) => ByteCode::ConstantLong,
+            Instruction::GetLocal(_) => ByteCode::GetLocal,
+            Instruction::SetLocal(_) => ByteCode::SetLocal,
+            Instruction::Call(_) => ByteCode::Call,
+            Instruction::TailCall(_) => ByteCode::TailCall,
+            Instruction::Pop => ByteCode::Pop,
+        }
+    }
+
+    /// Builds `op` around `operand`, keeping only as many low bytes as the opcode takes.
+    fn with_operand(op: ByteCode, operand: u16) -> Instruction {
+        let width = op.operand_width();
+        Instruction::decode(op, &operand.to_be_bytes()[2 - width..])
+    }
+
+    fn encode(self, code: &mut Vec<u8>) {
+        let width = self.op().operand_width();
+        code.push(self.op() as u8);
+        code.extend_from_slice(&self.operand().to_be_bytes()[2 - width..]);
+    }
+
+    /// The decoded operand, or 0 for opcodes without one.
+    pub(crate) fn operand(self) -> u16 {
+        match self {
+            Instruction::ConstantByte(byte)
+            | Instruction::GetLocal(byte)
+            | Instruction::SetLocal(byte)
+            | Instruction::Call(byte)
+            | Instruction::TailCall(byte) => byte as u16,
+            Instruction::Jump(word)
+            | Instruction::JumpIfFalse(word)
+            | Instruction::Loop(word)
+            | Instruction::ConstantLong(word) => word,
+            Instruction::Return
+            | Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Negate
+            | Instruction::Equal
+            | Instruction::Less
+            | Instruction::Greater
+            | Instruction::Pop => 0,
+        }
+    }
+
+    /// Encoded size, opcode included.
+    fn len(self) -> usize {
+        1 + self.op().operand_width()
+    }
+
+    /// Absolute offset a jump decoded at `offset` lands on, which may lie outside the code.
+    pub(crate) fn jump_target(self, offset: usize) -> Option<isize> {
+        let sign = self.op().jump_sign()?;
+        Some((offset + self.len()) as isize + sign * self.operand() as isize)
+    }
+}
+
+/// Why bytes don't decode to an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A byte no opcode has.
+    UnknownOpcode(u8),
+    /// The code ends before the operand of this opcode does.
+    Truncated(ByteCode),
+}
+
+/// Walks code yielding each instruction with its offset. An unknown opcode is skipped
+/// as a single byte, since its width is unknown, and a truncated operand ends the walk.
+pub(crate) struct Instructions<'a> {
+    code: &'a [u8],
+    ip: usize,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(code: &'a [u8]) -> Instructions<'a> {
+        Instructions { code, ip: 0 }
+    }
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = (usize, Result<Instruction, DecodeError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.ip;
+        let &byte = self.code.get(offset)?;
+        let Ok(op) = ByteCode::try_from(byte) else {
+            self.ip += 1;
+            return Some((offset, Err(DecodeError::UnknownOpcode(byte))));
+        };
+
+        let end = offset + 1 + op.operand_width();
+        let Some(operand) = self.code.get(offset + 1..end) else {
+            self.ip = self.code.len();
+            return Some((offset, Err(DecodeError::Truncated(op))));
+        };
+        self.ip = end;
+        Some((offset, Ok(Instruction::decode(op, operand))))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DisassembleOptions {
+    /// Precede jump targets with `L0:`-style labels and name them at each jump.
+    pub labels: bool,
+}
+
+/// A line of assembler source that couldn't be encoded; `line` is 1-based.
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub token: String,
+    pub message: &'static str,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {} '{}'", self.line, self.message, self.token)
+    }
+}
+
+/// Encodes one instruction per line, written as the disassembler prints it: a mnemonic
+/// followed by its decimal operand (optionally as `args=n` for calls), with anything after
+/// `;` ignored.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut code = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let error = |token: &str, message| AsmError {
+            line: i + 1,
+            token: token.to_string(),
+            message,
+        };
+
+        let text = line.split(';').next().unwrap_or_default();
+        let mut tokens = text.split_whitespace();
+        let Some(mnemonic) = tokens.next() else {
+            continue;
+        };
+
+        let op =
+            ByteCode::from_mnemonic(mnemonic).ok_or_else(|| error(mnemonic, "unknown mnemonic"))?;
+        code.push(op as u8);
+
+        let width = op.operand_width();
+        if width > 0 {
+            let operand = tokens
+                .next()
+                .ok_or_else(|| error(mnemonic, "missing operand for"))?;
+            let value: u64 = operand
+                .strip_prefix("args=")
+                .unwrap_or(operand)
+                .parse()
+                .map_err(|_| error(operand, "invalid operand"))?;
+            if value >> (8 * width) != 0 {
+                return Err(error(operand, "operand out of range"));
+            }
+            code.extend_from_slice(&value.to_be_bytes()[8 - width..]);
+        }
+
+        if let Some(extra) = tokens.next() {
+            return Err(error(extra, "unexpected token"));
+        }
+    }
+    Ok(code)
+}
+
+/// Why `verify_function` rejected some code, with the offset of the offending instruction.
+#[derive(Debug)]
+pub enum VerifyError {
+    UnknownOpcode { offset: usize, byte: u8 },
+    TruncatedOperand { offset: usize },
+    BadJumpTarget { offset: usize, target: isize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::UnknownOpcode { offset, byte } => {
+                write!(f, "unknown opcode {byte:#04x} at {offset:04}")
+            }
+            VerifyError::TruncatedOperand { offset } => {
+                write!(f, "truncated operand at {offset:04}")
+            }
+            VerifyError::BadJumpTarget { offset, target } => {
+                write!(
+                    f,
+                    "jump at {offset:04} targets {target}, which is not an instruction"
+                )
+            }
+        }
+    }
+}
+
+/// Checks that `code` decodes to whole instructions and that every jump lands on the
+/// start of one.
+pub fn verify_function(code: &[u8]) -> Result<(), VerifyError> {
+    let mut starts = vec![false; code.len()];
+    let mut jumps = Vec::new();
+
+    for (offset, instruction) in Instructions::new(code) {
+        let instruction = instruction.map_err(|e| match e {
+            DecodeError::UnknownOpcode(byte) => VerifyError::UnknownOpcode { offset, byte },
+            DecodeError::Truncated(_) => VerifyError::TruncatedOperand { offset },
+        })?;
+
+        if let Some(target) = instruction.jump_target(offset) {
+            jumps.push((offset, target));
+        }
+        starts[offset] = true;
+    }
+
+    for (offset, target) in jumps {
+        let lands = usize::try_from(target).is_ok_and(|t| starts.get(t) == Some(&true));
+        if !lands {
+            return Err(VerifyError::BadJumpTarget { offset, target });
+        }
+    }
+    Ok(())
+}
+
+/// Splits verified code into basic blocks and renders them as a Graphviz `digraph`, with
+/// fallthrough and jump edges. Blocks unreachable from the entry are drawn dashed.
+fn function_cfg(code: &[u8]) -> Result<String, VerifyError> {
+    verify_function(code)?;
+    let instructions = Instructions::new(code)
+        .map(|(offset, instruction)| (offset, instruction.expect("verified")))
+        .collect::<Vec<_>>();
+
+    // A block starts at the entry, at every jump target and after every branch or return
+    let mut leaders = vec![0];
+    for &(offset, instruction) in &instructions {
+        if let Some(target) = instruction.jump_target(offset) {
+            leaders.push(target as usize);
+        }
+        if ends_block(instruction) {
+            leaders.push(offset + instruction.len());
+        }
+    }
+    leaders.retain(|&leader| leader < code.len());
+    leaders.sort_unstable();
+    leaders.dedup();
+
+    let block_of = |offset: usize| leaders.iter().rposition(|&leader| leader <= offset);
+    let mut blocks = vec![Vec::new(); leaders.len()];
+    for &(offset, instruction) in &instructions {
+        if let Some(block) = block_of(offset) {
+            blocks[block].push((offset, instruction));
+        }
+    }
+
+    let mut edges = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let Some(&(offset, last)) = block.last() else {
+            continue;
+        };
+        if let Some(target) = last.jump_target(offset) {
+            edges.push((i, block_of(target as usize).unwrap_or_default(), true));
+        }
+        let falls_through = !matches!(
+            last,
+            Instruction::Jump(_)
+                | Instruction::Loop(_)
+                | Instruction::Return
+                | Instruction::TailCall(_)
+        );
+        if falls_through && i + 1 < blocks.len() {
+            edges.push((i, i + 1, false));
+        }
+    }
+
+    let mut reachable = vec![false; blocks.len()];
+    let mut pending = vec![0];
+    while let Some(block) = pending.pop() {
+        if block < reachable.len() && !reachable[block] {
+            reachable[block] = true;
+            pending.extend(edges.iter().filter(|e| e.0 == block).map(|e| e.1));
+        }
+    }
+
+    let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+    for (i, block) in blocks.iter().enumerate() {
+        let label = block
+            .iter()
+            .map(|&(offset, instruction)| {
+                format!("{offset:04} {}\\l", instruction_text(offset, instruction))
+            })
+            .collect::<String>();
+        let style = if reachable[i] {
+            ""
+        } else {
+            ", style=dashed, fontcolor=gray"
+        };
+        dot.push_str(&format!("    b{i} [label=\"{label}\"{style}];\n"));
+    }
+    for (from, to, jump) in edges {
+        let style = if jump { " [label=jump]" } else { "" };
+        dot.push_str(&format!("    b{from} -> b{to}{style};\n"));
+    }
+    dot.push_str("}\n");
+    Ok(dot)
+}
+
+fn ends_block(instruction: Instruction) -> bool {
+    instruction.op().jump_sign().is_some()
+        || matches!(instruction, Instruction::Return | Instruction::TailCall(_))
+}
+
+/// Renders an instruction without a constant table, for contexts like CFG nodes.
+fn instruction_text(offset: usize, instruction: Instruction) -> String {
+    let name = instruction.op().mnemonic();
+    match (
+        instruction.op().info().width,
+        instruction.jump_target(offset),
+    ) {
+        (_, Some(target)) => format!("{name} -> {target:04}"),
+        (0, None) => name.to_string(),
+        (_, None) => format!("{name} {}", instruction.operand()),
+    }
+}
+
+/// Writes `function_cfg` for every function in the pool to `dir/<identifier>.dot`,
+/// numbering repeated identifiers, and returns how many graphs were written. Functions
+/// that fail verification are skipped, each with a `Warning::SkippedCfg` in `warnings`.
+pub fn write_function_cfgs(
+    pool: &ObjectPool,
+    dir: &str,
+    warnings: &mut Vec<Warning>,
+) -> Result<usize, ConstantsError> {
+    fs::create_dir_all(dir).map_err(open_error(dir))?;
+
+    let mut written = Vec::<String>::new();
+    for object in pool {
+        let Object::Function {
+            identifier, code, ..
+        } = &**object
+        else {
+            continue;
+        };
+        let dot = match function_cfg(code) {
+            Ok(dot) => dot,
+            Err(error) => {
+                warnings.push(Warning::SkippedCfg {
+                    identifier: identifier.clone(),
+                    error,
+                });
+                continue;
+            }
+        };
+
+        let stem = identifier
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let repeats = written.iter().filter(|name| **name == stem).count();
+        let file_name = match repeats {
+            0 => format!("{dir}/{stem}.dot"),
+            n => format!("{dir}/{stem}-{n}.dot"),
+        };
+        fs::write(&file_name, dot).map_err(open_error(&file_name))?;
+        written.push(stem);
+    }
+    Ok(written.len())
+}
+
+/// One difference between two decoded instruction streams, with offsets into each side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodeDelta {
+    Removed {
+        offset: usize,
+        instruction: Result<Instruction, DecodeError>,
+    },
+    Inserted {
+        offset: usize,
+        instruction: Result<Instruction, DecodeError>,
+    },
+    /// The same opcode with a different operand.
+    Changed {
+        old_offset: usize,
+        new_offset: usize,
+        old: Instruction,
+        new: Instruction,
+    },
+}
+
+/// Compares `a` and `b` instruction by instruction along their longest common
+/// subsequence. Malformed bytes take part as their decode errors.
+pub fn diff_code(a: &[u8], b: &[u8]) -> Vec<CodeDelta> {
+    let a = Instructions::new(a).collect::<Vec<_>>();
+    let b = Instructions::new(b).collect::<Vec<_>>();
+
+    // common[i][j] is the LCS length of a[i..] and b[j..]
+    let mut common = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i].1 == b[j].1 {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut deltas = Vec::new();
+    let (mut removed, mut inserted) = (Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i].1 == b[j].1 {
+            pair_deltas(&mut deltas, &mut removed, &mut inserted);
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]) {
+            removed.push(a[i]);
+            i += 1;
+        } else {
+            inserted.push(b[j]);
+            j += 1;
+        }
+    }
+    pair_deltas(&mut deltas, &mut removed, &mut inserted);
+    deltas
+}
+
+type Decoded = (usize, Result<Instruction, DecodeError>);
+
+/// Flushes a run of removals and insertions between matches, pairing the i-th of each
+/// into a `Changed` when only the operand differs.
+fn pair_deltas(
+    deltas: &mut Vec<CodeDelta>,
+    removed: &mut Vec<Decoded>,
+    inserted: &mut Vec<Decoded>,
+) {
+    let mut rest_removed = Vec::new();
+    let mut rest_inserted = Vec::new();
+    let mut inserted_iter = inserted.drain(..);
+    for (old_offset, old) in removed.drain(..) {
+        match (old, inserted_iter.next()) {
+            (Ok(old), Some((new_offset, Ok(new)))) if old.op() == new.op() => {
+                deltas.push(CodeDelta::Changed {
+                    old_offset,
+                    new_offset,
+                    old,
+                    new,
+                });
+            }
+            (old, new) => {
+                rest_removed.push((old_offset, old));
+                rest_inserted.extend(new);
+            }
+        }
+    }
+    rest_inserted.extend(inserted_iter);
+
+    deltas.extend(
+        rest_removed
+            .into_iter()
+            .map(|(offset, instruction)| CodeDelta::Removed {
+                offset,
+                instruction,
+            }),
+    );
+    deltas.extend(
+        rest_inserted
+            .into_iter()
+            .map(|(offset, instruction)| CodeDelta::Inserted {
+                offset,
+                instruction,
+            }),
+    );
+}
+
+/// Renders deltas in the style of a unified diff, one `-`/`+` line per instruction side.
+fn render_code_diff(deltas: &[CodeDelta]) -> String {
+    let mut text = String::new();
+    for delta in deltas {
+        match delta {
+            CodeDelta::Removed {
+                offset,
+                instruction,
+            } => text.push_str(&format!(
+                "-{offset:04} {}\n",
+                decoded_text(*offset, instruction)
+            )),
+            CodeDelta::Inserted {
+                offset,
+                instruction,
+            } => text.push_str(&format!(
+                "+{offset:04} {}\n",
+                decoded_text(*offset, instruction)
+            )),
+            CodeDelta::Changed {
+                old_offset,
+                new_offset,
+                old,
+                new,
+            } => {
+                text.push_str(&format!(
+                    "-{old_offset:04} {}\n",
+                    instruction_text(*old_offset, *old)
+                ));
+                text.push_str(&format!(
+                    "+{new_offset:04} {}\n",
+                    instruction_text(*new_offset, *new)
+                ));
+            }
+        }
+    }
+    text
+}
+
+pub(crate) fn decoded_text(
+    offset: usize,
+    instruction: &Result<Instruction, DecodeError>,
+) -> String {
+    match instruction {
+        Ok(instruction) => instruction_text(offset, *instruction),
+        Err(DecodeError::UnknownOpcode(byte)) => format!("UNKNOWN_OPCODE {byte:#04x}"),
+        Err(DecodeError::Truncated(op)) => format!("{} <truncated>", op.mnemonic()),
+    }
+}
+
+/// Writes `render_code_diff` to `out` for each function in `old` whose code differs from
+/// the first function with the same identifier in `new`.
+pub fn write_function_diffs(
+    out: &mut impl Write,
+    old: &ObjectPool,
+    new: &ObjectPool,
+) -> io::Result<()> {
+    let function = |object: &Rc<Object>| match &**object {
+        Object::Function {
+            identifier, code, ..
+        } => Some((identifier.clone(), code.clone())),
+        _ => None,
+    };
+    let new = new.iter().filter_map(function).collect::<Vec<_>>();
+
+    for (identifier, code) in old.iter().filter_map(function) {
+        let Some((_, new_code)) = new.iter().find(|(id, _)| *id == identifier) else {
+            writeln!(out, "--- {identifier}: only in the loaded file")?;
+            continue;
+        };
+        let deltas = diff_code(&code, new_code);
+        if !deltas.is_empty() {
+            writeln!(out, "--- {identifier}\n+++ {identifier}")?;
+            write!(out, "{}", render_code_diff(&deltas))?;
+        }
+    }
+    Ok(())
+}
+
+/// Encodes `instructions`, each given with its offset in the code it was decoded from,
+/// fixing jump operands so every jump still lands where its original target did. A target
+/// whose instruction was dropped moves to the next one kept. Fails with the original
+/// offset of a jump whose new operand doesn't fit.
+fn relayout(instructions: &[(usize, Instruction)]) -> Result<Vec<u8>, usize> {
+    let mut new_offsets = Vec::with_capacity(instructions.len());
+    let mut new_len = 0;
+    for (_, instruction) in instructions {
+        new_offsets.push(new_len);
+        new_len += instruction.len();
+    }
+    let new_target = |old_target: isize| {
+        instructions
+            .iter()
+            .position(|(offset, _)| *offset as isize >= old_target)
+            .map_or(new_len, |i| new_offsets[i])
+    };
+
+    let mut code = Vec::with_capacity(new_len);
+    for (i, &(old_offset, instruction)) in instructions.iter().enumerate() {
+        let instruction = match (
+            instruction.jump_target(old_offset),
+            instruction.op().jump_sign(),
+        ) {
+            (Some(target), Some(sign)) => {
+                let end = new_offsets[i] + instruction.len();
+                let jump = (new_target(target) as isize - end as isize) * sign;
+                let jump = u16::try_from(jump).map_err(|_| old_offset)?;
+                Instruction::with_operand(instruction.op(), jump)
+            }
+            _ => instruction,
+        };
+        instruction.encode(&mut code);
+    }
+    Ok(code)
+}
+
+#[derive(Debug)]
+pub enum RelocateError {
+    Invalid(VerifyError),
+    /// A remapped constant index too large even for `CONSTANT_LONG`.
+    IndexTooLarge {
+        offset: usize,
+        index: u32,
+    },
+    /// A jump whose offset no longer fits in its operand once instructions grew.
+    JumpTooFar {
+        offset: usize,
+    },
+}
+
+impl fmt::Display for RelocateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelocateError::Invalid(e) => write!(f, "{e}"),
+            RelocateError::IndexTooLarge { offset, index } => {
+                write!(f, "constant index {index} at {offset:04} is too large")
+            }
+            RelocateError::JumpTooFar { offset } => {
+                write!(f, "jump at {offset:04} is too far once relocated")
+            }
+        }
+    }
+}
+
+/// Rewrites every constant operand in `code` through `remap`, widening `CONSTANT_BYTE`
+/// to `CONSTANT_LONG` where the new index needs it and fixing jumps to match. Code that
+/// fails `verify_function` is left untouched, as is code that fails to relocate. Line
+/// tables aren't adjusted, so callers whose code may grow must rebuild them.
+pub fn relocate_code(code: &mut Vec<u8>, remap: &dyn Fn(u32) -> u32) -> Result<(), RelocateError> {
+    verify_function(code).map_err(RelocateError::Invalid)?;
+
+    let mut relocated = Vec::new();
+    for (offset, instruction) in Instructions::new(code) {
+        let instruction = instruction.expect("verified");
+        if instruction.op().info().operand != OperandKind::Constant {
+            relocated.push((offset, instruction));
+            continue;
+        }
+
+        let index = remap(instruction.operand() as u32);
+        let op = match instruction.op() {
+            ByteCode::ConstantByte if index <= u8::MAX as u32 => ByteCode::ConstantByte,
+            _ => ByteCode::ConstantLong,
+        };
+        let index =
+            u16::try_from(index).map_err(|_| RelocateError::IndexTooLarge { offset, index })?;
+        relocated.push((offset, Instruction::with_operand(op, index)));
+    }
+
+    *code = relayout(&relocated).map_err(|offset| RelocateError::JumpTooFar { offset })?;
+    Ok(())
+}
+
+/// Rebuilds `value` with `rewrite` applied to every function inside it, innermost first,
+/// however deep in other objects: constants and defaults, class methods, module members,
+/// and the items of containers. Objects with no function inside are shared unchanged.
+pub fn map_functions(
+    value: &Value,
+    rewrite: &mut dyn FnMut(Object) -> Object,
+    pool: &mut ObjectPool,
+) -> Value {
+    let o = match value {
+        Value::Optional(Some(inner)) => {
+            return Value::Optional(Some(Box::new(map_functions(inner, rewrite, pool))))
+        }
+        Value::Object(o) => o,
+        _ => return value.clone(),
+    };
+
+    let object = match &**o {
+        Object::Function { .. } => {
+            let mut function = (**o).clone();
+            if let Object::Function {
+                constants,
+                defaults,
+                ..
+            } = &mut function
+            {
+                for values in [constants, defaults] {
+                    *values = values
+                        .iter()
+                        .map(|value| map_functions(value, rewrite, pool))
+                        .collect();
+                }
+            }
+            rewrite(function)
+        }
+        Object::Class { name, methods } => Object::Class {
+            name: name.clone(),
+            methods: methods
+                .iter()
+                .map(|(name, method)| {
+                    let method = Value::Object(Rc::clone(method));
+                    let Value::Object(method) = map_functions(&method, rewrite, pool) else {
+                        unreachable!("objects map to objects");
+                    };
+                    (name.clone(), method)
+                })
+                .collect(),
+        },
+        Object::Module { name, constants } => Object::Module {
+            name: name.clone(),
+            constants: constants
+                .iter()
+                .map(|constant| map_functions(constant, rewrite, pool))
+                .collect(),
+        },
+        Object::Pair(..) => return map_pair_functions(o, rewrite, pool),
+        object => {
+            let Some(object) = map_contents(object, rewrite, pool) else {
+                return value.clone();
+            };
+            object
+        }
+    };
+
+    let object = Rc::new(object);
+    pool.push(Rc::clone(&object));
+    Value::Object(object)
+}
+
+/// `map_functions` over the values a list, tuple, set, map, enum variant or record holds,
+/// or `None` when none of them changed.
+fn map_contents(
+    object: &Object,
+    rewrite: &mut dyn FnMut(Object) -> Object,
+    pool: &mut ObjectPool,
+) -> Option<Object> {
+    let mut map = |values: &mut dyn Iterator<Item = &Value>| {
+        let mut changed = false;
+        let mapped = values
+            .map(|value| {
+                let new = map_functions(value, rewrite, pool);
+                changed |= !shares_objects(&new, value);
+                new
+            })
+            .collect::<Vec<_>>();
+        changed.then_some(mapped)
+    };
+    Some(match object {
+        Object::List(items) => Object::List(map(&mut items.iter())?),
+        Object::Tuple(items) => Object::Tuple(map(&mut items.iter())?),
+        Object::Set(items) => Object::Set(map(&mut items.iter())?),
+        Object::Map(entries) => {
+            let mapped = map(&mut entries.iter().flat_map(|(key, value)| [key, value]))?;
+            let mut mapped = mapped.into_iter();
+            Object::Map(std::iter::from_fn(|| Some((mapped.next()?, mapped.next()?))).collect())
+        }
+        Object::EnumVariant {
+            type_name,
+            variant,
+            payload,
+        } => Object::EnumVariant {
+            type_name: type_name.clone(),
+            variant: variant.clone(),
+            payload: map(&mut payload.as_deref().into_iter())?
+                .pop()
+                .map(Box::new),
+        },
+        Object::Record { type_name, fields } => Object::Record {
+            type_name: type_name.clone(),
+            fields: fields
+                .iter()
+                .map(|(name, _)| name.clone())
+                .zip(map(&mut fields.iter().map(|(_, value)| value))?)
+                .collect(),
+        },
+        _ => return None,
+    })
+}
+
+/// `map_functions` over a pair chain, walked along its cdrs rather than recursed into,
+/// since chains can be far longer than any nesting limit. Cells from the first changed
+/// one back to the head are rebuilt; the rest of the chain is shared.
+fn map_pair_functions(
+    head: &Rc<Object>,
+    rewrite: &mut dyn FnMut(Object) -> Object,
+    pool: &mut ObjectPool,
+) -> Value {
+    let mut cells = Vec::new();
+    let mut cell = head;
+    let tail = loop {
+        let Object::Pair(car, cdr) = &**cell else {
+            unreachable!("the chain only holds pairs");
+        };
+        cells.push((cell, map_functions(car, rewrite, pool)));
+        match cdr {
+            Value::Object(next) if matches!(**next, Object::Pair(..)) => cell = next,
+            cdr => break cdr,
+        }
+    };
+
+    let mut cdr = map_functions(tail, rewrite, pool);
+    let mut changed = !shares_objects(&cdr, tail);
+    for (cell, car) in cells.into_iter().rev() {
+        let Object::Pair(old_car, _) = &**cell else {
+            unreachable!("the chain only holds pairs");
+        };
+        changed |= !shares_objects(&car, old_car);
+        cdr = if changed {
+            Value::from_pair(car, cdr, pool)
+        } else {
+            Value::Object(Rc::clone(cell))
+        };
+    }
+    cdr
+}
+
+/// Whether `new` is `old` itself, as `map_functions` hands back what it didn't change.
+fn shares_objects(new: &Value, old: &Value) -> bool {
+    match (new, old) {
+        (Value::Object(new), Value::Object(old)) => Rc::ptr_eq(new, old),
+        (Value::Optional(Some(new)), Value::Optional(Some(old))) => shares_objects(new, old),
+        _ => true,
+    }
+}
+
+/// Drops repeated entries from a function's constant table and relocates its code onto
+/// the first copy. Entries are repeats when they encode to the same bytes. A function
+/// whose code can't be relocated, such as code that doesn't verify, is left as it is.
+pub fn compact_constants(mut function: Object) -> Object {
+    let Object::Function {
+        code, constants, ..
+    } = &mut function
+    else {
+        return function;
+    };
+
+    let encoded = constants
+        .iter()
+        .map(|constant| {
+            let mut bytes = Vec::new();
+            // Saving the function reports any constant that can't be encoded
+            let _ = constant.write(&mut bytes, &WriteState::new(&WriteOptions::default()));
+            bytes
+        })
+        .collect::<Vec<_>>();
+
+    let mut kept = Vec::new();
+    let mut remap = Vec::<u32>::new();
+    for (i, bytes) in encoded.iter().enumerate() {
+        match encoded[..i].iter().position(|earlier| earlier == bytes) {
+            Some(first) => remap.push(remap[first]),
+            None => {
+                remap.push(kept.len() as u32);
+                kept.push(constants[i].clone());
+            }
+        }
+    }
+
+    let remap = |index: u32| remap.get(index as usize).copied().unwrap_or(index);
+    if kept.len() < constants.len() && relocate_code(code, &remap).is_ok() {
+        *constants = kept;
+    }
+    function
+}
+
+/// What `optimize_code` changed, per rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptStats {
+    /// Bytes of constants pushed only to be popped straight away.
+    pub constant_pop_bytes: usize,
+    /// Bytes of `NEGATE` pairs that cancel out.
+    pub double_negate_bytes: usize,
+    /// Jumps retargeted past a jump they landed on. These save no bytes by themselves.
+    pub threaded_jumps: usize,
+}
+
+impl OptStats {
+    fn merge(&mut self, other: &OptStats) {
+        self.constant_pop_bytes += other.constant_pop_bytes;
+        self.double_negate_bytes += other.double_negate_bytes;
+        self.threaded_jumps += other.threaded_jumps;
+    }
+}
+
+/// Applies peephole rules to `code`: drops a constant followed by `POP`, drops `NEGATE`
+/// pairs and sends jumps that land on an unconditional jump straight to its target. Jump
+/// offsets are fixed to match. Code failing `verify_function` is returned unchanged.
+pub fn optimize_code(code: &[u8]) -> (Vec<u8>, OptStats) {
+    optimize_instructions(code)
+        .and_then(|(kept, stats)| Some((relayout(&kept).ok()?, stats)))
+        .unwrap_or_else(|| (code.to_vec(), OptStats::default()))
+}
+
+/// The instructions `optimize_code` keeps, with the offsets they had in `code`.
+fn optimize_instructions(code: &[u8]) -> Option<(Vec<(usize, Instruction)>, OptStats)> {
+    verify_function(code).ok()?;
+    let mut stats = OptStats::default();
+    let mut instructions = Instructions::new(code)
+        .map(|(offset, instruction)| (offset, instruction.expect("verified")))
+        .collect::<Vec<_>>();
+    let at = |instructions: &[(usize, Instruction)], target: usize| {
+        instructions
+            .iter()
+            .find(|(offset, _)| *offset == target)
+            .map(|&(_, instruction)| instruction)
+    };
+
+    // Follow chains of unconditional jumps, giving up on cycles
+    for i in 0..instructions.len() {
+        let (offset, instruction) = instructions[i];
+        let Some(mut target) = instruction.jump_target(offset) else {
+            continue;
+        };
+        let mut seen = vec![offset as isize];
+        while let Some(next @ (Instruction::Jump(_) | Instruction::Loop(_))) =
+            at(&instructions, target as usize)
+        {
+            if seen.contains(&target) {
+                break;
+            }
+            seen.push(target);
+            target = next
+                .jump_target(target as usize)
+                .expect("jumps have targets");
+        }
+        if seen.len() == 1 || seen.contains(&target) {
+            continue;
+        }
+
+        let end = (offset + instruction.len()) as isize;
+        let op = match instruction {
+            Instruction::JumpIfFalse(_) if target >= end => ByteCode::JumpIfFalse,
+            Instruction::Jump(_) | Instruction::Loop(_) if target >= end => ByteCode::Jump,
+            Instruction::Jump(_) | Instruction::Loop(_) => ByteCode::Loop,
+            _ => continue,
+        };
+        let Ok(jump) = u16::try_from((target - end).abs()) else {
+            continue;
+        };
+        instructions[i].1 = Instruction::with_operand(op, jump);
+        stats.threaded_jumps += 1;
+    }
+
+    // A removed pair must not be split by a jump landing on its second half
+    let targets = instructions
+        .iter()
+        .filter_map(|&(offset, instruction)| instruction.jump_target(offset))
+        .collect::<Vec<_>>();
+    let is_target = |offset: usize| targets.contains(&(offset as isize));
+
+    let mut kept = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+        let (offset, instruction) = instructions[i];
+        if let Some(&(next_offset, next)) = instructions.get(i + 1) {
+            let pushes_constant = instruction.op().info().operand == OperandKind::Constant;
+            let saved = instruction.len() + next.len();
+            if !is_target(next_offset) {
+                match (instruction, next) {
+                    (_, Instruction::Pop) if pushes_constant => {
+                        stats.constant_pop_bytes += saved;
+                        i += 2;
+                        continue;
+                    }
+                    (Instruction::Negate, Instruction::Negate) => {
+                        stats.double_negate_bytes += saved;
+                        i += 2;
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        kept.push((offset, instruction));
+        i += 1;
+    }
+
+    Some((kept, stats))
+}
+
+/// Runs `optimize_code` on a function and moves its line table onto the new offsets.
+pub fn optimize_function(mut function: Object, stats: &mut OptStats) -> Object {
+    let Object::Function { code, lines, .. } = &mut function else {
+        return function;
+    };
+    let (optimized, function_stats) = optimize_code(code);
+    if optimized == *code {
+        return function;
+    }
+
+    // Optimized code always came from the kept instructions, so they're there to map through
+    if let (Some(lines), Some((kept, _))) = (lines, optimize_instructions(code)) {
+        let mut new_offset = 0;
+        let mut new_offsets = Vec::with_capacity(kept.len());
+        for (offset, instruction) in &kept {
+            new_offsets.push((*offset, new_offset));
+            new_offset += instruction.len();
+        }
+        for (start, _) in lines.iter_mut() {
+            *start = new_offsets
+                .iter()
+                .find(|(offset, _)| *offset >= *start as usize)
+                .map_or(new_offset, |(_, new)| *new) as u32;
+        }
+        lines.dedup_by_key(|(start, _)| *start);
+    }
+
+    *code = optimized;
+    stats.merge(&function_stats);
+    function
+}
+
+/// Instruction counts and sizes over one or more functions' code.
+#[derive(Debug, Clone, Default)]
+pub struct CodeStats {
+    /// Count per opcode, indexed like `OPCODES`.
+    pub counts: Vec<usize>,
+    /// Unknown opcodes and truncated trailing instructions.
+    pub invalid: usize,
+    pub instructions: usize,
+    pub bytes: usize,
+}
+
+impl CodeStats {
+    fn merge(&mut self, other: &CodeStats) {
+        self.counts.resize(OPCODES.len(), 0);
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.invalid += other.invalid;
+        self.instructions += other.instructions;
+        self.bytes += other.bytes;
+    }
+
+    /// Writes one row per opcode that occurs, most frequent first.
+    pub fn write_table(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut rows = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(byte, count)| {
+                (
+                    OPCODES[byte].name,
+                    *count,
+                    count * (1 + OPCODES[byte].width),
+                )
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        writeln!(out, "{:<16} {:>8} {:>8}", "opcode", "count", "bytes")?;
+        for (name, count, bytes) in rows {
+            writeln!(out, "{name:<16} {count:>8} {bytes:>8}")?;
+        }
+        if self.invalid > 0 {
+            writeln!(out, "{:<16} {:>8}", "invalid", self.invalid)?;
+        }
+        writeln!(
+            out,
+            "{:<16} {:>8} {:>8}",
+            "total", self.instructions, self.bytes
+        )
+    }
+}
+
+/// Counts the instructions in `code` by opcode, the ones that don't decode as `invalid`.
+pub fn analyze_code(code: &[u8]) -> CodeStats {
+    let mut stats = CodeStats {
+        counts: vec![0; OPCODES.len()],
+        bytes: code.len(),
+        ..CodeStats::default()
+    };
+
+    for (_, instruction) in Instructions::new(code) {
+        stats.instructions += 1;
+        match instruction {
+            Ok(instruction) => stats.counts[instruction.op() as usize] += 1,
+            Err(_) => stats.invalid += 1,
+        }
+    }
+    stats
+}
+
+/// Sums `analyze_code` over every function in the pool, nested ones included.
+pub fn pool_code_stats(pool: &ObjectPool) -> CodeStats {
+    let mut stats = CodeStats::default();
+    for object in pool {
+        if let Object::Function { code, .. } = &**object {
+            stats.merge(&analyze_code(code));
+        }
+    }
+    stats
+}
+
+/// Writes an instruction with a one-byte local slot operand, marking slots at or past
+/// `local_count` when the function declares one.
+pub(crate) fn byte_instruction(
+    out: &mut impl Write,
+    label: &'static str,
+    slot: u8,
+    local_count: u16,
+) -> io::Result<()> {
+    if local_count > 0 && slot as u16 >= local_count {
+        writeln!(out, "{label} {slot} !! only {local_count} locals")
+    } else {
+        writeln!(out, "{label} {slot}")
+    }
+}
+
+pub(crate) fn call_instruction(
+    out: &mut impl Write,
+    label: &'static str,
+    arg_count: u8,
+) -> io::Result<()> {
+    writeln!(out, "{label} args={arg_count}")
+}
+
+pub(crate) fn simple_instruction(out: &mut impl Write, label: &'static str) -> io::Result<()> {
+    writeln!(out, "{label}")
+}
+
+/// Writes a jump with its big-endian u16 offset and the absolute target it resolves to.
+/// With `labels`, the target is also named, or marked `!!` if it isn't an instruction.
+pub(crate) fn jump_instruction(
+    out: &mut impl Write,
+    label: &'static str,
+    jump: u16,
+    target: isize,
+    labels: Option<&[usize]>,
+) -> io::Result<()> {
+    let Some(labels) = labels else {
+        return writeln!(out, "{label} {jump} -> {target:04}");
+    };
+
+    match labels.iter().position(|&t| t as isize == target) {
+        Some(i) => writeln!(out, "{label} {jump} -> L{i} ({target:04})"),
+        None => writeln!(out, "{label} {jump} -> !! ({target:04})"),
+    }
+}
+
+/// Finds the source line of the instruction at `offset` in a run-length line table.
+pub(crate) fn line_at(lines: &[(u32, u32)], offset: usize) -> Option<u32> {
+    lines
+        .iter()
+        .take_while(|(start, _)| *start as usize <= offset)
+        .last()
+        .map(|(_, line)| *line)
+}
+
+/// Longest constant rendering shown beside an instruction before it's cut short.
+const MAX_OPERAND_CHARS: usize = 40;
+
+/// Writes a constant instruction followed by the value it loads from the function's own
+/// table, the way it would appear nested in another constant.
+pub(crate) fn constant_instruction(
+    out: &mut impl Write,
+    label: &'static str,
+    index: usize,
+    constants: &[Value],
+) -> io::Result<()> {
+    let Some(constant) = constants.get(index) else {
+        return writeln!(out, "{label} {index} ; <out of range>");
+    };
+
+    let rendered = NestedValue(constant).to_string();
+    let rendered = rendered.lines().next().unwrap_or_default();
+    if rendered.chars().count() > MAX_OPERAND_CHARS {
+        let cut = rendered.chars().take(MAX_OPERAND_CHARS).collect::<String>();
+        writeln!(out, "{label} {index} ; {cut}...")
+    } else {
+        writeln!(out, "{label} {index} ; {rendered}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The disassembly of `value`, as `Value::display` would print it.
+    fn disassembly(value: &Value) -> String {
+        let mut out = Vec::new();
+        value
+            .disassemble_at(&mut out, 0, &DisassembleOptions::default())
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// `values` written out and loaded back.
+    fn reloaded(values: &[Value]) -> Vec<Value> {
+        let bytes = serialize_to_vec(values, &WriteOptions::default()).unwrap();
+        deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn out_of_range_opcodes_load_and_disassemble_as_unknown() {
+        let mut pool = ObjectPool::new();
+        let code = [0x7f, ByteCode::Return as u8, 0xff];
+        let function = FunctionBuilder::new("f", 0, &code)
+            .build(&mut pool)
+            .unwrap();
+        let read = reloaded(&[function]);
+        assert_eq!(
+            disassembly(&read[0]),
+            "func<'f', 0, 0>\n0000 UNKNOWN_OPCODE 0x7f\n0001 RETURN\n0002 UNKNOWN_OPCODE 0xff\n"
+        );
+        for byte in 0..=u8::MAX {
+            assert_eq!(
+                ByteCode::try_from(byte).is_ok(),
+                byte <= ByteCode::Pop as u8
+            );
+        }
+    }
+
+    #[test]
+    fn assembled_code_disassembles_to_its_source() {
+        let source = "\
+            ; negates its argument, then calls it
+            CONSTANT_BYTE 0 ; three
+            NEGATE
+            CALL args=2
+
+            JUMP 1
+            POP
+            RETURN
+        ";
+        let mut pool = ObjectPool::new();
+        let function = FunctionBuilder::new("f", 1, &assemble(source).unwrap())
+            .constants(vec![Value::Int(3)])
+            .build(&mut pool)
+            .unwrap();
+        let code = disassembly(&reloaded(&[function])[0]);
+        let instructions = code
+            .lines()
+            .filter(|line| line.starts_with(|c: char| c.is_ascii_digit()))
+            .map(|line| &line[5..])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            instructions,
+            [
+                "CONSTANT_BYTE 0 ; 3",
+                "NEGATE",
+                "CALL args=2",
+                "JUMP 1 -> 0009",
+                "POP",
+                "RETURN"
+            ]
+        );
+
+        let errors = [
+            ("RETURN\nPUSH 1", 2, "PUSH"),
+            ("GET_LOCAL", 1, "GET_LOCAL"),
+            ("GET_LOCAL 256", 1, "256"),
+            ("JUMP -1", 1, "-1"),
+            ("RETURN\n\nPOP 1", 3, "1"),
+        ];
+        for (source, line, token) in errors {
+            let e = assemble(source).unwrap_err();
+            assert_eq!((e.line, e.token.as_str()), (line, token), "{source:?}");
+        }
+        assert_eq!(
+            assemble("ADD\nCALL x").unwrap_err().to_string(),
+            "line 2: invalid operand 'x'"
+        );
+    }
+
+    #[test]
+    fn a_truncated_final_instruction_ends_the_walk() {
+        let code = [
+            ByteCode::Return as u8,
+            0x7f,
+            ByteCode::GetLocal as u8,
+            2,
+            ByteCode::Jump as u8,
+            0,
+        ];
+        assert_eq!(
+            Instructions::new(&code).collect::<Vec<_>>(),
+            [
+                (0, Ok(Instruction::Return)),
+                (1, Err(DecodeError::UnknownOpcode(0x7f))),
+                (2, Ok(Instruction::GetLocal(2))),
+                (4, Err(DecodeError::Truncated(ByteCode::Jump))),
+            ]
+        );
+        assert_eq!(
+            Instructions::new(&[ByteCode::ConstantLong as u8]).collect::<Vec<_>>(),
+            [(0, Err(DecodeError::Truncated(ByteCode::ConstantLong)))]
+        );
+        assert_eq!(Instructions::new(&[]).count(), 0);
+
+        let function = FunctionBuilder::new("f", 0, &code)
+            .build(&mut ObjectPool::new())
+            .unwrap();
+        assert!(
+            disassembly(&function).ends_with("0004 JUMP <truncated>\n"),
+            "{}",
+            disassembly(&function)
+        );
+    }
+
+    #[test]
+    fn relocating_widens_constants_and_fixes_the_jumps_around_them() {
+        use ByteCode::*;
+        #[rustfmt::skip]
+        let mut code = vec![
+            ConstantByte as u8, 0,
+            // Lands on the LOOP, past the constant that grows
+            JumpIfFalse as u8, 0, 2,
+            ConstantByte as u8, 1,
+            // Back to the start, over it
+            Loop as u8, 0, 10,
+            Return as u8,
+        ];
+        let remap = |index: u32| if index == 1 { 300 } else { index };
+        relocate_code(&mut code, &remap).unwrap();
+        #[rustfmt::skip]
+        let relocated = [
+            ConstantByte as u8, 0,
+            JumpIfFalse as u8, 0, 3,
+            ConstantLong as u8, 0x01, 0x2c,
+            Loop as u8, 0, 11,
+            Return as u8,
+        ];
+        assert_eq!(code, relocated);
+        verify_function(&code).unwrap();
+
+        let e = relocate_code(&mut code, &|_| 70_000).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                RelocateError::IndexTooLarge {
+                    offset: 0,
+                    index: 70_000
+                }
+            ),
+            "{e}"
+        );
+        assert_eq!(code, relocated);
+
+        let mut unknown = vec![Return as u8, 0x7f];
+        let e = relocate_code(&mut unknown, &remap).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                RelocateError::Invalid(VerifyError::UnknownOpcode {
+                    offset: 1,
+                    byte: 0x7f
+                })
+            ),
+            "{e}"
+        );
+        assert_eq!(e.to_string(), "unknown opcode 0x7f at 0001");
+        assert_eq!(unknown, [Return as u8, 0x7f]);
+    }
+
+    #[test]
+    fn compacting_leaves_code_it_cannot_relocate_alone() {
+        let mut pool = ObjectPool::new();
+        let code = [0x7f, ByteCode::Return as u8];
+        let function = FunctionBuilder::new("f", 0, &code)
+            .constants(vec![Value::Int(1), Value::Int(1)])
+            .build(&mut pool)
+            .unwrap();
+        let compacted = map_functions(&function, &mut compact_constants, &mut pool);
+        assert_eq!(compacted, function);
+
+        // The same constants under code that verifies lose their repeat
+        let code = [ByteCode::ConstantByte as u8, 1, ByteCode::Return as u8];
+        let function = FunctionBuilder::new("f", 0, &code)
+            .constants(vec![Value::Int(1), Value::Int(1)])
+            .build(&mut pool)
+            .unwrap();
+        let compacted = map_functions(&function, &mut compact_constants, &mut pool);
+        let expected = FunctionBuilder::new("f", 0, &[ByteCode::ConstantByte as u8, 0, 1])
+            .constants(vec![Value::Int(1)])
+            .build(&mut pool)
+            .unwrap();
+        assert_eq!(compacted, expected);
+    }
+
+    #[test]
+    fn functions_are_rewritten_wherever_they_are_held() {
+        let mut pool = ObjectPool::new();
+        let f = FunctionBuilder::new("f", 1, &[ByteCode::Return as u8])
+            .build(&mut pool)
+            .unwrap();
+        let chain = (0..100_000).fold(f.clone(), |cdr, i| {
+            Value::from_pair(Value::Int(i), cdr, &mut pool)
+        });
+        let values = [
+            Value::from_list(vec![Value::Int(1), f.clone()], &mut pool),
+            Value::from_tuple(vec![f.clone()], &mut pool),
+            Value::from_map(vec![(Value::Int(1), f.clone())], &mut pool),
+            Value::from_set(vec![f.clone()], &mut pool),
+            Value::from_record("R", vec![("f", f.clone())], &mut pool),
+            Value::from_enum_variant("E", "V", Some(f.clone()), &mut pool),
+            Value::Optional(Some(Box::new(f.clone()))),
+            FunctionBuilder::new("g", 1, &[ByteCode::Return as u8])
+                .defaults(vec![f.clone()])
+                .build(&mut pool)
+                .unwrap(),
+            chain,
+        ];
+        let mut rewritten = Vec::new();
+        let mut rename = |mut function: Object| {
+            if let Object::Function { identifier, .. } = &mut function {
+                rewritten.push(identifier.clone());
+                *identifier = identifier.to_uppercase();
+            }
+            function
+        };
+        let mapped = values
+            .iter()
+            .map(|value| map_functions(value, &mut rename, &mut pool))
+            .collect::<Vec<_>>();
+        assert_eq!(rewritten.len(), 10, "{rewritten:?}");
+        assert_eq!(&rewritten[7..], ["f", "g", "f"]);
+        for (old, new) in values.iter().zip(&mapped) {
+            let (old, new) = (old.to_string(), new.to_string());
+            assert_eq!(new, old.replace("'f'", "'F'").replace("'g'", "'G'"));
+            assert_ne!(new, old);
+        }
+
+        // Nothing is rebuilt around values with no function in them
+        let plain = Value::from_list(vec![Value::Int(1)], &mut pool);
+        let (Value::Object(old), Value::Object(new)) =
+            (&plain, &map_functions(&plain, &mut |f| f, &mut pool))
+        else {
+            panic!("expected objects");
+        };
+        assert!(Rc::ptr_eq(old, new));
+    }
+
+    #[test]
+    fn each_peephole_rule_saves_its_bytes() {
+        use ByteCode::*;
+        let stats = |constant_pop_bytes, double_negate_bytes, threaded_jumps| OptStats {
+            constant_pop_bytes,
+            double_negate_bytes,
+            threaded_jumps,
+        };
+
+        let code = [ConstantByte as u8, 0, Pop as u8, Return as u8];
+        assert_eq!(optimize_code(&code), (vec![Return as u8], stats(3, 0, 0)));
+        let code = [ConstantLong as u8, 1, 0, Pop as u8, Return as u8];
+        assert_eq!(optimize_code(&code), (vec![Return as u8], stats(4, 0, 0)));
+        let code = [Negate as u8, Negate as u8, Return as u8];
+        assert_eq!(optimize_code(&code), (vec![Return as u8], stats(0, 2, 0)));
+
+        // A jump over a removed pair lands on what followed it
+        #[rustfmt::skip]
+        let code = [
+            JumpIfFalse as u8, 0, 3,
+            ConstantByte as u8, 0, Pop as u8,
+            Return as u8,
+        ];
+        let expected = vec![JumpIfFalse as u8, 0, 0, Return as u8];
+        assert_eq!(optimize_code(&code), (expected, stats(3, 0, 0)));
+
+        // But a pair a jump lands inside stays
+        #[rustfmt::skip]
+        let code = [
+            JumpIfFalse as u8, 0, 2,
+            ConstantByte as u8, 0, Pop as u8,
+            Return as u8,
+        ];
+        assert_eq!(optimize_code(&code), (code.to_vec(), stats(0, 0, 0)));
+    }
+
+    #[test]
+    fn jumps_thread_through_jumps_but_not_round_cycles() {
+        use ByteCode::*;
+        #[rustfmt::skip]
+        let code = [
+            Jump as u8, 0, 1,
+            Return as u8,
+            Jump as u8, 0, 1,
+            Return as u8,
+            Return as u8,
+        ];
+        let mut threaded = code.to_vec();
+        threaded[2] = 5;
+        let stats = OptStats {
+            threaded_jumps: 1,
+            ..OptStats::default()
+        };
+        assert_eq!(optimize_code(&code), (threaded, stats));
+
+        #[rustfmt::skip]
+        let cycle = [
+            Jump as u8, 0, 0,
+            Loop as u8, 0, 6,
+            Return as u8,
+        ];
+        assert_eq!(optimize_code(&cycle), (cycle.to_vec(), OptStats::default()));
+    }
+
+    #[test]
+    fn code_that_does_not_verify_is_not_optimized() {
+        let code = [ByteCode::Negate as u8, ByteCode::Negate as u8, 0x7f];
+        assert_eq!(optimize_code(&code), (code.to_vec(), OptStats::default()));
+    }
+
+    #[test]
+    fn diffs_pair_operand_changes_and_place_the_rest_on_their_side() {
+        use ByteCode::*;
+        let a = [ConstantByte as u8, 0, Return as u8];
+        let b = [ConstantByte as u8, 1, Return as u8];
+        assert_eq!(
+            diff_code(&a, &b),
+            [CodeDelta::Changed {
+                old_offset: 0,
+                new_offset: 0,
+                old: Instruction::ConstantByte(0),
+                new: Instruction::ConstantByte(1),
+            }]
+        );
+        assert_eq!(diff_code(&a, &a), []);
+
+        let a = [Negate as u8, Add as u8, Pop as u8, Return as u8];
+        let b = [GetLocal as u8, 3, Negate as u8, Add as u8, Return as u8];
+        let deltas = diff_code(&a, &b);
+        assert_eq!(
+            deltas,
+            [
+                CodeDelta::Inserted {
+                    offset: 0,
+                    instruction: Ok(Instruction::GetLocal(3)),
+                },
+                CodeDelta::Removed {
+                    offset: 2,
+                    instruction: Ok(Instruction::Pop),
+                },
+            ]
+        );
+        assert_eq!(render_code_diff(&deltas), "+0000 GET_LOCAL 3\n-0002 POP\n");
+
+        // Bytes that don't decode are compared as their errors
+        let a = [0x7f, Return as u8];
+        let b = [Return as u8, Jump as u8, 0];
+        let deltas = diff_code(&a, &b);
+        assert_eq!(
+            deltas,
+            [
+                CodeDelta::Removed {
+                    offset: 0,
+                    instruction: Err(DecodeError::UnknownOpcode(0x7f)),
+                },
+                CodeDelta::Inserted {
+                    offset: 1,
+                    instruction: Err(DecodeError::Truncated(Jump)),
+                },
+            ]
+        );
+        assert_eq!(
+            render_code_diff(&deltas),
+            "-0000 UNKNOWN_OPCODE 0x7f\n+0001 JUMP <truncated>\n"
+        );
+    }
+
+    #[test]
+    fn control_flow_graphs_split_at_jumps_and_dash_what_cannot_run() {
+        use ByteCode::*;
+        let header = "digraph cfg {\n    node [shape=box, fontname=monospace];\n";
+        let straight = function_cfg(&[Negate as u8, Return as u8]).unwrap();
+        assert_eq!(
+            straight,
+            format!(
+                "{header}{}",
+                r#"    b0 [label="0000 NEGATE\l0001 RETURN\l"];
+}
+"#
+            )
+        );
+
+        let branch = [JumpIfFalse as u8, 0, 1, Negate as u8, Return as u8];
+        assert_eq!(
+            function_cfg(&branch).unwrap(),
+            format!(
+                "{header}{}",
+                r#"    b0 [label="0000 JUMP_IF_FALSE -> 0004\l"];
+    b1 [label="0003 NEGATE\l"];
+    b2 [label="0004 RETURN\l"];
+    b0 -> b2 [label=jump];
+    b0 -> b1;
+    b1 -> b2;
+}
+"#
+            )
+        );
+
+        let skip = [Jump as u8, 0, 1, Negate as u8, Return as u8];
+        assert_eq!(
+            function_cfg(&skip).unwrap(),
+            format!(
+                "{header}{}",
+                r#"    b0 [label="0000 JUMP -> 0004\l"];
+    b1 [label="0003 NEGATE\l", style=dashed, fontcolor=gray];
+    b2 [label="0004 RETURN\l"];
+    b0 -> b2 [label=jump];
+    b1 -> b2;
+}
+"#
+            )
+        );
+
+        let e = function_cfg(&[Jump as u8, 0, 9, Return as u8]).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                VerifyError::BadJumpTarget {
+                    offset: 0,
+                    target: 12
+                }
+            ),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn code_stats_count_opcodes_and_list_the_most_frequent_first() {
+        use ByteCode::*;
+        #[rustfmt::skip]
+        let code = [
+            Negate as u8, Negate as u8, ConstantByte as u8, 0, Return as u8,
+            0x7f, Jump as u8, 0,
+        ];
+        let stats = analyze_code(&code);
+        assert_eq!((stats.instructions, stats.invalid, stats.bytes), (6, 2, 8));
+        assert_eq!(stats.counts[Negate as usize], 2);
+        assert_eq!(stats.counts[ConstantByte as usize], 1);
+        assert_eq!(stats.counts[Return as usize], 1);
+        assert_eq!(stats.counts.iter().sum::<usize>(), 4);
+
+        // Nested functions count too, as the pool holds every one
+        let mut pool = ObjectPool::new();
+        let inner = FunctionBuilder::new("inner", 0, &[Negate as u8, Negate as u8, Return as u8])
+            .build(&mut pool)
+            .unwrap();
+        FunctionBuilder::new("outer", 0, &code)
+            .constants(vec![inner, Value::from_string("s", &mut pool)])
+            .build(&mut pool)
+            .unwrap();
+        let mut table = Vec::new();
+        pool_code_stats(&pool).write_table(&mut table).unwrap();
+        let expected = concat!(
+            "opcode              count    bytes\n",
+            "NEGATE                  4        4\n",
+            "RETURN                  2        2\n",
+            "CONSTANT_BYTE           1        2\n",
+            "invalid                 2\n",
+            "total                   9       11\n",
+        );
+        assert_eq!(String::from_utf8(table).unwrap(), expected);
+    }
+
+    #[test]
+    fn verification_names_the_offset_of_what_it_rejects() {
+        use ByteCode::*;
+        verify_function(&[ConstantByte as u8, 0, Loop as u8, 0, 5, Return as u8]).unwrap();
+        verify_function(&[]).unwrap();
+
+        let e = verify_function(&[Return as u8, 0x7f]).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                VerifyError::UnknownOpcode {
+                    offset: 1,
+                    byte: 0x7f
+                }
+            ),
+            "{e}"
+        );
+        let e = verify_function(&[Return as u8, Jump as u8, 0]).unwrap_err();
+        assert!(
+            matches!(e, VerifyError::TruncatedOperand { offset: 1 }),
+            "{e}"
+        );
+        assert_eq!(e.to_string(), "truncated operand at 0001");
+
+        // Past the end, and into the middle of an instruction
+        let e = verify_function(&[Jump as u8, 0, 1, Return as u8]).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                VerifyError::BadJumpTarget {
+                    offset: 0,
+                    target: 4
+                }
+            ),
+            "{e}"
+        );
+        let e = verify_function(&[ConstantByte as u8, 0, Loop as u8, 0, 4]).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                VerifyError::BadJumpTarget {
+                    offset: 2,
+                    target: 1
+                }
+            ),
+            "{e}"
+        );
+        assert_eq!(
+            e.to_string(),
+            "jump at 0002 targets 1, which is not an instruction"
+        );
+    }
+
+    #[test]
+    fn code_that_fails_verification_loads_with_a_warning_unless_strict() {
+        let mut pool = ObjectPool::new();
+        let code = [ByteCode::Return as u8, 0x7f];
+        let inner = FunctionBuilder::new("inner", 0, &code)
+            .build(&mut pool)
+            .unwrap();
+        let values = [Value::from_list(vec![inner], &mut pool)];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+
+        let warnings = Warnings::default();
+        let options = LoadOptions {
+            warnings: Some(&warnings),
+            ..LoadOptions::default()
+        };
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &options).unwrap();
+        assert_eq!(read, values);
+        let warnings = warnings.take();
+        assert!(
+            warnings.iter().any(|warning| matches!(
+                warning,
+                Warning::FailedVerification {
+                    identifier,
+                    error: VerifyError::UnknownOpcode { offset: 1, byte: 0x7f },
+                } if identifier == "inner"
+            )),
+            "{warnings:?}"
+        );
+
+        let strict = LoadOptions {
+            strict: true,
+            ..LoadOptions::default()
+        };
+        let e = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &strict).unwrap_err();
+        assert!(
+            matches!(
+                &e,
+                ConstantsError::FailedVerification {
+                    identifier,
+                    error: VerifyError::UnknownOpcode { offset: 1, .. },
+                } if identifier == "inner"
+            ),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn every_opcode_has_a_row_in_the_table() {
+        // Listing the variants through an exhaustive match makes a new one fail to
+        // compile here until it's added, and then fail below until it has a row
+        let every = |op: ByteCode| match op {
+            ByteCode::ConstantByte
+            | ByteCode::Return
+            | ByteCode::Add
+            | ByteCode::Sub
+            | ByteCode::Mul
+            | ByteCode::Div
+            | ByteCode::Negate
+            | ByteCode::Equal
+            | ByteCode::Less
+            | ByteCode::Greater
+            | ByteCode::Jump
+            | ByteCode::JumpIfFalse
+            | ByteCode::Loop
+            | ByteCode::ConstantLong
+            | ByteCode::GetLocal
+            | ByteCode::SetLocal
+            | ByteCode::Call
+            | ByteCode::TailCall
+            | ByteCode::Pop => 19,
+        };
+        assert_eq!(OPCODES.len(), every(ByteCode::Pop));
+
+        for (byte, info) in OPCODES.iter().enumerate() {
+            let op = ByteCode::try_from(byte as u8).unwrap();
+            assert_eq!(op as usize, byte);
+            assert_eq!(
+                ByteCode::from_mnemonic(info.name),
+                Some(op),
+                "{}",
+                info.name
+            );
+            assert!(info.width <= 2, "{}", info.name);
+            assert_eq!(
+                info.width == 0,
+                info.operand == OperandKind::None,
+                "{}",
+                info.name
+            );
+
+            let mut code = vec![byte as u8];
+            code.resize(1 + info.width, 1);
+            let (_, decoded) = Instructions::new(&code).next().unwrap();
+            assert_eq!(decoded.unwrap().op(), op);
+        }
+    }
+}
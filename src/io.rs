@@ -0,0 +1,5564 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::RandomState, HashMap},
+    ffi::OsString,
+    fmt,
+    fs::{self, File},
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{bytecode::*, object::*, value::*};
+
+/// Base64 armor, which wraps any file in lines of text that survive copy and paste.
+mod armor;
+/// CBOR (RFC 8949) as an alternative container, for consumers that already have a decoder.
+/// Constants follow the `to_json` schema, except that function code and `bytes` values are
+/// byte strings, floats are CBOR floats and functions carry no disassembly. The array of
+/// constants is wrapped in the self-described CBOR tag, so files start with `d9 d9 f7`.
+/// Output follows the deterministic encoding of RFC 8949 section 4.2: shortest heads and
+/// floats, definite lengths, and map keys sorted by their encoded bytes.
+#[cfg(feature = "cbor")]
+mod cbor;
+/// XChaCha20 for encrypted bodies, and SHA-256 and HMAC-SHA256 for signed files.
+mod crypto;
+/// DEFLATE, compressing with fixed Huffman codes and inflating any valid stream.
+mod deflate;
+/// The JSON dump format, written by `to_json` and read back by `from_json`.
+mod json;
+/// Advisory locks that keep saves and loads of one file from interleaving.
+mod lock;
+/// Read-only file mappings through the C library's `mmap`, which std already links on
+/// unix, so `load_values_mmap` needs no crate of its own.
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+/// A bounds-checked reader over a slice, through which files in memory are decoded.
+mod slice;
+/// The indented text dump format, written by `to_text` and read back by `from_text`.
+mod text;
+
+pub use armor::{dearmor, ArmorError, ARMOR_BEGIN};
+pub use json::{from_json, to_json, JsonError};
+pub use lock::{load_locked, save_locked, FileLock, LockKind};
+pub use text::{from_text, to_text, ParseError};
+use {armor::*, crypto::*, deflate::*, json::*, slice::*};
+
+/// Maps an error opening `path` to `ConstantsError::Open`, which names the file.
+pub fn open_error(path: &str) -> impl FnOnce(io::Error) -> ConstantsError + '_ {
+    move |error| ConstantsError::Open {
+        path: path.to_string(),
+        error,
+    }
+}
+
+/// A file written under a temporary name in the directory of `path` and renamed over it by
+/// `commit`, so a save that fails part way, or panics, leaves `path` as it was. Dropping
+/// it uncommitted removes the temporary file.
+pub struct AtomicFile {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &str) -> io::Result<AtomicFile> {
+        let path = PathBuf::from(path);
+        let Some(name) = path.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' names no file", path.display()),
+            ));
+        };
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp = path.with_file_name(temp_name);
+
+        let file = File::create(&temp)?;
+        let file = AtomicFile {
+            file,
+            temp,
+            path,
+            committed: false,
+        };
+        // The replacement keeps the permissions of the file it replaces
+        if let Ok(metadata) = fs::metadata(&file.path) {
+            file.file.set_permissions(metadata.permissions())?;
+        }
+        Ok(file)
+    }
+
+    /// Waits for the data to reach the disk, then renames the file over the destination.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Saves `values` to `path` through an `AtomicFile`, so the file is either the old one or
+/// the finished new one, never a truncated mix.
+pub fn save_atomic(
+    path: &str,
+    values: &[Value],
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    let mut file = AtomicFile::create(path)?;
+    write_values_to_disk(&mut file, values, options)?;
+    file.commit()?;
+    if options.verify {
+        verify_saved(path, &[values], options)?;
+    }
+    Ok(())
+}
+
+/// Reopens the file just saved at `path`, loads it as the options it was written with
+/// allow, and checks it holds `written`, so a save cut short by a full disk is caught
+/// while the values are still at hand. `written` is split where its references count
+/// from: one group for most files, and one per section for `write_sections`.
+pub fn verify_saved(
+    path: &str,
+    written: &[&[Value]],
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    let load_options = LoadOptions {
+        key: options.key,
+        verify_key: options.sign_key,
+        ..LoadOptions::default()
+    };
+    let mut file = File::open(path).map_err(open_error(path))?;
+    let mut read = Vec::new();
+    load_detected(&mut file, &mut read, &mut ObjectPool::new(), &load_options)
+        .map_err(|e| ConstantsError::SaveUnreadable(Box::new(e)))?;
+
+    // Compared as a load resolves them, since that's how they read back
+    let mut expected = Vec::new();
+    for group in written {
+        collect_constants(group.iter().cloned().map(Ok), &mut expected)?;
+    }
+    let differences = expected
+        .iter()
+        .zip(&read)
+        .enumerate()
+        .filter(|(_, (written, read))| written != read)
+        .map(|(i, (written, read))| (i, written.to_string(), read.to_string()))
+        .collect::<Vec<_>>();
+    if differences.is_empty() && expected.len() == read.len() {
+        return Ok(());
+    }
+    Err(ConstantsError::SaveMismatch(SaveMismatch {
+        written: expected.len(),
+        read: read.len(),
+        differences,
+    }))
+}
+
+/// Opens every constants file, ahead of the format version and the constant count.
+const FILE_MAGIC: [u8; 4] = *b"CNST";
+/// Follows the CRC32 footer from `FormatVersion::V8`, ahead of any index and signature.
+const END_MARKER: [u8; 4] = *b"CEND";
+/// Layout revisions of the file format, numbered as in the header. Readers decide which
+/// fields are present from the version; writers always produce `FormatVersion::LATEST`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u16)]
+pub(crate) enum FormatVersion {
+    /// The layout when the header was introduced, which headerless legacy files share.
+    /// Function type-id revisions predate the header and are read under every version.
+    V1 = 1,
+    /// Adds the body length after the version and a CRC32 footer over the body.
+    V2 = 2,
+    /// Adds a byte after the version giving the byte order of every later number.
+    V3 = 3,
+    /// Narrows every length and count from u64 to u32, so files no longer depend on the
+    /// writer's pointer width. Older files still load with their 8-byte lengths.
+    V4 = 4,
+    /// Adds a byte of `HEADER_*` flags after the byte order, saying which optional
+    /// encodings the body uses.
+    V5 = 5,
+    /// Writes each object once in a pool ahead of the values, which refer to objects by
+    /// index, so values that shared an object before saving share it after loading.
+    V6 = 6,
+    /// Lets the body hold several segments, each laid out like a whole V6 body and read
+    /// in turn until the body ends, so constants can be appended without a rewrite.
+    V7 = 7,
+    /// Follows the CRC32 footer with `END_MARKER`, so a file cut short anywhere, or with
+    /// bytes after its end, is told apart from a whole one.
+    V8 = 8,
+}
+
+/// Lengths, counts and `Value::Int`s are LEB128 varints instead of fixed-width numbers.
+const HEADER_VARINT: u8 = 1 << 0;
+/// The body opens with a table of unique strings, and strings elsewhere are indices into it.
+const HEADER_STRING_TABLE: u8 = 1 << 1;
+/// The body is a declared uncompressed length and a DEFLATE stream of the real body.
+const HEADER_COMPRESSED: u8 = 1 << 2;
+/// The body, after any compression, is encrypted with XChaCha20 under a user's key.
+const HEADER_ENCRYPTED: u8 = 1 << 3;
+/// The footer is followed by an index of where each constant starts in the body, which
+/// is never compressed or encrypted, for `load_constant_at`.
+const HEADER_INDEX: u8 = 1 << 4;
+/// The body opens with a directory of named sections, each a segment of its own, which
+/// `load_section` can seek to. Never combined with compression, encryption or an index.
+const HEADER_SECTIONS: u8 = 1 << 5;
+/// The flags are followed by a length-prefixed `Metadata` block, before the body length.
+/// Loaders skip it by its length, so it never changes how the constants decode.
+const HEADER_METADATA: u8 = 1 << 6;
+/// The file ends with an HMAC-SHA256 tag over every byte before it, after any index.
+/// This is the last free bit, so any further flag needs a new format version.
+const HEADER_SIGNED: u8 = 1 << 7;
+
+impl FormatVersion {
+    const LATEST: FormatVersion = FormatVersion::V8;
+
+    fn from_u16(version: u16) -> Option<FormatVersion> {
+        match version {
+            1 => Some(FormatVersion::V1),
+            2 => Some(FormatVersion::V2),
+            3 => Some(FormatVersion::V3),
+            4 => Some(FormatVersion::V4),
+            5 => Some(FormatVersion::V5),
+            6 => Some(FormatVersion::V6),
+            7 => Some(FormatVersion::V7),
+            8 => Some(FormatVersion::V8),
+            _ => None,
+        }
+    }
+}
+
+/// Writes `values` as a whole constants file to `file`, which can be any writer: a file,
+/// a socket, or a `Vec<u8>`. The file is built by `serialize_to_vec` and written in one
+/// go, and nothing is read back, so the writer never needs to seek.
+pub fn write_values_to_disk(
+    file: &mut impl Write,
+    values: &[Value],
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    file.write_all(&serialize_to_vec(values, options)?)?;
+    Ok(())
+}
+
+/// Encodes `values` as a whole constants file in memory. The body is built first, which
+/// fixes the size of everything around it, so the file is allocated once at its
+/// `encoded_len`.
+pub fn serialize_to_vec(
+    values: &[Value],
+    options: &WriteOptions,
+) -> Result<Vec<u8>, ConstantsError> {
+    if options.deterministic && options.key.is_some() {
+        return unwritable(
+            "Encrypted output can't be deterministic, since each save draws a new nonce",
+        );
+    }
+    if options.index && (options.compression.is_some() || options.key.is_some()) {
+        return unwritable("An index can't point into a compressed or encrypted body");
+    }
+
+    check_refs(values)?;
+    let (mut body, offsets) = write_segment(values, options)?;
+    if let Some(level) = options.compression {
+        let mut compressed = Vec::new();
+        write_length(&mut compressed, body.len(), options)?;
+        compressed.extend(deflate(&body, level));
+        body = compressed;
+    }
+    if let Some(key) = options.key {
+        body = encrypt_body(&body, key, options);
+    }
+
+    let len = encoded_len(body.len(), offsets.len(), options)?;
+    let mut bytes = Vec::with_capacity(len);
+    let mut file = SigningWriter::new(&mut bytes, options.sign_key);
+    write_container(&mut file, &body, header_flags(options), options)?;
+    if options.index {
+        let entries = offsets
+            .iter()
+            .map(|&offset| (0, offset))
+            .collect::<Vec<_>>();
+        file.write_all(&write_index(&entries, options.endianness))?;
+    }
+    file.finish()?;
+    debug_assert_eq!(
+        bytes.len(),
+        len,
+        "encoded_len disagrees with the file written"
+    );
+    Ok(bytes)
+}
+
+/// Bytes in a file of `constants` whose encoded body is `body_len` bytes: the header and
+/// any metadata, the body behind its length, the CRC32 footer and end marker, then any
+/// index and signature, as `serialize_to_vec` lays them out.
+fn encoded_len(
+    body_len: usize,
+    constants: usize,
+    options: &WriteOptions,
+) -> Result<usize, ConstantsError> {
+    // Magic, then the version, byte order and flags
+    let mut len = FILE_MAGIC.len() + 4;
+    if let Some(metadata) = options.metadata {
+        let mut block = Vec::new();
+        metadata.write(&mut block, options)?;
+        len += length_len(block.len(), options)? + block.len();
+    }
+    len += length_len(body_len, options)? + body_len + 4 + END_MARKER.len();
+    if options.index {
+        len += index_len(constants) as usize;
+    }
+    if options.sign_key.is_some() {
+        len += SIGNATURE_LEN;
+    }
+    Ok(len)
+}
+
+/// Bytes `write_length` takes to write `len`.
+fn length_len(len: usize, options: &WriteOptions) -> Result<usize, ConstantsError> {
+    let mut prefix = Vec::with_capacity(MAX_VARINT_LEN);
+    write_length(&mut prefix, len, options)?;
+    Ok(prefix.len())
+}
+
+/// The `HEADER_*` flags for a file written with `options`.
+fn header_flags(options: &WriteOptions) -> u8 {
+    let mut flags = 0;
+    if options.varint {
+        flags |= HEADER_VARINT;
+    }
+    if options.string_table {
+        flags |= HEADER_STRING_TABLE;
+    }
+    if options.compression.is_some() {
+        flags |= HEADER_COMPRESSED;
+    }
+    if options.key.is_some() {
+        flags |= HEADER_ENCRYPTED;
+    }
+    if options.index {
+        flags |= HEADER_INDEX;
+    }
+    if options.metadata.is_some() {
+        flags |= HEADER_METADATA;
+    }
+    if options.sign_key.is_some() {
+        flags |= HEADER_SIGNED;
+    }
+    flags
+}
+
+/// Writes the header, any metadata, `body`, the CRC32 footer over it and the end marker.
+fn write_container(
+    file: &mut impl Write,
+    body: &[u8],
+    flags: u8,
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    if let Some(Metadata {
+        created: Some(_), ..
+    }) = options.metadata
+    {
+        if options.deterministic {
+            return unwritable("Deterministic output can't record when it was created");
+        }
+    }
+
+    // The version is always big-endian, since readers need it to find the byte order
+    file.write_all(&FILE_MAGIC)?;
+    file.write_all(&(FormatVersion::LATEST as u16).to_be_bytes())?;
+    file.write_all(&[options.endianness as u8])?;
+    file.write_all(&[flags])?;
+    if let Some(metadata) = options.metadata {
+        let mut block = Vec::new();
+        metadata.write(&mut block, options)?;
+        write_bytes(file, &block, options)?;
+    }
+    write_length(file, body.len(), options)?;
+    file.write_all(body)?;
+    write_number(file, crc32(body), options)?;
+    file.write_all(&END_MARKER)?;
+    Ok(())
+}
+
+/// Writes each of `sections` as a named segment behind a directory of them, so a reader
+/// can load one section with `load_section` without decoding the rest. References in a
+/// section index that section's constants. Files written by `write_values_to_disk` have
+/// no directory and read as a single unnamed section.
+pub fn write_sections(
+    file: &mut impl Write,
+    sections: &[(&str, &[Value])],
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    if options.compression.is_some() || options.key.is_some() || options.index {
+        return unwritable("Sections can't be compressed, encrypted or indexed");
+    }
+    for (i, (name, _)) in sections.iter().enumerate() {
+        if sections[..i].iter().any(|(earlier, _)| earlier == name) {
+            return unwritable(format!("Section '{name}' is given twice"));
+        }
+    }
+
+    let mut directory = Vec::new();
+    let mut segments = Vec::new();
+    write_length(&mut directory, sections.len(), options)?;
+    for (name, values) in sections {
+        check_refs(values)?;
+        let (segment, _) = write_segment(values, options)?;
+        write_bytes(&mut directory, name.as_bytes(), options)?;
+        write_length(&mut directory, segment.len(), options)?;
+        write_length(&mut directory, values.len(), options)?;
+        write_number(&mut directory, crc32(&segment), options)?;
+        segments.extend(segment);
+    }
+    directory.extend(segments);
+    let mut file = SigningWriter::new(file, options.sign_key);
+    write_container(
+        &mut file,
+        &directory,
+        header_flags(options) | HEADER_SECTIONS,
+        options,
+    )?;
+    file.finish()?;
+    Ok(())
+}
+
+/// Encodes `values` as one body segment: the string table if `options` asks for one, then
+/// the object pool and the constants. Also returns where each constant starts in it.
+fn write_segment(
+    values: &[Value],
+    options: &WriteOptions,
+) -> Result<(Vec<u8>, Vec<u32>), ConstantsError> {
+    // Both tables fill up as the values are written, so those go to their own buffer
+    let strings = RefCell::new(StringTable::default());
+    let objects = RefCell::new(ObjectTable::default());
+    let state = WriteState {
+        options,
+        strings: options.string_table.then_some(&strings),
+        objects: Some(&objects),
+    };
+    check_depths(values, LoadLimits::DEFAULT.max_depth)?;
+    let mut values_body = Vec::new();
+    write_length(&mut values_body, values.len(), options)?;
+    let mut starts = Vec::with_capacity(values.len());
+    for value in values {
+        starts.push(values_body.len());
+        value.write(&mut values_body, &state)?;
+    }
+
+    let mut segment = Vec::new();
+    if options.string_table {
+        let strings = strings.into_inner();
+        write_length(&mut segment, strings.strings.len(), options)?;
+        let inline = WriteState::new(options);
+        for str in &strings.strings {
+            write_string(&mut segment, str, &inline)?;
+        }
+    }
+    let objects = objects.into_inner();
+    write_length(&mut segment, objects.count, options)?;
+    segment.extend(objects.entries);
+    let offsets = starts
+        .into_iter()
+        .map(|start| {
+            let offset = segment.len() + start;
+            u32::try_from(offset)
+                .or_else(|_| unwritable(format!("Offset {offset} exceeds the u32 limit")))
+        })
+        .collect::<Result<_, _>>()?;
+    segment.extend(values_body);
+    Ok((segment, offsets))
+}
+
+/// Bytes in an index of `count` entries: the count, the entries and a CRC32 over both.
+fn index_len(count: usize) -> u64 {
+    8 + 8 * count as u64
+}
+
+/// Encodes an index of `(segment, constant)` pairs, each the offset in the body where the
+/// constant's segment and the constant itself start. The numbers are always fixed-width,
+/// even in files with `HEADER_VARINT`, so entries can be found without decoding others.
+fn write_index(entries: &[(u32, u32)], endianness: Endianness) -> Vec<u8> {
+    let mut index = Vec::with_capacity(index_len(entries.len()) as usize);
+    index.extend((entries.len() as u32).to_endian_bytes(endianness));
+    for (segment, constant) in entries {
+        index.extend(segment.to_endian_bytes(endianness));
+        index.extend(constant.to_endian_bytes(endianness));
+    }
+    index.extend(crc32(&index).to_endian_bytes(endianness));
+    index
+}
+
+/// Reads an index `write_index` wrote, checking its CRC32, from a file with `available`
+/// bytes left.
+fn read_index(
+    file: &mut impl Read,
+    endianness: Endianness,
+    available: u64,
+) -> Result<Vec<(u32, u32)>, &'static str> {
+    let mut count = [0u8; 4];
+    if available < index_len(0) || file.read_exact(&mut count).is_err() {
+        return Err("index is truncated");
+    }
+    let len = index_len(u32::from_endian_bytes(count, endianness) as usize);
+    if len > available {
+        return Err("index is truncated");
+    }
+
+    let mut index = count.to_vec();
+    index.resize(len as usize, 0);
+    file.read_exact(&mut index[4..])
+        .map_err(|_| "index is truncated")?;
+    let (entries, footer) = index.split_at(len as usize - 4);
+    if crc32(entries) != u32::from_endian_bytes(footer.try_into().unwrap(), endianness) {
+        return Err("index checksum mismatch");
+    }
+
+    let number = |bytes: &[u8]| u32::from_endian_bytes(bytes.try_into().unwrap(), endianness);
+    Ok(entries[4..]
+        .chunks_exact(8)
+        .map(|entry| (number(&entry[..4]), number(&entry[4..])))
+        .collect())
+}
+
+/// Why `append_values_to_disk` left a file as it was.
+#[derive(Debug)]
+pub enum AppendError {
+    Io(io::Error),
+    /// The file doesn't open with a header this version of the format could have written.
+    BadHeader(String),
+    /// The file ends inside its header.
+    Truncated,
+    /// Only files of `FormatVersion::LATEST` can take another segment.
+    Version(u16),
+    /// The body can't grow in place, for the reason given.
+    Unsupported(&'static str),
+    /// The file's size disagrees with the body length in its header.
+    Size {
+        expected: u64,
+        found: u64,
+    },
+    /// The body would outgrow its u32 length.
+    TooLarge,
+    /// The index after the footer can't be trusted, for the reason given.
+    BadIndex(&'static str),
+    /// `END_MARKER` doesn't follow the footer, so the file may have been cut short.
+    MissingEndMarker,
+    /// The new values can't be encoded.
+    Write(ConstantsError),
+}
+
+impl From<io::Error> for AppendError {
+    fn from(e: io::Error) -> AppendError {
+        AppendError::Io(e)
+    }
+}
+
+impl From<ConstantsError> for AppendError {
+    fn from(e: ConstantsError) -> AppendError {
+        AppendError::Write(e)
+    }
+}
+
+impl fmt::Display for AppendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppendError::Io(e) => write!(f, "{e}"),
+            AppendError::BadHeader(message) => write!(f, "{message}"),
+            AppendError::Truncated => write!(f, "file ends inside its header"),
+            AppendError::Version(version) => write!(
+                f,
+                "file has format version {version}, but only version {} can be appended to",
+                FormatVersion::LATEST as u16
+            ),
+            AppendError::Unsupported(reason) => write!(f, "can't append, since {reason}"),
+            AppendError::Size { expected, found } => write!(
+                f,
+                "file is {found} bytes, but its header says it should be {expected}"
+            ),
+            AppendError::TooLarge => write!(f, "body would exceed the u32 length limit"),
+            AppendError::BadIndex(reason) => write!(f, "{reason}"),
+            AppendError::MissingEndMarker => write!(f, "the end marker is missing"),
+            AppendError::Write(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// What patching the body of a file in place needs to know about it.
+struct OpenBody {
+    endianness: Endianness,
+    flags: u8,
+    /// Bytes before the body, whose last four are its length.
+    header_len: u64,
+    body_len: u32,
+    crc: u32,
+    /// The index after the end marker, in files with `HEADER_INDEX`.
+    entries: Option<Vec<(u32, u32)>>,
+}
+
+impl OpenBody {
+    fn footer_start(&self) -> u64 {
+        self.header_len + self.body_len as u64
+    }
+}
+
+/// Reads the header and everything after the body of `file`, which is `file_len` bytes,
+/// refusing files whose body can't be patched in place.
+fn open_body(file: &mut (impl Read + Seek), file_len: u64) -> Result<OpenBody, AppendError> {
+    // Magic, version, byte order and flags, then any metadata and the body length, whose
+    // lengths are never varints here
+    let mut header = Vec::new();
+    Read::by_ref(file).take(12).read_to_end(&mut header)?;
+    if !header.starts_with(&FILE_MAGIC) {
+        return Err(AppendError::BadHeader("not a constants file".to_string()));
+    }
+    if header.len() < 12 {
+        return Err(AppendError::Truncated);
+    }
+    let version = u16::from_be_bytes([header[4], header[5]]);
+    if version != FormatVersion::LATEST as u16 {
+        return Err(AppendError::Version(version));
+    }
+    let endianness = match header[6] {
+        0 => Endianness::Big,
+        1 => Endianness::Little,
+        byte => return Err(AppendError::BadHeader(format!("unknown byte order {byte}"))),
+    };
+    let flags = header[7];
+    if flags & HEADER_COMPRESSED != 0 {
+        return Err(AppendError::Unsupported("the body is compressed"));
+    }
+    if flags & HEADER_ENCRYPTED != 0 {
+        return Err(AppendError::Unsupported("the body is encrypted"));
+    }
+    if flags & HEADER_VARINT != 0 {
+        return Err(AppendError::Unsupported(
+            "lengths are varints, and the body length may need more bytes",
+        ));
+    }
+    if flags & HEADER_SECTIONS != 0 {
+        return Err(AppendError::Unsupported(
+            "the body is divided into sections",
+        ));
+    }
+    if flags & HEADER_SIGNED != 0 {
+        return Err(AppendError::Unsupported(
+            "the file is signed, and its signing key isn't known here",
+        ));
+    }
+
+    let mut header_len = 12;
+    if flags & HEADER_METADATA != 0 {
+        let metadata_len =
+            u32::from_endian_bytes([header[8], header[9], header[10], header[11]], endianness);
+        header_len += metadata_len as u64 + 4;
+        let mut body_len = [0u8; 4];
+        file.seek(SeekFrom::Start(header_len - 4))?;
+        if file.read_exact(&mut body_len).is_err() {
+            return Err(AppendError::Truncated);
+        }
+        header[8..12].copy_from_slice(&body_len);
+    }
+
+    let body_len =
+        u32::from_endian_bytes([header[8], header[9], header[10], header[11]], endianness);
+    let footer_start = header_len + body_len as u64;
+    let mut footer = [0u8; 4];
+    let mut marker = [0u8; 4];
+    let mut expected = footer_start + 8;
+    let mut entries = None;
+    if file_len >= expected {
+        file.seek(SeekFrom::Start(footer_start))?;
+        file.read_exact(&mut footer)?;
+        file.read_exact(&mut marker)?;
+        if marker != END_MARKER {
+            return Err(AppendError::MissingEndMarker);
+        }
+        if flags & HEADER_INDEX != 0 {
+            let index =
+                read_index(file, endianness, file_len - expected).map_err(AppendError::BadIndex)?;
+            expected += index_len(index.len());
+            entries = Some(index);
+        }
+    }
+    if file_len != expected {
+        return Err(AppendError::Size {
+            expected,
+            found: file_len,
+        });
+    }
+
+    Ok(OpenBody {
+        endianness,
+        flags,
+        header_len,
+        body_len,
+        crc: u32::from_endian_bytes(footer, endianness),
+        entries,
+    })
+}
+
+/// Appends `values` to the constants file at `path` as a new body segment, then patches
+/// the body length and checksum in place, so the constants already there are never
+/// rewritten. A missing or empty file gets a fresh one. As in any file, `Value::Ref`s
+/// index every constant in it, and the appended ones follow those already there.
+pub fn append_values_to_disk(path: &str, values: &[Value]) -> Result<(), AppendError> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let file_len = file.metadata()?.len();
+    if file_len == 0 {
+        write_values_to_disk(&mut file, values, &WriteOptions::default())?;
+        return Ok(());
+    }
+    let OpenBody {
+        endianness,
+        flags,
+        header_len,
+        body_len,
+        crc,
+        entries,
+    } = open_body(&mut file, file_len)?;
+    let footer_start = header_len + body_len as u64;
+
+    let options = WriteOptions {
+        endianness,
+        string_table: flags & HEADER_STRING_TABLE != 0,
+        ..WriteOptions::default()
+    };
+    let (segment, offsets) = write_segment(values, &options)?;
+    let new_len =
+        u32::try_from(body_len as u64 + segment.len() as u64).map_err(|_| AppendError::TooLarge)?;
+
+    // The segment replaces the footer, and the new footer, end marker and any index follow
+    file.seek(SeekFrom::Start(footer_start))?;
+    file.write_all(&segment)?;
+    file.write_all(&crc32_extend(crc, &segment).to_endian_bytes(endianness))?;
+    file.write_all(&END_MARKER)?;
+    if let Some(mut entries) = entries {
+        entries.extend(offsets.iter().map(|&offset| (body_len, body_len + offset)));
+        file.write_all(&write_index(&entries, endianness))?;
+    }
+    file.seek(SeekFrom::Start(header_len - 4))?;
+    file.write_all(&new_len.to_endian_bytes(endianness))?;
+    Ok(())
+}
+
+/// Why `update_values` or `compact` left a file as it was.
+#[derive(Debug)]
+pub enum UpdateError {
+    /// The file can't be patched, for the reason appending to it would fail.
+    File(AppendError),
+    /// The constants already in the file can't be read, or the new ones written.
+    Constants(ConstantsError),
+    /// A change names a constant past the end of the file.
+    IndexOutOfRange(IndexOutOfRange),
+    /// The change to constant `index` references a constant that doesn't come before it.
+    BadRef { index: usize, target: u32 },
+}
+
+impl From<io::Error> for UpdateError {
+    fn from(e: io::Error) -> UpdateError {
+        UpdateError::File(AppendError::Io(e))
+    }
+}
+
+impl From<AppendError> for UpdateError {
+    fn from(e: AppendError) -> UpdateError {
+        UpdateError::File(e)
+    }
+}
+
+impl From<ConstantsError> for UpdateError {
+    fn from(e: ConstantsError) -> UpdateError {
+        UpdateError::Constants(e)
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::File(AppendError::Unsupported(reason)) => {
+                write!(f, "can't update in place, since {reason}")
+            }
+            UpdateError::File(e) => write!(f, "{e}"),
+            UpdateError::Constants(e) => write!(f, "{e}"),
+            UpdateError::IndexOutOfRange(e) => write!(f, "{e}"),
+            UpdateError::BadRef { index, target } => write!(
+                f,
+                "constant {index} can't reference constant {target}, which is not an earlier entry"
+            ),
+        }
+    }
+}
+
+/// What `update_values` or `compact` did to a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdateReport {
+    /// Constants given a new encoding, counting those that referenced a changed one.
+    pub updated: usize,
+    /// How many of them outgrew their bytes and moved to the end of the body.
+    pub moved: usize,
+    /// Whether the whole file was written again instead of patched.
+    pub rewritten: bool,
+    pub bytes_written: u64,
+}
+
+impl fmt::Display for UpdateReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.bytes_written;
+        match (self.rewritten, self.updated) {
+            (true, 0) => write!(f, "file rewritten, {bytes} bytes written"),
+            (true, updated) => write!(
+                f,
+                "{updated} constants updated by rewriting the file, {bytes} bytes written"
+            ),
+            (false, updated) => write!(
+                f,
+                "{updated} constants updated, {} of them moved, {bytes} bytes written",
+                self.moved
+            ),
+        }
+    }
+}
+
+/// Bytes a forward takes: its type id and the u32 offset.
+const FORWARD_LEN: usize = 5;
+
+/// Replaces the constants at the given indices of the file at `path`, rewriting only
+/// their bytes, the footer and whatever follows it. A new encoding no longer than the old
+/// one takes its place behind padding; a longer one moves to a segment appended to the
+/// body, its old place becomes a forward to it, and any index entry is repointed. Files
+/// load exactly as a full rewrite with the changes would, so constants referencing a
+/// changed one are given the value they had. Holes are only reclaimed by `compact`, which
+/// is also used if a constant has to move but has too few bytes for the forward.
+///
+/// Like `append_values_to_disk` this patches the file in place, and it doesn't take files
+/// with string tables, whose strings are shared by a segment's constants, or any that
+/// can't be appended to.
+pub fn update_values(path: &str, changes: &[(usize, Value)]) -> Result<UpdateReport, UpdateError> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = file.metadata()?.len();
+    let body = open_body(&mut file, file_len)?;
+    if body.flags & HEADER_STRING_TABLE != 0 {
+        return Err(AppendError::Unsupported("its strings are in per-segment tables").into());
+    }
+    let mut bytes = Vec::new();
+    file.rewind()?;
+    file.read_to_end(&mut bytes)?;
+    let (slots, mut raw) = scan_slots(&bytes)?;
+
+    let count = raw.len();
+    if body
+        .entries
+        .as_ref()
+        .is_some_and(|entries| entries.len() != count)
+    {
+        return Err(AppendError::BadIndex("index doesn't list every constant").into());
+    }
+    let mut values = Vec::with_capacity(count);
+    for value in &raw {
+        values.push(resolve_ref(&values, value.clone())?);
+    }
+    let mut updated = vec![None; count];
+    for (index, value) in changes {
+        if *index >= count {
+            let index = *index;
+            return Err(UpdateError::IndexOutOfRange(IndexOutOfRange {
+                index,
+                count,
+            }));
+        }
+        if let Value::Ref(target) = value {
+            if *target as usize >= *index {
+                let (index, target) = (*index, *target);
+                return Err(UpdateError::BadRef { index, target });
+            }
+        }
+        updated[*index] = Some(value.clone());
+    }
+    // A reference to a changed constant still has to load as the value it had
+    let stale = raw
+        .iter()
+        .enumerate()
+        .filter(|(i, value)| match value {
+            Value::Ref(target) => updated[*i].is_none() && updated[*target as usize].is_some(),
+            _ => false,
+        })
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    for i in stale {
+        updated[i] = Some(values[i].clone());
+    }
+    let mut depths = ObjectDepths::new();
+    for (i, value) in updated.iter().enumerate() {
+        if let Some(value) = value {
+            check_depth(value, LoadLimits::DEFAULT.max_depth, &mut depths)
+                .map_err(|e| e.in_entry(Entry::Constant(i)))?;
+            raw[i] = value.clone();
+        }
+    }
+
+    // Encoded without tables, since the segment's were written without these constants
+    let options = WriteOptions {
+        endianness: body.endianness,
+        ..WriteOptions::default()
+    };
+    let mut patches = Vec::new();
+    let mut detached = Vec::new();
+    for (i, value) in updated.iter().enumerate() {
+        let Some(value) = value else {
+            continue;
+        };
+        let slot = slots[i];
+        let room = (slot.end - slot.start) as usize;
+        let mut encoded = Vec::new();
+        value.write(&mut encoded, &WriteState::new(&options))?;
+        if encoded.len() <= room {
+            patches.push((i, slot, encoded, None));
+        } else if room >= FORWARD_LEN {
+            detached.push(DETACHED_TYPE_ID);
+            patches.push((i, slot, Vec::new(), Some(detached.len())));
+            detached.extend(encoded);
+        } else {
+            return rewrite_values(path, &bytes, &body, &raw).map(|bytes_written| UpdateReport {
+                updated: updated.iter().flatten().count(),
+                moved: 0,
+                rewritten: true,
+                bytes_written,
+            });
+        }
+    }
+
+    // Moved constants follow in a segment with no objects of its own
+    let mut segment = Vec::new();
+    let moved = patches.iter().filter(|patch| patch.3.is_some()).count();
+    if moved > 0 {
+        write_length(&mut segment, 0, &options)?;
+        write_length(&mut segment, moved, &options)?;
+    }
+    let segment_start = body.body_len;
+    let detached_start = segment_start as usize + segment.len();
+    segment.extend(detached);
+    let new_len = u32::try_from(body.body_len as u64 + segment.len() as u64)
+        .map_err(|_| AppendError::TooLarge)?;
+
+    let header_len = body.header_len as usize;
+    let mut body_bytes = bytes[header_len..header_len + body.body_len as usize].to_vec();
+    let mut entries = body.entries.clone();
+    let mut bytes_written = 0;
+    for (i, slot, mut encoded, detached_at) in patches {
+        let mut entry = (slot.segment as u32, slot.start as u32);
+        if let Some(at) = detached_at {
+            let target = (detached_start + at) as u32;
+            encoded = vec![FORWARD_TYPE_ID];
+            encoded.extend(target.to_endian_bytes(body.endianness));
+            entry = (segment_start, target);
+        }
+        let (start, end) = (slot.start as usize, slot.end as usize);
+        let padding = end - start - encoded.len();
+        body_bytes[start..start + padding].fill(PADDING_TYPE_ID);
+        body_bytes[start + padding..end].copy_from_slice(&encoded);
+        if let Some(entries) = &mut entries {
+            entries[i] = entry;
+        }
+
+        file.seek(SeekFrom::Start(body.header_len + slot.start))?;
+        file.write_all(&body_bytes[start..end])?;
+        bytes_written += (end - start) as u64;
+    }
+
+    // The segment replaces the footer, which is now over the patched body as well
+    let crc = crc32_extend(crc32(&body_bytes), &segment);
+    let mut tail = segment;
+    tail.extend(crc.to_endian_bytes(body.endianness));
+    if moved > 0 || entries != body.entries {
+        tail.extend(END_MARKER);
+        if let Some(entries) = &entries {
+            tail.extend(write_index(entries, body.endianness));
+        }
+    }
+    file.seek(SeekFrom::Start(body.footer_start()))?;
+    file.write_all(&tail)?;
+    bytes_written += tail.len() as u64;
+    if new_len != body.body_len {
+        file.seek(SeekFrom::Start(body.header_len - 4))?;
+        file.write_all(&new_len.to_endian_bytes(body.endianness))?;
+        bytes_written += 4;
+    }
+
+    Ok(UpdateReport {
+        updated: updated.iter().flatten().count(),
+        moved,
+        rewritten: false,
+        bytes_written,
+    })
+}
+
+/// Rewrites the file at `path` in full, reclaiming the padding and moved-out constants
+/// `update_values` leaves behind. It takes the files `update_values` takes, and keeps
+/// their byte order, string table, index and metadata.
+pub fn compact(path: &str) -> Result<UpdateReport, UpdateError> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let body = open_body(&mut file, file_len)?;
+    let mut bytes = Vec::new();
+    file.rewind()?;
+    file.read_to_end(&mut bytes)?;
+    let (_, values) = scan_slots(&bytes)?;
+
+    let bytes_written = rewrite_values(path, &bytes, &body, &values)?;
+    Ok(UpdateReport {
+        rewritten: true,
+        bytes_written,
+        ..UpdateReport::default()
+    })
+}
+
+/// Every constant of the file in `bytes` as it was written, references and all, with
+/// where it lies in the body.
+fn scan_slots(bytes: &[u8]) -> Result<(Vec<Slot>, Vec<Value>), ConstantsError> {
+    let mut pool = Vec::new();
+    let mut reader =
+        ConstantsReader::new(io::Cursor::new(bytes), &mut pool, &LoadOptions::default())?;
+    let mut slots = Vec::new();
+    let mut values = Vec::new();
+    while let Some(value) = reader.next() {
+        values.push(value?);
+        slots.push(reader.slot());
+    }
+    Ok((slots, values))
+}
+
+/// Saves `values` over `path` with the options the file in `bytes`, opened as `body`, was
+/// written with, returning the new file's length.
+fn rewrite_values(
+    path: &str,
+    bytes: &[u8],
+    body: &OpenBody,
+    values: &[Value],
+) -> Result<u64, UpdateError> {
+    let metadata = read_metadata(&mut io::Cursor::new(bytes))?;
+    let options = WriteOptions {
+        endianness: body.endianness,
+        string_table: body.flags & HEADER_STRING_TABLE != 0,
+        index: body.entries.is_some(),
+        metadata: metadata.as_ref(),
+        ..WriteOptions::default()
+    };
+    save_atomic(path, values, &options)?;
+    Ok(fs::metadata(path)?.len())
+}
+
+/// Splits `values` into the sections `s --sections` writes: strings, functions and the
+/// rest, each with its repeated strings deduplicated. References are resolved first, as
+/// they may point into another section, and fail as on writing unless each points to an
+/// earlier constant.
+pub fn split_sections(values: &[Value]) -> Result<[(&'static str, Vec<Value>); 3], ConstantsError> {
+    check_refs(values)?;
+    let mut sections = [
+        ("strings", Vec::new()),
+        ("functions", Vec::new()),
+        ("values", Vec::new()),
+    ];
+    // A reference to a reference resolves to what that one did
+    let mut resolved = Vec::<Value>::with_capacity(values.len());
+    for value in values {
+        let value = match value {
+            Value::Ref(index) => resolved[*index as usize].clone(),
+            value => value.clone(),
+        };
+        let section = match &value {
+            Value::Object(o) if matches!(**o, Object::String(_)) => 0,
+            Value::Object(o) if matches!(**o, Object::Function { .. }) => 1,
+            _ => 2,
+        };
+        sections[section].1.push(value.clone());
+        resolved.push(value);
+    }
+    Ok(sections.map(|(name, values)| (name, dedupe_strings(&values))))
+}
+
+/// Replaces repeated top-level strings with `Value::Ref`s to their first occurrence.
+pub fn dedupe_strings(values: &[Value]) -> Vec<Value> {
+    let mut first_seen = Vec::<(&str, u32)>::new();
+
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let Value::Object(o) = value else {
+                return value.clone();
+            };
+            let Object::String(s) = &**o else {
+                return value.clone();
+            };
+
+            match first_seen.iter().find(|(seen, _)| *seen == s.as_str()) {
+                Some((_, index)) => Value::Ref(*index),
+                None => {
+                    first_seen.push((s, i as u32));
+                    value.clone()
+                }
+            }
+        })
+        .collect()
+}
+
+/// What a reader needs from the header to decode the rest of a file.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout<'a> {
+    pub(crate) version: FormatVersion,
+    pub(crate) endianness: Endianness,
+    pub(crate) varint: bool,
+    pub(crate) string_table: bool,
+    pub(crate) compressed: bool,
+    pub(crate) encrypted: bool,
+    pub(crate) indexed: bool,
+    pub(crate) sections: bool,
+    pub(crate) signed: bool,
+    pub(crate) limits: LoadLimits,
+    /// The file's string table once it has been read; strings are indices into it.
+    pub(crate) strings: Option<&'a [Rc<Object>]>,
+    /// The objects read so far from the file's object pool, which values refer to.
+    pub(crate) objects: Option<&'a [Rc<Object>]>,
+    pub(crate) utf8: Utf8Policy,
+    /// Counts the strings `utf8` let through despite invalid UTF-8, when the reader keeps
+    /// count.
+    pub(crate) invalid_utf8: Option<&'a Cell<usize>>,
+    /// The symbols met so far, which repeats of them share, when the reader keeps them.
+    pub(crate) symbols: Option<&'a RefCell<SymbolTable>>,
+    /// Values the one being read is inside, so nesting fails against `limits.max_depth`
+    /// before it can run the reader out of stack.
+    pub(crate) depth: usize,
+}
+
+impl Layout<'_> {
+    /// Headerless files, which predate both the version and the byte order choice.
+    const LEGACY: Layout<'static> = Layout {
+        version: FormatVersion::V1,
+        endianness: Endianness::Big,
+        varint: false,
+        string_table: false,
+        compressed: false,
+        encrypted: false,
+        indexed: false,
+        sections: false,
+        signed: false,
+        limits: LoadLimits::DEFAULT,
+        strings: None,
+        objects: None,
+        utf8: Utf8Policy::Strict,
+        invalid_utf8: None,
+        symbols: None,
+        depth: 0,
+    };
+}
+
+/// Unique strings in the order `write_string` first met them, for files with
+/// `HEADER_STRING_TABLE`.
+#[derive(Debug, Default)]
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, usize>,
+}
+
+impl StringTable {
+    fn index_of(&mut self, str: &str) -> usize {
+        if let Some(index) = self.indices.get(str) {
+            return *index;
+        }
+
+        let index = self.strings.len();
+        self.strings.push(str.to_string());
+        self.indices.insert(str.to_string(), index);
+        index
+    }
+}
+
+/// Reads the string table at the start of a body, adding one shared object per entry to
+/// `pool`.
+fn read_string_table(
+    file: &mut impl Read,
+    layout: Layout,
+    pool: &mut ObjectPool,
+) -> Result<Vec<Rc<Object>>, ConstantsError> {
+    let count = read_usize(file, layout)?;
+    let mut strings = Vec::new();
+    for _ in 0..count {
+        let obj = Rc::new(Object::String(read_string(file, layout)?));
+        pool.push(Rc::clone(&obj));
+        strings.push(obj);
+    }
+
+    Ok(strings)
+}
+
+/// Objects in the order they were first written, each entry a type id and payload. An
+/// object's entry follows the entries of every object it holds.
+#[derive(Debug, Default)]
+pub(crate) struct ObjectTable {
+    entries: Vec<u8>,
+    count: usize,
+    indices: HashMap<*const Object, usize>,
+    /// Entries by their bytes, kept for deterministic output only.
+    by_entry: HashMap<Vec<u8>, usize>,
+}
+
+/// An encoding of `value` that doesn't depend on what the string table or object pool
+/// already hold, for ordering values canonically.
+pub(crate) fn canonical_encoding(value: &Value, options: &WriteOptions) -> Vec<u8> {
+    let inline = WriteState::new(options);
+    let mut bytes = Vec::new();
+    // A value that can't be written fails again when it's written for real
+    let _ = value.write(&mut bytes, &inline);
+    bytes
+}
+
+/// The elements of a set sorted by `canonical_encoding`, with duplicates dropped, so equal
+/// sets serialize identically regardless of insertion order.
+pub(crate) fn canonical_set_items<'a>(
+    items: &'a [Value],
+    options: &WriteOptions,
+) -> Vec<&'a Value> {
+    let mut keyed = items
+        .iter()
+        .map(|item| (canonical_encoding(item, options), item))
+        .collect::<Vec<_>>();
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let len = keyed.len();
+    keyed.dedup_by(|(a, _), (b, _)| a == b);
+    if keyed.len() != len {
+        options.warn(Warning::DuplicateSetItems(len - keyed.len()));
+    }
+
+    keyed.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Writes a reference to `o`, first adding its entry to `objects` if it isn't there yet.
+pub(crate) fn write_object_ref(
+    file: &mut impl Write,
+    o: &Rc<Object>,
+    objects: &RefCell<ObjectTable>,
+    options: &WriteState,
+) -> Result<(), ConstantsError> {
+    let known = objects.borrow().indices.get(&Rc::as_ptr(o)).copied();
+    let index = match known {
+        Some(index) => index,
+        None => {
+            // Written before borrowing, since the payload adds entries for nested objects
+            let mut entry = vec![o.encoded_type_id(options)];
+            o.write(&mut entry, options)?;
+
+            let mut objects = objects.borrow_mut();
+            // Equal objects share an entry, so whether they shared an `Rc` doesn't show
+            match objects.by_entry.get(&entry).copied() {
+                Some(index) => {
+                    objects.indices.insert(Rc::as_ptr(o), index);
+                    index
+                }
+                None => {
+                    let index = objects.count;
+                    objects.entries.extend(&entry);
+                    objects.count += 1;
+                    objects.indices.insert(Rc::as_ptr(o), index);
+                    if options.deterministic {
+                        objects.by_entry.insert(entry, index);
+                    }
+                    index
+                }
+            }
+        }
+    };
+
+    file.write_all(&[POOL_REF_TYPE_ID])?;
+    write_length(file, index, options)?;
+    Ok(())
+}
+
+/// Reads the object pool, resolving each entry's references against the entries before it.
+fn read_object_pool(
+    file: &mut impl Read,
+    layout: Layout,
+    pool: &mut ObjectPool,
+    depths: &mut ObjectDepths,
+) -> Result<Vec<Rc<Object>>, ConstantsError> {
+    let count = read_usize(file, layout)?;
+    let mut objects = Vec::new();
+    for i in 0..count {
+        let byte_id = read_u8(file)?;
+        let entry_layout = Layout {
+            objects: Some(&objects),
+            ..layout
+        };
+        let entry = Object::read(file, byte_id, entry_layout, pool)
+            .and_then(|entry| {
+                check_depth(&entry, layout.limits.max_depth, depths)?;
+                Ok(entry)
+            })
+            .map_err(|e| e.in_entry(Entry::Object(i)))?;
+        match entry {
+            Value::Object(o) => objects.push(o),
+            value => return invalid(format!("Object pool entry {i} is not an object: {value}")),
+        }
+    }
+
+    Ok(objects)
+}
+
+/// Reads a string table index and returns the string object it names.
+pub(crate) fn read_table_string(
+    file: &mut impl Read,
+    strings: &[Rc<Object>],
+    layout: Layout,
+) -> Result<Rc<Object>, ConstantsError> {
+    let index = read_usize(file, layout)?;
+    match strings.get(index) {
+        Some(obj) => Ok(Rc::clone(obj)),
+        None => invalid(format!(
+            "String index {index} is past the end of the {}-entry string table",
+            strings.len()
+        )),
+    }
+}
+
+/// Why constants couldn't be read or written. Every failure of the load and save paths is
+/// one of these rather than a panic or an out-of-bounds index, whether the file is on disk
+/// or in memory.
+#[derive(Debug)]
+pub enum ConstantsError {
+    Io(io::Error),
+    /// The file at `path` couldn't be opened or created.
+    Open {
+        path: String,
+        error: io::Error,
+    },
+    /// The input ends in the middle of something it declares.
+    UnexpectedEof,
+    /// A constant or object opens with a type id this version doesn't know, such as one a
+    /// newer version added. `offset` is where the constant holding it starts in the body,
+    /// when the reader knows.
+    UnknownTypeId {
+        id: u8,
+        offset: Option<u64>,
+    },
+    /// A string that should be UTF-8 isn't, in the entry given when the reader knows which.
+    InvalidUtf8 {
+        entry: Option<Entry>,
+        error: std::string::FromUtf8Error,
+    },
+    /// A value nests deeper than `limit` levels, in the entry given when the reader or
+    /// writer knows which.
+    MaxDepthExceeded {
+        entry: Option<Entry>,
+        limit: usize,
+    },
+    /// The bytes can't be constants, for the reason given.
+    Invalid(String),
+    /// `load_constant_at` was asked for a constant past the end of the file.
+    IndexOutOfRange(IndexOutOfRange),
+    /// The input opens with the armor header but isn't valid armor.
+    Armor(ArmorError),
+    /// `load_section` was asked for a section the file doesn't have.
+    MissingSection(String),
+    /// The file stops short of its end, after this many of its constants could be read.
+    Truncated {
+        recovered: usize,
+    },
+    /// Bytes follow everything the file should end with.
+    TrailingData {
+        bytes: u64,
+    },
+    /// The file's signature doesn't match its bytes under the key it was verified with.
+    SignatureMismatch,
+    /// A signature was asked for, and the file has none.
+    Unsigned,
+    /// The file is encrypted, and `LoadOptions::key` is `None`.
+    MissingKey,
+    /// The bytes of the body, or of the section named, don't match their checksum.
+    ChecksumMismatch {
+        section: Option<String>,
+        expected: u32,
+        computed: u32,
+    },
+    /// A function's code fails `verify_function`, which `LoadOptions::strict` makes fatal.
+    FailedVerification {
+        identifier: String,
+        error: VerifyError,
+    },
+    /// `check_function` found `issue` in a function inside `constant`, which
+    /// `LoadOptions::strict` makes fatal.
+    FunctionIssue {
+        identifier: String,
+        constant: usize,
+        issue: Issue,
+    },
+    /// The file declares more of something than `LoadLimits` allows.
+    LimitExceeded {
+        what: &'static str,
+        declared: u64,
+        limit: usize,
+    },
+    /// The values can't be written with the options given, for the reason given.
+    Unwritable(String),
+    /// A file `verify_saved` read back fails to load.
+    SaveUnreadable(Box<ConstantsError>),
+    /// A file `verify_saved` read back loads, but not as the values written to it.
+    SaveMismatch(SaveMismatch),
+}
+
+impl std::error::Error for ConstantsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConstantsError::Io(e) | ConstantsError::Open { error: e, .. } => Some(e),
+            ConstantsError::InvalidUtf8 { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConstantsError {
+    fn from(e: io::Error) -> ConstantsError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => ConstantsError::UnexpectedEof,
+            _ => ConstantsError::Io(e),
+        }
+    }
+}
+
+impl From<IndexOutOfRange> for ConstantsError {
+    fn from(e: IndexOutOfRange) -> ConstantsError {
+        ConstantsError::IndexOutOfRange(e)
+    }
+}
+
+impl From<ArmorError> for ConstantsError {
+    fn from(e: ArmorError) -> ConstantsError {
+        ConstantsError::Armor(e)
+    }
+}
+
+impl fmt::Display for ConstantsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConstantsError::Io(e) => write!(f, "{e}"),
+            ConstantsError::Open { path, error } => write!(f, "Could not open '{path}': {error}"),
+            ConstantsError::UnexpectedEof => {
+                write!(f, "Input ends before the constants it declares")
+            }
+            ConstantsError::UnknownTypeId { id, offset: None } => {
+                write!(f, "Unknown type id {id}")
+            }
+            ConstantsError::UnknownTypeId {
+                id,
+                offset: Some(offset),
+            } => write!(
+                f,
+                "Unknown type id {id} in the constant at body offset {offset:#010x}"
+            ),
+            ConstantsError::InvalidUtf8 { entry, error } => {
+                write!(f, "Invalid UTF-8 string")?;
+                if let Some(entry) = entry {
+                    write!(f, " in {entry}")?;
+                }
+                write!(
+                    f,
+                    ", at byte {} of the string: {error}",
+                    error.utf8_error().valid_up_to()
+                )
+            }
+            ConstantsError::Invalid(message) => write!(f, "{message}"),
+            ConstantsError::IndexOutOfRange(e) => write!(f, "{e}"),
+            ConstantsError::Armor(e) => write!(f, "Invalid armor: {e}"),
+            ConstantsError::MissingSection(name) => write!(f, "File has no section '{name}'"),
+            ConstantsError::Truncated { recovered } => write!(
+                f,
+                "File is truncated: only {recovered} constants could be recovered"
+            ),
+            ConstantsError::TrailingData { bytes } => {
+                write!(f, "File has {bytes} unexpected bytes after its end")
+            }
+            ConstantsError::SignatureMismatch => write!(
+                f,
+                "Signature doesn't match: the file was changed, or signed with another key"
+            ),
+            ConstantsError::Unsigned => {
+                write!(f, "File isn't signed, and unsigned files aren't allowed")
+            }
+            ConstantsError::MissingKey => write!(f, "File is encrypted and no key was supplied"),
+            ConstantsError::ChecksumMismatch {
+                section,
+                expected,
+                computed,
+            } => {
+                write!(f, "Checksum mismatch")?;
+                if let Some(name) = section {
+                    write!(f, " in section '{name}'")?;
+                }
+                write!(f, ": expected {expected:#010x}, computed {computed:#010x}")
+            }
+            ConstantsError::FailedVerification { identifier, error } => {
+                write!(f, "Function '{identifier}' failed verification: {error}")
+            }
+            ConstantsError::FunctionIssue {
+                identifier,
+                constant,
+                issue,
+            } => write!(f, "Function '{identifier}' in constant {constant} {issue}"),
+            ConstantsError::LimitExceeded {
+                what,
+                declared,
+                limit,
+            } => write!(f, "{what} of {declared} exceeds the limit of {limit}"),
+            ConstantsError::Unwritable(message) => write!(f, "{message}"),
+            ConstantsError::SaveUnreadable(e) => {
+                write!(f, "The saved file doesn't load back: {e}")
+            }
+            ConstantsError::SaveMismatch(mismatch) => write!(f, "{mismatch}"),
+            ConstantsError::MaxDepthExceeded { entry, limit } => {
+                match entry {
+                    Some(entry) => write!(f, "The value in {entry}")?,
+                    None => write!(f, "A value")?,
+                }
+                write!(f, " nests deeper than the limit of {limit} levels")
+            }
+        }
+    }
+}
+
+impl ConstantsError {
+    /// Says the error was met in `entry`, unless it already names one.
+    pub(crate) fn in_entry(self, entry: Entry) -> ConstantsError {
+        match self {
+            ConstantsError::InvalidUtf8 { entry: None, error } => ConstantsError::InvalidUtf8 {
+                entry: Some(entry),
+                error,
+            },
+            ConstantsError::MaxDepthExceeded { entry: None, limit } => {
+                ConstantsError::MaxDepthExceeded {
+                    entry: Some(entry),
+                    limit,
+                }
+            }
+            e => e,
+        }
+    }
+}
+
+/// How a saved file differs from the values written to it.
+#[derive(Debug)]
+pub struct SaveMismatch {
+    pub written: usize,
+    pub read: usize,
+    /// The constants read back as something else, by index, with the value written and
+    /// the value read as they display.
+    pub differences: Vec<(usize, String, String)>,
+}
+
+impl SaveMismatch {
+    /// Differences listed before the rest are only counted.
+    const SHOWN: usize = 10;
+}
+
+impl fmt::Display for SaveMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The saved file doesn't read back as written")?;
+        if self.read != self.written {
+            write!(
+                f,
+                "\n  {} constants read back, {} written",
+                self.read, self.written
+            )?;
+        }
+        for (index, written, read) in self.differences.iter().take(SaveMismatch::SHOWN) {
+            write!(f, "\n  constant {index}: wrote {written}, read {read}")?;
+        }
+        if let Some(more) = self.differences.len().checked_sub(SaveMismatch::SHOWN) {
+            if more > 0 {
+                write!(f, "\n  and {more} more constants differ")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where in a file a value is: a constant, counting from the file's first, or an entry of
+/// the object pool of the segment it's in.
+#[derive(Debug, Clone, Copy)]
+pub enum Entry {
+    Constant(usize),
+    Object(usize),
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Constant(index) => write!(f, "constant {index}"),
+            Entry::Object(index) => write!(f, "object pool entry {index}"),
+        }
+    }
+}
+
+/// Something a load or save let through rather than failing on. The library never prints
+/// these: they go to the `warnings` of the `LoadOptions` or `WriteOptions` in use, if
+/// given, for the caller to report.
+#[derive(Debug)]
+pub enum Warning {
+    /// A function's code fails `verify_function`, outside `LoadOptions::strict`.
+    FailedVerification {
+        identifier: String,
+        error: VerifyError,
+    },
+    /// `check_function` found `issue` in a function inside `constant`, outside
+    /// `LoadOptions::strict`.
+    FunctionIssue {
+        identifier: String,
+        constant: usize,
+        issue: Issue,
+    },
+    /// Constants with a type id this version doesn't know, loaded as `Value::Nil` under
+    /// `LoadOptions::skip_unknown`.
+    SkippedUnknown(usize),
+    /// Strings with invalid UTF-8 loaded as `policy` allows.
+    InvalidUtf8 { count: usize, policy: Utf8Policy },
+    /// Elements of a set dropped on writing for encoding the same as another.
+    DuplicateSetItems(usize),
+    /// A function `write_function_cfgs` wrote no graph for, its code failing verification.
+    SkippedCfg {
+        identifier: String,
+        error: VerifyError,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::FailedVerification { identifier, error } => {
+                write!(f, "function '{identifier}' failed verification: {error}")
+            }
+            Warning::FunctionIssue {
+                identifier,
+                constant,
+                issue,
+            } => write!(f, "function '{identifier}' in constant {constant} {issue}"),
+            Warning::SkippedUnknown(count) => write!(
+                f,
+                "{count} constants with unknown type ids were loaded as nil"
+            ),
+            Warning::InvalidUtf8 { count, policy } => {
+                let how = match policy {
+                    Utf8Policy::Bytes => "as bytes",
+                    _ => "with replacement characters",
+                };
+                write!(f, "{count} strings with invalid UTF-8 were loaded {how}")
+            }
+            Warning::DuplicateSetItems(count) => {
+                write!(f, "dropped {count} duplicate set element(s)")
+            }
+            Warning::SkippedCfg { identifier, error } => {
+                write!(f, "skipping cfg for '{identifier}': {error}")
+            }
+        }
+    }
+}
+
+/// Where warnings go: each is pushed, in the order met, for the caller to read once the
+/// load or save returns.
+pub type Warnings = RefCell<Vec<Warning>>;
+
+pub fn invalid<T>(message: impl Into<String>) -> Result<T, ConstantsError> {
+    Err(ConstantsError::Invalid(message.into()))
+}
+
+pub(crate) fn unwritable<T>(message: impl Into<String>) -> Result<T, ConstantsError> {
+    Err(ConstantsError::Unwritable(message.into()))
+}
+
+/// The layout `options` read `file` with: the header's, or the legacy one for headerless
+/// files, held to `options.limits`. With `options.verify_key`, the whole file is checked
+/// against its signature first.
+fn read_layout(
+    file: &mut (impl Read + Seek),
+    options: &LoadOptions,
+) -> Result<Layout<'static>, ConstantsError> {
+    let start = file.stream_position()?;
+    let layout = if options.legacy {
+        Layout::LEGACY
+    } else {
+        read_header(file)?
+    };
+    if let Some(key) = options.verify_key {
+        if layout.signed {
+            let after_header = file.stream_position()?;
+            file.seek(SeekFrom::Start(start))?;
+            verify_signature(file, key)?;
+            file.seek(SeekFrom::Start(after_header))?;
+        } else if !options.allow_unsigned {
+            return Err(ConstantsError::Unsigned);
+        }
+    }
+    Ok(Layout {
+        limits: options.limits,
+        utf8: options.utf8,
+        ..layout
+    })
+}
+
+fn read_header(file: &mut impl Read) -> Result<Layout<'static>, ConstantsError> {
+    let (layout, flags) = read_header_flags(file)?;
+    if flags & HEADER_METADATA != 0 {
+        // Skipped unread, since it never changes how the constants decode
+        let len = read_usize(file, layout)? as u64;
+        if io::copy(&mut file.by_ref().take(len), &mut io::sink())? != len {
+            return Err(ConstantsError::UnexpectedEof);
+        }
+    }
+    Ok(layout)
+}
+
+/// The metadata a file was written with, or `None` if it has none. Leaves `file` where
+/// `read_header` would, at the body length.
+pub fn read_metadata(file: &mut impl Read) -> Result<Option<Metadata>, ConstantsError> {
+    let (layout, flags) = read_header_flags(file)?;
+    if flags & HEADER_METADATA == 0 {
+        return Ok(None);
+    }
+    let len = read_usize(file, layout)? as u64;
+    let mut block = file.by_ref().take(len);
+    let metadata = Metadata::read(&mut block, layout)?;
+    if block.limit() != 0 {
+        return invalid(format!(
+            "Metadata block has {} bytes after its entries",
+            block.limit()
+        ));
+    }
+    Ok(Some(metadata))
+}
+
+/// Reads the header up to the end of the flags, which are also returned.
+fn read_header_flags(file: &mut impl Read) -> Result<(Layout<'static>, u8), ConstantsError> {
+    let mut magic = [0u8; 4];
+    if file.read_exact(&mut magic).is_err() {
+        return invalid("File is too short to be a constants file");
+    }
+    if magic != FILE_MAGIC {
+        return invalid(format!(
+            "Not a constants file: expected magic {FILE_MAGIC:?}, found {magic:?} \
+             (files written before the header need --legacy)"
+        ));
+    }
+
+    let version = read_u16(file, Layout::LEGACY)?;
+    let Some(version) = FormatVersion::from_u16(version) else {
+        let latest = FormatVersion::LATEST as u16;
+        if version > latest {
+            return invalid(format!(
+                "Format version {version} is newer than the supported version {latest}"
+            ));
+        }
+        return invalid(format!("Invalid format version {version}"));
+    };
+
+    let endianness = if version >= FormatVersion::V3 {
+        let byte = read_u8(file)?;
+        match Endianness::from_byte(byte) {
+            Some(endianness) => endianness,
+            None => return invalid(format!("Unknown endianness {byte}")),
+        }
+    } else {
+        Endianness::Big
+    };
+
+    let flags = if version >= FormatVersion::V5 {
+        read_u8(file)?
+    } else {
+        0
+    };
+    // Every bit is a known flag, so there are no unknown ones to refuse
+    let layout = Layout {
+        version,
+        endianness,
+        varint: flags & HEADER_VARINT != 0,
+        string_table: flags & HEADER_STRING_TABLE != 0,
+        compressed: flags & HEADER_COMPRESSED != 0,
+        encrypted: flags & HEADER_ENCRYPTED != 0,
+        indexed: flags & HEADER_INDEX != 0,
+        sections: flags & HEADER_SECTIONS != 0,
+        signed: flags & HEADER_SIGNED != 0,
+        limits: LoadLimits::DEFAULT,
+        strings: None,
+        objects: None,
+        utf8: Utf8Policy::Strict,
+        invalid_utf8: None,
+        symbols: None,
+        depth: 0,
+    };
+    Ok((layout, flags))
+}
+
+/// The CRC32 of the next `len` bytes of `file`, and how many of them there were, read in
+/// chunks so a length the file can't back is never allocated.
+fn crc32_of(file: &mut impl Read, len: usize) -> Result<(u32, usize), ConstantsError> {
+    let mut crc = 0;
+    let mut found = 0;
+    let mut buffer = [0u8; 8192];
+    let mut bytes = file.by_ref().take(len as u64);
+    loop {
+        let read = bytes.read(&mut buffer)?;
+        if read == 0 {
+            return Ok((crc, found));
+        }
+        crc = crc32_extend(crc, &buffer[..read]);
+        found += read;
+    }
+}
+
+/// Checks the `body_len` bytes at the current position against the CRC32 footer that
+/// follows them, then seeks back so the body can be parsed.
+fn verify_checksum(
+    file: &mut (impl Read + Seek),
+    body_len: usize,
+    layout: Layout,
+) -> Result<(), ConstantsError> {
+    let body_start = file.stream_position()?;
+    let (crc, mut found) = crc32_of(file, body_len)?;
+
+    let mut footer = Vec::new();
+    file.by_ref().take(4).read_to_end(&mut footer)?;
+    found += footer.len();
+    if found < body_len + 4 {
+        return invalid(format!(
+            "File is truncated: expected {} bytes after the header, found {found}",
+            body_len + 4
+        ));
+    }
+
+    let expected = u32::from_endian_bytes(footer[..].try_into().unwrap(), layout.endianness);
+    if expected != crc {
+        return Err(ConstantsError::ChecksumMismatch {
+            section: None,
+            expected,
+            computed: crc,
+        });
+    }
+
+    file.seek(SeekFrom::Start(body_start))?;
+    Ok(())
+}
+
+/// Decodes the constants of a native file one at a time, so scanning a file never holds
+/// more than the constant being read. The header, checksum and the tables that open each
+/// body segment are read up front; objects go to `pool` as they're met. A `Value::Ref` is
+/// yielded as it was written, since resolving it would mean keeping every earlier
+/// constant, and a short or malformed file ends the iteration with its error.
+pub struct ConstantsReader<'a, R> {
+    body: ReaderBody<R>,
+    end: BodyEnd,
+    layout: Layout<'static>,
+    pool: &'a mut ObjectPool,
+    options: LoadOptions<'a>,
+    /// The current segment's string table and object pool.
+    strings: Vec<Rc<Object>>,
+    objects: Vec<Rc<Object>>,
+    remaining: usize,
+    /// Constants yielded so far, and how many of them came before the current segment
+    /// when that's a section, whose references count from its own first constant.
+    yielded: usize,
+    ref_base: usize,
+    /// Set at the end of the body or on the first error, after which nothing is yielded.
+    done: bool,
+    /// Bytes in the body, from which positions in it are worked out, then where the
+    /// current segment starts and the last constant yielded lies.
+    body_len: u64,
+    segment_start: u64,
+    slot: Slot,
+    /// Where the body starts in the file, unless it was decoded from a compressed or
+    /// encrypted one.
+    body_start: Option<u64>,
+    /// With `LoadOptions::skip_unknown`, the offsets in the body where constants and
+    /// segments start, in order and ending with the body's length, so a constant that
+    /// can't be read can be stepped over. Then how many were.
+    boundaries: Option<Vec<u64>>,
+    skipped: usize,
+    /// Strings `LoadOptions::utf8` let through despite invalid UTF-8.
+    invalid_utf8: Cell<usize>,
+    symbols: RefCell<SymbolTable>,
+    depths: ObjectDepths,
+}
+
+/// Where a constant lies in the body of its file, as offsets from the start of the body:
+/// its segment, and the bytes from any padding before it to its end. A moved constant's
+/// bytes are the forward to it.
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    segment: u64,
+    start: u64,
+    end: u64,
+}
+
+/// What follows a file's body, as `read_body_end` found it. Problems are only reported once
+/// the constants run out, so they can say how many were recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyEnd {
+    Complete,
+    /// The file stops before the body, footer or anything after them does.
+    Truncated,
+    /// This many bytes follow everything the file should end with.
+    Trailing(u64),
+}
+
+/// Checks what follows a body of `body_len` bytes starting at the current position,
+/// then seeks back. That's the CRC32 footer, and from V8 the end marker, any index and
+/// any signature, then nothing; earlier versions may carry anything after the footer.
+fn read_body_end(
+    file: &mut (impl Read + Seek),
+    layout: Layout,
+    body_len: u64,
+) -> Result<BodyEnd, ConstantsError> {
+    let body_start = file.stream_position()?;
+    let file_end = file.seek(SeekFrom::End(0))?;
+    let end = read_body_end_at(file, layout, body_start + body_len, file_end)?;
+    file.seek(SeekFrom::Start(body_start))?;
+    Ok(end)
+}
+
+fn read_body_end_at(
+    file: &mut (impl Read + Seek),
+    layout: Layout,
+    body_end: u64,
+    file_end: u64,
+) -> Result<BodyEnd, ConstantsError> {
+    let mut expected = body_end + 4;
+    if layout.version < FormatVersion::V8 {
+        return Ok(if file_end < expected {
+            BodyEnd::Truncated
+        } else {
+            BodyEnd::Complete
+        });
+    }
+
+    expected += END_MARKER.len() as u64;
+    if file_end < expected {
+        return Ok(BodyEnd::Truncated);
+    }
+    let mut marker = [0u8; 4];
+    file.seek(SeekFrom::Start(expected - 4))?;
+    file.read_exact(&mut marker)?;
+    if marker != END_MARKER {
+        return Ok(BodyEnd::Truncated);
+    }
+
+    if layout.indexed {
+        match read_index(file, layout.endianness, file_end - expected) {
+            Ok(entries) => expected += index_len(entries.len()),
+            Err("index is truncated") => return Ok(BodyEnd::Truncated),
+            Err(e) => return invalid(format!("Could not read the index: {e}")),
+        }
+    }
+    if layout.signed {
+        expected += SIGNATURE_LEN as u64;
+    }
+    Ok(match file_end.cmp(&expected) {
+        std::cmp::Ordering::Less => BodyEnd::Truncated,
+        std::cmp::Ordering::Equal => BodyEnd::Complete,
+        std::cmp::Ordering::Greater => BodyEnd::Trailing(file_end - expected),
+    })
+}
+
+/// The bytes a `ConstantsReader` decodes: the file's own body, or the plaintext of a
+/// compressed or encrypted one.
+enum ReaderBody<R> {
+    File(io::Take<R>),
+    Decoded(io::Cursor<Vec<u8>>),
+}
+
+impl<R: Read> ReaderBody<R> {
+    fn len(&self) -> u64 {
+        match self {
+            ReaderBody::File(body) => body.limit(),
+            ReaderBody::Decoded(body) => {
+                (body.get_ref().len() as u64).saturating_sub(body.position())
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<R: Read + Seek> ReaderBody<R> {
+    /// Moves to `offset` in a body of `body_len` bytes.
+    fn seek_to(&mut self, offset: u64, body_len: u64) -> io::Result<()> {
+        match self {
+            ReaderBody::File(body) => {
+                let position = body_len - body.limit();
+                body.get_mut()
+                    .seek(SeekFrom::Current(offset as i64 - position as i64))?;
+                body.set_limit(body_len - offset);
+            }
+            ReaderBody::Decoded(body) => body.set_position(offset),
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ReaderBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ReaderBody::File(body) => body.read(buf),
+            ReaderBody::Decoded(body) => body.read(buf),
+        }
+    }
+}
+
+impl<'a, R: Read + Seek> ConstantsReader<'a, R> {
+    /// Reads the header of `file` and the tables opening its first segment, leaving it at
+    /// that segment's first constant.
+    pub fn new(
+        mut file: R,
+        pool: &'a mut ObjectPool,
+        options: &LoadOptions<'a>,
+    ) -> Result<ConstantsReader<'a, R>, ConstantsError> {
+        let layout = read_layout(&mut file, options)?;
+
+        let mut end = BodyEnd::Complete;
+        let mut body_start = None;
+        let body = if layout.version < FormatVersion::V2 {
+            body_start = Some(file.stream_position()?);
+            ReaderBody::File(file.take(u64::MAX))
+        } else {
+            let body_len = read_usize(&mut file, layout)?;
+            // A truncated body is read as far as it goes instead, as the checksum can't hold
+            end = read_body_end(&mut file, layout, body_len as u64)?;
+            if !options.skip_checksum && end != BodyEnd::Truncated {
+                verify_checksum(&mut file, body_len, layout)?;
+            }
+
+            // Both only appear in headers with flags, which always come with a body length
+            if (layout.compressed || layout.encrypted) && end == BodyEnd::Truncated {
+                return Err(ConstantsError::Truncated { recovered: 0 });
+            }
+            if layout.compressed || layout.encrypted {
+                let mut body = Vec::new();
+                file.take(body_len as u64).read_to_end(&mut body)?;
+                if body.len() != body_len {
+                    return invalid("File is too short for its body");
+                }
+                if layout.encrypted {
+                    let Some(key) = options.key else {
+                        return Err(ConstantsError::MissingKey);
+                    };
+                    body = decrypt_body(&body, key, layout)?;
+                }
+                if layout.compressed {
+                    body = decompress_body(&body, layout)?;
+                }
+                ReaderBody::Decoded(io::Cursor::new(body))
+            } else {
+                body_start = Some(file.stream_position()?);
+                ReaderBody::File(file.take(body_len as u64))
+            }
+        };
+        let mut reader = ConstantsReader::from_body(body, end, layout, pool, options)?;
+        reader.body_start = body_start;
+        // Only bodies stored as they are can have an index, so its offsets hold in them
+        let body_len = reader.body_len;
+        if options.skip_unknown && layout.indexed && end != BodyEnd::Truncated {
+            if let (ReaderBody::File(body), Some(body_start)) = (&mut reader.body, body_start) {
+                let file = body.get_mut();
+                let back = file.stream_position()?;
+                let entries = read_file_index(file, layout, body_start, body_len)?;
+                file.seek(SeekFrom::Start(back))?;
+
+                let mut boundaries = entries
+                    .into_iter()
+                    .flat_map(|(segment, constant)| [segment as u64, constant as u64])
+                    .chain([body_len])
+                    .collect::<Vec<_>>();
+                boundaries.sort_unstable();
+                boundaries.dedup();
+                reader.boundaries = Some(boundaries);
+            }
+        }
+        Ok(reader)
+    }
+
+    /// Reads the constants of `body`, which has already been checked and decoded, and
+    /// reports any problem with its `end` after the last of them. A body with sections
+    /// opens with their directory, which is checked against its length.
+    fn from_body(
+        mut body: ReaderBody<R>,
+        end: BodyEnd,
+        layout: Layout<'static>,
+        pool: &'a mut ObjectPool,
+        options: &LoadOptions<'a>,
+    ) -> Result<ConstantsReader<'a, R>, ConstantsError> {
+        if end == BodyEnd::Truncated && layout.sections {
+            return Err(ConstantsError::Truncated { recovered: 0 });
+        }
+        if layout.sections {
+            let directory = read_section_directory(&mut body, layout)?;
+            let total = directory.iter().map(|entry| entry.len as u64).sum::<u64>();
+            if total != body.len() {
+                return invalid(format!(
+                    "Sections add up to {total} bytes, but {} follow the directory",
+                    body.len()
+                ));
+            }
+        }
+
+        let body_len = body.len();
+        let symbols = RefCell::new(SymbolTable::of(pool));
+        let mut reader = ConstantsReader {
+            body,
+            end,
+            layout,
+            pool,
+            options: *options,
+            strings: Vec::new(),
+            objects: Vec::new(),
+            remaining: 0,
+            yielded: 0,
+            ref_base: 0,
+            done: false,
+            body_len,
+            segment_start: 0,
+            slot: Slot::default(),
+            body_start: None,
+            boundaries: None,
+            skipped: 0,
+            invalid_utf8: Cell::new(0),
+            symbols,
+            depths: ObjectDepths::new(),
+        };
+        // Older bodies are always one segment, where newer ones may hold none at all
+        if layout.version < FormatVersion::V7 || !reader.body.is_empty() {
+            reader
+                .start_segment()
+                .map_err(|e| reader.truncation_or(e))?;
+        }
+        Ok(reader)
+    }
+
+    /// Constants left in the segment being read. Appended segments may follow with more.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Whether no segment follows this one. Bodies before `FormatVersion::V7` are a single
+    /// segment, which `new` has already started.
+    fn is_last_segment(&self) -> bool {
+        self.layout.version < FormatVersion::V7 || self.body.is_empty()
+    }
+
+    /// Reads the optional string table, the object pool and the count that open a segment.
+    fn start_segment(&mut self) -> Result<(), ConstantsError> {
+        self.segment_start = self.offset();
+        let first_object = self.pool.len();
+        let mut layout: Layout = Layout {
+            invalid_utf8: Some(&self.invalid_utf8),
+            symbols: Some(&self.symbols),
+            ..self.layout
+        };
+        self.strings = Vec::new();
+        if layout.string_table {
+            self.strings = read_string_table(&mut self.body, layout, self.pool)?;
+            layout.strings = Some(&self.strings);
+        }
+        self.objects = Vec::new();
+        if layout.version >= FormatVersion::V6 {
+            self.objects = read_object_pool(&mut self.body, layout, self.pool, &mut self.depths)?;
+        }
+
+        self.remaining = read_usize(&mut self.body, layout)?;
+        let declared = (self.yielded as u64).saturating_add(self.remaining as u64);
+        LoadLimits::check(layout.limits.max_constants, declared, "Constant count")?;
+        // Every constant takes at least its type id
+        if self.remaining as u64 > self.body.len() {
+            return invalid(format!(
+                "Segment declares {} constants, but only {} bytes are left for them",
+                self.remaining,
+                self.body.len()
+            ));
+        }
+        if self.layout.sections {
+            self.ref_base = self.yielded;
+        }
+        verify_functions(&self.pool[first_object..], &self.options)
+    }
+
+    /// In a truncated body, stands in for whatever error ended the read.
+    fn truncation_or(&self, error: ConstantsError) -> ConstantsError {
+        match self.end {
+            BodyEnd::Truncated => ConstantsError::Truncated {
+                recovered: self.yielded,
+            },
+            _ => error,
+        }
+    }
+
+    /// Where the body is being read, as an offset from its start.
+    fn offset(&self) -> u64 {
+        self.body_len - self.body.len()
+    }
+
+    /// Where the last constant yielded lies in the body.
+    fn slot(&self) -> Slot {
+        self.slot
+    }
+
+    /// The bytes the last constant yielded was read from, which `slot` gives the place of.
+    fn slot_span(&mut self) -> Result<ConstantSpan, ConstantsError> {
+        let Slot { start, end, .. } = self.slot;
+        let back = self.offset();
+        self.body.seek_to(start, self.body_len)?;
+        let mut bytes = vec![0; (end - start) as usize];
+        self.body.read_exact(&mut bytes)?;
+        self.body.seek_to(back, self.body_len)?;
+        let (offset, in_file) = self.place(start);
+        Ok(ConstantSpan {
+            offset,
+            in_file,
+            bytes,
+        })
+    }
+
+    /// Where `offset` in the body lies in the file, or in the decoded body when the second
+    /// value is false.
+    fn place(&self, offset: u64) -> (u64, bool) {
+        match self.body_start {
+            Some(body_start) => (body_start + offset, true),
+            None => (offset, false),
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<Value>, ConstantsError> {
+        // Detached constants are counted in their segment, but only read through forwards
+        let (start, first_object, value) = loop {
+            while self.remaining == 0 {
+                if self.is_last_segment() {
+                    return match self.end {
+                        BodyEnd::Complete => Ok(None),
+                        BodyEnd::Truncated => {
+                            Err(self.truncation_or(ConstantsError::UnexpectedEof))
+                        }
+                        BodyEnd::Trailing(bytes) => Err(ConstantsError::TrailingData { bytes }),
+                    };
+                }
+                self.start_segment()?;
+            }
+
+            let start = self.offset();
+            let first_object = self.pool.len();
+            let byte_id = read_type_id(&mut self.body)?;
+            let id_offset = self.offset() - 1;
+            let value = match byte_id {
+                DETACHED_TYPE_ID => self.read_value().map(|_| None),
+                FORWARD_TYPE_ID => self.read_forwarded().map(Some),
+                byte_id => self.read_value_of(byte_id).map(Some),
+            };
+            let value = match value {
+                Err(ConstantsError::UnknownTypeId { id, .. }) => {
+                    // The index points a detached constant's entry past its type id
+                    let from = match byte_id {
+                        DETACHED_TYPE_ID => id_offset + 1,
+                        _ => id_offset,
+                    };
+                    let Some(next) = self.boundary_after(from) else {
+                        return Err(ConstantsError::UnknownTypeId {
+                            id,
+                            offset: Some(id_offset),
+                        });
+                    };
+                    self.body.seek_to(next, self.body_len)?;
+                    self.pool.truncate(first_object);
+                    if byte_id == DETACHED_TYPE_ID {
+                        None
+                    } else {
+                        self.skipped += 1;
+                        Some(Value::Nil)
+                    }
+                }
+                value => value?,
+            };
+            match value {
+                Some(value) => break (start, first_object, value),
+                None => {
+                    self.pool.truncate(first_object);
+                    self.remaining -= 1;
+                }
+            }
+        };
+        self.slot = Slot {
+            segment: self.segment_start,
+            start,
+            end: self.offset(),
+        };
+
+        let value = match value {
+            // Made to count from the file's first constant, as in a file without sections
+            Value::Ref(index) if self.ref_base > 0 => {
+                match u32::try_from(index as usize + self.ref_base) {
+                    Ok(index) => Value::Ref(index),
+                    Err(_) => return invalid(format!("Reference {index} is out of range")),
+                }
+            }
+            value => value,
+        };
+        self.remaining -= 1;
+        self.yielded += 1;
+        verify_functions(&self.pool[first_object..], &self.options)?;
+        Ok(Some(value))
+    }
+
+    /// Reads the constant a forward at the current position points to, and comes back.
+    fn read_forwarded(&mut self) -> Result<Value, ConstantsError> {
+        let target = read_u32(&mut self.body, self.layout)? as u64;
+        if target >= self.body_len {
+            return invalid(format!(
+                "Forward to offset {target} is past the end of the body"
+            ));
+        }
+        let back = self.offset();
+        self.body.seek_to(target, self.body_len)?;
+        let value = self.read_value()?;
+        self.body.seek_to(back, self.body_len)?;
+        Ok(value)
+    }
+
+    /// Where the next constant or segment starts after `offset`, if the constant there
+    /// can be skipped.
+    fn boundary_after(&self, offset: u64) -> Option<u64> {
+        let boundaries = self.boundaries.as_ref()?;
+        boundaries
+            .iter()
+            .copied()
+            .find(|&boundary| boundary > offset)
+    }
+
+    /// Constants loaded as `Value::Nil` so far, for having a type id this version
+    /// doesn't know.
+    fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Warns of how many constants were skipped and strings let through with invalid
+    /// UTF-8, if any were.
+    fn warn_recovered(&self) {
+        if self.skipped() > 0 {
+            self.options.warn(Warning::SkippedUnknown(self.skipped()));
+        }
+        if self.invalid_utf8() > 0 {
+            self.options.warn(Warning::InvalidUtf8 {
+                count: self.invalid_utf8(),
+                policy: self.options.utf8,
+            });
+        }
+    }
+
+    /// Strings loaded despite invalid UTF-8 so far, as `LoadOptions::utf8` allows.
+    fn invalid_utf8(&self) -> usize {
+        self.invalid_utf8.get()
+    }
+
+    /// Reads a value at the current position, which mustn't be padding or a forward.
+    fn read_value(&mut self) -> Result<Value, ConstantsError> {
+        match read_u8(&mut self.body)? {
+            byte_id @ (PADDING_TYPE_ID | FORWARD_TYPE_ID | DETACHED_TYPE_ID) => {
+                invalid(format!("Type id {byte_id} can't be a forwarded constant"))
+            }
+            byte_id => self.read_value_of(byte_id),
+        }
+    }
+
+    fn read_value_of(&mut self, byte_id: u8) -> Result<Value, ConstantsError> {
+        let layout = Layout {
+            strings: self.layout.string_table.then_some(&self.strings[..]),
+            objects: (self.layout.version >= FormatVersion::V6).then_some(&self.objects[..]),
+            invalid_utf8: Some(&self.invalid_utf8),
+            symbols: Some(&self.symbols),
+            ..self.layout
+        };
+        Value::read(&mut self.body, byte_id, layout, self.pool)
+            .and_then(|value| {
+                check_depth(&value, layout.limits.max_depth, &mut self.depths)?;
+                Ok(value)
+            })
+            .map_err(|e| e.in_entry(Entry::Constant(self.yielded)))
+    }
+}
+
+impl<R: Read + Seek> Iterator for ConstantsReader<'_, R> {
+    type Item = Result<Value, ConstantsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.read_next().map_err(|e| self.truncation_or(e));
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result.transpose()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // The count comes from the file, so it bounds what's left without promising it
+        if self.done {
+            (0, Some(0))
+        } else if self.is_last_segment() {
+            (0, Some(self.remaining()))
+        } else {
+            (0, None)
+        }
+    }
+}
+
+/// Reads a whole constants file from `file`, the one parser behind every native load,
+/// resolving references against the constants read before them.
+fn read_constants(
+    file: &mut (impl Read + Seek),
+    values: &mut Vec<Value>,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<(), ConstantsError> {
+    let base = values.len();
+    let mut reader = ConstantsReader::new(file, pool, options)?;
+    collect_constants(&mut reader, values)?;
+    reader.warn_recovered();
+    check_functions(&values[base..], options)
+}
+
+/// Reads constants from `file` like `read_constants` until one fails, then stops with
+/// those before it, and says where and why it stopped. Damage near the end of a file only
+/// costs the constants from there on.
+pub fn load_values_partial(
+    file: &mut (impl Read + Seek),
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> (Vec<Value>, Option<PartialLoadError>) {
+    let mut values = Vec::new();
+    let mut reader = match ConstantsReader::new(file, pool, options) {
+        Ok(reader) => reader,
+        Err(error) => {
+            let error = PartialLoadError {
+                index: 0,
+                offset: None,
+                error,
+            };
+            return (values, Some(error));
+        }
+    };
+
+    loop {
+        let offset = reader.place(reader.offset());
+        let value = match reader.next() {
+            Some(value) => value.and_then(|value| resolve_ref(&values, value)),
+            None => {
+                reader.warn_recovered();
+                return (values, None);
+            }
+        };
+        match value {
+            Ok(value) => values.push(value),
+            Err(error) => {
+                let error = PartialLoadError {
+                    index: values.len(),
+                    offset: Some(offset),
+                    error,
+                };
+                return (values, Some(error));
+            }
+        }
+    }
+}
+
+/// Why `load_values_partial` stopped before the end of a file.
+#[derive(Debug)]
+pub struct PartialLoadError {
+    /// The constant it failed on, which is also how many were read.
+    pub index: usize,
+    /// Where reading that constant started, as `ConstantsReader::place` gives it, or `None`
+    /// when the header or the first segment's tables failed before any constant.
+    pub offset: Option<(u64, bool)>,
+    pub error: ConstantsError,
+}
+
+impl fmt::Display for PartialLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some((offset, in_file)) => {
+                let place = if in_file { "file" } else { "decoded body" };
+                write!(
+                    f,
+                    "Stopped at constant {} ({place} offset {offset:#010x}): {}",
+                    self.index, self.error
+                )
+            }
+            None => write!(f, "Stopped before the first constant: {}", self.error),
+        }
+    }
+}
+
+/// Reads a whole constants file like `read_constants`, and returns the bytes each
+/// constant was read from.
+pub fn read_constant_spans(
+    file: &mut (impl Read + Seek),
+    values: &mut Vec<Value>,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<Vec<ConstantSpan>, ConstantsError> {
+    let mut reader = ConstantsReader::new(file, pool, options)?;
+    let base = values.len();
+    let mut spans = Vec::new();
+    while let Some(value) = reader.next() {
+        values.push(resolve_ref(&values[base..], value?)?);
+        spans.push(reader.slot_span()?);
+    }
+    reader.warn_recovered();
+    Ok(spans)
+}
+
+/// The bytes a constant was read from, from any padding `update_values` left before it.
+/// An object's are its reference into the segment's object pool, and a moved constant's
+/// its forward.
+pub struct ConstantSpan {
+    /// Where the bytes start in the file, or in the decoded body of a compressed or
+    /// encrypted one, which is when `in_file` is false.
+    pub offset: u64,
+    pub in_file: bool,
+    pub bytes: Vec<u8>,
+}
+
+impl ConstantSpan {
+    /// Writes a line saying where constant `index` lies, then its bytes as `hex_dump` does.
+    pub fn write(&self, out: &mut impl Write, index: usize) -> io::Result<()> {
+        let place = if self.in_file { "file" } else { "decoded body" };
+        writeln!(
+            out,
+            "constant {index}: {} bytes at {place} offset {:#010x}",
+            self.bytes.len(),
+            self.offset
+        )?;
+        out.write_all(hex_dump(&self.bytes, self.offset).as_bytes())
+    }
+}
+
+/// Rows of 16 bytes, each led by the offset of its first byte counting from `offset`,
+/// with the printable ASCII ones repeated in a gutter and `.` for the rest.
+fn hex_dump(bytes: &[u8], offset: u64) -> String {
+    let mut out = String::new();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let hex = chunk.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>();
+        let ascii = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        out.push_str(&format!(
+            "  {:08x}  {:<47}  |{ascii}|\n",
+            offset + row as u64 * 16,
+            hex.join(" ")
+        ));
+    }
+    out
+}
+
+/// Adds what `reader` yields to `values`, replacing each reference with the constant it
+/// names.
+fn collect_constants(
+    reader: impl Iterator<Item = Result<Value, ConstantsError>>,
+    values: &mut Vec<Value>,
+) -> Result<(), ConstantsError> {
+    // References index constants from this file, not anything already in `values`
+    let base = values.len();
+    for value in reader {
+        let value = resolve_ref(&values[base..], value?)?;
+        values.push(value);
+    }
+    Ok(())
+}
+
+/// One entry of the directory that opens a body with `HEADER_SECTIONS`.
+struct SectionEntry {
+    name: String,
+    /// Bytes in the section, which follows the sections before it.
+    len: usize,
+    count: usize,
+    crc: u32,
+}
+
+fn read_section_directory(
+    file: &mut impl Read,
+    layout: Layout,
+) -> Result<Vec<SectionEntry>, ConstantsError> {
+    let count = read_usize(file, layout)?;
+    let mut directory = Vec::new();
+    for _ in 0..count {
+        directory.push(SectionEntry {
+            name: read_string(file, layout)?,
+            len: read_usize(file, layout)?,
+            count: read_usize(file, layout)?,
+            crc: read_u32(file, layout)?,
+        });
+    }
+    Ok(directory)
+}
+
+/// Loads only the section called `name` of a file `write_sections` wrote, seeking past
+/// the sections before it. The body checksum would mean reading every section, so the
+/// section is checked against its own CRC32 in the directory instead.
+pub fn load_section(
+    file: &mut (impl Read + Seek),
+    name: &str,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<Vec<Value>, ConstantsError> {
+    let layout = read_layout(file, options)?;
+    if !layout.sections {
+        return Err(ConstantsError::MissingSection(name.to_string()));
+    }
+    if layout.compressed || layout.encrypted {
+        return invalid("Sections in a compressed or encrypted body can't be sought");
+    }
+
+    read_usize(file, layout)?;
+    let directory = read_section_directory(file, layout)?;
+    let mut offset = 0;
+    let Some(entry) = directory.iter().find(|entry| {
+        offset += entry.len as u64;
+        entry.name == name
+    }) else {
+        return Err(ConstantsError::MissingSection(name.to_string()));
+    };
+    file.seek(SeekFrom::Current((offset - entry.len as u64) as i64))?;
+
+    if !options.skip_checksum {
+        let start = file.stream_position()?;
+        let (crc, found) = crc32_of(file, entry.len)?;
+        if found != entry.len {
+            return Err(ConstantsError::UnexpectedEof);
+        }
+        if crc != entry.crc {
+            return Err(ConstantsError::ChecksumMismatch {
+                section: Some(name.to_string()),
+                expected: entry.crc,
+                computed: crc,
+            });
+        }
+        file.seek(SeekFrom::Start(start))?;
+    }
+
+    // Read as a body of its own, so references count from the section's first constant
+    let body = ReaderBody::File(file.take(entry.len as u64));
+    let layout = Layout {
+        sections: false,
+        ..layout
+    };
+    let mut values = Vec::new();
+    collect_constants(
+        ConstantsReader::from_body(body, BodyEnd::Complete, layout, pool, options)?,
+        &mut values,
+    )?;
+    if values.len() != entry.count {
+        return invalid(format!(
+            "Section '{name}' holds {} constants, but the directory says {}",
+            values.len(),
+            entry.count
+        ));
+    }
+    check_functions(&values, options)?;
+    Ok(values)
+}
+
+/// Reads every constant of a native file from `file` onto `values`. The checksum is
+/// checked before the body is parsed, which needs a reader that can seek, so a socket or
+/// pipe is read to the end first and passed as an `io::Cursor`, or to
+/// `load_values_from_bytes`.
+pub fn load_values_from_disk(
+    file: &mut (impl Read + Seek),
+    values: &mut Vec<Value>,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<(), ConstantsError> {
+    read_constants(file, values, pool, options)
+}
+
+/// Decodes a constants file held in memory, exactly as `load_values_from_disk` reads one
+/// from disk, first taking off any armor and then parsing with `deserialize_from_slice`.
+pub fn load_values_from_bytes(
+    bytes: &[u8],
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<Vec<Value>, ConstantsError> {
+    if bytes.starts_with(ARMOR_BEGIN.as_bytes()) {
+        return deserialize_from_slice(&dearmor(bytes)?, pool, options);
+    }
+    deserialize_from_slice(bytes, pool, options)
+}
+
+/// Parses the native file `serialize_to_vec` encodes from `bytes`, without the armor
+/// `load_values_from_bytes` also takes off. The parser is the one every load shares, fed
+/// by a `SliceReader` that checks each read against the end of `bytes` before copying out
+/// of it, so a length that runs past the end fails with `ConstantsError::UnexpectedEof`.
+pub fn deserialize_from_slice(
+    bytes: &[u8],
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<Vec<Value>, ConstantsError> {
+    let mut values = Vec::new();
+    read_constants(&mut SliceReader::new(bytes), &mut values, pool, options)?;
+    Ok(values)
+}
+
+/// Maps the constants file at `path` into memory and decodes it with
+/// `load_values_from_bytes`, so the body is never copied into a buffer of its own. Without
+/// the `mmap` feature, or off unix, the file is read into memory instead.
+///
+/// # Safety
+///
+/// Nothing may truncate the file at `path` until this returns: the mapping would then
+/// cover pages the file no longer has, and reading them kills the process with `SIGBUS`.
+/// Bytes changed in place are read as they are met, so at worst fail to decode. Holding a
+/// `LockKind::Shared` lock against writers that take `LockKind::Exclusive` is enough;
+/// replacing the file by renaming over it, as `save_atomic` does, leaves the mapped one be.
+pub unsafe fn load_values_mmap(
+    path: &str,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<Vec<Value>, ConstantsError> {
+    #[cfg(all(feature = "mmap", unix))]
+    // SAFETY: the caller keeps the file from shrinking while it's mapped
+    let map = unsafe { mmap::Mmap::open(&File::open(path)?)? };
+    #[cfg(all(feature = "mmap", unix))]
+    let bytes = map.bytes();
+    #[cfg(not(all(feature = "mmap", unix)))]
+    let bytes = &fs::read(path)?;
+
+    load_values_from_bytes(bytes, pool, options)
+}
+
+/// The container a constants file is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContainerFormat {
+    /// This crate's own binary layout, shaped by `WriteOptions` and `LoadOptions`.
+    #[default]
+    Native,
+    /// The `cbor` module's encoding of the same constants, which has no compression or
+    /// encryption of its own.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl ContainerFormat {
+    pub fn from_name(name: &str) -> Option<ContainerFormat> {
+        match name {
+            "native" => Some(ContainerFormat::Native),
+            #[cfg(feature = "cbor")]
+            "cbor" => Some(ContainerFormat::Cbor),
+            _ => None,
+        }
+    }
+}
+
+/// Writes `values` to `file` in `format`. Only the native format reads `options`, so
+/// asking another to compress or encrypt is an error rather than a silent no-op.
+pub fn save_as(
+    format: ContainerFormat,
+    file: &mut impl Write,
+    values: &[Value],
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    if options.armor {
+        let mut bytes = Vec::new();
+        let options = WriteOptions {
+            armor: false,
+            ..*options
+        };
+        save_as(format, &mut bytes, values, &options)?;
+        file.write_all(armor(&bytes).as_bytes())?;
+        return Ok(());
+    }
+
+    match format {
+        ContainerFormat::Native => write_values_to_disk(file, values, options),
+        #[cfg(feature = "cbor")]
+        ContainerFormat::Cbor => {
+            if options.compression.is_some()
+                || options.key.is_some()
+                || options.index
+                || options.sign_key.is_some()
+            {
+                return unwritable(
+                    "The CBOR container can't be compressed, encrypted, indexed or signed",
+                );
+            }
+            check_depths(values, LoadLimits::DEFAULT.max_depth)?;
+            check_refs(values)?;
+            file.write_all(&cbor::to_cbor(values))?;
+            Ok(())
+        }
+    }
+}
+
+/// Reads constants written by `save_as` in the same `format`, verifying functions just as
+/// `load_values_from_disk` does.
+fn load_as(
+    format: ContainerFormat,
+    file: &mut (impl Read + Seek),
+    values: &mut Vec<Value>,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<(), ConstantsError> {
+    match format {
+        ContainerFormat::Native => load_values_from_disk(file, values, pool, options),
+        #[cfg(feature = "cbor")]
+        ContainerFormat::Cbor => {
+            if options.verify_key.is_some() && !options.allow_unsigned {
+                return Err(ConstantsError::Unsigned);
+            }
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            let first_object = pool.len();
+            let base = values.len();
+            let constants = cbor::from_cbor(&bytes, pool, options.limits.max_depth)
+                .or_else(|e| invalid(format!("Invalid CBOR constants: {e}")))?;
+            for value in constants {
+                let value = resolve_ref(&values[base..], value)?;
+                values.push(value);
+            }
+            verify_functions(&pool[first_object..], options)?;
+            check_functions(&values[base..], options)
+        }
+    }
+}
+
+/// What `detect_format` can tell an input is from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// `ContainerFormat::Native`, opening with `FILE_MAGIC`. Legacy files have no magic,
+    /// so they're never detected and need `LoadOptions::legacy`.
+    Native,
+    /// Another format wrapped by `armor`, opening with `ARMOR_BEGIN`.
+    Armored,
+    /// `to_json` output, opening with `[` or `{` after any whitespace.
+    Json,
+    /// `to_text` stanzas, opening with a type name such as `int` after any whitespace.
+    Text,
+    /// `ContainerFormat::Cbor`, opening with the self-described CBOR tag.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl InputFormat {
+    /// Every format `detect_format` looks for, in the order it looks.
+    const ALL: &'static [InputFormat] = &[
+        InputFormat::Native,
+        InputFormat::Armored,
+        #[cfg(feature = "cbor")]
+        InputFormat::Cbor,
+        InputFormat::Json,
+        InputFormat::Text,
+    ];
+}
+
+impl From<ContainerFormat> for InputFormat {
+    fn from(format: ContainerFormat) -> InputFormat {
+        match format {
+            ContainerFormat::Native => InputFormat::Native,
+            #[cfg(feature = "cbor")]
+            ContainerFormat::Cbor => InputFormat::Cbor,
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            InputFormat::Native => "native",
+            InputFormat::Armored => "armored",
+            InputFormat::Json => "JSON",
+            InputFormat::Text => "text",
+            #[cfg(feature = "cbor")]
+            InputFormat::Cbor => "CBOR",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Works out the format of `file` from its first bytes, then seeks back to where it was,
+/// so whatever loads it next starts from the same place.
+pub fn detect_format(file: &mut (impl Read + Seek)) -> Result<InputFormat, ConstantsError> {
+    let start = file.stream_position()?;
+    let mut head = Vec::new();
+    file.by_ref()
+        .take(ARMOR_BEGIN.len() as u64)
+        .read_to_end(&mut head)?;
+    file.seek(SeekFrom::Start(start))?;
+    // JSON and text may open with any amount of whitespace, so the first word is found
+    // past it. Type names are short, so the word is cut off well before any could end.
+    let mut word = Vec::new();
+    for byte in io::BufReader::new(file.by_ref()).bytes() {
+        let byte = byte?;
+        if !byte.is_ascii_whitespace() {
+            word.push(byte);
+        } else if !word.is_empty() {
+            break;
+        }
+        if word.len() > 32 {
+            break;
+        }
+    }
+    file.seek(SeekFrom::Start(start))?;
+
+    if head.starts_with(&FILE_MAGIC) {
+        return Ok(InputFormat::Native);
+    }
+    if head.starts_with(ARMOR_BEGIN.as_bytes()) {
+        return Ok(InputFormat::Armored);
+    }
+    // The self-described tag `to_cbor` opens with
+    #[cfg(feature = "cbor")]
+    if head.starts_with(&[0xd9, 0xd9, 0xf7]) {
+        return Ok(InputFormat::Cbor);
+    }
+    match word.first() {
+        Some(b'[' | b'{') => return Ok(InputFormat::Json),
+        Some(_)
+            if word.len() <= 32 && word.iter().all(|b| b.is_ascii_lowercase() || *b == b'_') =>
+        {
+            return Ok(InputFormat::Text)
+        }
+        Some(_) => {}
+        None => {
+            return invalid("Input is empty or only whitespace, so its format can't be told apart")
+        }
+    }
+
+    let considered = InputFormat::ALL
+        .iter()
+        .map(|format| format.to_string())
+        .collect::<Vec<_>>();
+    invalid(format!(
+        "Input is in no format known here; considered {}",
+        considered.join(", ")
+    ))
+}
+
+/// Loads constants from `file` in whatever format `detect_format` finds, resolving references
+/// and verifying functions as a native load does, and returns the format. Armored input
+/// is decoded first, and the format is the one inside it.
+fn load_detected(
+    file: &mut (impl Read + Seek),
+    values: &mut Vec<Value>,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<InputFormat, ConstantsError> {
+    let format = detect_format(file)?;
+    if format == InputFormat::Native {
+        read_constants(file, values, pool, options)?;
+        return Ok(format);
+    }
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    if format == InputFormat::Armored {
+        return load_detected(
+            &mut io::Cursor::new(dearmor(&bytes)?),
+            values,
+            pool,
+            options,
+        );
+    }
+    if options.verify_key.is_some() && !options.allow_unsigned {
+        return Err(ConstantsError::Unsigned);
+    }
+
+    let first_object = pool.len();
+    let constants = match format {
+        #[cfg(feature = "cbor")]
+        InputFormat::Cbor => {
+            cbor::from_cbor(&bytes, pool, options.limits.max_depth).map_err(|e| e.to_string())
+        }
+        _ => {
+            let text = String::from_utf8(bytes)
+                .or_else(|_| invalid(format!("{format} input is not valid UTF-8")))?;
+            if format == InputFormat::Json {
+                json::from_json_within(&text, pool, options.limits.max_depth)
+                    .map_err(|e| e.to_string())
+            } else {
+                text::from_text_within(&text, pool, options.limits.max_depth)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+    .or_else(|e| invalid(format!("Invalid {format} constants: {e}")))?;
+    check_depths(&constants, options.limits.max_depth)?;
+    let base = values.len();
+    collect_constants(constants.into_iter().map(Ok), values)?;
+    verify_functions(&pool[first_object..], options)?;
+    check_functions(&values[base..], options)?;
+    Ok(format)
+}
+
+/// Loads `file` with `load_as` in `format`, or without one in whatever format
+/// `detect_format` finds. Legacy files have no magic to find, so are read as native.
+pub fn load_in(
+    format: Option<ContainerFormat>,
+    file: &mut (impl Read + Seek),
+    values: &mut Vec<Value>,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<(), ConstantsError> {
+    match format {
+        Some(format) => load_as(format, file, values, pool, options),
+        None if options.legacy => load_values_from_disk(file, values, pool, options),
+        None => load_detected(file, values, pool, options).map(|_| ()),
+    }
+}
+
+/// `load_constant_at` was asked for a constant past the end of the file.
+#[derive(Debug)]
+pub struct IndexOutOfRange {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl fmt::Display for IndexOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "constant {} is out of range for a file of {} constants",
+            self.index, self.count
+        )
+    }
+}
+
+/// Loads only the constant at `index`, adding the objects it needs to `pool`. A file with
+/// `HEADER_INDEX` is read from the constant's segment, whose string table and object pool
+/// the constant may refer into, and then from the constant itself; other files are loaded
+/// in full. Seeking skips the body checksum, but the index has its own.
+pub fn load_constant_at(
+    file: &mut (impl Read + Seek),
+    index: usize,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+) -> Result<Value, ConstantsError> {
+    let layout = read_layout(file, options)?;
+    if !layout.indexed {
+        file.rewind()?;
+        let mut values = Vec::new();
+        read_constants(file, &mut values, pool, options)?;
+        let count = values.len();
+        return Ok(values
+            .into_iter()
+            .nth(index)
+            .ok_or(IndexOutOfRange { index, count })?);
+    }
+
+    let body_len = read_usize(file, layout)? as u64;
+    let body_start = file.stream_position()?;
+    let entries = read_file_index(file, layout, body_start, body_len)?;
+    let Some(&(segment, offset)) = entries.get(index) else {
+        let count = entries.len();
+        return Err(IndexOutOfRange { index, count }.into());
+    };
+
+    let first_object = pool.len();
+    let strings;
+    let symbols = RefCell::new(SymbolTable::of(pool));
+    let mut layout: Layout = Layout {
+        symbols: Some(&symbols),
+        ..layout
+    };
+    file.seek(SeekFrom::Start(body_start + segment as u64))?;
+    if layout.string_table {
+        strings = read_string_table(file, layout, pool)?;
+        layout.strings = Some(&strings);
+    }
+    let objects = read_object_pool(file, layout, pool, &mut ObjectDepths::new())?;
+    layout.objects = Some(&objects);
+
+    file.seek(SeekFrom::Start(body_start + offset as u64))?;
+    let byte_id = read_type_id(file)?;
+    let value = Value::read(file, byte_id, layout, pool).map_err(|e| match e {
+        ConstantsError::UnknownTypeId { id, .. } => ConstantsError::UnknownTypeId {
+            id,
+            offset: Some(offset as u64),
+        },
+        e => e,
+    })?;
+    verify_functions(&pool[first_object..], options)?;
+
+    match value {
+        Value::Ref(target) if (target as usize) < index => {
+            file.rewind()?;
+            load_constant_at(file, target as usize, pool, options)
+        }
+        Value::Ref(target) => invalid(format!(
+            "Constant {index} references constant {target}, which is not an earlier entry"
+        )),
+        value => Ok(value),
+    }
+}
+
+/// Reads the index of a file with `HEADER_INDEX`, whose body of `body_len` bytes starts
+/// at `body_start`.
+fn read_file_index(
+    file: &mut (impl Read + Seek),
+    layout: Layout,
+    body_start: u64,
+    body_len: u64,
+) -> Result<Vec<(u32, u32)>, ConstantsError> {
+    let mut index_start = body_start + body_len + 4;
+    if layout.version >= FormatVersion::V8 {
+        index_start += END_MARKER.len() as u64;
+    }
+    let mut available = file.seek(SeekFrom::End(0))?.saturating_sub(index_start);
+    if layout.signed {
+        available = available.saturating_sub(SIGNATURE_LEN as u64);
+    }
+    file.seek(SeekFrom::Start(index_start))?;
+    read_index(file, layout.endianness, available)
+        .or_else(|e| invalid(format!("Could not read the index: {e}")))
+}
+
+/// Expands a compressed body, refusing to produce more than its declared length.
+fn decompress_body(mut compressed: &[u8], layout: Layout) -> Result<Vec<u8>, ConstantsError> {
+    let declared = read_usize(&mut compressed, layout)?;
+    LoadLimits::check(
+        layout.limits.max_body_len,
+        declared as u64,
+        "Decompressed body length",
+    )?;
+    let body = inflate(compressed, declared)
+        .or_else(|e| invalid(format!("Compressed body is corrupt: {e}")))?;
+    if body.len() != declared {
+        return invalid(format!(
+            "Compressed body is corrupt: expanded to {} bytes instead of the declared {declared}",
+            body.len()
+        ));
+    }
+
+    Ok(body)
+}
+
+/// Replaces a top-level `Value::Ref` with a clone of the earlier constant it points at,
+/// `earlier` being the constants read from the same file so far.
+fn resolve_ref(earlier: &[Value], value: Value) -> Result<Value, ConstantsError> {
+    match value {
+        Value::Ref(index) if (index as usize) < earlier.len() => {
+            Ok(earlier[index as usize].clone())
+        }
+        Value::Ref(index) => invalid(format!(
+            "Constant {} references constant {index}, which is not an earlier entry",
+            earlier.len()
+        )),
+        value => Ok(value),
+    }
+}
+
+/// Fails unless every top-level `Value::Ref` in `values` points to an earlier constant,
+/// as `resolve_ref` needs, so nothing is written that couldn't be read back.
+pub(crate) fn check_refs(values: &[Value]) -> Result<(), ConstantsError> {
+    for (i, value) in values.iter().enumerate() {
+        match value {
+            Value::Ref(index) if *index as usize >= i => {
+                return unwritable(format!(
+                    "Constant {i} references constant {index}, which is not an earlier entry"
+                ))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions<'a> {
+    pub string_encoding: StringEncoding,
+    pub endianness: Endianness,
+    /// Writes lengths, counts and `Value::Int`s as LEB128 varints.
+    pub varint: bool,
+    /// Writes each unique string once in a table at the start of the body. Strings in an
+    /// encoding other than UTF-8 are still written in place.
+    pub string_table: bool,
+    /// DEFLATE level from 1 to 9 for everything after the body length, or `None` to
+    /// leave the body uncompressed.
+    pub compression: Option<u8>,
+    /// Encrypts the body, after any compression, under this key.
+    pub key: Option<&'a [u8]>,
+    /// Makes equal pools write identical bytes: map entries are sorted and equal objects
+    /// are written once whether or not they shared an `Rc`. Encryption draws a random
+    /// nonce, so it is refused in this mode.
+    pub deterministic: bool,
+    /// Follows the footer with an index of where each constant starts, so
+    /// `load_constant_at` can seek to one. Refused with compression or encryption.
+    pub index: bool,
+    /// Wraps the whole file, in whichever container `save_as` writes, in base64 armor.
+    pub armor: bool,
+    /// Records what wrote the file after the header, for `read_metadata`.
+    pub metadata: Option<&'a Metadata>,
+    /// Ends the file with an HMAC-SHA256 tag under this key, which loads given
+    /// `LoadOptions::verify_key` check.
+    pub sign_key: Option<&'a [u8]>,
+    /// Has `save_atomic` load the file back once it's on disk, with `verify_saved`, and
+    /// fail unless it holds the values written. Writers given only an `impl Write` can't
+    /// reopen it, so leave this to their callers.
+    pub verify: bool,
+    /// Collects what the save let through, such as duplicate set elements dropped.
+    pub warnings: Option<&'a Warnings>,
+}
+
+impl WriteOptions<'_> {
+    pub(crate) fn warn(&self, warning: Warning) {
+        if let Some(warnings) = self.warnings {
+            warnings.borrow_mut().push(warning);
+        }
+    }
+}
+
+/// A write under way: the caller's `WriteOptions`, which it derefs to, and the tables
+/// `write_segment` fills as the values are written.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteState<'a> {
+    options: &'a WriteOptions<'a>,
+    /// The string table, in files with `WriteOptions::string_table`.
+    strings: Option<&'a RefCell<StringTable>>,
+    /// The object pool, which turns every `Value::Object` into a reference.
+    pub(crate) objects: Option<&'a RefCell<ObjectTable>>,
+}
+
+impl<'a> WriteState<'a> {
+    /// Writes every value inline, with no string table or object pool to point into.
+    pub fn new(options: &'a WriteOptions<'a>) -> WriteState<'a> {
+        WriteState {
+            options,
+            strings: None,
+            objects: None,
+        }
+    }
+}
+
+impl<'a> std::ops::Deref for WriteState<'a> {
+    type Target = WriteOptions<'a>;
+
+    fn deref(&self) -> &WriteOptions<'a> {
+        self.options
+    }
+}
+
+/// What wrote a file, kept after the header so a file from a bug report can be traced to
+/// the build that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub tool: String,
+    pub version: String,
+    /// Seconds since the Unix epoch, or `None` so that rebuilds write the same bytes.
+    pub created: Option<u64>,
+    /// Pairs supplied by the writer, in the order they were given.
+    pub entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// This generator, writing now.
+    pub fn generator() -> Metadata {
+        Metadata {
+            tool: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|elapsed| elapsed.as_secs()),
+            entries: Vec::new(),
+        }
+    }
+
+    fn write(&self, file: &mut impl Write, options: &WriteOptions) -> Result<(), ConstantsError> {
+        write_bytes(file, self.tool.as_bytes(), options)?;
+        write_bytes(file, self.version.as_bytes(), options)?;
+        match self.created {
+            Some(created) => {
+                file.write_all(&[1])?;
+                write_number(file, created, options)?;
+            }
+            None => file.write_all(&[0])?,
+        }
+        write_length(file, self.entries.len(), options)?;
+        for (key, value) in &self.entries {
+            write_bytes(file, key.as_bytes(), options)?;
+            write_bytes(file, value.as_bytes(), options)?;
+        }
+        Ok(())
+    }
+
+    fn read(file: &mut impl Read, layout: Layout) -> Result<Metadata, ConstantsError> {
+        let tool = read_string(file, layout)?;
+        let version = read_string(file, layout)?;
+        let created = match read_u8(file)? {
+            0 => None,
+            1 => Some(read_number(file, layout)?),
+            byte => return invalid(format!("Invalid creation time marker {byte}")),
+        };
+        let count = read_usize(file, layout)?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push((read_string(file, layout)?, read_string(file, layout)?));
+        }
+        Ok(Metadata {
+            tool,
+            version,
+            created,
+            entries,
+        })
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Written by {} {}", self.tool, self.version)?;
+        if let Some(created) = self.created {
+            write!(f, " at {created} (unix time)")?;
+        }
+        for (key, value) in &self.entries {
+            write!(f, "\n  {key}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Byte order of every multi-byte number in a file after the version. Function code and
+/// UTF-16 strings have their own fixed big-endian encodings and aren't affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Endianness {
+    #[default]
+    Big,
+    Little,
+}
+
+impl Endianness {
+    fn from_byte(byte: u8) -> Option<Endianness> {
+        match byte {
+            0 => Some(Endianness::Big),
+            1 => Some(Endianness::Little),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Endianness> {
+        match name {
+            "big" => Some(Endianness::Big),
+            "little" => Some(Endianness::Little),
+            _ => None,
+        }
+    }
+}
+
+/// Fixed-width numbers that can be encoded in either byte order.
+pub(crate) trait EndianBytes: Sized {
+    type Bytes: AsRef<[u8]> + AsMut<[u8]> + Default;
+
+    fn to_endian_bytes(self, endianness: Endianness) -> Self::Bytes;
+    fn from_endian_bytes(bytes: Self::Bytes, endianness: Endianness) -> Self;
+}
+
+macro_rules! impl_endian_bytes {
+    ($($t:ty),*) => {$(
+        impl EndianBytes for $t {
+            type Bytes = [u8; std::mem::size_of::<$t>()];
+
+            fn to_endian_bytes(self, endianness: Endianness) -> Self::Bytes {
+                match endianness {
+                    Endianness::Big => self.to_be_bytes(),
+                    Endianness::Little => self.to_le_bytes(),
+                }
+            }
+
+            fn from_endian_bytes(bytes: Self::Bytes, endianness: Endianness) -> Self {
+                match endianness {
+                    Endianness::Big => <$t>::from_be_bytes(bytes),
+                    Endianness::Little => <$t>::from_le_bytes(bytes),
+                }
+            }
+        }
+    )*};
+}
+
+impl_endian_bytes!(u8, u16, i16, u32, i32, u64, i64);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadOptions<'a> {
+    /// Fail the load on function code that fails `verify_function` rather than warning
+    /// and loading it anyway.
+    pub strict: bool,
+    /// Read a file from before the magic and version header, starting at the count.
+    pub legacy: bool,
+    /// Parse without first checking the body against its CRC32 footer.
+    pub skip_checksum: bool,
+    /// Key for encrypted files. Files that aren't encrypted ignore it.
+    pub key: Option<&'a [u8]>,
+    pub limits: LoadLimits,
+    /// Refuse files whose signature doesn't verify under this key, before reading any
+    /// constants. Unsigned files are refused too unless `allow_unsigned` is set.
+    pub verify_key: Option<&'a [u8]>,
+    pub allow_unsigned: bool,
+    /// Load constants with a type id this version doesn't know as `Value::Nil`, found the
+    /// end of through the file's index, instead of failing. Files without one still fail.
+    pub skip_unknown: bool,
+    pub utf8: Utf8Policy,
+    /// Collects what the load let through instead of failing on, such as function issues
+    /// outside `strict`. Without it they go unreported.
+    pub warnings: Option<&'a Warnings>,
+}
+
+impl LoadOptions<'_> {
+    pub(crate) fn warn(&self, warning: Warning) {
+        if let Some(warnings) = self.warnings {
+            warnings.borrow_mut().push(warning);
+        }
+    }
+}
+
+/// What a load does with a string that isn't valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Utf8Policy {
+    /// Fail with `ConstantsError::InvalidUtf8`.
+    #[default]
+    Strict,
+    /// Replace each invalid sequence with U+FFFD.
+    Lossy,
+    /// Load a string constant as `Object::Bytes` instead. Other strings, such as names and
+    /// string table entries, have no such fallback and fail as under `Strict`.
+    Bytes,
+}
+
+impl Utf8Policy {
+    pub fn from_name(name: &str) -> Option<Utf8Policy> {
+        match name {
+            "strict" => Some(Utf8Policy::Strict),
+            "lossy" => Some(Utf8Policy::Lossy),
+            "bytes" => Some(Utf8Policy::Bytes),
+            _ => None,
+        }
+    }
+}
+
+/// The most a file may declare of each, checked before reading so a crafted length fails
+/// the load at once instead of running it out of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadLimits {
+    /// Bytes in one string, byte string or big integer.
+    pub max_string_len: usize,
+    /// Bytes of code in one function.
+    pub max_code_len: usize,
+    /// Constants in the whole file, across every segment.
+    pub max_constants: usize,
+    /// Bytes a compressed body may expand to.
+    pub max_body_len: usize,
+    /// Levels a value may nest to, counting itself and each value inside another, so
+    /// `[[1]]` is three deep. Writers keep to the default.
+    pub max_depth: usize,
+    /// Parameters a function may declare before `check_function` flags it.
+    pub max_params: usize,
+}
+
+impl LoadLimits {
+    const DEFAULT: LoadLimits = LoadLimits {
+        max_string_len: 16 << 20,
+        max_code_len: 16 << 20,
+        max_constants: 1 << 24,
+        max_body_len: 1 << 30,
+        max_depth: 128,
+        max_params: 128,
+    };
+
+    fn check(limit: usize, declared: u64, what: &'static str) -> Result<(), ConstantsError> {
+        if declared > limit as u64 {
+            return Err(ConstantsError::LimitExceeded {
+                what,
+                declared,
+                limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for LoadLimits {
+    fn default() -> LoadLimits {
+        LoadLimits::DEFAULT
+    }
+}
+
+/// On-disk encoding of `Object::String` payloads. The in-memory form is always `String`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum StringEncoding {
+    #[default]
+    Utf8,
+    Utf16Be,
+    Latin1,
+}
+
+impl StringEncoding {
+    pub(crate) fn from_byte(byte: u8) -> Option<StringEncoding> {
+        match byte {
+            0 => Some(StringEncoding::Utf8),
+            1 => Some(StringEncoding::Utf16Be),
+            2 => Some(StringEncoding::Latin1),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<StringEncoding> {
+        match name {
+            "utf8" => Some(StringEncoding::Utf8),
+            "utf16" => Some(StringEncoding::Utf16Be),
+            "latin1" => Some(StringEncoding::Latin1),
+            _ => None,
+        }
+    }
+}
+
+pub trait ConstantIO {
+    fn to_type_id(&self) -> u8;
+    fn read(
+        file: &mut impl Read,
+        byte_id: u8,
+        layout: Layout,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, ConstantsError>;
+    fn write(&self, file: &mut impl Write, options: &WriteState) -> Result<(), ConstantsError>;
+}
+
+/// Type ids of each revision of the function layout, oldest first. A function is always
+/// written with the newest, and the revision read decides which fields are present.
+pub(crate) const FUNCTION_TYPE_IDS: [u8; 8] = [
+    3,  // identifier, param_count, code
+    31, // + local_count
+    32, // + constants
+    33, // + optional line table
+    34, // + upvalues
+    35, // + default parameter values
+    36, // + parameter and return type tags
+    37, // + flags after param_count
+];
+pub(crate) const FUNCTION_TYPE_ID: u8 = FUNCTION_TYPE_IDS[FUNCTION_TYPE_IDS.len() - 1];
+
+/// A `Value::Object` written as an index into the object pool.
+pub(crate) const POOL_REF_TYPE_ID: u8 = 41;
+/// Fills the bytes `update_values` freed ahead of a constant it shrank. Readers skip any
+/// run of it where a constant may start.
+const PADDING_TYPE_ID: u8 = 42;
+/// Stands in for a constant `update_values` moved to the end of the body, followed by the
+/// u32 offset in the body where the constant now starts.
+const FORWARD_TYPE_ID: u8 = 43;
+/// Opens a constant that's only reached through a forward, and which sequential readers
+/// step over instead of yielding.
+const DETACHED_TYPE_ID: u8 = 44;
+
+/// The type id of the constant that starts at the current position, past any padding.
+fn read_type_id(file: &mut impl Read) -> Result<u8, ConstantsError> {
+    loop {
+        let byte_id = read_u8(file)?;
+        if byte_id != PADDING_TYPE_ID {
+            return Ok(byte_id);
+        }
+    }
+}
+
+pub(crate) fn write_values(
+    file: &mut impl Write,
+    values: &[Value],
+    options: &WriteState,
+) -> Result<(), ConstantsError> {
+    write_length(file, values.len(), options)?;
+
+    for value in values {
+        value.write(file, options)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_values(
+    file: &mut impl Read,
+    layout: Layout,
+    pool: &mut ObjectPool,
+) -> Result<Vec<Value>, ConstantsError> {
+    let count = read_usize(file, layout)?;
+    // Not preallocated, since the count can't be trusted until the values behind it are read
+    let mut values = Vec::new();
+
+    for _ in 0..count {
+        let byte_id = read_u8(file)?;
+        values.push(Value::read(file, byte_id, layout, pool)?);
+    }
+
+    Ok(values)
+}
+
+pub(crate) fn write_string(
+    file: &mut impl Write,
+    str: &String,
+    options: &WriteState,
+) -> Result<(), ConstantsError> {
+    match options.strings {
+        Some(table) => write_length(file, table.borrow_mut().index_of(str), options),
+        None => write_bytes(file, str.as_bytes(), options),
+    }
+}
+
+pub(crate) fn write_string_encoded(
+    file: &mut impl Write,
+    str: &str,
+    encoding: StringEncoding,
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    match encoding {
+        StringEncoding::Utf8 => write_bytes(file, str.as_bytes(), options)?,
+        StringEncoding::Utf16Be => {
+            let bytes = str
+                .encode_utf16()
+                .flat_map(|unit| unit.to_be_bytes())
+                .collect::<Vec<_>>();
+            write_bytes(file, &bytes, options)?;
+        }
+        StringEncoding::Latin1 => {
+            let bytes = str
+                .chars()
+                .map(|c| {
+                    u8::try_from(c).or_else(|_| {
+                        unwritable(format!(
+                            "String {str:?} cannot be encoded as Latin-1 ({c:?})"
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            write_bytes(file, &bytes, options)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn write_number<T: EndianBytes>(
+    file: &mut impl Write,
+    value: T,
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    file.write_all(value.to_endian_bytes(options.endianness).as_ref())?;
+    Ok(())
+}
+
+/// Writes a length or count as a u32, the width every platform can read back.
+pub(crate) fn write_length(
+    file: &mut impl Write,
+    len: usize,
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    let len = u32::try_from(len)
+        .or_else(|_| unwritable(format!("Length {len} exceeds the u32 limit")))?;
+    if options.varint {
+        write_varint(file, len as u64)?;
+    } else {
+        write_number(file, len, options)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_int(
+    file: &mut impl Write,
+    value: i32,
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    if options.varint {
+        write_varint(file, zigzag(value as i64))?;
+    } else {
+        write_number(file, value, options)?;
+    }
+    Ok(())
+}
+
+/// Writes `value` as unsigned LEB128: seven bits per byte, low bits first, with the top
+/// bit set on every byte but the last.
+fn write_varint(file: &mut impl Write, mut value: u64) -> Result<(), ConstantsError> {
+    let mut bytes = Vec::with_capacity(MAX_VARINT_LEN);
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            break;
+        }
+        bytes.push(byte | 0x80);
+    }
+    file.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Maps signed integers onto unsigned ones so small magnitudes of either sign stay short.
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+pub(crate) fn write_bytes(
+    file: &mut impl Write,
+    bytes: &[u8],
+    options: &WriteOptions,
+) -> Result<(), ConstantsError> {
+    write_length(file, bytes.len(), options)?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+/// IEEE CRC32 lookup table for the reflected polynomial, built at compile time.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    crc32_extend(0, bytes)
+}
+
+/// The CRC32 of some bytes followed by `bytes`, given `crc`, the CRC32 of the former.
+fn crc32_extend(crc: u32, bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!crc, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+pub(crate) fn read_bytes(file: &mut impl Read, layout: Layout) -> Result<Vec<u8>, ConstantsError> {
+    read_bytes_within(file, layout, layout.limits.max_string_len, "String length")
+}
+
+/// Reads a length and that many bytes, refusing a length over `limit`.
+pub(crate) fn read_bytes_within(
+    file: &mut impl Read,
+    layout: Layout,
+    limit: usize,
+    what: &'static str,
+) -> Result<Vec<u8>, ConstantsError> {
+    let size = read_usize(file, layout)?;
+    LoadLimits::check(limit, size as u64, what)?;
+    // Read in chunks rather than into a buffer of the claimed size, so a length that
+    // overstates the bytes left can't make the allocation outgrow the input
+    let mut buffer = Vec::new();
+    file.by_ref().take(size as u64).read_to_end(&mut buffer)?;
+    if buffer.len() != size {
+        return Err(ConstantsError::UnexpectedEof);
+    }
+
+    Ok(buffer)
+}
+
+pub(crate) fn read_string(file: &mut impl Read, layout: Layout) -> Result<String, ConstantsError> {
+    if let Some(strings) = layout.strings {
+        let Object::String(str) = &*read_table_string(file, strings, layout)? else {
+            unreachable!("The string table only holds strings")
+        };
+        return Ok(str.clone());
+    }
+
+    let buffer = read_bytes(file, layout)?;
+    decode_utf8(buffer, layout)
+}
+
+/// Makes a string of `bytes` as `layout.utf8` says to. Only string constants can fall
+/// back to bytes, so `Utf8Policy::Bytes` is as strict as `Utf8Policy::Strict` here.
+pub(crate) fn decode_utf8(bytes: Vec<u8>, layout: Layout) -> Result<String, ConstantsError> {
+    match String::from_utf8(bytes) {
+        Ok(str) => Ok(str),
+        Err(error) if layout.utf8 == Utf8Policy::Lossy => {
+            count_invalid_utf8(layout);
+            Ok(String::from_utf8_lossy(error.as_bytes()).into_owned())
+        }
+        Err(error) => Err(ConstantsError::InvalidUtf8 { entry: None, error }),
+    }
+}
+
+pub(crate) fn count_invalid_utf8(layout: Layout) {
+    if let Some(count) = layout.invalid_utf8 {
+        count.set(count.get() + 1);
+    }
+}
+
+pub(crate) fn read_string_encoded(
+    file: &mut impl Read,
+    encoding: StringEncoding,
+    layout: Layout,
+) -> Result<String, ConstantsError> {
+    match encoding {
+        StringEncoding::Utf8 => read_string(file, layout),
+        StringEncoding::Utf16Be => {
+            let buffer = read_bytes(file, layout)?;
+            if !buffer.len().is_multiple_of(2) {
+                return invalid(format!(
+                    "UTF-16 string has an odd byte length {}",
+                    buffer.len()
+                ));
+            }
+
+            let units = buffer
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .or_else(|e| invalid(format!("Invalid UTF-16 string: {e}")))
+        }
+        StringEncoding::Latin1 => Ok(read_bytes(file, layout)?
+            .into_iter()
+            .map(char::from)
+            .collect()),
+    }
+}
+
+pub(crate) fn read_u8(file: &mut impl Read) -> Result<u8, ConstantsError> {
+    let mut buffer = [0u8; 1];
+    file.read_exact(&mut buffer)?;
+
+    Ok(u8::from_be_bytes(buffer))
+}
+
+fn read_number<T: EndianBytes>(file: &mut impl Read, layout: Layout) -> Result<T, ConstantsError> {
+    let mut buffer = T::Bytes::default();
+    file.read_exact(buffer.as_mut())?;
+
+    Ok(T::from_endian_bytes(buffer, layout.endianness))
+}
+
+pub(crate) fn read_u16(file: &mut impl Read, layout: Layout) -> Result<u16, ConstantsError> {
+    read_number(file, layout)
+}
+
+pub(crate) fn read_i16(file: &mut impl Read, layout: Layout) -> Result<i16, ConstantsError> {
+    read_number(file, layout)
+}
+
+fn read_i32(file: &mut impl Read, layout: Layout) -> Result<i32, ConstantsError> {
+    read_number(file, layout)
+}
+
+pub(crate) fn read_u32(file: &mut impl Read, layout: Layout) -> Result<u32, ConstantsError> {
+    read_number(file, layout)
+}
+
+pub(crate) fn read_i64(file: &mut impl Read, layout: Layout) -> Result<i64, ConstantsError> {
+    read_number(file, layout)
+}
+
+pub(crate) fn read_u64(file: &mut impl Read, layout: Layout) -> Result<u64, ConstantsError> {
+    read_number(file, layout)
+}
+
+/// Reads a length or count, which is a u32 from `FormatVersion::V4` on and a u64 before.
+pub(crate) fn read_usize(file: &mut impl Read, layout: Layout) -> Result<usize, ConstantsError> {
+    if layout.varint {
+        let len = read_varint(file)?;
+        match u32::try_from(len) {
+            Ok(len) => Ok(len as usize),
+            Err(_) => invalid(format!("Length {len} exceeds the u32 limit")),
+        }
+    } else if layout.version >= FormatVersion::V4 {
+        Ok(read_number::<u32>(file, layout)? as usize)
+    } else {
+        let len = read_number::<u64>(file, layout)?;
+        usize::try_from(len)
+            .or_else(|_| invalid(format!("Length {len} is too large for this platform")))
+    }
+}
+
+pub(crate) fn read_int(file: &mut impl Read, layout: Layout) -> Result<i32, ConstantsError> {
+    if layout.varint {
+        let value = unzigzag(read_varint(file)?);
+        i32::try_from(value).or_else(|_| invalid(format!("Int {value} is out of range")))
+    } else {
+        read_i32(file, layout)
+    }
+}
+
+/// The most bytes a u64 can take as LEB128.
+const MAX_VARINT_LEN: usize = 10;
+
+/// Reads an unsigned LEB128 number written by `write_varint`, rejecting encodings that
+/// run past `MAX_VARINT_LEN` bytes or overflow a u64.
+fn read_varint(file: &mut impl Read) -> Result<u64, ConstantsError> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let byte = read_u8(file)?;
+        let bits = (byte & 0x7f) as u64;
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return invalid("Varint overflows a u64");
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    invalid(format!("Varint is longer than {MAX_VARINT_LEN} bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Xorshift, so every run checks the same assortment.
+    pub(super) struct Rng(pub(super) u64);
+
+    impl Rng {
+        pub(super) fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub(super) fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("constants-io-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn random_string(rng: &mut Rng) -> String {
+        let len = rng.below(12);
+        (0..len)
+            .map(|_| char::from_u32(rng.below(0x800) as u32).unwrap_or('?'))
+            .collect()
+    }
+
+    /// A value of any kind, containers only while `depth` lasts.
+    pub(super) fn random_value(rng: &mut Rng, pool: &mut ObjectPool, depth: usize) -> Value {
+        let kinds = if depth == 0 { 16 } else { 19 };
+        match rng.below(kinds) {
+            0 => Value::Int(rng.next() as i32),
+            1 => Value::Bool(rng.below(2) == 1),
+            2 => Value::Float(f64::from_bits(rng.next())),
+            3 => Value::Long(rng.next() as i64),
+            4 => Value::UInt(rng.next()),
+            5 => Value::Char(char::from_u32(rng.below(0x11_0000) as u32).unwrap_or('?')),
+            6 => Value::Nil,
+            7 => Value::Byte(rng.next() as u8),
+            8 => Value::Short(rng.next() as i16),
+            9 => Value::Decimal {
+                mantissa: rng.next() as i64,
+                scale: rng.below(19) as u8,
+            },
+            10 => Value::Timestamp {
+                seconds: rng.next() as i64,
+                nanos: rng.below(1_000_000_000) as u32,
+            },
+            11 => Value::Uuid(std::array::from_fn(|_| rng.next() as u8)),
+            12 => Value::Complex {
+                re: f64::from_bits(rng.next()),
+                im: f64::from_bits(rng.next()),
+            },
+            13 => Value::Color {
+                r: rng.next() as u8,
+                g: rng.next() as u8,
+                b: rng.next() as u8,
+                a: rng.next() as u8,
+            },
+            14 => {
+                let string = Rc::new(Object::String(random_string(rng)));
+                pool.push(Rc::clone(&string));
+                Value::Object(string)
+            }
+            15 => {
+                let bytes = (0..rng.below(8))
+                    .map(|_| rng.next() as u8)
+                    .collect::<Vec<_>>();
+                Value::from_bytes(&bytes, pool)
+            }
+            16 => Value::Optional(
+                Some(Box::new(random_value(rng, pool, depth - 1))).filter(|_| rng.below(4) > 0),
+            ),
+            17 => {
+                let items = (0..rng.below(5))
+                    .map(|_| random_value(rng, pool, depth - 1))
+                    .collect();
+                Value::from_list(items, pool)
+            }
+            _ => {
+                let entries = (0..rng.below(4))
+                    .map(|i| {
+                        let key = Value::Int(i as i32);
+                        (key, random_value(rng, pool, depth - 1))
+                    })
+                    .collect();
+                Value::from_map(entries, pool)
+            }
+        }
+    }
+
+    #[test]
+    fn random_pools_round_trip_through_a_vec() {
+        let key = [7; 32];
+        let metadata = Metadata::generator();
+        let options = [
+            WriteOptions::default(),
+            WriteOptions {
+                varint: true,
+                string_table: true,
+                ..WriteOptions::default()
+            },
+            WriteOptions {
+                endianness: Endianness::Little,
+                index: true,
+                metadata: Some(&metadata),
+                ..WriteOptions::default()
+            },
+            WriteOptions {
+                deterministic: true,
+                sign_key: Some(&key),
+                ..WriteOptions::default()
+            },
+            WriteOptions {
+                compression: Some(6),
+                key: Some(&key),
+                ..WriteOptions::default()
+            },
+        ];
+        let load_options = LoadOptions {
+            key: Some(&key),
+            ..LoadOptions::default()
+        };
+
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..64 {
+            let mut pool = ObjectPool::new();
+            let values = (0..rng.below(20))
+                .map(|_| random_value(&mut rng, &mut pool, 3))
+                .collect::<Vec<_>>();
+            for options in &options {
+                let bytes = serialize_to_vec(&values, options).unwrap();
+                let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &load_options)
+                    .unwrap_or_else(|e| panic!("{e} with {options:?}"));
+                assert_eq!(read, values, "with {options:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn encrypted_files_need_their_key() {
+        let options = WriteOptions {
+            key: Some(b"secret"),
+            ..WriteOptions::default()
+        };
+        let bytes = serialize_to_vec(&[Value::Int(1)], &options).unwrap();
+        let load = |key| {
+            let options = LoadOptions {
+                key,
+                ..LoadOptions::default()
+            };
+            deserialize_from_slice(&bytes, &mut ObjectPool::new(), &options)
+        };
+        let e = load(None).unwrap_err();
+        assert!(matches!(e, ConstantsError::MissingKey));
+        assert_eq!(e.to_string(), "File is encrypted and no key was supplied");
+        assert_eq!(load(Some(b"secret")).unwrap(), [Value::Int(1)]);
+    }
+
+    #[test]
+    fn serialize_to_vec_is_as_long_as_predicted() {
+        let metadata = Metadata::generator();
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..64 {
+            let mut pool = ObjectPool::new();
+            let values = (0..rng.below(300))
+                .map(|_| random_value(&mut rng, &mut pool, 2))
+                .collect::<Vec<_>>();
+            let options = WriteOptions {
+                varint: rng.below(2) == 1,
+                string_table: rng.below(2) == 1,
+                index: rng.below(2) == 1,
+                metadata: Some(&metadata).filter(|_| rng.below(2) == 1),
+                sign_key: Some(&[1, 2, 3][..]).filter(|_| rng.below(2) == 1),
+                ..WriteOptions::default()
+            };
+            let (body, _) = write_segment(&values, &options).unwrap();
+            let predicted = encoded_len(body.len(), values.len(), &options).unwrap();
+            assert_eq!(
+                serialize_to_vec(&values, &options).unwrap().len(),
+                predicted
+            );
+        }
+    }
+
+    #[test]
+    fn headers_are_checked_and_legacy_files_need_asking_for() {
+        let load = |bytes: &[u8], legacy| {
+            let options = LoadOptions {
+                legacy,
+                ..LoadOptions::default()
+            };
+            deserialize_from_slice(bytes, &mut ObjectPool::new(), &options)
+        };
+        let bytes = serialize_to_vec(&[Value::Int(7)], &WriteOptions::default()).unwrap();
+        assert_eq!(bytes[..6], *b"CNST\0\x08");
+        assert_eq!(load(&bytes, false).unwrap(), [Value::Int(7)]);
+
+        let mut wrong_file = bytes.clone();
+        wrong_file[..4].copy_from_slice(b"PK\x03\x04");
+        let e = load(&wrong_file, false).unwrap_err();
+        assert!(e.to_string().starts_with("Not a constants file"), "{e}");
+
+        let mut from_the_future = bytes;
+        from_the_future[5] = FormatVersion::LATEST as u8 + 1;
+        assert_eq!(
+            load(&from_the_future, false).unwrap_err().to_string(),
+            format!(
+                "Format version {} is newer than the supported version {}",
+                FormatVersion::LATEST as u16 + 1,
+                FormatVersion::LATEST as u16
+            )
+        );
+
+        // A count and an int, as written before the header
+        let legacy = [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 7];
+        assert_eq!(load(&legacy, true).unwrap(), [Value::Int(7)]);
+        assert!(load(&legacy, false).is_err());
+    }
+
+    #[test]
+    fn varints_round_trip_and_shrink_small_pools() {
+        for value in [0, 127, 128, 1 << 31, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value).unwrap();
+            assert_eq!(read_varint(&mut &bytes[..]).unwrap(), value);
+        }
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 128).unwrap();
+        assert_eq!(bytes, [0x80, 0x01]);
+        for value in [0, -1, 1, i64::MIN, i64::MAX, -(1 << 31)] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+        assert!(zigzag(-1) < zigzag(128));
+
+        let overlong = [0x80; MAX_VARINT_LEN + 1];
+        assert!(read_varint(&mut &overlong[..]).is_err());
+        let mut overflowing = [0xff; MAX_VARINT_LEN];
+        overflowing[MAX_VARINT_LEN - 1] = 0x02;
+        assert!(read_varint(&mut &overflowing[..]).is_err());
+
+        // Short strings and tiny ints, as a scripting language's pools mostly are
+        let mut pool = ObjectPool::new();
+        let values = (0..200)
+            .flat_map(|i| {
+                let name = Rc::new(Object::String(format!("name{}", i % 40)));
+                pool.push(Rc::clone(&name));
+                [Value::Int(i - 100), Value::Object(name)]
+            })
+            .collect::<Vec<_>>();
+        let varint = WriteOptions {
+            varint: true,
+            ..WriteOptions::default()
+        };
+        let fixed = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let compact = serialize_to_vec(&values, &varint).unwrap();
+        let read =
+            deserialize_from_slice(&compact, &mut ObjectPool::new(), &LoadOptions::default())
+                .unwrap();
+        assert_eq!(read, values);
+        assert!(
+            compact.len() * 3 < fixed.len() * 2,
+            "{} bytes with varints, {} without",
+            compact.len(),
+            fixed.len()
+        );
+    }
+
+    #[test]
+    fn string_tables_write_repeated_strings_once() {
+        let mut pool = ObjectPool::new();
+        let values = (0..300)
+            .map(|i| {
+                let name = ["self", "init", "initialize_everything"][i % 3];
+                if i % 2 == 0 {
+                    // Repeats made apart, as a compiler emitting each use would make them
+                    let string = Rc::new(Object::String(name.to_string()));
+                    pool.push(Rc::clone(&string));
+                    Value::Object(string)
+                } else {
+                    FunctionBuilder::new(name, 0, &[ByteCode::Return as u8])
+                        .build(&mut pool)
+                        .unwrap()
+                }
+            })
+            .collect::<Vec<_>>();
+        let table = WriteOptions {
+            string_table: true,
+            ..WriteOptions::default()
+        };
+        let plain = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let tabled = serialize_to_vec(&values, &table).unwrap();
+        assert!(
+            tabled.len() * 4 < plain.len() * 3,
+            "{} bytes with a string table, {} without",
+            tabled.len(),
+            plain.len()
+        );
+
+        let mut pool = ObjectPool::new();
+        let read = deserialize_from_slice(&tabled, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, values);
+        let (Value::Object(first), Value::Object(again)) = (&read[0], &read[6]) else {
+            panic!("expected strings");
+        };
+        assert!(Rc::ptr_eq(first, again));
+        let strings = pool
+            .iter()
+            .filter(|o| matches!(***o, Object::String(_)))
+            .count();
+        assert_eq!(strings, 3);
+    }
+
+    #[test]
+    fn values_sharing_an_object_share_it_after_loading() {
+        let mut pool = ObjectPool::new();
+        let shared = Value::from_string("shared", &mut pool);
+        let twin = Value::from_string("shared", &mut pool);
+        let values = [
+            shared.clone(),
+            Value::from_list(vec![shared.clone(), twin], &mut pool),
+            shared,
+        ];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let mut pool = ObjectPool::new();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, values);
+
+        let object = |value: &Value| match value {
+            Value::Object(o) => Rc::clone(o),
+            _ => panic!("expected an object"),
+        };
+        let Object::List(items) = &*object(&read[1]) else {
+            panic!("expected a list");
+        };
+        assert!(Rc::ptr_eq(&object(&read[0]), &object(&read[2])));
+        assert!(Rc::ptr_eq(&object(&read[0]), &object(&items[0])));
+        // Equal objects that were never shared stay apart
+        assert!(!Rc::ptr_eq(&object(&read[0]), &object(&items[1])));
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn deterministic_output_ignores_how_a_pool_was_built() {
+        // The same constants, with one string shared by every use or a copy for each, and
+        // map entries and set items inserted in either order
+        let build = |shared: bool, reversed: bool| {
+            let mut pool = ObjectPool::new();
+            let first = Value::from_string("name", &mut pool);
+            let name = |pool: &mut ObjectPool| {
+                if shared {
+                    first.clone()
+                } else {
+                    Value::from_string("name", pool)
+                }
+            };
+            let mut entries = vec![
+                (name(&mut pool), Value::Int(1)),
+                (Value::Int(2), Value::Nil),
+            ];
+            let mut items = vec![Value::Int(3), name(&mut pool), Value::Bool(false)];
+            if reversed {
+                entries.reverse();
+                items.reverse();
+            }
+            vec![
+                first.clone(),
+                Value::from_map(entries, &mut pool),
+                Value::from_set(items, &mut pool),
+                name(&mut pool),
+            ]
+        };
+        let deterministic = WriteOptions {
+            deterministic: true,
+            ..WriteOptions::default()
+        };
+        let save =
+            |values: &[Value], options: &WriteOptions| serialize_to_vec(values, options).unwrap();
+
+        let canonical = save(&build(true, false), &deterministic);
+        assert_eq!(save(&build(false, true), &deterministic), canonical);
+        assert_eq!(save(&build(false, false), &deterministic), canonical);
+        assert_ne!(
+            save(&build(false, true), &WriteOptions::default()),
+            save(&build(true, false), &WriteOptions::default())
+        );
+
+        let key = [1; 32];
+        let metadata = Metadata::generator();
+        let refused = [
+            WriteOptions {
+                key: Some(&key),
+                ..deterministic
+            },
+            WriteOptions {
+                metadata: Some(&metadata),
+                ..deterministic
+            },
+        ];
+        for options in &refused {
+            let e = serialize_to_vec(&build(true, false), options).unwrap_err();
+            assert!(matches!(e, ConstantsError::Unwritable(_)), "{e}");
+        }
+        let undated = Metadata {
+            created: None,
+            ..Metadata::generator()
+        };
+        let options = WriteOptions {
+            metadata: Some(&undated),
+            ..deterministic
+        };
+        assert_eq!(
+            save(&build(true, false), &options),
+            save(&build(false, true), &options)
+        );
+    }
+
+    #[test]
+    fn appended_batches_load_as_one_file() {
+        let path = temp_path("append");
+        let mut pool = ObjectPool::new();
+        let batches = [
+            vec![Value::Int(1), Value::from_string("one", &mut pool)],
+            vec![Value::Ref(1), Value::Nil],
+            vec![Value::from_list(vec![Value::Int(3)], &mut pool)],
+        ];
+        for batch in &batches {
+            append_values_to_disk(&path, batch).unwrap();
+        }
+        let read = load_locked(&path, &mut ObjectPool::new(), &LoadOptions::default(), None);
+        let mut expected = batches.concat();
+        expected[2] = expected[1].clone();
+        assert_eq!(read.unwrap(), expected);
+
+        let bytes = fs::read(&path).unwrap();
+        let mut older = bytes.clone();
+        older[5] = FormatVersion::V7 as u8;
+        fs::write(&path, &older).unwrap();
+        let e = append_values_to_disk(&path, &batches[0]).unwrap_err();
+        assert!(matches!(e, AppendError::Version(7)), "{e}");
+
+        for len in [bytes.len() - 3, 20, 7] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            let e = append_values_to_disk(&path, &batches[0]).unwrap_err();
+            assert!(
+                matches!(
+                    e,
+                    AppendError::Truncated
+                        | AppendError::Size { .. }
+                        | AppendError::MissingEndMarker
+                ),
+                "{len} bytes: {e}"
+            );
+            assert_eq!(fs::read(&path).unwrap(), bytes[..len], "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn random_access_reads_match_a_full_load() {
+        let mut rng = Rng(0xbb67_ae85_84ca_a73b);
+        let mut pool = ObjectPool::new();
+        let values = (0..300)
+            .map(|_| random_value(&mut rng, &mut pool, 2))
+            .collect::<Vec<_>>();
+        let encoded = |value: &Value| {
+            serialize_to_vec(std::slice::from_ref(value), &WriteOptions::default()).unwrap()
+        };
+
+        for index in [true, false] {
+            let options = WriteOptions {
+                index,
+                string_table: true,
+                ..WriteOptions::default()
+            };
+            let bytes = serialize_to_vec(&values, &options).unwrap();
+            let full =
+                deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+                    .unwrap();
+            let mut file = io::Cursor::new(&bytes);
+            for at in [0, 1, 150, 299] {
+                let value = load_constant_at(
+                    &mut file,
+                    at,
+                    &mut ObjectPool::new(),
+                    &LoadOptions::default(),
+                )
+                .unwrap();
+                assert_eq!(encoded(&value), encoded(&full[at]), "constant {at}");
+                file.rewind().unwrap();
+            }
+            let past_the_end = load_constant_at(
+                &mut file,
+                300,
+                &mut ObjectPool::new(),
+                &LoadOptions::default(),
+            );
+            assert!(past_the_end.is_err());
+        }
+    }
+
+    #[test]
+    fn crafted_lengths_fail_before_allocating() {
+        let legacy = LoadOptions {
+            legacy: true,
+            ..LoadOptions::default()
+        };
+        // Ten bytes declaring 2^60 constants
+        let count = [0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let e = deserialize_from_slice(&count, &mut ObjectPool::new(), &legacy).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Constant count of {} exceeds the limit of {}",
+                1u64 << 60,
+                LoadLimits::DEFAULT.max_constants
+            )
+        );
+
+        let mut pool = ObjectPool::new();
+        let values = [Value::from_string("hi", &mut pool)];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let at = bytes
+            .windows(7)
+            .position(|window| window == [2, 0, 0, 0, 2, b'h', b'i'])
+            .unwrap();
+        let mut huge = bytes.clone();
+        huge[at + 1..at + 5].copy_from_slice(&[0xff, 0xff, 0xff, 0]);
+        let unchecked = LoadOptions {
+            skip_checksum: true,
+            ..LoadOptions::default()
+        };
+        let e = deserialize_from_slice(&huge, &mut ObjectPool::new(), &unchecked).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                ConstantsError::LimitExceeded {
+                    what: "String length",
+                    declared: 0xffff_ff00,
+                    ..
+                }
+            ),
+            "{e}"
+        );
+        let tight = LoadOptions {
+            limits: LoadLimits {
+                max_string_len: 1,
+                ..LoadLimits::default()
+            },
+            ..LoadOptions::default()
+        };
+        assert!(deserialize_from_slice(&bytes, &mut ObjectPool::new(), &tight).is_err());
+
+        // Every field of a file turned huge, in turn, must fail or load, and quickly
+        let mut rng = Rng(0x3c6e_f372_fe94_f82b);
+        let values = (0..40)
+            .map(|_| random_value(&mut rng, &mut pool, 2))
+            .collect::<Vec<_>>();
+        let options = WriteOptions {
+            index: true,
+            string_table: true,
+            ..WriteOptions::default()
+        };
+        let bytes = serialize_to_vec(&values, &options).unwrap();
+        for at in 0..bytes.len() - 4 {
+            for field in [[0xff; 4], [0x7f, 0xff, 0xff, 0xff], [0, 0xff, 0xff, 0xff]] {
+                let mut crafted = bytes.clone();
+                crafted[at..at + 4].copy_from_slice(&field);
+                let _ = deserialize_from_slice(&crafted, &mut ObjectPool::new(), &unchecked);
+            }
+        }
+    }
+
+    /// Passes `left` bytes through to `inner`, then fails every write, as a full disk would.
+    struct FailingWriter<W> {
+        inner: W,
+        left: usize,
+    }
+
+    impl<W: Write> Write for FailingWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.left == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            let len = buf.len().min(self.left);
+            self.left -= len;
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn saves_failing_part_way_leave_the_old_file() {
+        let path = temp_path("atomic");
+        let old = [Value::Int(1)];
+        save_atomic(&path, &old, &WriteOptions::default()).unwrap();
+        let saved = fs::read(&path).unwrap();
+
+        let new = (0..100).map(Value::Int).collect::<Vec<_>>();
+        let mut file = AtomicFile::create(&path).unwrap();
+        let mut failing = FailingWriter {
+            inner: &mut file,
+            left: 40,
+        };
+        let e = write_values_to_disk(&mut failing, &new, &WriteOptions::default()).unwrap_err();
+        assert!(e.to_string().contains("disk full"), "{e}");
+        drop(file);
+
+        assert_eq!(fs::read(&path).unwrap(), saved);
+        let dir = PathBuf::from(&path);
+        let dir = dir.parent().unwrap();
+        let leftovers = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(".atomic"))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        save_atomic(&path, &new, &WriteOptions::default()).unwrap();
+        let read = load_locked(&path, &mut ObjectPool::new(), &LoadOptions::default(), None);
+        assert_eq!(read.unwrap(), new);
+    }
+
+    #[test]
+    fn every_format_round_trips_through_detection() {
+        let mut pool = ObjectPool::new();
+        let values = vec![
+            Value::Int(-3),
+            Value::from_string("two words", &mut pool),
+            Value::from_list(vec![Value::Float(0.25), Value::Nil], &mut pool),
+        ];
+        let native = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let inputs = vec![
+            (InputFormat::Native, native.clone()),
+            (InputFormat::Native, armor(&native).into_bytes()),
+            (
+                InputFormat::Json,
+                format!("\n  {}", to_json(&values)).into_bytes(),
+            ),
+            (InputFormat::Text, to_text(&values).into_bytes()),
+        ];
+        #[cfg(feature = "cbor")]
+        let inputs = {
+            let cbor = cbor::to_cbor(&values);
+            let mut inputs = inputs;
+            inputs.push((InputFormat::Cbor, armor(&cbor).into_bytes()));
+            inputs.push((InputFormat::Cbor, cbor));
+            inputs
+        };
+
+        for (expected, bytes) in inputs {
+            let mut read = Vec::new();
+            let mut file = io::Cursor::new(&bytes);
+            let format = load_detected(
+                &mut file,
+                &mut read,
+                &mut ObjectPool::new(),
+                &LoadOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(format, expected);
+            assert_eq!(read, values, "from {expected}");
+        }
+
+        let mut file = io::Cursor::new(b"\x00\x01 not constants");
+        let e = detect_format(&mut file).unwrap_err().to_string();
+        assert!(e.contains("considered native, armored"), "{e}");
+        assert_eq!(file.position(), 0);
+        assert!(detect_format(&mut io::Cursor::new(b" \n\t")).is_err());
+    }
+
+    #[test]
+    fn unknown_type_ids_fail_unless_skipped_through_the_index() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::Int(1),
+            Value::Int(0x0bad_f00d),
+            Value::from_string("after", &mut pool),
+        ];
+        // The middle constant as a newer version might write one, behind an id this one lacks
+        let from_a_newer_build = |options: &WriteOptions| {
+            let mut bytes = serialize_to_vec(&values, options).unwrap();
+            let at = bytes
+                .windows(5)
+                .position(|w| w == [0, 0x0b, 0xad, 0xf0, 0x0d])
+                .unwrap();
+            bytes[at] = 0xee;
+            bytes
+        };
+        let load = |bytes: &[u8], options: &LoadOptions| {
+            let options = LoadOptions {
+                skip_checksum: true,
+                ..*options
+            };
+            deserialize_from_slice(bytes, &mut ObjectPool::new(), &options)
+        };
+        let indexed = from_a_newer_build(&WriteOptions {
+            index: true,
+            ..WriteOptions::default()
+        });
+
+        let e = load(&indexed, &LoadOptions::default()).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                ConstantsError::UnknownTypeId {
+                    id: 0xee,
+                    offset: Some(_)
+                }
+            ),
+            "{e}"
+        );
+
+        let warnings = Warnings::default();
+        let skipping = LoadOptions {
+            skip_unknown: true,
+            warnings: Some(&warnings),
+            ..LoadOptions::default()
+        };
+        let read = load(&indexed, &skipping).unwrap();
+        assert_eq!(read, [values[0].clone(), Value::Nil, values[2].clone()]);
+        assert_eq!(
+            warnings
+                .borrow()
+                .iter()
+                .map(|w| w.to_string())
+                .collect::<Vec<_>>(),
+            ["1 constants with unknown type ids were loaded as nil"]
+        );
+
+        // Without an index there's no telling where the unknown constant ends
+        let unindexed = from_a_newer_build(&WriteOptions::default());
+        let e = load(&unindexed, &skipping).unwrap_err();
+        assert!(
+            matches!(e, ConstantsError::UnknownTypeId { id: 0xee, .. }),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_loads_as_its_policy_says() {
+        let mut pool = ObjectPool::new();
+        let values = [Value::Int(1), Value::from_string("café!", &mut pool)];
+        let mut bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        // 'é' with its continuation byte made a space, which can't continue anything
+        let at = bytes.windows(2).position(|w| w == [0xc3, 0xa9]).unwrap();
+        bytes[at + 1] = b' ';
+        let load = |utf8| {
+            let warnings = Warnings::default();
+            let options = LoadOptions {
+                skip_checksum: true,
+                utf8,
+                warnings: Some(&warnings),
+                ..LoadOptions::default()
+            };
+            let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &options);
+            let warnings = warnings
+                .take()
+                .iter()
+                .map(|w| w.to_string())
+                .collect::<Vec<_>>();
+            (read, warnings)
+        };
+
+        let (read, _) = load(Utf8Policy::Strict);
+        let e = read.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid UTF-8 string in object pool entry 0, at byte 3 of the string: \
+             invalid utf-8 sequence of 1 bytes from index 3"
+        );
+
+        let (read, warnings) = load(Utf8Policy::Lossy);
+        assert_eq!(
+            read.unwrap()[1],
+            Value::from_string("caf\u{fffd} !", &mut pool)
+        );
+        assert_eq!(
+            warnings,
+            ["1 strings with invalid UTF-8 were loaded with replacement characters"]
+        );
+
+        let (read, warnings) = load(Utf8Policy::Bytes);
+        assert_eq!(read.unwrap()[1], Value::from_bytes(b"caf\xc3 !", &mut pool));
+        assert_eq!(
+            warnings,
+            ["1 strings with invalid UTF-8 were loaded as bytes"]
+        );
+    }
+
+    #[test]
+    fn nesting_fails_just_past_the_depth_limit() {
+        /// A list `depth` levels deep, counting the int at the bottom.
+        fn nested(depth: usize, pool: &mut ObjectPool) -> Value {
+            (1..depth).fold(Value::Int(0), |inner, _| {
+                Value::from_list(vec![inner], pool)
+            })
+        }
+        let limit = LoadLimits::DEFAULT.max_depth;
+        let mut pool = ObjectPool::new();
+        let deepest = [Value::Nil, nested(limit, &mut pool)];
+        let bytes = serialize_to_vec(&deepest, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, deepest);
+        assert_eq!(
+            read[1].to_string(),
+            format!("{}0{}", "[".repeat(limit - 1), "]".repeat(limit - 1))
+        );
+
+        let too_deep = [Value::Nil, nested(limit + 1, &mut pool)];
+        let e = serialize_to_vec(&too_deep, &WriteOptions::default()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("The value in constant 1 nests deeper than the limit of {limit} levels")
+        );
+
+        // A reader with a lower limit meets the same file as one nested past it
+        let options = LoadOptions {
+            limits: LoadLimits {
+                max_depth: limit - 1,
+                ..LoadLimits::default()
+            },
+            ..LoadOptions::default()
+        };
+        let e = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &options).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                ConstantsError::MaxDepthExceeded { entry: Some(_), limit: l } if l == limit - 1
+            ),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn json_nesting_fails_past_the_depth_limit_instead_of_overflowing() {
+        fn nested(depth: usize) -> Value {
+            (1..depth).fold(Value::Int(0), |inner, _| {
+                Value::Optional(Some(Box::new(inner)))
+            })
+        }
+        let limit = LoadLimits::DEFAULT.max_depth;
+        let deepest = [nested(limit)];
+        let read = from_json(&to_json(&deepest), &mut ObjectPool::new()).unwrap();
+        assert_eq!(read, deepest);
+
+        let too_deep = [nested(limit + 1)];
+        let e = from_json(&to_json(&too_deep), &mut ObjectPool::new()).unwrap_err();
+        assert!(
+            matches!(&e, JsonError::Schema { message, .. } if message == "nests deeper than the depth limit"),
+            "{e}"
+        );
+
+        // Brackets alone are refused by the parser, before any value is built
+        let e = from_json(&"[".repeat(100_000), &mut ObjectPool::new()).unwrap_err();
+        assert!(
+            matches!(&e, JsonError::Syntax { message, .. } if message.contains("nest deeper than")),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn text_nesting_fails_past_the_depth_limit_and_pair_chains_read_flat() {
+        let limit = LoadLimits::DEFAULT.max_depth;
+        let nested = |depth: usize| {
+            (0..depth)
+                .map(|level| format!("{}optional\n", "  ".repeat(level)))
+                .chain([format!("{}int 0\n", "  ".repeat(depth))])
+                .collect::<String>()
+        };
+        let deepest = (1..limit).fold(Value::Int(0), |inner, _| {
+            Value::Optional(Some(Box::new(inner)))
+        });
+        let read = from_text(&nested(limit - 1), &mut ObjectPool::new()).unwrap();
+        assert_eq!(read, [deepest]);
+        let e = from_text(&nested(limit), &mut ObjectPool::new()).unwrap_err();
+        assert_eq!(
+            (e.line, e.column, &e.message[..]),
+            (
+                limit + 1,
+                2 * limit + 1,
+                "nests deeper than the depth limit"
+            )
+        );
+
+        // A chain's cars and tail sit side by side under one line, however long it is
+        let cells = 10_000;
+        let text = (0..cells)
+            .map(|i| format!("  int {i}\n"))
+            .chain(["  nil\n".to_string()])
+            .collect::<String>();
+        let mut pool = ObjectPool::new();
+        let read = from_text(&format!("pair\n{text}"), &mut pool).unwrap();
+        let mut pool = ObjectPool::new();
+        let chain = (0..cells).rev().fold(Value::Nil, |cdr, i| {
+            Value::from_pair(Value::Int(i), cdr, &mut pool)
+        });
+        assert_eq!(read, [chain]);
+
+        // The two values of a lone cell still nest a pair as its cdr
+        let text = "pair\n  int 1\n  pair\n    int 2\n    nil\n";
+        let mut pool = ObjectPool::new();
+        let two = Value::from_pair(Value::Int(2), Value::Nil, &mut pool);
+        let one = Value::from_pair(Value::Int(1), two, &mut pool);
+        assert_eq!(from_text(text, &mut ObjectPool::new()).unwrap(), [one]);
+        let e = from_text("pair\n  int 1\n", &mut ObjectPool::new()).unwrap_err();
+        assert_eq!(
+            e.message,
+            "expected at least 2 values under this line, found 1"
+        );
+    }
+
+    #[test]
+    fn pair_chains_dump_as_text_without_nesting_per_cell() {
+        let chain = |cells: i32, pool: &mut ObjectPool| {
+            (0..cells).rev().fold(Value::Nil, |cdr, i| {
+                Value::from_pair(Value::Int(i), cdr, pool)
+            })
+        };
+        let mut pool = ObjectPool::new();
+        let short = [chain(3, &mut pool)];
+        assert_eq!(to_text(&short), "pair\n  int 0\n  int 1\n  int 2\n  nil\n");
+
+        let long = [chain(200_000, &mut pool)];
+        let text = to_text(&long);
+        assert_eq!(text.lines().count(), 200_002);
+        assert_eq!(from_text(&text, &mut ObjectPool::new()).unwrap(), long);
+    }
+
+    #[test]
+    fn pair_chains_render_as_json_without_recursing_per_cell() {
+        let chain = |cells: i32, pool: &mut ObjectPool| {
+            (0..cells).rev().fold(Value::Nil, |cdr, i| {
+                Value::from_pair(Value::Int(i), cdr, pool)
+            })
+        };
+        let mut pool = ObjectPool::new();
+        assert_eq!(
+            to_json(&[chain(2, &mut pool)]),
+            concat!(
+                "[\n  ",
+                r#"{"type": "pair", "car": {"type": "int", "value": 0}, "cdr": "#,
+                r#"{"type": "pair", "car": {"type": "int", "value": 1}, "cdr": "#,
+                r#"{"type": "nil"}}}"#,
+                "\n]"
+            )
+        );
+
+        // Longer than the depth limit, since a chain counts as one level, but within the
+        // parser's bound on nested objects
+        let values = [chain(300, &mut pool)];
+        assert_eq!(
+            from_json(&to_json(&values), &mut ObjectPool::new()).unwrap(),
+            values
+        );
+
+        let long = to_json(&[chain(200_000, &mut pool)]);
+        assert_eq!(long.matches(r#""type": "pair""#).count(), 200_000);
+    }
+
+    #[test]
+    fn functions_from_before_type_tags_load_untyped() {
+        let code = [ByteCode::Return as u8];
+        let mut pool = ObjectPool::new();
+        let Value::Object(function) = FunctionBuilder::new("f", 1, &code)
+            .local_count(2)
+            .build(&mut pool)
+            .unwrap()
+        else {
+            unreachable!("the builder makes objects");
+        };
+        let options = WriteOptions::default();
+        let mut bytes = Vec::new();
+        function
+            .write(&mut bytes, &WriteState::new(&options))
+            .unwrap();
+
+        // The revision with defaults but no tags: no flags after the parameter count, and
+        // nothing after the defaults
+        let flags_at = 4 + "f".len() + 1;
+        bytes.remove(flags_at);
+        bytes.truncate(bytes.len() - 2);
+        let layout = Layout {
+            version: FormatVersion::V8,
+            ..Layout::LEGACY
+        };
+        let mut cursor = io::Cursor::new(&bytes);
+        let read = Object::read(&mut cursor, FUNCTION_TYPE_IDS[5], layout, &mut pool).unwrap();
+        assert_eq!(cursor.position(), bytes.len() as u64);
+        let Value::Object(read) = read else {
+            panic!("expected an object");
+        };
+        let Object::Function {
+            param_types,
+            return_type,
+            local_count,
+            ..
+        } = &*read
+        else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            (&param_types[..], *return_type, *local_count),
+            (&[][..], TypeTag::Any, 2)
+        );
+        assert_eq!(*read, *function);
+    }
+
+    #[test]
+    fn files_and_single_constants_round_trip_through_a_cursor() {
+        let mut rng = Rng(0x100);
+        let mut pool = ObjectPool::new();
+        let values = (0..50)
+            .map(|_| random_value(&mut rng, &mut pool, 3))
+            .collect::<Vec<_>>();
+        let mut cursor = io::Cursor::new(Vec::new());
+        write_values_to_disk(&mut cursor, &values, &WriteOptions::default()).unwrap();
+        assert_eq!(
+            cursor.get_ref(),
+            &serialize_to_vec(&values, &WriteOptions::default()).unwrap()
+        );
+
+        cursor.set_position(0);
+        let mut read = Vec::new();
+        let mut pool = ObjectPool::new();
+        load_values_from_disk(&mut cursor, &mut read, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, values);
+
+        // The helpers under a constant take any reader or writer too
+        let options = WriteOptions::default();
+        let inline = WriteState::new(&options);
+        let layout = Layout {
+            version: FormatVersion::V8,
+            ..Layout::LEGACY
+        };
+        let mut cursor = io::Cursor::new(Vec::new());
+        write_string(&mut cursor, &"λ".to_string(), &inline).unwrap();
+        write_int(&mut cursor, -3, &options).unwrap();
+        Value::from_string("inline", &mut pool)
+            .write(&mut cursor, &inline)
+            .unwrap();
+        cursor.set_position(0);
+        assert_eq!(read_string(&mut cursor, layout).unwrap(), "λ");
+        assert_eq!(read_int(&mut cursor, layout).unwrap(), -3);
+        let byte_id = read_u8(&mut cursor).unwrap();
+        assert_eq!(
+            Value::read(&mut cursor, byte_id, layout, &mut pool).unwrap(),
+            Value::from_string("inline", &mut pool)
+        );
+        assert!(read_u8(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn sections_resolve_ref_chains_and_refuse_refs_that_dont_point_back() {
+        let mut pool = ObjectPool::new();
+        let string = Value::from_string("s", &mut pool);
+        let values = [
+            Value::Int(1),
+            string.clone(),
+            Value::Ref(1),
+            Value::Ref(2),
+            Value::Bool(true),
+        ];
+        let [strings, functions, rest] = split_sections(&values).unwrap();
+        assert_eq!(
+            strings,
+            (
+                "strings",
+                vec![string.clone(), Value::Ref(0), Value::Ref(0)]
+            )
+        );
+        assert_eq!(functions, ("functions", vec![]));
+        assert_eq!(rest, ("values", vec![Value::Int(1), Value::Bool(true)]));
+
+        // Every section loads, the chain of references included
+        let sections = [&strings, &functions, &rest].map(|(name, values)| (*name, &values[..]));
+        let mut bytes = Vec::new();
+        write_sections(&mut bytes, &sections, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(
+            read,
+            [
+                values[1].clone(),
+                string.clone(),
+                string,
+                values[0].clone(),
+                values[4].clone()
+            ]
+        );
+
+        for target in [3, 9] {
+            let mut refs = values.clone();
+            refs[3] = Value::Ref(target);
+            let e = split_sections(&refs).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("Constant 3 references constant {target}, which is not an earlier entry")
+            );
+        }
+    }
+
+    #[test]
+    fn one_section_loads_by_name_without_reading_the_others() {
+        let mut pool = ObjectPool::new();
+        let first = [Value::Int(0x5eed), Value::from_string("first", &mut pool)];
+        let second = [Value::from_string("second", &mut pool), Value::Ref(0)];
+        let mut bytes = Vec::new();
+        let sections = [("first", &first[..]), ("second", &second[..])];
+        write_sections(&mut bytes, &sections, &WriteOptions::default()).unwrap();
+        let load = |bytes: &[u8], name| {
+            load_section(
+                &mut io::Cursor::new(bytes),
+                name,
+                &mut ObjectPool::new(),
+                &LoadOptions::default(),
+            )
+        };
+
+        // Breaking the first section leaves the second as loadable as it was
+        let mut broken = bytes.clone();
+        let at = bytes
+            .windows(5)
+            .position(|w| w == [0, 0, 0, 0x5e, 0xed])
+            .unwrap();
+        broken[at] = 0xee;
+        let second_read = load(&broken, "second").unwrap();
+        assert_eq!(second_read, [second[0].clone(), second[0].clone()]);
+        assert!(load(&broken, "first").is_err());
+        assert_eq!(load(&bytes, "first").unwrap(), first);
+
+        let e = load(&bytes, "third").unwrap_err();
+        assert!(
+            matches!(&e, ConstantsError::MissingSection(name) if name == "third"),
+            "{e}"
+        );
+        assert_eq!(e.to_string(), "File has no section 'third'");
+        let plain = serialize_to_vec(&first, &WriteOptions::default()).unwrap();
+        assert!(matches!(
+            load(&plain, "first"),
+            Err(ConstantsError::MissingSection(_))
+        ));
+    }
+
+    #[test]
+    fn updates_patch_in_place_or_move_and_load_as_a_rewrite_would() {
+        let path = temp_path("update");
+        let mut pool = ObjectPool::new();
+        let long = Value::Long(1 << 40);
+        let values = [
+            Value::Int(1),
+            long.clone(),
+            Value::Ref(1),
+            Value::Int(2),
+            Value::Int(5),
+        ];
+        let options = WriteOptions::default();
+        fs::write(&path, serialize_to_vec(&values, &options).unwrap()).unwrap();
+        let before = fs::read(&path).unwrap();
+        let encoded = |value: &Value| {
+            let mut bytes = Vec::new();
+            value.write(&mut bytes, &WriteState::new(&options)).unwrap();
+            bytes
+        };
+        let body = open_body(&mut io::Cursor::new(&before), before.len() as u64).unwrap();
+        let (slots, _) = scan_slots(&before).unwrap();
+        let place = |i: usize| {
+            let slot = slots[i];
+            (body.header_len + slot.start) as usize..(body.header_len + slot.end) as usize
+        };
+
+        let short = Value::Int(7);
+        let grown = Value::from_list((0..20).map(Value::Int).collect(), &mut pool);
+        let changes = [(1, short.clone()), (3, grown.clone())];
+        let report = update_values(&path, &changes).unwrap();
+        let after = fs::read(&path).unwrap();
+
+        // The shrunk constant keeps its place behind padding, ending where it used to
+        let (shrunk, new) = (place(1), encoded(&short));
+        let padding = shrunk.len() - new.len();
+        assert!(padding > 0);
+        assert!(after[shrunk.start..shrunk.start + padding]
+            .iter()
+            .all(|&byte| byte == PADDING_TYPE_ID));
+        assert_eq!(after[shrunk.start + padding..shrunk.end], new);
+
+        // The grown list is a forward to its encoding past the old body
+        let moved = place(3);
+        let end = moved.end;
+        assert_eq!(after[end - FORWARD_LEN], FORWARD_TYPE_ID);
+        let target = u32::from_be_bytes(after[end - 4..end].try_into().unwrap()) as u64;
+        assert!(target > body.body_len as u64);
+        let at = (body.header_len + target) as usize;
+        assert_eq!(after[at - 1], DETACHED_TYPE_ID);
+        assert_eq!(after[at..at + encoded(&grown).len()], encoded(&grown));
+
+        // The reference to the shrunk constant moved too, keeping the value it had
+        let patched = [1, 2, 3].map(|i| place(i).len() as u64);
+        let tail = after.len() as u64 - body.footer_start();
+        assert_eq!(
+            report,
+            UpdateReport {
+                updated: 3,
+                moved: 2,
+                rewritten: false,
+                bytes_written: patched.iter().sum::<u64>() + tail + 4,
+            }
+        );
+
+        let expected = [Value::Int(1), short, long, grown, Value::Int(5)];
+        let rewrite = serialize_to_vec(&expected, &options).unwrap();
+        let load = |path: &str| {
+            let loaded = load_locked(path, &mut ObjectPool::new(), &LoadOptions::default(), None);
+            serialize_to_vec(&loaded.unwrap(), &options).unwrap()
+        };
+        assert_eq!(load(&path), rewrite);
+        let report = compact(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), rewrite);
+        assert_eq!(
+            report,
+            UpdateReport {
+                rewritten: true,
+                bytes_written: rewrite.len() as u64,
+                ..UpdateReport::default()
+            }
+        );
+        assert_eq!(load(&path), rewrite);
+    }
+
+    #[test]
+    fn reading_counts_down_and_stops_at_the_first_error() {
+        let mut values = (0..10).map(Value::Int).collect::<Vec<_>>();
+        values[5] = Value::Int(0x5eed);
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let mut pool = ObjectPool::new();
+        let options = LoadOptions::default();
+        let mut reader =
+            ConstantsReader::new(io::Cursor::new(&bytes), &mut pool, &options).unwrap();
+        for (i, expected) in values.iter().enumerate() {
+            assert_eq!(reader.remaining(), values.len() - i);
+            assert_eq!(&reader.next().unwrap().unwrap(), expected);
+        }
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.next().is_none());
+
+        // An unknown type id ends the iteration with its error, after the ones before it
+        let mut broken = bytes.clone();
+        let at = bytes
+            .windows(4)
+            .position(|w| w == [0, 0, 0x5e, 0xed])
+            .unwrap();
+        broken[at - 1] = 0xff;
+        let options = LoadOptions {
+            skip_checksum: true,
+            ..LoadOptions::default()
+        };
+        let mut pool = ObjectPool::new();
+        let reader = ConstantsReader::new(io::Cursor::new(&broken), &mut pool, &options).unwrap();
+        let read = reader.collect::<Vec<_>>();
+        assert_eq!(read.len(), 6);
+        for (read, expected) in read[..5].iter().zip(&values) {
+            assert_eq!(read.as_ref().unwrap(), expected);
+        }
+        let e = read[5].as_ref().unwrap_err();
+        assert!(
+            matches!(e, ConstantsError::UnknownTypeId { id: 0xff, .. }),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn truncated_files_say_how_much_was_recovered_and_trailing_bytes_differ() {
+        let values = (1..=4).map(Value::Int).collect::<Vec<_>>();
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let load = |bytes: &[u8]| {
+            deserialize_from_slice(bytes, &mut ObjectPool::new(), &LoadOptions::default())
+        };
+        let body = open_body(&mut io::Cursor::new(&bytes), bytes.len() as u64).unwrap();
+        let (slots, _) = scan_slots(&bytes).unwrap();
+        let third = (body.header_len + slots[2].start) as usize;
+
+        // Cut cleanly between constants, part way through one, or only in the footer
+        for (len, recovered) in [(third, 2), (third + 2, 2), (bytes.len() - 1, 4)] {
+            let e = load(&bytes[..len]).unwrap_err();
+            assert!(
+                matches!(e, ConstantsError::Truncated { recovered: r } if r == recovered),
+                "{len} bytes: {e}"
+            );
+            assert_eq!(
+                e.to_string(),
+                format!("File is truncated: only {recovered} constants could be recovered")
+            );
+        }
+
+        let mut junk = bytes.clone();
+        junk.extend(b"junk");
+        let e = load(&junk).unwrap_err();
+        assert!(
+            matches!(e, ConstantsError::TrailingData { bytes: 4 }),
+            "{e}"
+        );
+        assert_eq!(e.to_string(), "File has 4 unexpected bytes after its end");
+    }
+
+    #[test]
+    fn metadata_reads_back_without_changing_the_constants() {
+        let mut pool = ObjectPool::new();
+        let values = [Value::from_string("body", &mut pool), Value::Long(-9)];
+        let metadata = Metadata {
+            tool: "compiler".to_string(),
+            version: "2.1".to_string(),
+            created: Some(1_700_000_000),
+            entries: vec![
+                ("commit".to_string(), "7f3a".to_string()),
+                ("target".to_string(), "wasm32".to_string()),
+                ("commit".to_string(), "".to_string()),
+            ],
+        };
+        for (endianness, varint) in [(Endianness::Big, false), (Endianness::Little, true)] {
+            let options = WriteOptions {
+                endianness,
+                varint,
+                ..WriteOptions::default()
+            };
+            let bare = serialize_to_vec(&values, &options).unwrap();
+            let with = WriteOptions {
+                metadata: Some(&metadata),
+                ..options
+            };
+            let bytes = serialize_to_vec(&values, &with).unwrap();
+            let read = read_metadata(&mut io::Cursor::new(&bytes)).unwrap();
+            assert_eq!(read.as_ref(), Some(&metadata));
+            assert_eq!(read_metadata(&mut io::Cursor::new(&bare)).unwrap(), None);
+
+            // Only the header grows: the body and everything after it are the same bytes
+            let grown = bytes.len() - bare.len();
+            assert_eq!(bytes[8 + grown..], bare[8..]);
+            let load = |bytes: &[u8]| {
+                deserialize_from_slice(bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            };
+            assert_eq!(load(&bytes).unwrap(), values);
+        }
+        assert_eq!(
+            metadata.to_string(),
+            "Written by compiler 2.1 at 1700000000 (unix time)\n  commit: 7f3a\n  target: \
+             wasm32\n  commit: "
+        );
+    }
+
+    #[test]
+    fn partial_loads_keep_what_came_before_the_constant_that_failed() {
+        let mut pool = ObjectPool::new();
+        let ok = Value::from_string("ok", &mut pool);
+        let values = [
+            Value::Int(1),
+            ok.clone(),
+            Value::Ref(1),
+            Value::Int(0x5eed),
+            Value::Int(5),
+        ];
+        let options = LoadOptions {
+            skip_checksum: true,
+            ..LoadOptions::default()
+        };
+        let load = |bytes: &[u8]| {
+            load_values_partial(
+                &mut io::Cursor::new(bytes),
+                &mut ObjectPool::new(),
+                &options,
+            )
+        };
+        let mut bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let (read, error) = load(&bytes);
+        let mut expected = values.to_vec();
+        expected[2] = ok.clone();
+        assert_eq!(read, expected);
+        assert!(error.is_none());
+
+        let at = bytes
+            .windows(4)
+            .position(|w| w == [0, 0, 0x5e, 0xed])
+            .unwrap();
+        bytes[at - 1] = 0xff;
+        let (read, error) = load(&bytes);
+        assert_eq!(read, [Value::Int(1), ok.clone(), ok]);
+        let error = error.unwrap();
+        assert_eq!(error.index, 3);
+        assert_eq!(error.offset, Some((at as u64 - 1, true)));
+        assert!(
+            matches!(error.error, ConstantsError::UnknownTypeId { id: 0xff, .. }),
+            "{error}"
+        );
+        assert!(
+            error.to_string().starts_with(&format!(
+                "Stopped at constant 3 (file offset {:#010x}): ",
+                at - 1
+            )),
+            "{error}"
+        );
+
+        // Nothing is read past a header that fails
+        let (read, error) = load(&bytes[..6]);
+        assert!(read.is_empty());
+        let error = error.unwrap();
+        assert_eq!((error.index, error.offset), (0, None));
+        assert!(
+            matches!(error.error, ConstantsError::UnexpectedEof),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn verified_saves_report_what_reads_back_differently() {
+        let path = temp_path("verify");
+        let values = (0..15).map(Value::Int).collect::<Vec<_>>();
+        let verified = WriteOptions {
+            verify: true,
+            ..WriteOptions::default()
+        };
+        save_atomic(&path, &values, &verified).unwrap();
+
+        // What the file holds is checked against what the caller says was written
+        let mut written = values.clone();
+        written[1] = Value::Int(-1);
+        written[4] = Value::Bool(true);
+        let e = verify_saved(&path, &[&written], &WriteOptions::default()).unwrap_err();
+        let ConstantsError::SaveMismatch(mismatch) = &e else {
+            panic!("{e}");
+        };
+        assert_eq!((mismatch.written, mismatch.read), (15, 15));
+        assert_eq!(
+            mismatch.differences,
+            [
+                (1, "-1".to_string(), "1".to_string()),
+                (4, "true".to_string(), "4".to_string())
+            ]
+        );
+        assert_eq!(
+            e.to_string(),
+            "The saved file doesn't read back as written\n  constant 1: wrote -1, read 1\n  \
+             constant 4: wrote true, read 4"
+        );
+
+        let shifted = (1..16).map(Value::Int).collect::<Vec<_>>();
+        let e = verify_saved(&path, &[&shifted, &[Value::Nil]], &WriteOptions::default());
+        let e = e.unwrap_err().to_string();
+        assert!(
+            e.starts_with(
+                "The saved file doesn't read back as written\n  15 constants read back, 16 \
+                 written\n  constant 0: wrote 1, read 0\n"
+            ),
+            "{e}"
+        );
+        assert!(e.ends_with("\n  and 5 more constants differ"), "{e}");
+
+        // A file that doesn't load is reported with why, not as a mismatch
+        let mut bytes = fs::read(&path).unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let e = verify_saved(&path, &[&values], &WriteOptions::default()).unwrap_err();
+        assert!(
+            matches!(&e, ConstantsError::SaveUnreadable(inner) if matches!(**inner, ConstantsError::Truncated { recovered: 15 })),
+            "{e}"
+        );
+        assert!(
+            e.to_string()
+                .starts_with("The saved file doesn't load back: File is truncated"),
+            "{e}"
+        );
+        let signed = WriteOptions {
+            sign_key: Some(&[1; 32]),
+            ..WriteOptions::default()
+        };
+        save_atomic(&path, &values, &WriteOptions::default()).unwrap();
+        let e = verify_saved(&path, &[&values], &signed).unwrap_err();
+        assert!(
+            matches!(&e, ConstantsError::SaveUnreadable(inner) if matches!(**inner, ConstantsError::Unsigned)),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn mapped_loads_match_reading_the_file() {
+        let path = temp_path("mmap");
+        let key = [9; 32];
+        let load_options = LoadOptions {
+            key: Some(&key),
+            ..LoadOptions::default()
+        };
+        let mut rng = Rng(0x3c6e_f372_fe94_f82b);
+        for options in [
+            WriteOptions::default(),
+            WriteOptions {
+                index: true,
+                string_table: true,
+                ..WriteOptions::default()
+            },
+            WriteOptions {
+                compression: Some(9),
+                key: Some(&key),
+                ..WriteOptions::default()
+            },
+        ] {
+            let mut pool = ObjectPool::new();
+            let values = (0..100)
+                .map(|_| random_value(&mut rng, &mut pool, 3))
+                .collect::<Vec<_>>();
+            save_atomic(&path, &values, &options).unwrap();
+
+            let mut read = Vec::new();
+            let mut file = File::open(&path).unwrap();
+            load_values_from_disk(&mut file, &mut read, &mut ObjectPool::new(), &load_options)
+                .unwrap();
+            // SAFETY: nothing else touches this test's file
+            let mapped =
+                unsafe { load_values_mmap(&path, &mut ObjectPool::new(), &load_options) }.unwrap();
+            let encoded = |values: &[Value]| serialize_to_vec(values, &options).unwrap().len();
+            assert_eq!(mapped.len(), read.len());
+            assert_eq!(encoded(&mapped), encoded(&read));
+            for (mapped, read) in mapped.iter().zip(&read) {
+                assert_eq!(mapped.to_string(), read.to_string());
+            }
+        }
+
+        // Empty files have no mapping, and fail just as reading them does
+        fs::write(&path, b"").unwrap();
+        // SAFETY: as above
+        let mapped = unsafe { load_values_mmap(&path, &mut ObjectPool::new(), &load_options) };
+        let mut file = File::open(&path).unwrap();
+        let read = load_values_from_disk(
+            &mut file,
+            &mut Vec::new(),
+            &mut ObjectPool::new(),
+            &load_options,
+        );
+        assert_eq!(format!("{mapped:?}"), format!("{read:?}"));
+    }
+
+    #[test]
+    fn utf16_and_latin1_strings_round_trip_and_refuse_what_they_cant_hold() {
+        let encoded = |encoding| WriteOptions {
+            string_encoding: encoding,
+            ..WriteOptions::default()
+        };
+        let load = |bytes: &[u8]| {
+            let options = LoadOptions {
+                skip_checksum: true,
+                ..LoadOptions::default()
+            };
+            deserialize_from_slice(bytes, &mut ObjectPool::new(), &options)
+        };
+        let mut pool = ObjectPool::new();
+        let utf16 = [Value::from_string("h\u{e9}llo \u{1d11e}", &mut pool)];
+        let bytes = serialize_to_vec(&utf16, &encoded(StringEncoding::Utf16Be)).unwrap();
+        let units = [0x00, 0x68, 0x00, 0xe9];
+        assert!(bytes.windows(4).any(|w| w == units));
+        assert!(bytes.windows(4).any(|w| w == [0xd8, 0x34, 0xdd, 0x1e]));
+        assert_eq!(load(&bytes).unwrap(), utf16);
+
+        let latin1 = [Value::from_string("caf\u{e9} \u{ff}", &mut pool)];
+        let bytes = serialize_to_vec(&latin1, &encoded(StringEncoding::Latin1)).unwrap();
+        assert!(bytes.windows(6).any(|w| w == b"caf\xe9 \xff"));
+        assert_eq!(load(&bytes).unwrap(), latin1);
+        let e = serialize_to_vec(&utf16, &encoded(StringEncoding::Latin1)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "String \"h\u{e9}llo \u{1d11e}\" cannot be encoded as Latin-1 ('\u{1d11e}')"
+        );
+
+        // Sequences UTF-16 can't decode fail the load
+        let layout = Layout {
+            version: FormatVersion::V8,
+            ..Layout::LEGACY
+        };
+        let read = |bytes: &[u8]| {
+            read_string_encoded(&mut &bytes[..], StringEncoding::Utf16Be, layout)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            read(&[0, 0, 0, 3, 0, 0x61, 0]),
+            "UTF-16 string has an odd byte length 3"
+        );
+        assert_eq!(
+            read(&[0, 0, 0, 4, 0xd8, 0x00, 0x00, 0x61]),
+            "Invalid UTF-16 string: unpaired surrogate found: d800"
+        );
+        assert_eq!(
+            read(&[0, 0, 0, 2, 0xdc, 0x00]),
+            "Invalid UTF-16 string: unpaired surrogate found: dc00"
+        );
+
+        let ab = [Value::from_string("ab", &mut pool)];
+        let bytes = serialize_to_vec(&ab, &encoded(StringEncoding::Utf16Be)).unwrap();
+        let at = bytes
+            .windows(6)
+            .position(|w| w == [26, 1, 0, 0, 0, 4])
+            .unwrap();
+        let mut unknown = bytes.clone();
+        unknown[at + 1] = 3;
+        let e = load(&unknown).unwrap_err();
+        assert!(e.to_string().contains("Unknown string encoding 3"), "{e}");
+    }
+}
@@ -0,0 +1,273 @@
+use super::*;
+
+/// The lines that open and close an armored file, around its base64 lines.
+pub const ARMOR_BEGIN: &str = "-----BEGIN CONSTANTS-----";
+const ARMOR_END: &str = "-----END CONSTANTS-----";
+/// Base64 characters per armor line, as in PEM.
+const ARMOR_LINE_WIDTH: usize = 64;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes `bytes` as padded base64 (RFC 4648) in lines of `ARMOR_LINE_WIDTH`, between
+/// the armor lines, so a file can be pasted where only text survives.
+pub(super) fn armor(bytes: &[u8]) -> String {
+    let mut encoded = Vec::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            encoded.push(if i <= chunk.len() {
+                BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize]
+            } else {
+                b'='
+            });
+        }
+    }
+
+    let mut text = format!("{ARMOR_BEGIN}\n");
+    for line in encoded.chunks(ARMOR_LINE_WIDTH) {
+        text.push_str(std::str::from_utf8(line).unwrap());
+        text.push('\n');
+    }
+    text.push_str(ARMOR_END);
+    text.push('\n');
+    text
+}
+
+/// Why armored input couldn't be decoded. Lines and columns are 1-based.
+#[derive(Debug)]
+pub enum ArmorError {
+    MissingBegin,
+    BadChar {
+        line: usize,
+        column: usize,
+        byte: u8,
+    },
+    /// The `=` padding is missing, misplaced or too long, or the last character has bits
+    /// set that padding would have left clear.
+    BadPadding {
+        line: usize,
+    },
+    MissingEnd,
+    /// Something other than whitespace follows the end line.
+    TrailingData {
+        line: usize,
+    },
+}
+
+impl fmt::Display for ArmorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArmorError::MissingBegin => write!(f, "input doesn't start with '{ARMOR_BEGIN}'"),
+            ArmorError::BadChar { line, column, byte } => {
+                write!(f, "line {line}, column {column}: ")?;
+                if byte.is_ascii_graphic() {
+                    write!(f, "'{}' is not a base64 character", *byte as char)
+                } else {
+                    write!(f, "byte {byte:#04x} is not a base64 character")
+                }
+            }
+            ArmorError::BadPadding { line } => write!(f, "line {line}: invalid base64 padding"),
+            ArmorError::MissingEnd => write!(f, "input ends without '{ARMOR_END}'"),
+            ArmorError::TrailingData { line } => {
+                write!(f, "line {line}: unexpected data after '{ARMOR_END}'")
+            }
+        }
+    }
+}
+
+/// Decodes what `armor` wrote. Lines may be of any width and end in `\r\n`, but every
+/// character between the armor lines must be base64, and the padding must be exact.
+pub fn dearmor(text: &[u8]) -> Result<Vec<u8>, ArmorError> {
+    let mut lines = text
+        .split(|&byte| byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .zip(1..);
+    if !matches!(lines.next(), Some((line, _)) if line == ARMOR_BEGIN.as_bytes()) {
+        return Err(ArmorError::MissingBegin);
+    }
+
+    let mut digits = Vec::new();
+    let mut padding = 0;
+    let mut last_line = 1;
+    loop {
+        let Some((line, number)) = lines.next() else {
+            return Err(ArmorError::MissingEnd);
+        };
+        if line == ARMOR_END.as_bytes() {
+            break;
+        }
+
+        for (&byte, column) in line.iter().zip(1..) {
+            if byte == b'=' {
+                padding += 1;
+                continue;
+            }
+            let Some(digit) = BASE64_ALPHABET.iter().position(|&c| c == byte) else {
+                return Err(ArmorError::BadChar {
+                    line: number,
+                    column,
+                    byte,
+                });
+            };
+            if padding > 0 {
+                return Err(ArmorError::BadPadding { line: number });
+            }
+            digits.push(digit as u32);
+        }
+        last_line = number;
+    }
+    if let Some((_, number)) = lines.find(|(line, _)| !line.iter().all(u8::is_ascii_whitespace)) {
+        return Err(ArmorError::TrailingData { line: number });
+    }
+
+    // A final group of n digits holds n - 1 bytes, and leaves the rest of its bits clear
+    let partial = digits.len() % 4;
+    let expected_padding = (4 - partial) % 4;
+    let bad_padding = ArmorError::BadPadding { line: last_line };
+    if partial == 1 || padding != expected_padding {
+        return Err(bad_padding);
+    }
+    let mut bytes = Vec::with_capacity(digits.len() / 4 * 3 + 2);
+    for group in digits.chunks(4) {
+        let bits = group
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &digit)| bits | digit << (18 - 6 * i));
+        let len = group.len() - 1;
+        if bits & (0xff_ffff >> (8 * len)) != 0 {
+            return Err(bad_padding);
+        }
+        bytes.extend(&bits.to_be_bytes()[1..1 + len]);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn armored(base64: &str) -> String {
+        format!("{ARMOR_BEGIN}\n{base64}\n{ARMOR_END}\n")
+    }
+
+    #[test]
+    fn base64_matches_rfc_4648() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (bytes, base64) in vectors {
+            let text = match base64 {
+                "" => format!("{ARMOR_BEGIN}\n{ARMOR_END}\n"),
+                base64 => armored(base64),
+            };
+            assert_eq!(armor(bytes.as_bytes()), text);
+            assert_eq!(dearmor(text.as_bytes()).unwrap(), bytes.as_bytes());
+        }
+
+        // Any line width and line ending decode, so long as the characters are the same
+        let bytes = (0..=255).collect::<Vec<u8>>();
+        let text = armor(&bytes);
+        assert!(text.lines().all(|line| line.len() <= ARMOR_LINE_WIDTH));
+        let rewrapped = text
+            .lines()
+            .map(|line| match line.starts_with("-----") {
+                true => format!("{line}\r\n"),
+                false => line.chars().map(|c| format!("{c}\r\n")).collect(),
+            })
+            .collect::<String>();
+        assert_eq!(dearmor(rewrapped.as_bytes()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn malformed_armor_says_where() {
+        let error = |text: &str| dearmor(text.as_bytes()).unwrap_err();
+        assert!(matches!(error("Zm9v\n"), ArmorError::MissingBegin));
+
+        let e = error(&armored("Zm9v\nZm*v"));
+        assert!(
+            matches!(
+                e,
+                ArmorError::BadChar {
+                    line: 3,
+                    column: 3,
+                    byte: b'*'
+                }
+            ),
+            "{e}"
+        );
+        assert_eq!(
+            e.to_string(),
+            "line 3, column 3: '*' is not a base64 character"
+        );
+        let e = error(&armored("Zm\t9v"));
+        assert_eq!(
+            e.to_string(),
+            "line 2, column 3: byte 0x09 is not a base64 character"
+        );
+
+        // Padding must be exactly what the last group needs, and leave its spare bits clear
+        for base64 in [
+            "Zg=", "Zg===", "Zm8==", "Zm9v=", "Zg==Zg==", "Z", "Zh==", "Zm9=",
+        ] {
+            let e = error(&armored(base64));
+            assert!(
+                matches!(e, ArmorError::BadPadding { line: 2 }),
+                "{base64}: {e}"
+            );
+        }
+
+        let e = error(&format!("{ARMOR_BEGIN}\nZm9v\n"));
+        assert!(matches!(e, ArmorError::MissingEnd), "{e}");
+        let e = error(&format!("{}\n  \nZm9v\n", armored("Zm9v")));
+        assert!(matches!(e, ArmorError::TrailingData { line: 6 }), "{e}");
+        assert_eq!(
+            dearmor(format!("{}\n \t\n", armored("Zm9v")).as_bytes()).unwrap(),
+            b"foo"
+        );
+    }
+
+    #[test]
+    fn armored_files_decode_to_the_raw_ones() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::Int(1),
+            Value::from_string("armored", &mut pool),
+            Value::from_list(vec![Value::Float(0.5), Value::Nil], &mut pool),
+        ];
+        let options = WriteOptions {
+            index: true,
+            ..WriteOptions::default()
+        };
+        let mut raw = Vec::new();
+        save_as(ContainerFormat::Native, &mut raw, &values, &options).unwrap();
+        let mut text = Vec::new();
+        let armored = WriteOptions {
+            armor: true,
+            ..options
+        };
+        save_as(ContainerFormat::Native, &mut text, &values, &armored).unwrap();
+        assert!(text.starts_with(ARMOR_BEGIN.as_bytes()));
+        assert_eq!(dearmor(&text).unwrap(), raw);
+
+        let mut read = Vec::new();
+        let mut file = io::Cursor::new(&text);
+        load_in(
+            None,
+            &mut file,
+            &mut read,
+            &mut pool,
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read, values);
+    }
+}
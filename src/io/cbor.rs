@@ -0,0 +1,757 @@
+use super::*;
+
+const SELF_DESCRIBED_TAG: u64 = 55799;
+
+/// Why a CBOR container couldn't be read.
+#[derive(Debug)]
+pub(super) enum CborError {
+    /// The bytes aren't CBOR this reader takes; `offset` is where decoding stopped.
+    Malformed { offset: usize, message: String },
+    /// Well-formed CBOR that doesn't follow the schema.
+    Schema(JsonError),
+}
+
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CborError::Malformed { offset, message } => write!(f, "byte {offset}: {message}"),
+            CborError::Schema(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+enum Cbor {
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Null,
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(&'static str, Cbor)>),
+}
+
+pub(super) fn to_cbor(values: &[Value]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_head(&mut out, 6, SELF_DESCRIBED_TAG);
+    encode(&mut out, &cbor_values(values));
+    out
+}
+
+/// Builds constants from a container `to_cbor` wrote. A leading self-described tag is
+/// optional, and indefinite lengths and other tags are rejected, as are values nesting
+/// deeper than `max_depth`.
+pub(super) fn from_cbor(
+    bytes: &[u8],
+    pool: &mut ObjectPool,
+    max_depth: usize,
+) -> Result<Vec<Value>, CborError> {
+    let mut decoder = Decoder {
+        bytes,
+        at: 0,
+        depth: 0,
+        max_depth: max_depth
+            .saturating_mul(JSON_LEVELS_PER_VALUE)
+            .saturating_add(1),
+    };
+    if bytes.starts_with(&[0xd9, 0xd9, 0xf7]) {
+        decoder.at = 3;
+    }
+    let json = decoder.decode()?;
+    if decoder.at != bytes.len() {
+        return decoder.error("unexpected bytes after the constants");
+    }
+    json_to_values(&json, "constants", pool, max_depth).map_err(CborError::Schema)
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend([major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+fn encode(out: &mut Vec<u8>, cbor: &Cbor) {
+    match cbor {
+        Cbor::Int(n) if *n >= 0 => write_head(out, 0, *n as u64),
+        Cbor::Int(n) => write_head(out, 1, (-1 - *n) as u64),
+        Cbor::Float(x) => encode_float(out, *x),
+        Cbor::Bool(b) => out.push(if *b { 0xf5 } else { 0xf4 }),
+        Cbor::Null => out.push(0xf6),
+        Cbor::Bytes(bytes) => {
+            write_head(out, 2, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        }
+        Cbor::Text(s) => {
+            write_head(out, 3, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Cbor::Array(items) => {
+            write_head(out, 4, items.len() as u64);
+            for item in items {
+                encode(out, item);
+            }
+        }
+        Cbor::Map(entries) => {
+            let mut entries = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut key_bytes = Vec::new();
+                    encode(&mut key_bytes, &Cbor::Text(key.to_string()));
+                    (key_bytes, value)
+                })
+                .collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            write_head(out, 5, entries.len() as u64);
+            for (key, value) in entries {
+                out.extend(key);
+                encode(out, value);
+            }
+        }
+    }
+}
+
+/// Writes `x` in the shortest of half, single and double precision that holds it
+/// exactly, with NaN always as the half-precision quiet NaN.
+fn encode_float(out: &mut Vec<u8>, x: f64) {
+    if x.is_nan() {
+        return out.extend([0xf9, 0x7e, 0x00]);
+    }
+    let single = x as f32;
+    if single as f64 != x {
+        out.push(0xfb);
+        return out.extend(x.to_bits().to_be_bytes());
+    }
+    match half_bits(single) {
+        Some(half) => {
+            out.push(0xf9);
+            out.extend(half.to_be_bytes());
+        }
+        None => {
+            out.push(0xfa);
+            out.extend(single.to_bits().to_be_bytes());
+        }
+    }
+}
+
+/// The half-precision bits of `x`, if it has any that are exact.
+fn half_bits(x: f32) -> Option<u16> {
+    let bits = x.to_bits();
+    let sign = (bits >> 16) as u16 & 0x8000;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    match exponent {
+        // Zero or infinity; single-precision subnormals are too small for a half
+        0 | 0xff => (mantissa == 0).then_some(sign | if exponent == 0 { 0 } else { 0x7c00 }),
+        _ => {
+            let exponent = exponent - 127;
+            if (-14..=15).contains(&exponent) {
+                let half = sign | (((exponent + 15) as u16) << 10) | (mantissa >> 13) as u16;
+                (mantissa & 0x1fff == 0).then_some(half)
+            } else if (-24..-14).contains(&exponent) {
+                // A half subnormal counts units of 2^-24
+                let shift = -(exponent + 1) as u32;
+                let full = mantissa | 0x80_0000;
+                (full & ((1 << shift) - 1) == 0).then_some(sign | (full >> shift) as u16)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn half_to_f64(half: u16) -> f64 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f64;
+    sign * match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        0x1f if mantissa == 0.0 => f64::INFINITY,
+        0x1f => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent - 25),
+    }
+}
+
+/// A CBOR map with `"type": name` beside `fields`.
+fn typed(name: &str, mut fields: Vec<(&'static str, Cbor)>) -> Cbor {
+    fields.push(("type", Cbor::Text(name.to_string())));
+    Cbor::Map(fields)
+}
+
+fn text(s: &str) -> Cbor {
+    Cbor::Text(s.to_string())
+}
+
+fn int(n: impl Into<i128>) -> Cbor {
+    Cbor::Int(n.into())
+}
+
+fn cbor_values(values: &[Value]) -> Cbor {
+    Cbor::Array(values.iter().map(cbor_value).collect())
+}
+
+fn cbor_value(value: &Value) -> Cbor {
+    match value {
+        Value::Int(i) => typed("int", vec![("value", int(*i))]),
+        Value::Bool(b) => typed("bool", vec![("value", Cbor::Bool(*b))]),
+        Value::Float(x) => typed("float", vec![("value", Cbor::Float(*x))]),
+        Value::Long(l) => typed("long", vec![("value", int(*l))]),
+        Value::UInt(u) => typed("uint", vec![("value", int(*u))]),
+        Value::Char(c) => typed("char", vec![("value", text(&c.to_string()))]),
+        Value::Nil => typed("nil", vec![]),
+        Value::Byte(b) => typed("byte", vec![("value", int(*b))]),
+        Value::Short(s) => typed("short", vec![("value", int(*s))]),
+        Value::Decimal { mantissa, scale } => typed(
+            "decimal",
+            vec![("mantissa", int(*mantissa)), ("scale", int(*scale))],
+        ),
+        Value::Timestamp { seconds, nanos } => typed(
+            "timestamp",
+            vec![("seconds", int(*seconds)), ("nanos", int(*nanos))],
+        ),
+        Value::Uuid(_) => typed("uuid", vec![("value", text(&value.to_string()))]),
+        Value::Optional(inner) => typed(
+            "optional",
+            vec![("value", inner.as_deref().map_or(Cbor::Null, cbor_value))],
+        ),
+        Value::Ref(index) => typed("ref", vec![("index", int(*index))]),
+        Value::Complex { re, im } => typed(
+            "complex",
+            vec![("re", Cbor::Float(*re)), ("im", Cbor::Float(*im))],
+        ),
+        Value::Color { r, g, b, a } => typed(
+            "color",
+            vec![
+                ("r", int(*r)),
+                ("g", int(*g)),
+                ("b", int(*b)),
+                ("a", int(*a)),
+            ],
+        ),
+        Value::Object(o) => cbor_object_value(o),
+    }
+}
+
+fn cbor_object_value(object: &Object) -> Cbor {
+    match object {
+        Object::String(s) => typed("string", vec![("value", text(s))]),
+        Object::Function {
+            identifier,
+            param_count,
+            flags,
+            local_count,
+            code,
+            constants,
+            lines,
+            upvalues,
+            defaults,
+            param_types,
+            return_type,
+            source,
+        } => {
+            let lines = lines.as_ref().map_or(Cbor::Null, |lines| {
+                Cbor::Array(
+                    lines
+                        .iter()
+                        .map(|(offset, line)| Cbor::Array(vec![int(*offset), int(*line)]))
+                        .collect(),
+                )
+            });
+            let upvalues = upvalues
+                .iter()
+                .map(|upvalue| {
+                    Cbor::Map(vec![
+                        ("is_local", Cbor::Bool(upvalue.is_local)),
+                        ("index", int(upvalue.index)),
+                    ])
+                })
+                .collect();
+            let param_types = param_types.iter().map(|tag| text(tag.name())).collect();
+
+            typed(
+                "function",
+                vec![
+                    ("identifier", text(identifier)),
+                    ("param_count", int(*param_count)),
+                    ("variadic", Cbor::Bool(flags & FUNCTION_VARIADIC != 0)),
+                    ("local_count", int(*local_count)),
+                    ("code", Cbor::Bytes(code.clone())),
+                    ("constants", cbor_values(constants)),
+                    ("lines", lines),
+                    ("upvalues", Cbor::Array(upvalues)),
+                    ("defaults", cbor_values(defaults)),
+                    ("param_types", Cbor::Array(param_types)),
+                    ("return_type", text(return_type.name())),
+                    ("source", source.as_deref().map_or(Cbor::Null, text)),
+                ],
+            )
+        }
+        Object::List(items) => typed("list", vec![("items", cbor_values(items))]),
+        Object::Tuple(items) => typed("tuple", vec![("items", cbor_values(items))]),
+        Object::Map(entries) => {
+            let entries = entries
+                .iter()
+                .map(|(key, value)| {
+                    Cbor::Map(vec![("key", cbor_value(key)), ("value", cbor_value(value))])
+                })
+                .collect();
+            typed("map", vec![("entries", Cbor::Array(entries))])
+        }
+        Object::Bytes(bytes) => typed("bytes", vec![("value", Cbor::Bytes(bytes.clone()))]),
+        Object::EnumVariant {
+            type_name,
+            variant,
+            payload,
+        } => typed(
+            "enum_variant",
+            vec![
+                ("type_name", text(type_name)),
+                ("variant", text(variant)),
+                ("payload", payload.as_deref().map_or(Cbor::Null, cbor_value)),
+            ],
+        ),
+        Object::Record { type_name, fields } => {
+            let fields = fields
+                .iter()
+                .map(|(name, value)| {
+                    Cbor::Map(vec![("name", text(name)), ("value", cbor_value(value))])
+                })
+                .collect();
+            typed(
+                "record",
+                vec![
+                    ("type_name", text(type_name)),
+                    ("fields", Cbor::Array(fields)),
+                ],
+            )
+        }
+        Object::BigInt { .. } => typed("big_int", vec![("value", text(&object.to_string()))]),
+        Object::Set(items) => {
+            // In the order a native file with default options lists them
+            let items = canonical_set_items(items, &WriteOptions::default());
+            let items = items.into_iter().map(cbor_value).collect();
+            typed("set", vec![("items", Cbor::Array(items))])
+        }
+        Object::Pair(car, cdr) => typed(
+            "pair",
+            vec![("car", cbor_value(car)), ("cdr", cbor_value(cdr))],
+        ),
+        Object::Symbol(name) => typed("symbol", vec![("name", text(name))]),
+        Object::FloatArray { rows, cols, data } => typed(
+            "float_array",
+            vec![
+                ("rows", int(*rows)),
+                ("cols", int(*cols)),
+                (
+                    "data",
+                    Cbor::Array(data.iter().map(|x| Cbor::Float(*x)).collect()),
+                ),
+            ],
+        ),
+        Object::Regex { pattern, flags } => {
+            let letters = REGEX_FLAGS
+                .iter()
+                .filter(|(bit, _)| flags & bit != 0)
+                .map(|(_, letter)| letter)
+                .collect::<String>();
+            typed(
+                "regex",
+                vec![("pattern", text(pattern)), ("flags", text(&letters))],
+            )
+        }
+        Object::NativeRef {
+            identifier,
+            param_count,
+        } => typed(
+            "native_ref",
+            vec![
+                ("identifier", text(identifier)),
+                ("param_count", int(*param_count)),
+            ],
+        ),
+        Object::Class { name, methods } => {
+            let methods = methods
+                .iter()
+                .map(|(name, method)| {
+                    Cbor::Map(vec![
+                        ("name", text(name)),
+                        ("function", cbor_object_value(method)),
+                    ])
+                })
+                .collect();
+            typed(
+                "class",
+                vec![("name", text(name)), ("methods", Cbor::Array(methods))],
+            )
+        }
+        Object::Module { name, constants } => typed(
+            "module",
+            vec![("name", text(name)), ("constants", cbor_values(constants))],
+        ),
+    }
+}
+
+/// Decodes CBOR into the same tree `JsonParser` builds, so `value_from_json` checks
+/// the schema for both. Integers and floats become number text, non-finite floats the
+/// strings `to_json` uses, and byte strings arrays of numbers. Arrays and maps may nest
+/// `max_depth` deep, as `JsonParser` allows.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Decoder<'_> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, CborError> {
+        Err(CborError::Malformed {
+            offset: self.at,
+            message: message.into(),
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8], CborError> {
+        if self.bytes.len() - self.at < len {
+            return self.error("unexpected end of input");
+        }
+        self.at += len;
+        Ok(&self.bytes[self.at - len..self.at])
+    }
+
+    /// Reads an item's head: its major type and argument.
+    fn head(&mut self) -> Result<(u8, u64), CborError> {
+        let start = self.at;
+        let initial = self.take(1)?[0];
+        let argument = match initial & 0x1f {
+            n @ 0..=23 => n as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            info => {
+                self.at = start;
+                return match info {
+                    31 => self.error("indefinite lengths are not supported"),
+                    _ => self.error(format!("reserved initial byte {initial:#04x}")),
+                };
+            }
+        };
+        Ok((initial >> 5, argument))
+    }
+
+    /// A length that can't claim more items than there are bytes left, each item
+    /// taking at least one.
+    fn len(&self, argument: u64) -> Result<usize, CborError> {
+        match usize::try_from(argument) {
+            Ok(len) if len <= self.bytes.len() - self.at => Ok(len),
+            _ => self.error(format!("length {argument} runs past the end of the input")),
+        }
+    }
+
+    fn decode(&mut self) -> Result<Json, CborError> {
+        let start = self.at;
+        let (major, argument) = self.head()?;
+        Ok(match major {
+            0 => Json::Number(argument.to_string()),
+            1 => Json::Number((-1 - argument as i128).to_string()),
+            2 => {
+                let len = self.len(argument)?;
+                let bytes = self.take(len)?;
+                Json::Array(bytes.iter().map(|b| Json::Number(b.to_string())).collect())
+            }
+            3 => {
+                let len = self.len(argument)?;
+                let text = std::str::from_utf8(self.take(len)?);
+                match text {
+                    Ok(text) => Json::String(text.to_string()),
+                    Err(_) => {
+                        self.at = start;
+                        return self.error("text string is not valid UTF-8");
+                    }
+                }
+            }
+            4 | 5 if self.depth == self.max_depth => {
+                self.at = start;
+                return self.error(format!(
+                    "arrays and maps nest deeper than {} levels",
+                    self.max_depth
+                ));
+            }
+            4 => {
+                let len = self.len(argument)?;
+                self.depth += 1;
+                let items = (0..len).map(|_| self.decode());
+                let items = items.collect::<Result<_, _>>()?;
+                self.depth -= 1;
+                Json::Array(items)
+            }
+            5 => {
+                let len = self.len(argument)?;
+                self.depth += 1;
+                let mut members = Vec::<(String, Json)>::new();
+                for _ in 0..len {
+                    let key_start = self.at;
+                    let Json::String(key) = self.decode_key()? else {
+                        unreachable!("keys are text")
+                    };
+                    if members.iter().any(|(k, _)| *k == key) {
+                        self.at = key_start;
+                        return self.error(format!("duplicate key '{key}'"));
+                    }
+                    members.push((key, self.decode()?));
+                }
+                self.depth -= 1;
+                Json::Object(members)
+            }
+            6 => {
+                self.at = start;
+                return self.error(format!("unsupported tag {argument}"));
+            }
+            _ => match self.bytes[start] & 0x1f {
+                20 => Json::Bool(false),
+                21 => Json::Bool(true),
+                22 => Json::Null,
+                25 => float_json(half_to_f64(argument as u16)),
+                26 => float_json(f32::from_bits(argument as u32) as f64),
+                27 => float_json(f64::from_bits(argument)),
+                _ => {
+                    self.at = start;
+                    return self.error(format!("unsupported simple value {argument}"));
+                }
+            },
+        })
+    }
+
+    fn decode_key(&mut self) -> Result<Json, CborError> {
+        let start = self.at;
+        match self.decode()? {
+            key @ Json::String(_) => Ok(key),
+            _ => {
+                self.at = start;
+                self.error("map keys must be text strings")
+            }
+        }
+    }
+}
+
+fn float_json(x: f64) -> Json {
+    if x.is_finite() {
+        Json::Number(format!("{x:?}"))
+    } else {
+        Json::String(x.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{random_value, Rng};
+    use super::*;
+
+    const MAX_DEPTH: usize = LoadLimits::DEFAULT.max_depth;
+
+    #[test]
+    fn cbor_holds_the_same_constants_as_native_files() {
+        let mut rng = Rng(0x6a09_e667_f3bc_c908);
+        for _ in 0..64 {
+            let mut pool = ObjectPool::new();
+            let values = (0..rng.below(20))
+                .map(|_| random_value(&mut rng, &mut pool, 3))
+                .collect::<Vec<_>>();
+            let native = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+
+            let loaded =
+                deserialize_from_slice(&native, &mut ObjectPool::new(), &LoadOptions::default())
+                    .unwrap();
+            let through_cbor =
+                from_cbor(&to_cbor(&loaded), &mut ObjectPool::new(), MAX_DEPTH).unwrap();
+            assert_eq!(through_cbor, values);
+            assert_eq!(
+                serialize_to_vec(&through_cbor, &WriteOptions::default()).unwrap(),
+                native
+            );
+        }
+    }
+
+    #[test]
+    fn cbor_output_is_canonical() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::Int(1),
+            Value::Float(1.5),
+            Value::from_bytes(&[0xff], &mut pool),
+        ];
+        #[rustfmt::skip]
+        let expected = [
+            // tag 55799, self-described CBOR
+            0xd9, 0xd9, 0xf7,
+            // array(3)
+            0x83,
+            // map(2), keys in bytewise order: {"type": "int", "value": 1}
+            0xa2, 0x64, b't', b'y', b'p', b'e', 0x63, b'i', b'n', b't',
+            0x65, b'v', b'a', b'l', b'u', b'e', 0x01,
+            // {"type": "float", "value": 1.5 as a half-precision float}
+            0xa2, 0x64, b't', b'y', b'p', b'e', 0x65, b'f', b'l', b'o', b'a', b't',
+            0x65, b'v', b'a', b'l', b'u', b'e', 0xf9, 0x3e, 0x00,
+            // {"type": "bytes", "value": h'ff'}
+            0xa2, 0x64, b't', b'y', b'p', b'e', 0x65, b'b', b'y', b't', b'e', b's',
+            0x65, b'v', b'a', b'l', b'u', b'e', 0x41, 0xff,
+        ];
+        assert_eq!(to_cbor(&values), expected);
+        assert_eq!(from_cbor(&expected, &mut pool, MAX_DEPTH).unwrap(), values);
+        // The tag is optional on the way in
+        assert_eq!(
+            from_cbor(&expected[3..], &mut pool, MAX_DEPTH).unwrap(),
+            values
+        );
+    }
+
+    #[test]
+    fn cbor_matches_the_rfc_8949_examples() {
+        /// The tree `Decoder` should build for `cbor`.
+        fn decoded(cbor: &Cbor) -> Json {
+            match cbor {
+                Cbor::Int(n) => Json::Number(n.to_string()),
+                Cbor::Float(x) => float_json(*x),
+                Cbor::Bool(b) => Json::Bool(*b),
+                Cbor::Null => Json::Null,
+                Cbor::Bytes(bytes) => {
+                    Json::Array(bytes.iter().map(|b| Json::Number(b.to_string())).collect())
+                }
+                Cbor::Text(s) => Json::String(s.clone()),
+                Cbor::Array(items) => Json::Array(items.iter().map(decoded).collect()),
+                Cbor::Map(entries) => Json::Object(
+                    entries
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), decoded(value)))
+                        .collect(),
+                ),
+            }
+        }
+        let hex = |hex: &str| {
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Appendix A of RFC 8949, written independently of this encoder, in preferred
+        // serialization; the examples this schema can't hold, like tags, are left out
+        let examples = [
+            (Cbor::Int(0), "00"),
+            (Cbor::Int(23), "17"),
+            (Cbor::Int(24), "1818"),
+            (Cbor::Int(1000), "1903e8"),
+            (Cbor::Int(1000000), "1a000f4240"),
+            (Cbor::Int(1000000000000), "1b000000e8d4a51000"),
+            (Cbor::Int(18446744073709551615), "1bffffffffffffffff"),
+            (Cbor::Int(-1), "20"),
+            (Cbor::Int(-100), "3863"),
+            (Cbor::Int(-1000), "3903e7"),
+            (Cbor::Int(-18446744073709551616), "3bffffffffffffffff"),
+            (Cbor::Float(0.0), "f90000"),
+            (Cbor::Float(-0.0), "f98000"),
+            (Cbor::Float(1.0), "f93c00"),
+            (Cbor::Float(1.1), "fb3ff199999999999a"),
+            (Cbor::Float(1.5), "f93e00"),
+            (Cbor::Float(65504.0), "f97bff"),
+            (Cbor::Float(100000.0), "fa47c35000"),
+            (Cbor::Float(3.4028234663852886e+38), "fa7f7fffff"),
+            (Cbor::Float(1.0e+300), "fb7e37e43c8800759c"),
+            (Cbor::Float(5.960464477539063e-8), "f90001"),
+            (Cbor::Float(0.00006103515625), "f90400"),
+            (Cbor::Float(-4.1), "fbc010666666666666"),
+            (Cbor::Float(f64::INFINITY), "f97c00"),
+            (Cbor::Float(f64::NAN), "f97e00"),
+            (Cbor::Float(f64::NEG_INFINITY), "f9fc00"),
+            (Cbor::Bool(false), "f4"),
+            (Cbor::Bool(true), "f5"),
+            (Cbor::Null, "f6"),
+            (Cbor::Bytes(vec![]), "40"),
+            (Cbor::Bytes(vec![1, 2, 3, 4]), "4401020304"),
+            (text(""), "60"),
+            (text("IETF"), "6449455446"),
+            (text("\"\\"), "62225c"),
+            (text("\u{fc}"), "62c3bc"),
+            (text("\u{6c34}"), "63e6b0b4"),
+            (Cbor::Array(vec![]), "80"),
+            (
+                Cbor::Array(vec![
+                    Cbor::Int(1),
+                    Cbor::Array(vec![Cbor::Int(2), Cbor::Int(3)]),
+                    Cbor::Array(vec![Cbor::Int(4), Cbor::Int(5)]),
+                ]),
+                "8301820203820405",
+            ),
+            (Cbor::Map(vec![]), "a0"),
+            (
+                Cbor::Map(vec![
+                    ("a", Cbor::Int(1)),
+                    ("b", Cbor::Array(vec![Cbor::Int(2), Cbor::Int(3)])),
+                ]),
+                "a26161016162820203",
+            ),
+            (
+                Cbor::Array(vec![text("a"), Cbor::Map(vec![("b", text("c"))])]),
+                "826161a161626163",
+            ),
+        ];
+        for (cbor, expected) in examples {
+            let mut out = Vec::new();
+            encode(&mut out, &cbor);
+            assert_eq!(out, hex(expected), "{expected}");
+            let mut decoder = Decoder {
+                bytes: &out,
+                at: 0,
+                depth: 0,
+                max_depth: 8,
+            };
+            assert_eq!(decoder.decode().unwrap(), decoded(&cbor), "{expected}");
+            assert_eq!(decoder.at, out.len());
+        }
+    }
+
+    #[test]
+    fn nesting_fails_past_the_depth_limit_instead_of_overflowing() {
+        let nested = |depth: usize| {
+            (1..depth).fold(Value::Int(0), |inner, _| {
+                Value::Optional(Some(Box::new(inner)))
+            })
+        };
+        let deepest = [nested(MAX_DEPTH)];
+        let read = from_cbor(&to_cbor(&deepest), &mut ObjectPool::new(), MAX_DEPTH).unwrap();
+        assert_eq!(read, deepest);
+        let e = from_cbor(
+            &to_cbor(&[nested(MAX_DEPTH + 1)]),
+            &mut ObjectPool::new(),
+            MAX_DEPTH,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&e, CborError::Schema(JsonError::Schema { message, .. }) if message == "nests deeper than the depth limit"),
+            "{e}"
+        );
+
+        // Arrays alone are refused as they're decoded, before any value is built
+        let mut bytes = vec![0x81; 100_000];
+        bytes.push(0x00);
+        let e = from_cbor(&bytes, &mut ObjectPool::new(), MAX_DEPTH).unwrap_err();
+        let levels = MAX_DEPTH * JSON_LEVELS_PER_VALUE + 1;
+        assert!(
+            matches!(&e, CborError::Malformed { offset, .. } if *offset == levels),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,445 @@
+use super::*;
+
+/// "expand 32-byte k", the first row of every ChaCha state.
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn le_words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut words = [0; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+/// The 20 ChaCha rounds, as ten column and diagonal double rounds.
+fn chacha_rounds(state: &mut [u32; 16]) {
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    for _ in 0..10 {
+        quarter_round(state, 0, 4, 8, 12);
+        quarter_round(state, 1, 5, 9, 13);
+        quarter_round(state, 2, 6, 10, 14);
+        quarter_round(state, 3, 7, 11, 15);
+        quarter_round(state, 0, 5, 10, 15);
+        quarter_round(state, 1, 6, 11, 12);
+        quarter_round(state, 2, 7, 8, 13);
+        quarter_round(state, 3, 4, 9, 14);
+    }
+}
+
+fn chacha_state(key: &[u32; 8], input: &[u32; 4]) -> [u32; 16] {
+    let mut state = [0; 16];
+    state[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    state[4..12].copy_from_slice(key);
+    state[12..].copy_from_slice(input);
+    state
+}
+
+/// Derives a subkey from `key` and 16 bytes of nonce, as XChaCha20 does before encrypting.
+fn hchacha20(key: &[u32; 8], input: &[u32; 4]) -> [u32; 8] {
+    let mut state = chacha_state(key, input);
+    chacha_rounds(&mut state);
+
+    let mut out = [0; 8];
+    out[..4].copy_from_slice(&state[..4]);
+    out[4..].copy_from_slice(&state[12..]);
+    out
+}
+
+/// XORs `data` with the XChaCha20 keystream for `key` and `nonce`, which both encrypts and
+/// decrypts.
+fn xchacha20(key: &[u32; 8], nonce: &[u8; 24], data: &mut [u8]) {
+    let subkey = hchacha20(key, &le_words(&nonce[..16]));
+    let [n0, n1] = le_words(&nonce[16..]);
+
+    for (counter, chunk) in data.chunks_mut(64).enumerate() {
+        let initial = chacha_state(&subkey, &[counter as u32, 0, n0, n1]);
+        let mut block = initial;
+        chacha_rounds(&mut block);
+
+        let keystream = block
+            .iter()
+            .zip(initial)
+            .flat_map(|(word, start)| word.wrapping_add(start).to_le_bytes());
+        for (byte, key_byte) in chunk.iter_mut().zip(keystream) {
+            *byte ^= key_byte;
+        }
+    }
+}
+
+/// Stretches a key of any length to the 32 bytes XChaCha20 takes by chaining HChaCha20
+/// over its 32-byte chunks, salted with the start of the nonce. This isn't a password
+/// hash: a short key is as guessable after stretching as before.
+fn derive_key(key: &[u8], nonce: &[u8; 24]) -> [u32; 8] {
+    let salt = le_words(&nonce[..16]);
+    // The length goes first so keys differing only in trailing zero bytes stay distinct
+    let mut input = (key.len() as u64).to_le_bytes().to_vec();
+    input.extend_from_slice(key);
+
+    let mut state = [0u32; 8];
+    for chunk in input.chunks(32) {
+        let mut block = [0u8; 32];
+        block[..chunk.len()].copy_from_slice(chunk);
+        for (word, block_word) in state.iter_mut().zip(le_words::<8>(&block)) {
+            *word ^= block_word;
+        }
+        state = hchacha20(&state, &salt);
+    }
+    state
+}
+
+/// A nonce from the process's random hasher keys and the clock. Nonces only need to differ
+/// between saves, not to be unpredictable.
+fn random_nonce() -> [u8; 24] {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let mut nonce = [0; 24];
+    for (i, chunk) in nonce.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        hasher.write_u128(time);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    nonce
+}
+
+/// Encrypts a body as its nonce followed by the XChaCha20 ciphertext of the body's CRC32
+/// and the body itself.
+pub(super) fn encrypt_body(body: &[u8], key: &[u8], options: &WriteOptions) -> Vec<u8> {
+    let nonce = random_nonce();
+    let mut plain = Vec::with_capacity(body.len() + 4);
+    plain.extend(crc32(body).to_endian_bytes(options.endianness).as_ref());
+    plain.extend_from_slice(body);
+    xchacha20(&derive_key(key, &nonce), &nonce, &mut plain);
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(plain);
+    encrypted
+}
+
+/// Reverses `encrypt_body`. The inner CRC32 only matches under the right key, since the
+/// footer checksum already vouched for the stored bytes.
+pub(super) fn decrypt_body(
+    encrypted: &[u8],
+    key: &[u8],
+    layout: Layout,
+) -> Result<Vec<u8>, ConstantsError> {
+    if encrypted.len() < 24 + 4 {
+        return invalid("Encrypted body is too short for its nonce and checksum");
+    }
+    let (nonce, ciphertext) = encrypted.split_at(24);
+    let nonce: [u8; 24] = nonce.try_into().unwrap();
+    let mut plain = ciphertext.to_vec();
+    xchacha20(&derive_key(key, &nonce), &nonce, &mut plain);
+
+    let (checksum, body) = plain.split_at(4);
+    if read_number::<u32>(&mut &checksum[..], layout)? != crc32(body) {
+        return invalid("Wrong key, or the encrypted body is corrupted");
+    }
+    Ok(body.to_vec())
+}
+
+/// SHA-256 round constants: the first 32 bits of the fractional parts of the cube roots of
+/// the first 64 primes.
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, fed in pieces of any size.
+#[derive(Clone)]
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let taken = bytes.len().min(64 - self.block_len);
+            self.block[self.block_len..self.block_len + taken].copy_from_slice(&bytes[..taken]);
+            self.block_len += taken;
+            bytes = &bytes[taken..];
+            if self.block_len == 64 {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub(super) fn finish(mut self) -> [u8; 32] {
+        let bits = self.total_len * 8;
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0; 32];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, chunk) in w.iter_mut().zip(self.block.chunks(4)) {
+            *word = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, add) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+}
+
+/// HMAC-SHA256 as in RFC 2104, fed in pieces like `Sha256`.
+#[derive(Clone)]
+struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        let mut block = [0u8; 64];
+        if key.len() > 64 {
+            let mut hash = Sha256::new();
+            hash.update(key);
+            block[..32].copy_from_slice(&hash.finish());
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|byte| byte ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|byte| byte ^ 0x5c));
+        HmacSha256 { inner, outer }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.inner.update(bytes);
+    }
+
+    pub(super) fn finish(self) -> [u8; 32] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+/// Bytes in the HMAC-SHA256 tag that ends a file with `HEADER_SIGNED`.
+pub(super) const SIGNATURE_LEN: usize = 32;
+
+/// Passes everything through to `inner`, and with a key, signs it: `finish` then follows
+/// it with the HMAC-SHA256 tag of every byte written.
+pub(super) struct SigningWriter<W: Write> {
+    inner: W,
+    mac: Option<HmacSha256>,
+}
+
+impl<W: Write> SigningWriter<W> {
+    pub fn new(inner: W, key: Option<&[u8]>) -> SigningWriter<W> {
+        SigningWriter {
+            inner,
+            mac: key.map(HmacSha256::new),
+        }
+    }
+
+    pub(super) fn finish(mut self) -> io::Result<()> {
+        if let Some(mac) = self.mac.take() {
+            self.inner.write_all(&mac.finish())?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SigningWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(mac) = &mut self.mac {
+            mac.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checks the tag that ends a signed file against the HMAC-SHA256 under `key` of
+/// everything from the current position, which must be the start of the file, to the tag.
+/// Leaves `file` where it started.
+pub(super) fn verify_signature(
+    file: &mut (impl Read + Seek),
+    key: &[u8],
+) -> Result<(), ConstantsError> {
+    let start = file.stream_position()?;
+    let end = file.seek(SeekFrom::End(0))?;
+    let Some(signed_len) = (end - start).checked_sub(SIGNATURE_LEN as u64) else {
+        return Err(ConstantsError::UnexpectedEof);
+    };
+    file.seek(SeekFrom::Start(start))?;
+
+    let mut mac = HmacSha256::new(key);
+    let mut buffer = [0u8; 8192];
+    let mut signed = file.by_ref().take(signed_len);
+    loop {
+        let read = signed.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        mac.update(&buffer[..read]);
+    }
+    let mut tag = [0u8; SIGNATURE_LEN];
+    file.read_exact(&mut tag)?;
+    file.seek(SeekFrom::Start(start))?;
+
+    // Compared in full whatever differs, so the time taken says nothing about the tag
+    let differences = mac
+        .finish()
+        .iter()
+        .zip(tag)
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    if differences != 0 {
+        return Err(ConstantsError::SignatureMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let mut mac = HmacSha256::new(b"Jefe");
+        mac.update(b"what do ya want for nothing?");
+        let tag = mac.finish().map(|byte| format!("{byte:02x}")).concat();
+        assert_eq!(
+            tag,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signed_files_load_only_under_their_key() {
+        let key = [3; 32];
+        let values = [Value::Int(0x5eed), Value::Bool(true)];
+        let signed = WriteOptions {
+            sign_key: Some(&key),
+            ..WriteOptions::default()
+        };
+        let bytes = serialize_to_vec(&values, &signed).unwrap();
+        let load = |bytes: &[u8], key: &[u8], allow_unsigned| {
+            let options = LoadOptions {
+                verify_key: Some(key),
+                allow_unsigned,
+                ..LoadOptions::default()
+            };
+            deserialize_from_slice(bytes, &mut ObjectPool::new(), &options)
+        };
+        assert_eq!(load(&bytes, &key, false).unwrap(), values);
+
+        // A changed constant fails on its tag before its checksum is ever read
+        let mut tampered = bytes.clone();
+        let at = bytes
+            .windows(4)
+            .position(|w| w == [0, 0, 0x5e, 0xed])
+            .unwrap();
+        tampered[at + 3] ^= 1;
+        let e = load(&tampered, &key, false).unwrap_err();
+        assert!(matches!(e, ConstantsError::SignatureMismatch), "{e}");
+        let e = load(&bytes, &[4; 32], false).unwrap_err();
+        assert!(matches!(e, ConstantsError::SignatureMismatch), "{e}");
+        // Allowing unsigned files doesn't excuse a signature that doesn't match
+        let e = load(&bytes, &[4; 32], true).unwrap_err();
+        assert!(matches!(e, ConstantsError::SignatureMismatch), "{e}");
+
+        let unsigned = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let e = load(&unsigned, &key, false).unwrap_err();
+        assert!(matches!(e, ConstantsError::Unsigned), "{e}");
+        assert_eq!(load(&unsigned, &key, true).unwrap(), values);
+
+        // Formats that can't carry a signature are unsigned files too
+        let json = json::to_json(&values);
+        let load_json = |allow_unsigned| {
+            let options = LoadOptions {
+                verify_key: Some(&key),
+                allow_unsigned,
+                ..LoadOptions::default()
+            };
+            let mut read = Vec::new();
+            let mut file = io::Cursor::new(json.as_bytes());
+            load_in(None, &mut file, &mut read, &mut ObjectPool::new(), &options).map(|()| read)
+        };
+        let e = load_json(false).unwrap_err();
+        assert!(matches!(e, ConstantsError::Unsigned), "{e}");
+        assert_eq!(load_json(true).unwrap(), values);
+    }
+}
@@ -0,0 +1,505 @@
+use super::*;
+
+/// Lengths 3..=258 as DEFLATE length codes 257..=285: the base of each code and how many
+/// extra bits follow it.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order the code length code lengths of a dynamic block are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const DEFLATE_WINDOW: usize = 32 * 1024;
+const DEFLATE_MAX_MATCH: usize = 258;
+
+/// Packs bits LSB first, the order DEFLATE streams use.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are defined MSB first, so they go out reversed.
+    fn code(&mut self, code: u32, len: u32) {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    pub(super) fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+/// Code and bit length of a literal/length symbol under the fixed Huffman code.
+fn fixed_literal_code(symbol: u16) -> (u32, u32) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    }
+}
+
+/// Compresses `data` as a single fixed-Huffman DEFLATE block. `level` runs from 1 to 9 and
+/// sets how many earlier positions are tried for each match.
+pub(super) fn deflate(data: &[u8], level: u8) -> Vec<u8> {
+    const HASH_BITS: u32 = 15;
+    let max_chain = 1usize << level;
+    let hash = |i: usize| {
+        let key = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+        (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    };
+    // Chains of earlier positions whose next three bytes hash alike, newest first
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+        if i + 3 <= data.len() {
+            let h = hash(i);
+            prev[i] = head[h];
+            head[h] = i;
+        }
+    };
+
+    let mut out = BitWriter::default();
+    out.bits(1, 1); // last block
+    out.bits(1, 2); // fixed Huffman codes
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        if i + 3 <= data.len() {
+            let limit = DEFLATE_MAX_MATCH.min(data.len() - i);
+            let mut candidate = head[hash(i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= DEFLATE_WINDOW && chain < max_chain {
+                let len = (0..limit)
+                    .take_while(|&k| data[candidate + k] == data[i + k])
+                    .count();
+                if len > best.0 {
+                    best = (len, i - candidate);
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        let (len, dist) = best;
+        if len >= 3 {
+            let code = LENGTH_BASE
+                .iter()
+                .rposition(|&base| base as usize <= len)
+                .unwrap();
+            let (bits, bit_len) = fixed_literal_code(257 + code as u16);
+            out.code(bits, bit_len);
+            out.bits(
+                (len - LENGTH_BASE[code] as usize) as u32,
+                LENGTH_EXTRA[code] as u32,
+            );
+
+            let code = DIST_BASE
+                .iter()
+                .rposition(|&base| base as usize <= dist)
+                .unwrap();
+            out.code(code as u32, 5);
+            out.bits(
+                (dist - DIST_BASE[code] as usize) as u32,
+                DIST_EXTRA[code] as u32,
+            );
+
+            for j in i..i + len {
+                insert(j, &mut head, &mut prev);
+            }
+            i += len;
+        } else {
+            let (bits, bit_len) = fixed_literal_code(data[i] as u16);
+            out.code(bits, bit_len);
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+
+    let (bits, bit_len) = fixed_literal_code(256);
+    out.code(bits, bit_len);
+    out.finish()
+}
+
+/// Why `inflate` rejected a compressed stream.
+#[derive(Debug)]
+pub(super) enum InflateError {
+    UnexpectedEnd,
+    InvalidBlockType,
+    StoredLengthMismatch,
+    InvalidCodeLengths,
+    InvalidCode,
+    DistanceTooFar { distance: usize, available: usize },
+    OutputTooLarge { limit: usize },
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InflateError::UnexpectedEnd => write!(f, "stream ends in the middle of a block"),
+            InflateError::InvalidBlockType => write!(f, "invalid block type"),
+            InflateError::StoredLengthMismatch => {
+                write!(f, "stored block length doesn't match its complement")
+            }
+            InflateError::InvalidCodeLengths => write!(f, "invalid Huffman code lengths"),
+            InflateError::InvalidCode => write!(f, "invalid Huffman code"),
+            InflateError::DistanceTooFar {
+                distance,
+                available,
+            } => write!(
+                f,
+                "match distance {distance} reaches before the {available} bytes produced"
+            ),
+            InflateError::OutputTooLarge { limit } => {
+                write!(f, "output exceeds the declared {limit} bytes")
+            }
+        }
+    }
+}
+
+/// Reads bits LSB first from a DEFLATE stream.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .bytes
+                .get(self.bit / 8)
+                .ok_or(InflateError::UnexpectedEnd)?;
+            value |= ((byte >> (self.bit % 8)) as u32 & 1) << i;
+            self.bit += 1;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.bit = self.bit.div_ceil(8) * 8;
+    }
+}
+
+/// A canonical Huffman code: how many codes have each bit length, and the symbols in
+/// code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    pub fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+
+        // Codes of each length must fit in the space the shorter ones leave
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::InvalidCode)
+    }
+}
+
+/// Decompresses a DEFLATE stream, failing rather than producing more than `limit` bytes.
+pub(super) fn inflate(bytes: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    let mut reader = BitReader { bytes, bit: 0 };
+    let mut out = Vec::new();
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let len = reader.bits(16)?;
+                if len != !reader.bits(16)? & 0xffff {
+                    return Err(InflateError::StoredLengthMismatch);
+                }
+                if out.len() + len as usize > limit {
+                    return Err(InflateError::OutputTooLarge { limit });
+                }
+                for _ in 0..len {
+                    out.push(reader.bits(8)? as u8);
+                }
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                inflate_block(&mut reader, &literals, &distances, &mut out, limit)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &literals, &distances, &mut out, limit)?;
+            }
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (len, repeat) = match code_lengths.decode(reader)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or(InflateError::InvalidCodeLengths)?;
+                (previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        if lengths.len() + repeat as usize > literal_count + distance_count {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        lengths.extend((0..repeat).map(|_| len));
+    }
+
+    // Without an end-of-block code the block could never finish
+    if lengths[256] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals)?, Huffman::new(distances)?))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let len = if symbol < 256 {
+            1
+        } else {
+            let code = symbol - 257;
+            if code >= LENGTH_BASE.len() {
+                return Err(InflateError::InvalidCode);
+            }
+            LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA[code] as u32)? as usize
+        };
+        if out.len() + len > limit {
+            return Err(InflateError::OutputTooLarge { limit });
+        }
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+
+        let code = distances.decode(reader)? as usize;
+        if code >= DIST_BASE.len() {
+            return Err(InflateError::InvalidCode);
+        }
+        let distance = DIST_BASE[code] as usize + reader.bits(DIST_EXTRA[code] as u32)? as usize;
+        if distance > out.len() {
+            return Err(InflateError::DistanceTooFar {
+                distance,
+                available: out.len(),
+            });
+        }
+        // Byte by byte, since a match may overlap the bytes it produces
+        let start = out.len() - distance;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text that repeats near and far, then bytes that hardly repeat at all.
+    fn sample() -> Vec<u8> {
+        let mut data = b"constants constants constants, ".repeat(300);
+        let mut state = 0x2545_f491_u32;
+        data.extend((0..5000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }));
+        data
+    }
+
+    #[test]
+    fn every_level_round_trips() {
+        let data = sample();
+        let fast = deflate(&data, 1);
+        let best = deflate(&data, 9);
+        for compressed in [&fast, &best] {
+            assert_eq!(inflate(compressed, data.len()).unwrap(), data);
+        }
+        assert!(best.len() <= fast.len());
+        assert!(fast.len() < data.len());
+        for data in [&b""[..], b"a", b"ab", b"abc", &[0; 1000][..]] {
+            assert_eq!(inflate(&deflate(data, 5), data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn inflate_reads_streams_it_did_not_write() {
+        // A stored block, then "hello" under the fixed codes as zlib writes it
+        let stored = [0x01, 0x05, 0x00, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o'];
+        assert_eq!(inflate(&stored, 5).unwrap(), b"hello");
+        let fixed = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        assert_eq!(inflate(&fixed, 5).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn corrupt_streams_and_overlong_output_fail() {
+        let error = |bytes: &[u8], limit| inflate(bytes, limit).unwrap_err();
+        assert!(matches!(error(&[0x07], 10), InflateError::InvalidBlockType));
+        let e = error(&[0x01, 0x05, 0x00, 0xfa, 0xfe, b'h'], 10);
+        assert!(matches!(e, InflateError::StoredLengthMismatch), "{e}");
+        let compressed = deflate(&sample(), 6);
+        let e = error(&compressed[..compressed.len() / 2], usize::MAX);
+        assert!(matches!(e, InflateError::UnexpectedEnd), "{e}");
+
+        let e = error(&compressed, sample().len() - 1);
+        assert!(matches!(e, InflateError::OutputTooLarge { .. }), "{e}");
+        let e = error(
+            &[0x01, 0x05, 0x00, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o'],
+            4,
+        );
+        assert!(
+            matches!(e, InflateError::OutputTooLarge { limit: 4 }),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn bodies_must_expand_to_their_declared_size() {
+        let mut pool = ObjectPool::new();
+        let word = Value::from_string("compressible", &mut pool);
+        let values = [Value::from_list(vec![word; 50], &mut pool), Value::Int(9)];
+        let bytes = |compression| {
+            let options = WriteOptions {
+                compression,
+                ..WriteOptions::default()
+            };
+            serialize_to_vec(&values, &options).unwrap()
+        };
+        let load = |bytes: &[u8]| {
+            let options = LoadOptions {
+                skip_checksum: true,
+                ..LoadOptions::default()
+            };
+            deserialize_from_slice(bytes, &mut ObjectPool::new(), &options)
+        };
+        let plain = bytes(None);
+        let declared = u32::from_be_bytes(plain[8..12].try_into().unwrap());
+        for level in [1, 9] {
+            let compressed = bytes(Some(level));
+            assert!(compressed.len() < plain.len());
+            assert_eq!(compressed[12..16], declared.to_be_bytes());
+            assert_eq!(load(&compressed).unwrap(), values);
+
+            for wrong in [declared - 1, declared + 1] {
+                let mut lying = compressed.clone();
+                lying[12..16].copy_from_slice(&wrong.to_be_bytes());
+                let e = load(&lying).unwrap_err();
+                let message = match wrong < declared {
+                    true => format!("output exceeds the declared {wrong} bytes"),
+                    false => {
+                        format!("expanded to {declared} bytes instead of the declared {wrong}")
+                    }
+                };
+                assert_eq!(
+                    e.to_string(),
+                    format!("Compressed body is corrupt: {message}")
+                );
+            }
+        }
+    }
+}
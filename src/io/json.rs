@@ -0,0 +1,1083 @@
+use super::*;
+
+/// Renders `values` as a JSON array with one constant per line, for tools that can't read
+/// the binary format. Every value is an object whose `"type"` names its variant in
+/// snake_case, with the variant's fields beside it and nested values in the same form.
+/// Functions carry their code both as bytes and as `"disassembly"` lines. Non-finite
+/// floats are the strings `"NaN"`, `"inf"` and `"-inf"`.
+pub fn to_json(values: &[Value]) -> String {
+    if values.is_empty() {
+        return "[]".to_string();
+    }
+
+    let lines = values
+        .iter()
+        .map(|value| format!("  {}", json_value(value)))
+        .collect::<Vec<_>>();
+    format!("[\n{}\n]", lines.join(",\n"))
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::Int(i) => json_typed("int", vec![("value", i.to_string())]),
+        Value::Bool(b) => json_typed("bool", vec![("value", b.to_string())]),
+        Value::Float(x) => json_typed("float", vec![("value", json_float(*x))]),
+        Value::Long(l) => json_typed("long", vec![("value", l.to_string())]),
+        Value::UInt(u) => json_typed("uint", vec![("value", u.to_string())]),
+        Value::Char(c) => json_typed("char", vec![("value", json_string(&c.to_string()))]),
+        Value::Nil => json_typed("nil", vec![]),
+        Value::Byte(b) => json_typed("byte", vec![("value", b.to_string())]),
+        Value::Short(s) => json_typed("short", vec![("value", s.to_string())]),
+        Value::Decimal { mantissa, scale } => json_typed(
+            "decimal",
+            vec![
+                ("mantissa", mantissa.to_string()),
+                ("scale", scale.to_string()),
+            ],
+        ),
+        Value::Timestamp { seconds, nanos } => json_typed(
+            "timestamp",
+            vec![
+                ("seconds", seconds.to_string()),
+                ("nanos", nanos.to_string()),
+            ],
+        ),
+        Value::Uuid(_) => json_typed("uuid", vec![("value", json_string(&value.to_string()))]),
+        Value::Optional(inner) => json_typed(
+            "optional",
+            vec![(
+                "value",
+                inner.as_deref().map_or(JSON_NULL.to_string(), json_value),
+            )],
+        ),
+        Value::Ref(index) => json_typed("ref", vec![("index", index.to_string())]),
+        Value::Complex { re, im } => json_typed(
+            "complex",
+            vec![("re", json_float(*re)), ("im", json_float(*im))],
+        ),
+        Value::Color { r, g, b, a } => json_typed(
+            "color",
+            vec![
+                ("r", r.to_string()),
+                ("g", g.to_string()),
+                ("b", b.to_string()),
+                ("a", a.to_string()),
+            ],
+        ),
+        Value::Object(o) => json_object_value(o),
+    }
+}
+
+fn json_object_value(object: &Object) -> String {
+    match object {
+        Object::String(s) => json_typed("string", vec![("value", json_string(s))]),
+        Object::Function {
+            identifier,
+            param_count,
+            flags,
+            local_count,
+            code,
+            constants,
+            lines,
+            upvalues,
+            defaults,
+            param_types,
+            return_type,
+            source,
+        } => {
+            let disassembly = Instructions::new(code).map(|(offset, instruction)| {
+                json_string(&format!(
+                    "{offset:04} {}",
+                    decoded_text(offset, &instruction)
+                ))
+            });
+            let lines = lines.as_ref().map_or(JSON_NULL.to_string(), |lines| {
+                json_array(
+                    lines
+                        .iter()
+                        .map(|(offset, line)| json_array([offset.to_string(), line.to_string()])),
+                )
+            });
+            let upvalues = upvalues.iter().map(|upvalue| {
+                json_fields(&[
+                    ("is_local", upvalue.is_local.to_string()),
+                    ("index", upvalue.index.to_string()),
+                ])
+            });
+
+            json_typed(
+                "function",
+                vec![
+                    ("identifier", json_string(identifier)),
+                    ("param_count", param_count.to_string()),
+                    ("variadic", (flags & FUNCTION_VARIADIC != 0).to_string()),
+                    ("local_count", local_count.to_string()),
+                    ("code", json_array(code.iter().map(u8::to_string))),
+                    ("disassembly", json_array(disassembly)),
+                    ("constants", json_values(constants)),
+                    ("lines", lines),
+                    ("upvalues", json_array(upvalues)),
+                    ("defaults", json_values(defaults)),
+                    (
+                        "param_types",
+                        json_array(param_types.iter().map(|tag| json_string(tag.name()))),
+                    ),
+                    ("return_type", json_string(return_type.name())),
+                    (
+                        "source",
+                        source.as_deref().map_or(JSON_NULL.to_string(), json_string),
+                    ),
+                ],
+            )
+        }
+        Object::List(items) => json_typed("list", vec![("items", json_values(items))]),
+        Object::Tuple(items) => json_typed("tuple", vec![("items", json_values(items))]),
+        Object::Map(entries) => {
+            let entries = entries.iter().map(|(key, value)| {
+                json_fields(&[("key", json_value(key)), ("value", json_value(value))])
+            });
+            json_typed("map", vec![("entries", json_array(entries))])
+        }
+        Object::Bytes(bytes) => json_typed(
+            "bytes",
+            vec![("value", json_array(bytes.iter().map(u8::to_string)))],
+        ),
+        Object::EnumVariant {
+            type_name,
+            variant,
+            payload,
+        } => json_typed(
+            "enum_variant",
+            vec![
+                ("type_name", json_string(type_name)),
+                ("variant", json_string(variant)),
+                (
+                    "payload",
+                    payload.as_deref().map_or(JSON_NULL.to_string(), json_value),
+                ),
+            ],
+        ),
+        Object::Record { type_name, fields } => {
+            let fields = fields.iter().map(|(name, value)| {
+                json_fields(&[("name", json_string(name)), ("value", json_value(value))])
+            });
+            json_typed(
+                "record",
+                vec![
+                    ("type_name", json_string(type_name)),
+                    ("fields", json_array(fields)),
+                ],
+            )
+        }
+        Object::BigInt { .. } => {
+            json_typed("big_int", vec![("value", json_string(&object.to_string()))])
+        }
+        Object::Set(items) => json_typed("set", vec![("items", json_values(items))]),
+        Object::Pair(car, cdr) => {
+            // A chain is rendered cell by cell rather than recursing along its cdrs, then
+            // closed once its tail is in
+            let mut json = String::new();
+            let (mut car, mut cdr, mut cells) = (car, cdr, 0);
+            loop {
+                json += &format!(r#"{{"type": "pair", "car": {}, "cdr": "#, json_value(car));
+                cells += 1;
+                match cdr.as_pair() {
+                    Some(next) => (car, cdr) = next,
+                    None => break,
+                }
+            }
+            json + &json_value(cdr) + &"}".repeat(cells)
+        }
+        Object::Symbol(name) => json_typed("symbol", vec![("name", json_string(name))]),
+        Object::FloatArray { rows, cols, data } => json_typed(
+            "float_array",
+            vec![
+                ("rows", rows.to_string()),
+                ("cols", cols.to_string()),
+                ("data", json_array(data.iter().map(|x| json_float(*x)))),
+            ],
+        ),
+        Object::Regex { pattern, flags } => {
+            let letters = REGEX_FLAGS
+                .iter()
+                .filter(|(bit, _)| flags & bit != 0)
+                .map(|(_, letter)| letter)
+                .collect::<String>();
+            json_typed(
+                "regex",
+                vec![
+                    ("pattern", json_string(pattern)),
+                    ("flags", json_string(&letters)),
+                ],
+            )
+        }
+        Object::NativeRef {
+            identifier,
+            param_count,
+        } => json_typed(
+            "native_ref",
+            vec![
+                ("identifier", json_string(identifier)),
+                ("param_count", param_count.to_string()),
+            ],
+        ),
+        Object::Class { name, methods } => {
+            let methods = methods.iter().map(|(name, method)| {
+                json_fields(&[
+                    ("name", json_string(name)),
+                    ("function", json_object_value(method)),
+                ])
+            });
+            json_typed(
+                "class",
+                vec![
+                    ("name", json_string(name)),
+                    ("methods", json_array(methods)),
+                ],
+            )
+        }
+        Object::Module { name, constants } => json_typed(
+            "module",
+            vec![
+                ("name", json_string(name)),
+                ("constants", json_values(constants)),
+            ],
+        ),
+    }
+}
+
+const JSON_NULL: &str = "null";
+
+/// A JSON object with `"type": name` ahead of `fields`, each already rendered.
+fn json_typed(name: &str, mut fields: Vec<(&str, String)>) -> String {
+    fields.insert(0, ("type", json_string(name)));
+    json_fields(&fields)
+}
+
+fn json_fields(fields: &[(&str, String)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("{}: {value}", json_string(key)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn json_array(items: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(", "))
+}
+
+fn json_values(values: &[Value]) -> String {
+    json_array(values.iter().map(json_value))
+}
+
+/// Debug formatting keeps the `.0` of whole floats and round-trips every finite value.
+fn json_float(x: f64) -> String {
+    if x.is_finite() {
+        format!("{x:?}")
+    } else {
+        json_string(&x.to_string())
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Why `from_json` rejected a document.
+#[derive(Debug)]
+pub enum JsonError {
+    /// The text isn't JSON; `offset` is a byte offset into it.
+    Syntax { offset: usize, message: String },
+    /// The JSON doesn't follow the `to_json` schema at `path`, e.g. `constants[3].code[7]`.
+    Schema { path: String, message: String },
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::Syntax { offset, message } => write!(f, "byte {offset}: {message}"),
+            JsonError::Schema { path, message } => write!(f, "{path}: {message}"),
+        }
+    }
+}
+
+fn schema_error<T>(path: &str, message: impl Into<String>) -> Result<T, JsonError> {
+    Err(JsonError::Schema {
+        path: path.to_string(),
+        message: message.into(),
+    })
+}
+
+/// A parsed JSON document. Numbers keep their text so each field can parse it into its
+/// own type without a detour through `f64`.
+#[derive(Debug, PartialEq)]
+pub(super) enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Arrays and objects one constant may open per level it nests, at most: a map's entries,
+/// each entry, and the value in it.
+pub(super) const JSON_LEVELS_PER_VALUE: usize = 3;
+
+struct JsonParser<'a> {
+    text: &'a str,
+    pos: usize,
+    /// Arrays and objects open around the position.
+    depth: usize,
+    max_depth: usize,
+}
+
+impl JsonParser<'_> {
+    /// Parses `text` with arrays and objects nested no deeper than constants of
+    /// `max_depth` levels need, before anything recurses far enough to exhaust the stack.
+    fn parse(text: &str, max_depth: usize) -> Result<Json, JsonError> {
+        let mut parser = JsonParser {
+            text,
+            pos: 0,
+            depth: 0,
+            max_depth: max_depth
+                .saturating_mul(JSON_LEVELS_PER_VALUE)
+                .saturating_add(1),
+        };
+        let json = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return parser.error("trailing characters after the document");
+        }
+        Ok(json)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, JsonError> {
+        Err(JsonError::Syntax {
+            offset: self.pos,
+            message: message.to_string(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), JsonError> {
+        if !self.text[self.pos..].starts_with(token) {
+            return self.error(&format!("expected '{token}'"));
+        }
+        self.pos += token.len();
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.nested(Self::array),
+            Some('{') => self.nested(Self::object),
+            Some('-' | '0'..='9') => self.number(),
+            Some(_) => self.error("expected a value"),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    /// Parses an array or object with `parse`, one level deeper.
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Json, JsonError>,
+    ) -> Result<Json, JsonError> {
+        if self.depth == self.max_depth {
+            return self.error(&format!(
+                "arrays and objects nest deeper than {} levels",
+                self.max_depth
+            ));
+        }
+        self.depth += 1;
+        let json = parse(self);
+        self.depth -= 1;
+        json
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        let rest = &self.text[start..];
+        let len = rest
+            .find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+            .unwrap_or(rest.len());
+        let number = &rest[..len];
+        // Integers parse as written, so only the full grammar needs checking here
+        if number.parse::<f64>().is_err() || number.starts_with('+') || number.ends_with('.') {
+            return self.error(&format!("invalid number '{number}'"));
+        }
+        self.pos += len;
+        Ok(Json::Number(number.to_string()))
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return self.error("unterminated string");
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => out.push(self.escape()?),
+                c if (c as u32) < 0x20 => {
+                    self.pos -= 1;
+                    return self.error("control character in string");
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, JsonError> {
+        let Some(c) = self.peek() else {
+            return self.error("unterminated escape");
+        };
+        self.pos += c.len_utf8();
+        Ok(match c {
+            '"' => '"',
+            '\\' => '\\',
+            '/' => '/',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let high = self.hex_unit()?;
+                if !(0xd800..0xdc00).contains(&high) {
+                    return char::from_u32(high as u32)
+                        .map_or_else(|| self.error("unpaired surrogate escape"), Ok);
+                }
+                self.expect("\\u")?;
+                let low = self.hex_unit()?;
+                char::decode_utf16([high, low])
+                    .next()
+                    .and_then(Result::ok)
+                    .map_or_else(|| self.error("unpaired surrogate escape"), Ok)?
+            }
+            _ => {
+                self.pos -= c.len_utf8();
+                return self.error("invalid escape");
+            }
+        })
+    }
+
+    fn hex_unit(&mut self) -> Result<u16, JsonError> {
+        let digits = self.text.get(self.pos..self.pos + 4).unwrap_or_default();
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return self.error("expected four hex digits after \\u");
+        }
+        self.pos += 4;
+        Ok(u16::from_str_radix(digits, 16).unwrap())
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        self.expect("{")?;
+        let mut fields = Vec::<(String, Json)>::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key_offset = self.pos;
+            let key = self.string()?;
+            if fields.iter().any(|(existing, _)| *existing == key) {
+                self.pos = key_offset;
+                return self.error(&format!("duplicate key '{key}'"));
+            }
+            self.skip_whitespace();
+            self.expect(":")?;
+            fields.push((key, self.value()?));
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+    }
+}
+
+/// The fields of a JSON object that must hold only the keys in `allowed`.
+struct JsonFields<'a, 'p> {
+    path: &'p str,
+    fields: &'a [(String, Json)],
+}
+
+impl<'a, 'p> JsonFields<'a, 'p> {
+    pub fn new(json: &'a Json, path: &'p str, allowed: &[&str]) -> Result<Self, JsonError> {
+        let Json::Object(fields) = json else {
+            return schema_error(path, "expected an object");
+        };
+        if let Some((key, _)) = fields.iter().find(|(key, _)| !allowed.contains(&&**key)) {
+            return schema_error(&format!("{path}.{key}"), "unknown key");
+        }
+        Ok(JsonFields { path, fields })
+    }
+
+    fn optional(&self, key: &str) -> Option<(&'a Json, String)> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| (value, format!("{}.{key}", self.path)))
+    }
+
+    fn get(&self, key: &str) -> Result<(&'a Json, String), JsonError> {
+        self.optional(key).map_or_else(
+            || schema_error(self.path, format!("missing key '{key}'")),
+            Ok,
+        )
+    }
+
+    fn int<T: std::str::FromStr>(&self, key: &str) -> Result<T, JsonError> {
+        let (json, path) = self.get(key)?;
+        json_to_int(json, &path)
+    }
+
+    fn str(&self, key: &str) -> Result<&'a str, JsonError> {
+        let (json, path) = self.get(key)?;
+        json_to_str(json, &path)
+    }
+
+    fn values(
+        &self,
+        key: &str,
+        pool: &mut ObjectPool,
+        limit: usize,
+    ) -> Result<Vec<Value>, JsonError> {
+        let (json, path) = self.get(key)?;
+        json_to_values(json, &path, pool, limit)
+    }
+
+    fn value(&self, key: &str, pool: &mut ObjectPool, limit: usize) -> Result<Value, JsonError> {
+        let (json, path) = self.get(key)?;
+        value_from_json(json, &path, pool, limit)
+    }
+}
+
+fn json_to_int<T: std::str::FromStr>(json: &Json, path: &str) -> Result<T, JsonError> {
+    match json {
+        Json::Number(number) => number.parse().map_or_else(
+            |_| {
+                schema_error(
+                    path,
+                    format!("expected an integer this field can hold, found {number}"),
+                )
+            },
+            Ok,
+        ),
+        _ => schema_error(path, "expected an integer"),
+    }
+}
+
+fn json_to_float(json: &Json, path: &str) -> Result<f64, JsonError> {
+    match json {
+        Json::Number(number) => Ok(number.parse().unwrap()),
+        Json::String(s) if matches!(s.as_str(), "NaN" | "inf" | "-inf") => Ok(s.parse().unwrap()),
+        _ => schema_error(path, "expected a number, \"NaN\", \"inf\" or \"-inf\""),
+    }
+}
+
+fn json_to_str<'a>(json: &'a Json, path: &str) -> Result<&'a str, JsonError> {
+    match json {
+        Json::String(s) => Ok(s),
+        _ => schema_error(path, "expected a string"),
+    }
+}
+
+fn json_to_bool(json: &Json, path: &str) -> Result<bool, JsonError> {
+    match json {
+        Json::Bool(b) => Ok(*b),
+        _ => schema_error(path, "expected true or false"),
+    }
+}
+
+fn json_to_array<'a>(json: &'a Json, path: &str) -> Result<&'a [Json], JsonError> {
+    match json {
+        Json::Array(items) => Ok(items),
+        _ => schema_error(path, "expected an array"),
+    }
+}
+
+/// Converts each element of an array with `convert`, giving it the path `path[i]`.
+fn json_map_array<T>(
+    json: &Json,
+    path: &str,
+    mut convert: impl FnMut(&Json, &str) -> Result<T, JsonError>,
+) -> Result<Vec<T>, JsonError> {
+    json_to_array(json, path)?
+        .iter()
+        .enumerate()
+        .map(|(i, item)| convert(item, &format!("{path}[{i}]")))
+        .collect()
+}
+
+/// Builds each value in the array `json`, none of them nesting deeper than `limit` levels.
+pub(super) fn json_to_values(
+    json: &Json,
+    path: &str,
+    pool: &mut ObjectPool,
+    limit: usize,
+) -> Result<Vec<Value>, JsonError> {
+    json_map_array(json, path, |item, path| {
+        value_from_json(item, path, pool, limit)
+    })
+}
+
+/// Builds constants from a JSON array in the schema `to_json` writes. Keys the schema
+/// doesn't define are errors, as is a function `"disassembly"` that doesn't match its code,
+/// or a value nesting deeper than `LoadLimits::max_depth` allows by default.
+pub fn from_json(text: &str, pool: &mut ObjectPool) -> Result<Vec<Value>, JsonError> {
+    from_json_within(text, pool, LoadLimits::DEFAULT.max_depth)
+}
+
+/// `from_json` with constants nesting at most `max_depth` levels.
+pub(super) fn from_json_within(
+    text: &str,
+    pool: &mut ObjectPool,
+    max_depth: usize,
+) -> Result<Vec<Value>, JsonError> {
+    json_to_values(
+        &JsonParser::parse(text, max_depth)?,
+        "constants",
+        pool,
+        max_depth,
+    )
+}
+
+pub(super) fn pooled(object: Object, pool: &mut ObjectPool) -> Value {
+    let v = Rc::new(object);
+    pool.push(Rc::clone(&v));
+    Value::Object(v)
+}
+
+/// The name in the `"type"` key of the object `json`.
+fn json_type<'a>(json: &'a Json, path: &str) -> Result<&'a str, JsonError> {
+    let Json::Object(members) = json else {
+        return schema_error(path, "expected an object");
+    };
+    let Some((_, Json::String(name))) = members.iter().find(|(key, _)| key == "type") else {
+        return schema_error(path, "missing string key 'type'");
+    };
+    Ok(name)
+}
+
+/// Builds the value `json` describes, counting it and each value inside it against
+/// `limit` as `LoadLimits::max_depth` counts them.
+fn value_from_json(
+    json: &Json,
+    path: &str,
+    pool: &mut ObjectPool,
+    limit: usize,
+) -> Result<Value, JsonError> {
+    let Some(limit) = limit.checked_sub(1) else {
+        return schema_error(path, "nests deeper than the depth limit");
+    };
+    let name = json_type(json, path)?;
+    let fields = |allowed: &[&str]| {
+        let keys = [&["type"][..], allowed].concat();
+        JsonFields::new(json, path, &keys)
+    };
+
+    Ok(match name {
+        "optional" => {
+            let (json, path) = fields(&["value"])?.get("value")?;
+            match json {
+                Json::Null => Value::Optional(None),
+                json => Value::Optional(Some(Box::new(value_from_json(json, &path, pool, limit)?))),
+            }
+        }
+        "function" => function_from_json(fields(FUNCTION_JSON_KEYS)?, pool, limit)?,
+        "list" => {
+            let items = fields(&["items"])?.values("items", pool, limit)?;
+            Value::from_list(items, pool)
+        }
+        "tuple" => {
+            let items = fields(&["items"])?.values("items", pool, limit)?;
+            Value::from_tuple(items, pool)
+        }
+        "map" => {
+            let (json, path) = fields(&["entries"])?.get("entries")?;
+            let entries = json_map_array(json, &path, |entry, path| {
+                let f = JsonFields::new(entry, path, &["key", "value"])?;
+                Ok((f.value("key", pool, limit)?, f.value("value", pool, limit)?))
+            })?;
+            Value::from_map(entries, pool)
+        }
+        "enum_variant" => {
+            let f = fields(&["type_name", "variant", "payload"])?;
+            let payload = match f.get("payload")? {
+                (Json::Null, _) => None,
+                (json, path) => Some(Box::new(value_from_json(json, &path, pool, limit)?)),
+            };
+            pooled(
+                Object::EnumVariant {
+                    type_name: f.str("type_name")?.to_string(),
+                    variant: f.str("variant")?.to_string(),
+                    payload,
+                },
+                pool,
+            )
+        }
+        "record" => {
+            let f = fields(&["type_name", "fields"])?;
+            let (json, path) = f.get("fields")?;
+            let record_fields = json_map_array(json, &path, |field, path| {
+                let f = JsonFields::new(field, path, &["name", "value"])?;
+                Ok((f.str("name")?.to_string(), f.value("value", pool, limit)?))
+            })?;
+            pooled(
+                Object::Record {
+                    type_name: f.str("type_name")?.to_string(),
+                    fields: record_fields,
+                },
+                pool,
+            )
+        }
+        "set" => {
+            let items = fields(&["items"])?.values("items", pool, limit)?;
+            Value::from_set(items, pool)
+        }
+        "pair" => {
+            // A chain is walked along its cdrs rather than recursed into, and nests no
+            // deeper for its length, as in the binary format
+            let mut cars = Vec::new();
+            let (mut cell, mut cell_path) = (json, path.to_string());
+            let tail = loop {
+                let f = JsonFields::new(cell, &cell_path, &["type", "car", "cdr"])?;
+                cars.push(f.value("car", pool, limit)?);
+                let (cdr, cdr_path) = f.get("cdr")?;
+                if json_type(cdr, &cdr_path)? != "pair" {
+                    break value_from_json(cdr, &cdr_path, pool, limit)?;
+                }
+                (cell, cell_path) = (cdr, cdr_path);
+            };
+
+            let mut value = tail;
+            for car in cars.into_iter().rev() {
+                value = Value::from_pair(car, value, pool);
+            }
+            value
+        }
+        "class" => {
+            let f = fields(&["name", "methods"])?;
+            let (json, path) = f.get("methods")?;
+            let methods = json_map_array(json, &path, |method, path| {
+                let f = JsonFields::new(method, path, &["name", "function"])?;
+                let (function, function_path) = f.get("function")?;
+                let function_fields =
+                    JsonFields::new(function, &function_path, FUNCTION_JSON_KEYS)?;
+                if function_fields.str("type")? != "function" {
+                    return schema_error(&function_path, "method is not a function");
+                }
+                let Value::Object(function) = function_from_json(function_fields, pool, limit)?
+                else {
+                    unreachable!("functions are objects");
+                };
+                Ok((f.str("name")?.to_string(), function))
+            })?;
+            pooled(
+                Object::Class {
+                    name: f.str("name")?.to_string(),
+                    methods,
+                },
+                pool,
+            )
+        }
+        "module" => {
+            let f = fields(&["name", "constants"])?;
+            let constants = f.values("constants", pool, limit)?;
+            pooled(
+                Object::Module {
+                    name: f.str("name")?.to_string(),
+                    constants,
+                },
+                pool,
+            )
+        }
+        _ => scalar_from_json(name, json, path, pool)?,
+    })
+}
+
+/// Builds a value of a type that holds no others, apart from `value_from_json` to keep
+/// the frame each level of nesting takes small.
+fn scalar_from_json(
+    name: &str,
+    json: &Json,
+    path: &str,
+    pool: &mut ObjectPool,
+) -> Result<Value, JsonError> {
+    let fields = |allowed: &[&str]| {
+        let keys = [&["type"][..], allowed].concat();
+        JsonFields::new(json, path, &keys)
+    };
+    let checked = |result: Result<Value, String>| result.map_or_else(|e| schema_error(path, e), Ok);
+
+    Ok(match name {
+        "int" => Value::Int(fields(&["value"])?.int("value")?),
+        "bool" => {
+            let (json, path) = fields(&["value"])?.get("value")?;
+            Value::Bool(json_to_bool(json, &path)?)
+        }
+        "float" => {
+            let (json, path) = fields(&["value"])?.get("value")?;
+            Value::Float(json_to_float(json, &path)?)
+        }
+        "long" => Value::Long(fields(&["value"])?.int("value")?),
+        "uint" => Value::UInt(fields(&["value"])?.int("value")?),
+        "char" => {
+            let f = fields(&["value"])?;
+            let s = f.str("value")?;
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Value::Char(c),
+                _ => return schema_error(&format!("{path}.value"), "expected one character"),
+            }
+        }
+        "nil" => {
+            fields(&[])?;
+            Value::Nil
+        }
+        "byte" => Value::Byte(fields(&["value"])?.int("value")?),
+        "short" => Value::Short(fields(&["value"])?.int("value")?),
+        "decimal" => {
+            let f = fields(&["mantissa", "scale"])?;
+            Value::Decimal {
+                mantissa: f.int("mantissa")?,
+                scale: f.int("scale")?,
+            }
+        }
+        "timestamp" => {
+            let f = fields(&["seconds", "nanos"])?;
+            checked(Value::from_timestamp(f.int("seconds")?, f.int("nanos")?))?
+        }
+        "uuid" => checked(Value::from_uuid_str(fields(&["value"])?.str("value")?))?,
+        "ref" => Value::Ref(fields(&["index"])?.int("index")?),
+        "complex" => {
+            let f = fields(&["re", "im"])?;
+            let (re, re_path) = f.get("re")?;
+            let (im, im_path) = f.get("im")?;
+            Value::Complex {
+                re: json_to_float(re, &re_path)?,
+                im: json_to_float(im, &im_path)?,
+            }
+        }
+        "color" => {
+            let f = fields(&["r", "g", "b", "a"])?;
+            Value::Color {
+                r: f.int("r")?,
+                g: f.int("g")?,
+                b: f.int("b")?,
+                a: f.int("a")?,
+            }
+        }
+        "string" => pooled(
+            Object::String(fields(&["value"])?.str("value")?.to_string()),
+            pool,
+        ),
+        "bytes" => {
+            let (json, path) = fields(&["value"])?.get("value")?;
+            let bytes = json_map_array(json, &path, json_to_int)?;
+            Value::from_bytes(&bytes, pool)
+        }
+        "big_int" => checked(Value::from_big_int_str(
+            fields(&["value"])?.str("value")?,
+            pool,
+        ))?,
+        "symbol" => Value::from_symbol(fields(&["name"])?.str("name")?, pool),
+        "float_array" => {
+            let f = fields(&["rows", "cols", "data"])?;
+            let (json, path) = f.get("data")?;
+            let data = json_map_array(json, &path, json_to_float)?;
+            checked(Value::from_float_array(
+                f.int("rows")?,
+                f.int("cols")?,
+                data,
+                pool,
+            ))?
+        }
+        "regex" => {
+            let f = fields(&["pattern", "flags"])?;
+            checked(Value::from_regex(f.str("pattern")?, f.str("flags")?, pool))?
+        }
+        "native_ref" => {
+            let f = fields(&["identifier", "param_count"])?;
+            pooled(
+                Object::NativeRef {
+                    identifier: f.str("identifier")?.to_string(),
+                    param_count: f.int("param_count")?,
+                },
+                pool,
+            )
+        }
+        _ => return schema_error(&format!("{path}.type"), format!("unknown type '{name}'")),
+    })
+}
+
+const FUNCTION_JSON_KEYS: &[&str] = &[
+    "type",
+    "identifier",
+    "param_count",
+    "variadic",
+    "local_count",
+    "code",
+    "disassembly",
+    "constants",
+    "lines",
+    "upvalues",
+    "defaults",
+    "param_types",
+    "return_type",
+    "source",
+];
+
+/// Everything past the identifier, parameter count and code may be left out, taking the
+/// same defaults as `FunctionBuilder`.
+fn function_from_json(
+    f: JsonFields,
+    pool: &mut ObjectPool,
+    limit: usize,
+) -> Result<Value, JsonError> {
+    let identifier = f.str("identifier")?;
+    check_identifier(identifier).map_or_else(|e| schema_error(f.path, e), Ok)?;
+    let param_count = f.int("param_count")?;
+    let (json, path) = f.get("code")?;
+    let code = json_map_array(json, &path, json_to_int)?;
+    let mut builder = FunctionBuilder::new(identifier, param_count, &code);
+
+    if let Some((json, path)) = f.optional("disassembly") {
+        let disassembly = json_map_array(json, &path, |line, path| {
+            json_to_str(line, path).map(str::to_string)
+        })?;
+        let expected = Instructions::new(&code)
+            .map(|(offset, instruction)| {
+                format!("{offset:04} {}", decoded_text(offset, &instruction))
+            })
+            .collect::<Vec<_>>();
+        if disassembly != expected {
+            return schema_error(&path, "doesn't match the disassembly of 'code'");
+        }
+    }
+    if let Some((json, path)) = f.optional("variadic") {
+        if json_to_bool(json, &path)? {
+            builder = builder.variadic();
+        }
+    }
+    if f.optional("local_count").is_some() {
+        builder = builder.local_count(f.int("local_count")?);
+    }
+    if f.optional("constants").is_some() {
+        builder = builder.constants(f.values("constants", pool, limit)?);
+    }
+    if let Some((json, path)) = f.optional("lines") {
+        if !matches!(json, Json::Null) {
+            let lines = json_map_array(json, &path, |run, path| {
+                match json_map_array(run, path, json_to_int)?.as_slice() {
+                    [offset, line] => Ok((*offset, *line)),
+                    _ => schema_error(path, "expected [offset, line]"),
+                }
+            })?;
+            builder = builder.lines(lines);
+        }
+    }
+    if let Some((json, path)) = f.optional("upvalues") {
+        let upvalues = json_map_array(json, &path, |upvalue, path| {
+            let f = JsonFields::new(upvalue, path, &["is_local", "index"])?;
+            let (is_local, is_local_path) = f.get("is_local")?;
+            Ok((json_to_bool(is_local, &is_local_path)?, f.int("index")?))
+        })?;
+        for (is_local, index) in upvalues {
+            builder = builder.upvalue(is_local, index);
+        }
+    }
+    if f.optional("defaults").is_some() {
+        let defaults = f.values("defaults", pool, limit)?;
+        check_defaults(identifier, param_count, defaults.len())
+            .map_or_else(|e| schema_error(f.path, e), Ok)?;
+        builder = builder.defaults(defaults);
+    }
+    let tag = |json: &Json, path: &str| {
+        let name = json_to_str(json, path)?;
+        TypeTag::from_name(name).map_or_else(
+            || schema_error(path, format!("unknown type tag '{name}'")),
+            Ok,
+        )
+    };
+    let param_types = match f.optional("param_types") {
+        Some((json, path)) => json_map_array(json, &path, tag)?,
+        None => Vec::new(),
+    };
+    let return_type = match f.optional("return_type") {
+        Some((json, path)) => tag(json, &path)?,
+        None => TypeTag::Any,
+    };
+    builder = builder.signature(param_types, return_type);
+    if let Some((json, path)) = f.optional("source") {
+        if !matches!(json, Json::Null) {
+            builder = builder.source(json_to_str(json, &path)?);
+        }
+    }
+
+    builder
+        .build(pool)
+        .map_or_else(|e| schema_error(f.path, e), Ok)
+}
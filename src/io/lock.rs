@@ -0,0 +1,247 @@
+use super::*;
+use std::{
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Held by readers, any number at once.
+    Shared,
+    /// Held by one writer, with no readers.
+    Exclusive,
+}
+
+/// An advisory lock on a constants file, held until dropped. It locks `PATH.lock` rather
+/// than the file itself, since an atomic save renames a new file over the old one. std
+/// takes it with `flock` on unix and `LockFileEx` on Windows. On unix the last holder
+/// removes the lock file as it lets go; elsewhere it stays for the next lock to reuse.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Waits up to `timeout` for the lock on `path`, or for as long as it takes with
+    /// `None` or a timeout too long to have a deadline, and fails with `ErrorKind::WouldBlock` if it's still held. A shared lock on
+    /// a file that doesn't exist fails with `ErrorKind::NotFound`, making no lock file
+    /// for a read that can't succeed.
+    pub fn acquire(path: &str, kind: LockKind, timeout: Option<Duration>) -> io::Result<FileLock> {
+        if kind == LockKind::Shared && !Path::new(path).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{path}' doesn't exist"),
+            ));
+        }
+        let mut lock_path = OsString::from(path);
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        // A timeout too long to reach waits without a deadline
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)?;
+            lock(&file, kind, deadline, path)?;
+            // The holder before may have removed the lock file after it was opened here,
+            // in which case another can be made and locked beside this one
+            if is_current(&file, &lock_path)? {
+                return Ok(FileLock {
+                    file,
+                    path: lock_path,
+                });
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Only a holder no one shares the lock with may remove the file; whoever is
+        // waiting on it finds it gone once they have it, and starts over
+        #[cfg(unix)]
+        if self.file.try_lock().is_ok() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Takes `kind` of lock on `file`, trying until `deadline`, or waiting on it without one.
+fn lock(file: &File, kind: LockKind, deadline: Option<Instant>, path: &str) -> io::Result<()> {
+    let Some(deadline) = deadline else {
+        return match kind {
+            LockKind::Shared => file.lock_shared(),
+            LockKind::Exclusive => file.lock(),
+        };
+    };
+    loop {
+        let locked = match kind {
+            LockKind::Shared => file.try_lock_shared(),
+            LockKind::Exclusive => file.try_lock(),
+        };
+        match locked {
+            Ok(()) => return Ok(()),
+            Err(fs::TryLockError::WouldBlock) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(fs::TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("'{path}' is locked by another process"),
+                ))
+            }
+            Err(fs::TryLockError::Error(e)) => return Err(e),
+        }
+    }
+}
+
+/// Whether `file` is still the one at `path`, rather than a lock file since removed.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let held = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(held.dev() == current.dev() && held.ino() == current.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Lock files are never removed off unix, so the one opened is always current.
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Saves `values` to `path` with `save_atomic` under an exclusive `FileLock`, waiting up
+/// to `timeout` for it, or as long as it takes with `None`.
+pub fn save_locked(
+    path: &str,
+    values: &[Value],
+    options: &WriteOptions,
+    timeout: Option<Duration>,
+) -> Result<(), ConstantsError> {
+    let _lock = FileLock::acquire(path, LockKind::Exclusive, timeout)?;
+    save_atomic(path, values, options)
+}
+
+/// Loads the file at `path`, in whichever format it's in, under a shared `FileLock`
+/// taken as `save_locked` takes its own.
+pub fn load_locked(
+    path: &str,
+    pool: &mut ObjectPool,
+    options: &LoadOptions,
+    timeout: Option<Duration>,
+) -> Result<Vec<Value>, ConstantsError> {
+    // Opened first, so a missing file fails as it would unlocked; a save that lands
+    // before the lock is taken replaces the file without touching what's open here
+    let mut file = File::open(path).map_err(open_error(path))?;
+    let _lock = FileLock::acquire(path, LockKind::Shared, timeout)?;
+    let mut values = Vec::new();
+    load_in(None, &mut file, &mut values, pool, options)?;
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("constants-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn racing_locked_saves_leave_a_loadable_file() {
+        let path = temp_path("race");
+        let writers = (0..2)
+            .map(|writer| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    for round in 0..25 {
+                        let mut pool = ObjectPool::new();
+                        let values = (0..200)
+                            .map(|i| {
+                                Value::from_list(vec![Value::Int(writer * 1000 + i)], &mut pool)
+                            })
+                            .chain([Value::Int(round)])
+                            .collect::<Vec<_>>();
+                        save_locked(&path, &values, &WriteOptions::default(), None).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let reader = {
+            let path = path.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    if Path::new(&path).exists() {
+                        let values = load_locked(
+                            &path,
+                            &mut ObjectPool::new(),
+                            &LoadOptions::default(),
+                            None,
+                        )
+                        .unwrap();
+                        assert_eq!(values.len(), 201);
+                    }
+                }
+            })
+        };
+        for thread in writers.into_iter().chain([reader]) {
+            thread.join().unwrap();
+        }
+
+        let values =
+            load_locked(&path, &mut ObjectPool::new(), &LoadOptions::default(), None).unwrap();
+        assert_eq!(values[200], Value::Int(24));
+        if cfg!(unix) {
+            assert!(!Path::new(&format!("{path}.lock")).exists());
+        }
+    }
+
+    #[test]
+    fn a_held_lock_times_out_with_would_block() {
+        let path = temp_path("held");
+        let _held = FileLock::acquire(&path, LockKind::Exclusive, None).unwrap();
+        let e = FileLock::acquire(&path, LockKind::Exclusive, Some(Duration::from_millis(30)))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            e.to_string(),
+            format!("'{path}' is locked by another process")
+        );
+    }
+
+    #[test]
+    fn a_timeout_past_any_deadline_waits_without_one() {
+        let path = temp_path("forever");
+        let held = FileLock::acquire(&path, LockKind::Exclusive, Some(Duration::MAX)).unwrap();
+        drop(held);
+        save_locked(
+            &path,
+            &[Value::Nil],
+            &WriteOptions::default(),
+            Some(Duration::MAX),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn reading_a_missing_file_makes_no_lock_file() {
+        let path = temp_path("missing");
+        let e = load_locked(&path, &mut ObjectPool::new(), &LoadOptions::default(), None);
+        assert!(matches!(e, Err(ConstantsError::Open { .. })));
+        let e = FileLock::acquire(&path, LockKind::Shared, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(!Path::new(&format!("{path}.lock")).exists());
+    }
+}
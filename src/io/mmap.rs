@@ -0,0 +1,73 @@
+use super::*;
+use std::{ffi::c_void, os::fd::AsRawFd, ptr, slice};
+
+// The same on Linux, macOS and the BSDs
+const PROT_READ: i32 = 1;
+const MAP_PRIVATE: i32 = 2;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: isize,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// A private, read-only mapping of a whole file, unmapped on drop. The file must not
+/// shrink while it's mapped, or reading the lost pages faults.
+pub(super) struct Mmap {
+    addr: *mut c_void,
+    len: usize,
+}
+
+impl Mmap {
+    /// Maps all of `file`, which must then not shrink until the mapping is dropped.
+    pub(super) unsafe fn open(file: &File) -> io::Result<Mmap> {
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::other("file is too large to map"))?;
+        // Mapping nothing is an error, so an empty file gets no mapping and no bytes
+        if len == 0 {
+            return Ok(Mmap {
+                addr: ptr::null_mut(),
+                len,
+            });
+        }
+
+        // SAFETY: a fresh mapping of an open descriptor, checked for failure below
+        let addr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ,
+                MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { addr, len })
+    }
+
+    pub(super) fn bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: the mapping is `len` readable bytes and lives as long as `self`
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            // SAFETY: unmaps exactly what `open` mapped, after every borrow has ended
+            unsafe { munmap(self.addr, self.len) };
+        }
+    }
+}
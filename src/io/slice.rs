@@ -0,0 +1,118 @@
+use super::*;
+
+/// Decodes a file held in memory for `deserialize_from_slice`, behind the same parser as
+/// every other load so the two can't drift. Each read checks its range against the slice
+/// before copying out of it, so a length or offset that runs past the end fails the read
+/// with `UnexpectedEof` rather than indexing out of bounds.
+pub(super) struct SliceReader<'b> {
+    bytes: &'b [u8],
+    /// May lie past the end after a seek, as with any file, leaving nothing to read.
+    position: usize,
+}
+
+impl<'b> SliceReader<'b> {
+    pub(super) fn new(bytes: &'b [u8]) -> SliceReader<'b> {
+        SliceReader { bytes, position: 0 }
+    }
+
+    /// The bytes from the position to the end, none once the position is past it.
+    fn rest(&self) -> &'b [u8] {
+        self.bytes.get(self.position..).unwrap_or_default()
+    }
+}
+
+impl Read for SliceReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let rest = self.rest();
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.position += len;
+        Ok(len)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let Some(bytes) = self.rest().get(..buf.len()) else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+        buf.copy_from_slice(bytes);
+        self.position += buf.len();
+        Ok(())
+    }
+}
+
+impl Seek for SliceReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(offset) => (self.bytes.len(), offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        let position = (base as u64)
+            .checked_add_signed(offset)
+            .and_then(|position| usize::try_from(position).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek to before the start of the slice",
+                )
+            })?;
+        self.position = position;
+        Ok(position as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_past_the_end_fail_without_moving() {
+        let mut reader = SliceReader::new(&[1, 2, 3]);
+        let mut buf = [0; 2];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+        let e = reader.read_exact(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+    }
+
+    #[test]
+    fn seeks_past_the_end_leave_nothing_to_read() {
+        let mut reader = SliceReader::new(&[1, 2, 3]);
+        assert_eq!(reader.seek(SeekFrom::End(5)).unwrap(), 8);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+        assert!(reader.read_exact(&mut [0]).is_err());
+        assert!(reader.seek(SeekFrom::Current(-9)).is_err());
+        assert_eq!(reader.seek(SeekFrom::Current(-8)).unwrap(), 0);
+    }
+
+    #[test]
+    fn every_truncation_of_a_file_fails_to_decode() {
+        let mut pool = ObjectPool::new();
+        let values = vec![
+            Value::Int(7),
+            Value::from_string("seven", &mut pool),
+            Value::from_list(vec![Value::Nil, Value::Char('λ')], &mut pool),
+            Value::Ref(1),
+        ];
+        let options = WriteOptions {
+            index: true,
+            ..WriteOptions::default()
+        };
+        let bytes = serialize_to_vec(&values, &options).unwrap();
+        let load =
+            |bytes| deserialize_from_slice(bytes, &mut ObjectPool::new(), &LoadOptions::default());
+
+        for len in 0..bytes.len() {
+            assert!(
+                load(&bytes[..len]).is_err(),
+                "{len} of {} bytes",
+                bytes.len()
+            );
+        }
+        let mut expected = values;
+        expected[3] = expected[1].clone();
+        assert_eq!(load(&bytes).unwrap(), expected);
+    }
+}
@@ -0,0 +1,894 @@
+use super::*;
+
+/// Renders `values` in the text dump format: one stanza per constant, separated by blank
+/// lines. A value is a line naming its type, followed on the same line by any scalar
+/// payload, and the values it holds follow on lines indented two spaces further. Strings
+/// are double-quoted with `\"`, `\\`, `\n`, `\r`, `\t` and `\u{..}` escapes for other
+/// control characters. Integers are decimal, and floats are the shortest text that parses
+/// back to the same value, or `NaN`, `inf` and `-inf`. Function code is listed as
+/// assembler mnemonics with raw operands, or as hex `bytes` when it doesn't decode. A pair
+/// whose cdr is a pair lists the cars of the whole chain and then its tail.
+pub fn to_text(values: &[Value]) -> String {
+    values
+        .iter()
+        .map(|value| {
+            let mut stanza = String::new();
+            write_text_value(&mut stanza, value, 0);
+            stanza
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn text_line(out: &mut String, depth: usize, line: &str) {
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(line);
+    out.push('\n');
+}
+
+fn text_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn text_float(x: f64) -> String {
+    if x.is_finite() {
+        format!("{x:?}")
+    } else {
+        x.to_string()
+    }
+}
+
+fn text_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!(" {b:02x}"))
+        .collect::<String>()
+}
+
+fn write_text_values(out: &mut String, values: &[Value], depth: usize) {
+    for value in values {
+        write_text_value(out, value, depth);
+    }
+}
+
+fn write_text_value(out: &mut String, value: &Value, depth: usize) {
+    let line = match value {
+        Value::Int(i) => format!("int {i}"),
+        Value::Bool(b) => format!("bool {b}"),
+        Value::Float(x) => format!("float {}", text_float(*x)),
+        Value::Long(l) => format!("long {l}"),
+        Value::UInt(u) => format!("uint {u}"),
+        Value::Char(c) => format!("char {}", text_string(&c.to_string())),
+        Value::Nil => "nil".to_string(),
+        Value::Byte(b) => format!("byte {b}"),
+        Value::Short(s) => format!("short {s}"),
+        Value::Decimal { .. } => format!("decimal {value}"),
+        Value::Timestamp { seconds, nanos } => format!("timestamp {seconds} {nanos}"),
+        Value::Uuid(_) => format!("uuid {value}"),
+        Value::Optional(None) => "optional none".to_string(),
+        Value::Optional(Some(inner)) => {
+            text_line(out, depth, "optional");
+            return write_text_value(out, inner, depth + 1);
+        }
+        Value::Ref(index) => format!("ref {index}"),
+        Value::Complex { re, im } => format!("complex {} {}", text_float(*re), text_float(*im)),
+        Value::Color { r, g, b, a } => format!("color {r} {g} {b} {a}"),
+        Value::Object(o) => return write_text_object(out, o, depth),
+    };
+    text_line(out, depth, &line);
+}
+
+fn write_text_object(out: &mut String, object: &Object, depth: usize) {
+    match object {
+        Object::String(s) => text_line(out, depth, &format!("string {}", text_string(s))),
+        Object::Function {
+            identifier,
+            param_count,
+            flags,
+            local_count,
+            code,
+            constants,
+            lines,
+            upvalues,
+            defaults,
+            param_types,
+            return_type,
+            source,
+        } => {
+            let header = format!("function {} {param_count}", text_string(identifier));
+            text_line(out, depth, &header);
+            let depth = depth + 1;
+            if flags & FUNCTION_VARIADIC != 0 {
+                text_line(out, depth, "variadic");
+            }
+            if *local_count != 0 {
+                text_line(out, depth, &format!("locals {local_count}"));
+            }
+            if !param_types.is_empty() || *return_type != TypeTag::Any {
+                let params = param_types
+                    .iter()
+                    .map(|tag| format!(" {}", tag.name()))
+                    .collect::<String>();
+                let signature = format!("signature{params} -> {}", return_type.name());
+                text_line(out, depth, &signature);
+            }
+            if let Some(source) = source {
+                text_line(out, depth, &format!("source {}", text_string(source)));
+            }
+            if let Some(lines) = lines {
+                let runs = lines
+                    .iter()
+                    .map(|(offset, line)| format!(" {offset}:{line}"))
+                    .collect::<String>();
+                text_line(out, depth, &format!("lines{runs}"));
+            }
+            for upvalue in upvalues {
+                let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                text_line(out, depth, &format!("upvalue {kind} {}", upvalue.index));
+            }
+            write_text_code(out, code, depth);
+            if !constants.is_empty() {
+                text_line(out, depth, "constants");
+                write_text_values(out, constants, depth + 1);
+            }
+            if !defaults.is_empty() {
+                text_line(out, depth, "defaults");
+                write_text_values(out, defaults, depth + 1);
+            }
+        }
+        Object::List(items) => {
+            text_line(out, depth, "list");
+            write_text_values(out, items, depth + 1);
+        }
+        Object::Tuple(items) => {
+            text_line(out, depth, "tuple");
+            write_text_values(out, items, depth + 1);
+        }
+        Object::Map(entries) => {
+            text_line(out, depth, "map");
+            for (key, value) in entries {
+                text_line(out, depth + 1, "entry");
+                write_text_value(out, key, depth + 2);
+                write_text_value(out, value, depth + 2);
+            }
+        }
+        Object::Bytes(bytes) => text_line(out, depth, &format!("bytes{}", text_hex(bytes))),
+        Object::EnumVariant {
+            type_name,
+            variant,
+            payload,
+        } => {
+            let header = format!(
+                "enum_variant {} {}",
+                text_string(type_name),
+                text_string(variant)
+            );
+            text_line(out, depth, &header);
+            if let Some(payload) = payload {
+                write_text_value(out, payload, depth + 1);
+            }
+        }
+        Object::Record { type_name, fields } => {
+            text_line(out, depth, &format!("record {}", text_string(type_name)));
+            for (name, value) in fields {
+                text_line(out, depth + 1, &format!("field {}", text_string(name)));
+                write_text_value(out, value, depth + 2);
+            }
+        }
+        Object::BigInt { .. } => text_line(out, depth, &format!("big_int {object}")),
+        Object::Set(items) => {
+            text_line(out, depth, "set");
+            write_text_values(out, items, depth + 1);
+        }
+        Object::Pair(car, cdr) => {
+            // A chain is listed as its cars and then its tail, all under the one line
+            text_line(out, depth, "pair");
+            write_text_value(out, car, depth + 1);
+            let mut cdr = cdr;
+            while let Some((car, next)) = cdr.as_pair() {
+                write_text_value(out, car, depth + 1);
+                cdr = next;
+            }
+            write_text_value(out, cdr, depth + 1);
+        }
+        Object::Symbol(name) => text_line(out, depth, &format!("symbol {}", text_string(name))),
+        Object::FloatArray { rows, cols, data } => {
+            let data = data
+                .iter()
+                .map(|x| format!(" {}", text_float(*x)))
+                .collect::<String>();
+            text_line(out, depth, &format!("float_array {rows} {cols}{data}"));
+        }
+        Object::Regex { pattern, flags } => {
+            let letters = REGEX_FLAGS
+                .iter()
+                .filter(|(bit, _)| flags & bit != 0)
+                .map(|(_, letter)| letter)
+                .collect::<String>();
+            let line = format!("regex {} {}", text_string(pattern), text_string(&letters));
+            text_line(out, depth, &line);
+        }
+        Object::NativeRef {
+            identifier,
+            param_count,
+        } => {
+            let line = format!("native_ref {} {param_count}", text_string(identifier));
+            text_line(out, depth, &line);
+        }
+        Object::Class { name, methods } => {
+            text_line(out, depth, &format!("class {}", text_string(name)));
+            for (method_name, method) in methods {
+                text_line(
+                    out,
+                    depth + 1,
+                    &format!("method {}", text_string(method_name)),
+                );
+                write_text_object(out, method, depth + 2);
+            }
+        }
+        Object::Module { name, constants } => {
+            text_line(out, depth, &format!("module {}", text_string(name)));
+            write_text_values(out, constants, depth + 1);
+        }
+    }
+}
+
+/// Lists code as mnemonics when every instruction decodes, with jump targets in comments
+/// the assembler skips, and as hex bytes otherwise.
+fn write_text_code(out: &mut String, code: &[u8], depth: usize) {
+    let instructions = Instructions::new(code)
+        .map(|(offset, instruction)| instruction.ok().map(|instruction| (offset, instruction)))
+        .collect::<Option<Vec<_>>>();
+    let Some(instructions) = instructions else {
+        return text_line(out, depth, &format!("code bytes{}", text_hex(code)));
+    };
+
+    text_line(out, depth, "code");
+    for (offset, instruction) in instructions {
+        let name = instruction.op().mnemonic();
+        let line = match (
+            instruction.op().info().width,
+            instruction.jump_target(offset),
+        ) {
+            (0, _) => name.to_string(),
+            (_, Some(target)) => format!("{name} {} ; -> {target:04}", instruction.operand()),
+            (_, None) => format!("{name} {}", instruction.operand()),
+        };
+        text_line(out, depth + 1, &line);
+    }
+}
+
+/// Where `from_text` rejected its input. `line` and `column` are 1-based, and the column
+/// counts characters.
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+/// A non-blank line of a text dump, with `text` after `depth` two-space indents.
+struct TextLine<'a> {
+    number: usize,
+    depth: usize,
+    text: &'a str,
+}
+
+impl TextLine<'_> {
+    fn error<T>(&self, at: usize, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.number,
+            column: 2 * self.depth + self.text[..at].chars().count() + 1,
+            message: message.into(),
+        })
+    }
+}
+
+fn text_lines(text: &str) -> Result<Vec<TextLine<'_>>, ParseError> {
+    let mut lines = Vec::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = raw.trim_start_matches(' ');
+        let indent = raw.len() - line.len();
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |message: &str| ParseError {
+            line: i + 1,
+            column: indent + 1,
+            message: message.to_string(),
+        };
+        if line.starts_with(char::is_whitespace) {
+            return Err(error("indent with spaces only"));
+        }
+        if indent % 2 != 0 {
+            return Err(error("indentation must be a multiple of two spaces"));
+        }
+        lines.push(TextLine {
+            number: i + 1,
+            depth: indent / 2,
+            text: line.trim_end(),
+        });
+    }
+    Ok(lines)
+}
+
+/// Reads the words and quoted strings of one line in turn.
+struct TextTokens<'a> {
+    line: &'a TextLine<'a>,
+    at: usize,
+}
+
+impl<'a> TextTokens<'a> {
+    pub fn new(line: &'a TextLine<'a>) -> TextTokens<'a> {
+        TextTokens { line, at: 0 }
+    }
+
+    /// Skips spaces, returning where the next token starts if there is one.
+    fn skip_spaces(&mut self) -> Option<usize> {
+        let rest = &self.line.text[self.at..];
+        self.at += rest.len() - rest.trim_start_matches(' ').len();
+        (self.at < self.line.text.len()).then_some(self.at)
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_spaces().is_none()
+    }
+
+    pub(super) fn finish(&mut self) -> Result<(), ParseError> {
+        match self.skip_spaces() {
+            Some(at) => self
+                .line
+                .error(at, "unexpected text at the end of the line"),
+            None => Ok(()),
+        }
+    }
+
+    fn word(&mut self, what: &str) -> Result<(usize, &'a str), ParseError> {
+        let line = self.line;
+        let Some(start) = self.skip_spaces() else {
+            return line.error(self.at, format!("expected {what}"));
+        };
+        let rest = &line.text[start..];
+        if rest.starts_with('"') {
+            return line.error(start, format!("expected {what}, found a string"));
+        }
+        let len = rest.find(' ').unwrap_or(rest.len());
+        self.at = start + len;
+        Ok((start, &rest[..len]))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, ParseError> {
+        let (at, word) = self.word(what)?;
+        word.parse().map_or_else(
+            |_| {
+                self.line
+                    .error(at, format!("expected {what}, found '{word}'"))
+            },
+            Ok,
+        )
+    }
+
+    /// Reads a string quoted and escaped the way `text_string` writes it.
+    fn string(&mut self, what: &str) -> Result<String, ParseError> {
+        let line = self.line;
+        let Some(start) = self
+            .skip_spaces()
+            .filter(|&at| line.text[at..].starts_with('"'))
+        else {
+            return line.error(self.at, format!("expected {what} as a quoted string"));
+        };
+
+        let mut out = String::new();
+        let mut chars = line.text[start + 1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            let at = start + 1 + i;
+            match c {
+                '"' => {
+                    self.at = at + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => {
+                        let escaped = line.text[at + 2..]
+                            .strip_prefix('{')
+                            .and_then(|rest| rest.split_once('}'))
+                            .map(|(hex, _)| hex)
+                            .filter(|hex| {
+                                !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit())
+                            });
+                        let Some(c) = escaped
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                        else {
+                            return line.error(at, "expected a character code like \\u{7f}");
+                        };
+                        out.push(c);
+                        chars.nth(escaped.map_or(0, str::len) + 1);
+                    }
+                    _ => return line.error(at, "unknown escape"),
+                },
+                c => out.push(c),
+            }
+        }
+        line.error(start, "unterminated string")
+    }
+
+    /// Reads the rest of the line as two-digit hex bytes.
+    fn hex(&mut self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes = Vec::new();
+        while !self.at_end() {
+            let (at, word) = self.word("a hex byte")?;
+            if word.len() != 2 || !word.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return self
+                    .line
+                    .error(at, format!("expected a two-digit hex byte, found '{word}'"));
+            }
+            bytes.push(u8::from_str_radix(word, 16).expect("checked hex digits"));
+        }
+        Ok(bytes)
+    }
+}
+
+/// Reads the values of a text dump line by line, each line's values nested under it at
+/// one more level of indent.
+struct TextParser<'a> {
+    lines: &'a [TextLine<'a>],
+    next: usize,
+    levels: usize,
+    max_depth: usize,
+}
+
+impl<'a> TextParser<'a> {
+    /// Takes the next line if it sits at `depth`. A deeper line is an error, since only
+    /// the line above it could have held it.
+    fn take_at(&mut self, depth: usize) -> Result<Option<&'a TextLine<'a>>, ParseError> {
+        match self.lines.get(self.next) {
+            Some(line) if line.depth == depth => {
+                self.next += 1;
+                Ok(Some(line))
+            }
+            Some(line) if line.depth > depth => line.error(0, "unexpected indentation"),
+            _ => Ok(None),
+        }
+    }
+
+    fn values(&mut self, depth: usize, pool: &mut ObjectPool) -> Result<Vec<Value>, ParseError> {
+        let mut values = Vec::new();
+        while let Some(line) = self.take_at(depth)? {
+            values.push(self.value(line, pool)?);
+        }
+        Ok(values)
+    }
+
+    /// Reads the values under `line`, which must number `count`.
+    fn children(
+        &mut self,
+        line: &TextLine,
+        count: usize,
+        pool: &mut ObjectPool,
+    ) -> Result<Vec<Value>, ParseError> {
+        let values = self.values(line.depth + 1, pool)?;
+        if values.len() != count {
+            let noun = if count == 1 { "value" } else { "values" };
+            let found = values.len();
+            return line.error(
+                0,
+                format!("expected {count} {noun} under this line, found {found}"),
+            );
+        }
+        Ok(values)
+    }
+
+    /// Reads the value `line` begins, counting it against `max_depth` while the values
+    /// under it are read.
+    fn value(
+        &mut self,
+        line: &'a TextLine<'a>,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, ParseError> {
+        if self.levels == self.max_depth {
+            return line.error(0, "nests deeper than the depth limit");
+        }
+        self.levels += 1;
+        let value = self.value_within(line, pool);
+        self.levels -= 1;
+        value
+    }
+
+    fn value_within(
+        &mut self,
+        line: &'a TextLine<'a>,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, ParseError> {
+        let mut tokens = TextTokens::new(line);
+        let (_, kind) = tokens.word("a type")?;
+        let depth = line.depth + 1;
+
+        let value = match kind {
+            "optional" if tokens.at_end() => {
+                let inner = self.children(line, 1, pool)?.remove(0);
+                Value::Optional(Some(Box::new(inner)))
+            }
+            "optional" => match tokens.word("none")? {
+                (_, "none") => Value::Optional(None),
+                (at, word) => return line.error(at, format!("expected none, found '{word}'")),
+            },
+            "function" => return self.function(line, tokens, pool),
+            "list" => {
+                tokens.finish()?;
+                let items = self.values(depth, pool)?;
+                Value::from_list(items, pool)
+            }
+            "tuple" => {
+                tokens.finish()?;
+                let items = self.values(depth, pool)?;
+                Value::from_tuple(items, pool)
+            }
+            "map" => {
+                tokens.finish()?;
+                let mut entries = Vec::new();
+                while let Some(entry) = self.take_at(depth)? {
+                    let mut tokens = TextTokens::new(entry);
+                    match tokens.word("entry")? {
+                        (_, "entry") => tokens.finish()?,
+                        (at, word) => {
+                            return entry.error(at, format!("expected entry, found '{word}'"))
+                        }
+                    }
+                    let mut pair = self.children(entry, 2, pool)?;
+                    let value = pair.pop().expect("two values");
+                    entries.push((pair.pop().expect("two values"), value));
+                }
+                Value::from_map(entries, pool)
+            }
+            "enum_variant" => {
+                let type_name = tokens.string("the type name")?;
+                let variant = tokens.string("the variant")?;
+                tokens.finish()?;
+                let mut payload = self.values(depth, pool)?;
+                if payload.len() > 1 {
+                    return line.error(0, "an enum variant holds at most one payload");
+                }
+                pooled(
+                    Object::EnumVariant {
+                        type_name,
+                        variant,
+                        payload: payload.pop().map(Box::new),
+                    },
+                    pool,
+                )
+            }
+            "record" => {
+                let type_name = tokens.string("the type name")?;
+                tokens.finish()?;
+                let mut fields = Vec::new();
+                while let Some(field) = self.take_at(depth)? {
+                    let mut tokens = TextTokens::new(field);
+                    match tokens.word("field")? {
+                        (_, "field") => {}
+                        (at, word) => {
+                            return field.error(at, format!("expected field, found '{word}'"))
+                        }
+                    }
+                    let name = tokens.string("the field name")?;
+                    tokens.finish()?;
+                    fields.push((name, self.children(field, 1, pool)?.remove(0)));
+                }
+                pooled(Object::Record { type_name, fields }, pool)
+            }
+            "set" => {
+                tokens.finish()?;
+                let items = self.values(depth, pool)?;
+                Value::from_set(items, pool)
+            }
+            "pair" => {
+                // More than two values are the cars of a chain ending in the last, read
+                // in one pass rather than a level of indent for each cell
+                tokens.finish()?;
+                let mut items = self.values(depth, pool)?;
+                if items.len() < 2 {
+                    let found = items.len();
+                    return line.error(
+                        0,
+                        format!("expected at least 2 values under this line, found {found}"),
+                    );
+                }
+                let tail = items.pop().expect("two values");
+                items
+                    .into_iter()
+                    .rev()
+                    .fold(tail, |cdr, car| Value::from_pair(car, cdr, pool))
+            }
+            "class" => {
+                let name = tokens.string("the class name")?;
+                tokens.finish()?;
+                let mut methods = Vec::new();
+                while let Some(method) = self.take_at(depth)? {
+                    let mut tokens = TextTokens::new(method);
+                    match tokens.word("method")? {
+                        (_, "method") => {}
+                        (at, word) => {
+                            return method.error(at, format!("expected method, found '{word}'"))
+                        }
+                    }
+                    let method_name = tokens.string("the method name")?;
+                    tokens.finish()?;
+                    match self.children(method, 1, pool)?.remove(0) {
+                        Value::Object(function) if matches!(*function, Object::Function { .. }) => {
+                            methods.push((method_name, function))
+                        }
+                        _ => return method.error(0, "expected a function under this method"),
+                    }
+                }
+                pooled(Object::Class { name, methods }, pool)
+            }
+            "module" => {
+                let name = tokens.string("the module name")?;
+                tokens.finish()?;
+                let constants = self.values(depth, pool)?;
+                pooled(Object::Module { name, constants }, pool)
+            }
+            _ => return scalar(line, kind, tokens, pool),
+        };
+        tokens.finish()?;
+        Ok(value)
+    }
+
+    /// Reads the properties under a `function` line in any order. Everything but `code`
+    /// may be left out, taking the same defaults as `FunctionBuilder`.
+    fn function(
+        &mut self,
+        line: &'a TextLine<'a>,
+        mut tokens: TextTokens,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, ParseError> {
+        let identifier = tokens.string("the identifier")?;
+        check_identifier(&identifier).map_or_else(|e| line.error(0, e), Ok)?;
+        let param_count = tokens.number("the parameter count")?;
+        tokens.finish()?;
+
+        let mut builder = FunctionBuilder::new(&identifier, param_count, &[]);
+        let mut code = None;
+        let mut seen = Vec::new();
+        while let Some(property) = self.take_at(line.depth + 1)? {
+            let mut tokens = TextTokens::new(property);
+            let (_, name) = tokens.word("a function property")?;
+            if name != "upvalue" && seen.contains(&name) {
+                return property.error(0, format!("'{name}' is given twice"));
+            }
+            seen.push(name);
+
+            let tag = |(at, name): (usize, &str)| {
+                TypeTag::from_name(name).map_or_else(
+                    || property.error(at, format!("unknown type tag '{name}'")),
+                    Ok,
+                )
+            };
+            match name {
+                "variadic" => builder = builder.variadic(),
+                "locals" => builder = builder.local_count(tokens.number("the local count")?),
+                "signature" => {
+                    let mut param_types = Vec::new();
+                    loop {
+                        match tokens.word("'->' and the return type")? {
+                            (_, "->") => break,
+                            word => param_types.push(tag(word)?),
+                        }
+                    }
+                    let return_type = tag(tokens.word("the return type")?)?;
+                    builder = builder.signature(param_types, return_type);
+                }
+                "source" => builder = builder.source(&tokens.string("the source path")?),
+                "lines" => {
+                    let mut runs = Vec::new();
+                    while !tokens.at_end() {
+                        let (at, word) = tokens.word("a line run")?;
+                        let run = word.split_once(':').and_then(|(offset, line)| {
+                            Some((offset.parse().ok()?, line.parse().ok()?))
+                        });
+                        let Some(run) = run else {
+                            return property
+                                .error(at, format!("expected offset:line, found '{word}'"));
+                        };
+                        runs.push(run);
+                    }
+                    builder = builder.lines(runs);
+                }
+                "upvalue" => {
+                    let is_local = match tokens.word("local or upvalue")? {
+                        (_, "local") => true,
+                        (_, "upvalue") => false,
+                        (at, word) => {
+                            return property
+                                .error(at, format!("expected local or upvalue, found '{word}'"))
+                        }
+                    };
+                    builder = builder.upvalue(is_local, tokens.number("the upvalue index")?);
+                }
+                "code" => code = Some(self.code(property, &mut tokens)?),
+                "constants" => {
+                    tokens.finish()?;
+                    builder = builder.constants(self.values(property.depth + 1, pool)?);
+                }
+                "defaults" => {
+                    tokens.finish()?;
+                    let defaults = self.values(property.depth + 1, pool)?;
+                    check_defaults(&identifier, param_count, defaults.len())
+                        .map_or_else(|e| property.error(0, e), Ok)?;
+                    builder = builder.defaults(defaults);
+                }
+                _ => return property.error(0, format!("unknown function property '{name}'")),
+            }
+            tokens.finish()?;
+        }
+
+        let Some(code) = code else {
+            return line.error(0, "function has no code");
+        };
+        FunctionBuilder { code, ..builder }
+            .build(pool)
+            .map_or_else(|e| line.error(0, e), Ok)
+    }
+
+    /// Reads `code bytes` followed by hex, or a bare `code` with one assembler line for
+    /// each instruction under it.
+    fn code(
+        &mut self,
+        property: &'a TextLine<'a>,
+        tokens: &mut TextTokens,
+    ) -> Result<Vec<u8>, ParseError> {
+        if !tokens.at_end() {
+            return match tokens.word("bytes")? {
+                (_, "bytes") => tokens.hex(),
+                (at, word) => property.error(at, format!("expected bytes, found '{word}'")),
+            };
+        }
+
+        let mut lines = Vec::new();
+        while let Some(line) = self.take_at(property.depth + 1)? {
+            lines.push(line);
+        }
+        let source = lines
+            .iter()
+            .map(|line| line.text)
+            .collect::<Vec<_>>()
+            .join("\n");
+        assemble(&source).or_else(|e| {
+            let line = lines[e.line - 1];
+            let at = line.text.find(&e.token).unwrap_or(0);
+            line.error(at, format!("{} '{}'", e.message, e.token))
+        })
+    }
+}
+
+/// Reads the rest of a `kind` line holding no other values, apart from
+/// `TextParser::value_within` to keep the frame each level of nesting takes small.
+fn scalar(
+    line: &TextLine,
+    kind: &str,
+    mut tokens: TextTokens,
+    pool: &mut ObjectPool,
+) -> Result<Value, ParseError> {
+    let payload = tokens.skip_spaces().unwrap_or(tokens.at);
+    let checked =
+        |result: Result<Value, String>| result.map_or_else(|e| line.error(payload, e), Ok);
+
+    let value = match kind {
+        "int" => Value::Int(tokens.number("an int")?),
+        "bool" => Value::Bool(tokens.number("true or false")?),
+        "float" => Value::Float(tokens.number("a float")?),
+        "long" => Value::Long(tokens.number("a long")?),
+        "uint" => Value::UInt(tokens.number("a uint")?),
+        "char" => {
+            let s = tokens.string("the character")?;
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Value::Char(c),
+                _ => return line.error(payload, "expected one character"),
+            }
+        }
+        "nil" => Value::Nil,
+        "byte" => Value::Byte(tokens.number("a byte")?),
+        "short" => Value::Short(tokens.number("a short")?),
+        "decimal" => checked(Value::from_decimal_str(tokens.word("a decimal")?.1))?,
+        "timestamp" => checked(Value::from_timestamp(
+            tokens.number("the seconds")?,
+            tokens.number("the nanoseconds")?,
+        ))?,
+        "uuid" => checked(Value::from_uuid_str(tokens.word("a UUID")?.1))?,
+        "ref" => Value::Ref(tokens.number("a constant index")?),
+        "complex" => Value::Complex {
+            re: tokens.number("the real part")?,
+            im: tokens.number("the imaginary part")?,
+        },
+        "color" => Value::Color {
+            r: tokens.number("the red channel")?,
+            g: tokens.number("the green channel")?,
+            b: tokens.number("the blue channel")?,
+            a: tokens.number("the alpha channel")?,
+        },
+        "string" => pooled(Object::String(tokens.string("the string")?), pool),
+        "bytes" => Value::from_bytes(&tokens.hex()?, pool),
+        "big_int" => checked(Value::from_big_int_str(
+            tokens.word("a big integer")?.1,
+            pool,
+        ))?,
+        "symbol" => Value::from_symbol(&tokens.string("the symbol")?, pool),
+        "float_array" => {
+            let rows = tokens.number("the row count")?;
+            let cols = tokens.number("the column count")?;
+            let mut data = Vec::new();
+            while !tokens.at_end() {
+                data.push(tokens.number("a float")?);
+            }
+            checked(Value::from_float_array(rows, cols, data, pool))?
+        }
+        "regex" => {
+            let pattern = tokens.string("the pattern")?;
+            let flags = tokens.string("the flags")?;
+            checked(Value::from_regex(&pattern, &flags, pool))?
+        }
+        "native_ref" => pooled(
+            Object::NativeRef {
+                identifier: tokens.string("the identifier")?,
+                param_count: tokens.number("the parameter count")?,
+            },
+            pool,
+        ),
+        _ => return line.error(0, format!("unknown type '{kind}'")),
+    };
+    tokens.finish()?;
+    Ok(value)
+}
+
+/// Builds constants from the text dump format `to_text` writes, feeding function code
+/// through the assembler. Blank lines are ignored, and a value nesting deeper than
+/// `LoadLimits::max_depth` allows by default is an error.
+pub fn from_text(text: &str, pool: &mut ObjectPool) -> Result<Vec<Value>, ParseError> {
+    from_text_within(text, pool, LoadLimits::DEFAULT.max_depth)
+}
+
+/// `from_text`, failing on values nesting deeper than `max_depth`.
+pub(super) fn from_text_within(
+    text: &str,
+    pool: &mut ObjectPool,
+    max_depth: usize,
+) -> Result<Vec<Value>, ParseError> {
+    let lines = text_lines(text)?;
+    TextParser {
+        lines: &lines,
+        next: 0,
+        levels: 0,
+        max_depth,
+    }
+    .values(0, pool)
+}
@@ -0,0 +1,19 @@
+/// Bytecode: opcodes and their decoding, the assembler and verifier, and the tools built
+/// on them, from control-flow graphs and diffs to the optimizer.
+pub mod bytecode;
+/// Reading and writing constants: the native format with its headers, flags and
+/// indexes, the JSON, text and CBOR forms, and the errors and options of each.
+pub mod io;
+/// Heap objects that constants point to, from strings to functions, classes and modules.
+pub mod object;
+/// Constants themselves, inline scalars or references to objects in the pool.
+pub mod value;
+
+pub use bytecode::ByteCode;
+pub use io::{
+    deserialize_from_slice, load_values_from_bytes, load_values_from_disk, save_as, save_atomic,
+    serialize_to_vec, write_values_to_disk, ConstantIO, ConstantsError, LoadOptions, Warning,
+    Warnings, WriteOptions,
+};
+pub use object::{Object, ObjectPool};
+pub use value::Value;
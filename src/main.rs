@@ -1,286 +1,1110 @@
 use std::{
+    fmt,
     fs::{self, File},
-    io::{Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     rc::Rc,
+    time::Duration,
 };
 
-type ObjectPool = Vec<Rc<Object>>;
+use constants_generator_rs::{bytecode::*, io::*, object::*, value::*};
 
 fn main() {
-    let args = std::env::args();
-    if args.len() != 2 {
-        println!("Malformed input");
-        return;
+    let warnings = Warnings::default();
+    let result = run(&warnings);
+    // Those of a load or save that failed are still worth seeing before its error
+    report_warnings(&warnings);
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(e.exit_code());
     }
+}
+
+/// Prints the warnings the library has collected since the last call.
+fn report_warnings(warnings: &Warnings) {
+    for warning in warnings.take() {
+        eprintln!("warning: {warning}");
+    }
+}
+
+/// The command line, whose failures are handed back for `main` to report, and whose
+/// loads and saves leave what they let through in `warnings`.
+fn run(warnings: &Warnings) -> Result<(), CliError> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let Some((command, flags)) = args.split_first() else {
+        return Err(usage("Expected a command: l, s, r, a or u"));
+    };
 
-    let file_name = "constants";
+    // `--no-lock` and `--wait SECS` apply to every command, so they're taken out first
+    let mut lock_wait = Some(Duration::ZERO);
+    let mut other_flags = Vec::new();
+    let mut all_flags = flags.iter();
+    while let Some(flag) = all_flags.next() {
+        match flag.as_str() {
+            "--no-lock" => lock_wait = None,
+            "--wait" => {
+                let wait = all_flags
+                    .next()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs.parse().ok()?).ok())
+                    .ok_or_else(|| {
+                        usage("Expected a non-negative number of seconds after --wait")
+                    })?;
+                lock_wait = lock_wait.map(|_| wait);
+            }
+            _ => other_flags.push(flag.clone()),
+        }
+    }
+    let flags = other_flags.as_slice();
+
+    // `l` and `s` take another path as a bare argument, `-` meaning stdin or stdout
+    let mut file_name = "constants";
 
     let mut objs = Vec::<Rc<Object>>::new();
     let mut values = Vec::<Value>::new();
 
-    match args.skip(1).next().unwrap().as_str() {
+    match command.as_str() {
         "l" => {
-            let mut f = fs::File::open(file_name).expect("Could not open file");
-            load_values_from_disk(&mut f, &mut values, &mut objs);
+            let mut module_path = None;
+            let mut options = LoadOptions::default();
+            let mut disassembly = DisassembleOptions::default();
+            let mut show_stats = false;
+            let mut cfg_dir = None;
+            let mut diff_path = None;
+            let mut key = None;
+            let mut json = false;
+            let mut text = false;
+            let mut format = None;
+            let mut verbose = false;
+            let mut at = None;
+            let mut section = None;
+            let mut verify_key = None;
+            let mut mmap = false;
+            let mut show_bytes = false;
+            let mut partial = false;
+            let mut spans = Vec::new();
+            let mut flags = flags.iter();
+            while let Some(flag) = flags.next() {
+                match flag.as_str() {
+                    "--at" => {
+                        at = flags
+                            .next()
+                            .and_then(|index| index.parse().ok())
+                            .map(Some)
+                            .ok_or_else(|| usage("Expected a constant index after --at"))?;
+                    }
+                    "--section" => {
+                        section = Some(
+                            flags
+                                .next()
+                                .ok_or_else(|| usage("Expected a name after --section"))?,
+                        )
+                    }
+                    "--module" => {
+                        module_path = Some(
+                            flags
+                                .next()
+                                .ok_or_else(|| usage("Expected a module path after --module"))?,
+                        )
+                    }
+                    "--strict" => options.strict = true,
+                    "--legacy" => options.legacy = true,
+                    "--no-checksum" => options.skip_checksum = true,
+                    "--verify-key" => verify_key = Some(read_key_file(flag, flags.next())?),
+                    "--allow-unsigned" => options.allow_unsigned = true,
+                    "--skip-unknown" => options.skip_unknown = true,
+                    "--utf8" => {
+                        options.utf8 = flags
+                            .next()
+                            .and_then(|name| Utf8Policy::from_name(name))
+                            .ok_or_else(|| usage("Expected strict, lossy or bytes after --utf8"))?;
+                    }
+                    "--max-string-len" | "--max-code-len" | "--max-constants"
+                    | "--max-body-len" | "--max-depth" | "--max-params" => {
+                        let limit = flags
+                            .next()
+                            .and_then(|limit| limit.parse().ok())
+                            .ok_or_else(|| usage(format!("Expected a number after {flag}")))?;
+                        let limits = &mut options.limits;
+                        match flag.as_str() {
+                            "--max-string-len" => limits.max_string_len = limit,
+                            "--max-code-len" => limits.max_code_len = limit,
+                            "--max-constants" => limits.max_constants = limit,
+                            "--max-depth" => limits.max_depth = limit,
+                            "--max-params" => limits.max_params = limit,
+                            _ => limits.max_body_len = limit,
+                        }
+                    }
+                    "--mmap" => mmap = true,
+                    "--bytes" => show_bytes = true,
+                    "--partial" => partial = true,
+                    "--labels" => disassembly.labels = true,
+                    "--stats" => show_stats = true,
+                    "--json" => json = true,
+                    "--text" => text = true,
+                    "-v" | "--verbose" => verbose = true,
+                    "--format" => {
+                        format = flags
+                            .next()
+                            .and_then(|name| ContainerFormat::from_name(name))
+                            .map(Some)
+                            .ok_or_else(|| {
+                                usage(
+                                    "Expected native or, with the cbor feature, cbor after --format",
+                                )
+                            })?;
+                    }
+                    "--cfg" => {
+                        cfg_dir = Some(
+                            flags
+                                .next()
+                                .ok_or_else(|| usage("Expected a directory after --cfg"))?,
+                        )
+                    }
+                    "--diff" => {
+                        diff_path = Some(
+                            flags
+                                .next()
+                                .ok_or_else(|| usage("Expected a file after --diff"))?,
+                        )
+                    }
+                    "--key" | "--key-file" => key = Some(read_key_flag(flag, flags.next())?),
+                    path if !path.starts_with("--") => file_name = path,
+                    f => return Err(usage(format!("Invalid '{f}'"))),
+                }
+            }
+            options.key = key.as_deref();
+            options.verify_key = verify_key.as_deref();
+            options.warnings = Some(warnings);
+
+            let _lock = cli_lock(file_name, LockKind::Shared, lock_wait)?;
+            let mut f = open_input(file_name)?;
+            // Without `--format` the input's first bytes say what it is, except for legacy
+            // files, which have no magic to find
+            let detected = match format {
+                Some(format) => InputFormat::from(format),
+                None if options.legacy => InputFormat::Native,
+                None => detect_format(&mut f)?,
+            };
+            if verbose && format.is_none() {
+                eprintln!("Detected {detected} input");
+            }
+            let native = detected == InputFormat::Native;
+            // Any metadata is shown first; a bad header is left for the load to report
+            if native && !options.legacy {
+                if let Ok(Some(metadata)) = read_metadata(&mut f) {
+                    eprintln!("{metadata}");
+                }
+                f.rewind().map_err(ConstantsError::from)?;
+            }
+            if show_bytes && (at.is_some() || section.is_some() || module_path.is_some()) {
+                return Err(usage("--bytes shows every constant of the file, so can't take --at, --section or --module"));
+            }
+            if partial && (at.is_some() || section.is_some() || mmap || show_bytes || !native) {
+                return Err(usage("--partial only reads a whole file in the native format, without --at, --section, --mmap or --bytes"));
+            }
+            // `--at n` loads only constant `n`, seeking to it in an indexed file
+            match (at, section) {
+                (Some(_), Some(_)) => return Err(usage("--at and --section can't be combined")),
+                (Some(index), None) => {
+                    values.push(load_constant_at(&mut f, index, &mut objs, &options)?);
+                    report_warnings(warnings);
+                }
+                // `--section name` loads only that section, seeking past the others
+                (None, Some(name)) => {
+                    values = load_section(&mut f, name, &mut objs, &options)?;
+                    report_warnings(warnings);
+                    eprintln!("{} constants read from section '{name}'", values.len());
+                }
+                // `--mmap` decodes the file in place rather than reading it
+                (None, None) if mmap => {
+                    if !native {
+                        return Err(usage("--mmap only reads the native format"));
+                    }
+                    if file_name == "-" {
+                        return Err(usage("--mmap needs a file to map, not stdin"));
+                    }
+                    // SAFETY: this tool only truncates a file under the exclusive lock,
+                    // which the shared one taken above keeps out until the load is done.
+                    // Without a lock, through `--no-lock` or a directory that can't hold
+                    // the lock file, `--mmap` trusts that nothing else writes the file
+                    values = unsafe { load_values_mmap(file_name, &mut objs, &options)? };
+                    report_warnings(warnings);
+                    eprintln!("{} constants read from file", values.len());
+                }
+                // `--partial` keeps the constants before any that fail to decode
+                (None, None) if partial => {
+                    let error;
+                    (values, error) = load_values_partial(&mut f, &mut objs, &options);
+                    report_warnings(warnings);
+                    eprintln!("{} constants read from file", values.len());
+                    if let Some(error) = error {
+                        eprintln!("warning: {error}");
+                    }
+                }
+                // `--bytes` also keeps the bytes of each constant, to show beside it
+                (None, None) if show_bytes => {
+                    if !native || mmap {
+                        return Err(usage(
+                            "--bytes only reads the native format, and not with --mmap",
+                        ));
+                    }
+                    spans = read_constant_spans(&mut f, &mut values, &mut objs, &options)?;
+                    report_warnings(warnings);
+                    eprintln!("{} constants read from file", values.len());
+                }
+                (None, None) => {
+                    load_in(format, &mut f, &mut values, &mut objs, &options)?;
+                    report_warnings(warnings);
+                    eprintln!("{} constants read from file", values.len());
+                }
+            }
 
-            for value in values {
-                value.display();
+            // `--stats` summarises the code of every function instead of listing constants
+            if show_stats {
+                pool_code_stats(&objs)
+                    .write_table(&mut io::stdout().lock())
+                    .map_err(CliError::write)?;
+                return Ok(());
+            }
+
+            // `--cfg dir` writes a Graphviz file per function instead of listing constants
+            if let Some(dir) = cfg_dir {
+                let mut skipped = Vec::new();
+                let written =
+                    write_function_cfgs(&objs, dir, &mut skipped).map_err(CliError::write)?;
+                for warning in skipped {
+                    eprintln!("warning: {warning}");
+                }
+                eprintln!("{written} graphs written to {dir}");
+                return Ok(());
+            }
+
+            // `--diff other` compares the code of functions that share an identifier
+            if let Some(path) = diff_path {
+                let mut other = open_input(path)?;
+                let mut other_objs = ObjectPool::new();
+                let mut other_values = Vec::new();
+                load_in(
+                    format,
+                    &mut other,
+                    &mut other_values,
+                    &mut other_objs,
+                    &options,
+                )?;
+                report_warnings(warnings);
+                eprintln!("{} constants read from file", other_values.len());
+                write_function_diffs(&mut io::stdout().lock(), &objs, &other_objs)
+                    .map_err(CliError::write)?;
+                return Ok(());
+            }
+
+            // `--module a.b` lists the members of module `b` inside module `a`
+            let mut shown = values.as_slice();
+            if let Some(path) = module_path {
+                for name in path.split('.') {
+                    shown = find_module(shown, name)
+                        .ok_or_else(|| usage(format!("No module '{name}' in '{path}'")))?;
+                }
+            }
+
+            // `--json` prints the constants for other tools instead of for people
+            if json {
+                println!("{}", to_json(shown));
+                return Ok(());
+            }
+
+            // `--text` prints the stable text dump meant for review and diffs
+            if text {
+                print!("{}", to_text(shown));
+                return Ok(());
+            }
+
+            // `--bytes` leads each constant with the bytes it was read from
+            for (i, value) in shown.iter().enumerate() {
+                if let Some(span) = spans.get(i) {
+                    span.write(&mut io::stdout().lock(), i)
+                        .map_err(CliError::write)?;
+                }
+                value.display(&disassembly).map_err(CliError::write)?;
             }
         }
         "s" => {
+            let mut options = WriteOptions::default();
+            let mut key = None;
+            let mut json_path = None;
+            let mut text_path = None;
+            let mut format = ContainerFormat::default();
+            let mut sections = false;
+            let mut metadata = None::<Metadata>;
+            let mut timestamp = true;
+            let mut atomic = true;
+            let mut sign_key = None;
+            let mut flags = flags.iter();
+            while let Some(flag) = flags.next() {
+                match flag.as_str() {
+                    "--encoding" => {
+                        options.string_encoding = flags
+                            .next()
+                            .and_then(|name| StringEncoding::from_name(name))
+                            .ok_or_else(|| {
+                                usage("Expected utf8, utf16 or latin1 after --encoding")
+                            })?;
+                    }
+                    "--endianness" => {
+                        options.endianness = flags
+                            .next()
+                            .and_then(|name| Endianness::from_name(name))
+                            .ok_or_else(|| usage("Expected big or little after --endianness"))?;
+                    }
+                    "--varint" => options.varint = true,
+                    "--string-table" => options.string_table = true,
+                    "--deterministic" => options.deterministic = true,
+                    "--index" => options.index = true,
+                    "--armor" => options.armor = true,
+                    "--verify" => options.verify = true,
+                    "--sections" => sections = true,
+                    "--metadata" => {
+                        metadata.get_or_insert_with(Metadata::generator);
+                    }
+                    "--meta" => {
+                        let entry = flags
+                            .next()
+                            .and_then(|entry| entry.split_once('='))
+                            .ok_or_else(|| usage("Expected KEY=VALUE after --meta"))?;
+                        metadata
+                            .get_or_insert_with(Metadata::generator)
+                            .entries
+                            .push((entry.0.to_string(), entry.1.to_string()));
+                    }
+                    "--no-timestamp" => timestamp = false,
+                    "--no-atomic" => atomic = false,
+                    "--sign-key" => sign_key = Some(read_key_file(flag, flags.next())?),
+                    "--format" => {
+                        format = flags
+                            .next()
+                            .and_then(|name| ContainerFormat::from_name(name))
+                            .ok_or_else(|| {
+                                usage(
+                                    "Expected native or, with the cbor feature, cbor after --format",
+                                )
+                            })?;
+                    }
+                    "--json" => {
+                        json_path = Some(
+                            flags
+                                .next()
+                                .ok_or_else(|| usage("Expected a file after --json"))?,
+                        )
+                    }
+                    "--text" => {
+                        text_path = Some(
+                            flags
+                                .next()
+                                .ok_or_else(|| usage("Expected a file after --text"))?,
+                        )
+                    }
+                    "--compress" => {
+                        options.compression = flags
+                            .next()
+                            .and_then(|level| level.parse().ok())
+                            .filter(|level| (1..=9).contains(level))
+                            .map(Some)
+                            .ok_or_else(|| {
+                                usage("Expected a level from 1 to 9 after --compress")
+                            })?;
+                    }
+                    "--key" | "--key-file" => key = Some(read_key_flag(flag, flags.next())?),
+                    path if !path.starts_with("--") => file_name = path,
+                    f => return Err(usage(format!("Invalid '{f}'"))),
+                }
+            }
+            options.key = key.as_deref();
+            // Deterministic output leaves out the creation time, which would differ per save
+            if let Some(metadata) = &mut metadata {
+                if !timestamp || options.deterministic {
+                    metadata.created = None;
+                }
+            }
+            options.metadata = metadata.as_ref();
+            options.sign_key = sign_key.as_deref();
+            options.warnings = Some(warnings);
+            if options.verify && file_name == "-" {
+                return Err(usage(
+                    "--verify reads the file back, so needs one rather than stdout",
+                ));
+            }
+            if sections && (format != ContainerFormat::Native || options.armor) {
+                return Err(usage(
+                    "--sections only writes the native format, without armor",
+                ));
+            }
+
+            // `--sections` splits the constants into strings, functions and everything else
+            let save = |values: &[Value]| -> Result<(), CliError> {
+                let _lock = cli_lock(file_name, LockKind::Exclusive, lock_wait)?;
+                let mut f = create_output(file_name, atomic)
+                    .map_err(|e| CliError::write(open_error(file_name)(e)))?;
+                // Each section's references count from its own first constant
+                let split = if sections {
+                    Vec::from(split_sections(values).map_err(CliError::write)?)
+                } else {
+                    vec![("", dedupe_strings(values))]
+                };
+                let split = split
+                    .iter()
+                    .map(|(name, values)| (*name, values.as_slice()))
+                    .collect::<Vec<_>>();
+                let count = split.iter().map(|(_, values)| values.len()).sum::<usize>();
+                if sections {
+                    write_sections(&mut f, &split, &options).map_err(CliError::write)?;
+                    report_warnings(warnings);
+                    eprintln!(
+                        "{count} constants written to file in {} sections",
+                        split.len()
+                    );
+                } else {
+                    save_as(format, &mut f, split[0].1, &options).map_err(CliError::write)?;
+                    report_warnings(warnings);
+                    eprintln!("{count} constants written to file");
+                }
+                f.finish().map_err(CliError::write)?;
+                if options.verify {
+                    let written = split.iter().map(|(_, values)| *values).collect::<Vec<_>>();
+                    verify_saved(file_name, &written, &options).map_err(CliError::save)?;
+                    eprintln!("{count} constants verified");
+                }
+                Ok(())
+            };
+
+            // `--json path` saves the constants described there instead of the sample
+            if let Some(path) = json_path {
+                let text = fs::read_to_string(path).map_err(open_error(path))?;
+                let values = from_json(&text, &mut objs)
+                    .or_else(|e| invalid(format!("Invalid constants in '{path}': {e}")))?;
+                save(&values)?;
+                return Ok(());
+            }
+
+            // `--text path` does the same for a text dump, as `l --text` prints it
+            if let Some(path) = text_path {
+                let text = fs::read_to_string(path).map_err(open_error(path))?;
+                let values = from_text(&text, &mut objs)
+                    .or_else(|e| invalid(format!("Invalid constants in '{path}': {e}")))?;
+                save(&values)?;
+                return Ok(());
+            }
+
             values.extend([
                 Value::Int(100),
                 Value::Bool(false),
+                Value::Float(-0.5),
+                Value::Long(i64::MAX),
+                Value::UInt(u64::MAX),
+                Value::Char('λ'),
+                Value::Nil,
+                Value::from_int_fitting(255),
+                Value::from_int_fitting(i16::MIN as i64),
+                Value::from_decimal_str("19.99").unwrap(),
+                Value::from_decimal_str("-0.0042").unwrap(),
+                Value::from_timestamp(-1, 500_000_000).unwrap(),
+                Value::from_timestamp(2_147_483_648, 0).unwrap(),
+                Value::from_uuid_str("123E4567-e89b-12d3-a456-426614174000").unwrap(),
+                Value::Complex { re: 3.0, im: -4.0 },
+                Value::Complex { re: 0.0, im: 1.5 },
+                Value::from_hex_color("#ff8800").unwrap(),
+                Value::Optional(None),
+                Value::Optional(Some(Box::new(Value::Optional(Some(Box::new(
+                    Value::from_string("deep", &mut objs),
+                )))))),
                 Value::from_string("Hello!", &mut objs),
-                Value::from_function_literal(
-                    "foo_bar",
-                    1,
-                    &[ByteCode::ConstantByte as u8, 3, ByteCode::Return as u8],
+                Value::from_string("Hello!", &mut objs),
+                Value::from_list(
+                    vec![
+                        Value::Int(1),
+                        Value::Int(2),
+                        Value::from_string("three", &mut objs),
+                    ],
+                    &mut objs,
+                ),
+                Value::from_tuple(vec![Value::Int(1)], &mut objs),
+                Value::from_map(
+                    vec![
+                        (Value::from_string("a", &mut objs), Value::Int(1)),
+                        (Value::from_string("b", &mut objs), Value::Bool(true)),
+                    ],
                     &mut objs,
                 ),
+                Value::from_bytes(&[0x00, 0x7f, 0xff], &mut objs),
+                Value::from_enum_variant("Color", "Red", None, &mut objs),
+                Value::from_enum_variant("Option", "Some", Some(Value::Int(3)), &mut objs),
+                Value::from_record(
+                    "Point",
+                    vec![("x", Value::Int(1)), ("y", Value::Int(2))],
+                    &mut objs,
+                ),
+                Value::from_set(vec![Value::Int(3), Value::Int(1), Value::Int(2)], &mut objs),
+                Value::from_pair(
+                    Value::Int(1),
+                    Value::from_pair(Value::Int(2), Value::Nil, &mut objs),
+                    &mut objs,
+                ),
+                Value::from_pair(Value::Int(1), Value::Int(2), &mut objs),
+                Value::from_symbol("init", &mut objs),
+                Value::from_symbol("init", &mut objs),
+                Value::from_float_array(
+                    4,
+                    4,
+                    (0..16)
+                        .map(|i| if i % 5 == 0 { 1.0 } else { 0.0 })
+                        .collect(),
+                    &mut objs,
+                )
+                .unwrap(),
+                Value::from_float_array(1, 3, vec![0.5, -1.0, 2.25], &mut objs).unwrap(),
+                Value::from_regex("^[a-z]+$", "im", &mut objs).unwrap(),
+                Value::from_native("clock", 0, &mut objs),
+                Value::from_module(
+                    "math",
+                    vec![
+                        Value::Float(std::f64::consts::PI),
+                        Value::from_module("consts", vec![Value::Int(42)], &mut objs),
+                    ],
+                    &mut objs,
+                ),
+                Value::from_class(
+                    "Counter",
+                    vec![
+                        (
+                            "init",
+                            Value::from_function_literal(
+                                "init",
+                                1,
+                                &[ByteCode::Return as u8],
+                                &mut objs,
+                            )
+                            .unwrap(),
+                        ),
+                        (
+                            "get",
+                            FunctionBuilder::new(
+                                "get",
+                                0,
+                                &[ByteCode::ConstantByte as u8, 0, ByteCode::Return as u8],
+                            )
+                            .constants(vec![Value::Int(0)])
+                            .build(&mut objs)
+                            .unwrap(),
+                        ),
+                    ],
+                    &mut objs,
+                )
+                .unwrap(),
+                Value::from_big_int_str(
+                    "-1606938044258990275541962092341162602522202993782792835301376",
+                    &mut objs,
+                )
+                .unwrap(),
+                FunctionBuilder::new(
+                    "foo_bar",
+                    1,
+                    &[
+                        ByteCode::ConstantByte as u8,
+                        0,
+                        ByteCode::ConstantByte as u8,
+                        0,
+                        ByteCode::Add as u8,
+                        ByteCode::ConstantByte as u8,
+                        0,
+                        ByteCode::Sub as u8,
+                        ByteCode::ConstantByte as u8,
+                        0,
+                        ByteCode::Mul as u8,
+                        ByteCode::ConstantByte as u8,
+                        0,
+                        ByteCode::Div as u8,
+                        ByteCode::Negate as u8,
+                        ByteCode::ConstantByte as u8,
+                        0,
+                        ByteCode::Less as u8,
+                        ByteCode::ConstantByte as u8,
+                        1,
+                        ByteCode::ConstantByte as u8,
+                        1,
+                        ByteCode::Equal as u8,
+                        ByteCode::Greater as u8,
+                        ByteCode::Return as u8,
+                    ],
+                )
+                .local_count(2)
+                .constants(vec![
+                    Value::Int(7),
+                    Value::from_string("local", &mut objs),
+                    FunctionBuilder::new(
+                        "inner",
+                        0,
+                        &[ByteCode::ConstantByte as u8, 0, ByteCode::Return as u8],
+                    )
+                    .constants(vec![FunctionBuilder::new(
+                        "innermost",
+                        0,
+                        &[ByteCode::Return as u8],
+                    )
+                    .build(&mut objs)
+                    .unwrap()])
+                    .upvalue(true, 1)
+                    .upvalue(false, 0)
+                    .build(&mut objs)
+                    .unwrap(),
+                ])
+                .lines(vec![(0, 12), (15, 13), (24, 14)])
+                .build(&mut objs)
+                .unwrap(),
+                Value::from_function_literal("noop", 0, &[ByteCode::Return as u8], &mut objs)
+                    .unwrap(),
+                FunctionBuilder::new(
+                    "spin",
+                    0,
+                    &assemble(
+                        "CONSTANT_LONG 0 ; true
+                        JUMP_IF_FALSE 7
+                        NEGATE
+                        JUMP 0
+                        LOOP 13
+                        RETURN",
+                    )
+                    .unwrap(),
+                )
+                .constants(vec![Value::Bool(true)])
+                .build(&mut objs)
+                .unwrap(),
+                Value::from_function_source("identity", 1, "RETURN", &mut objs).unwrap(),
+                FunctionBuilder::new(
+                    "swap",
+                    0,
+                    &assemble(
+                        "GET_LOCAL 0
+                        GET_LOCAL 1
+                        SET_LOCAL 0
+                        SET_LOCAL 1
+                        RETURN",
+                    )
+                    .unwrap(),
+                )
+                .local_count(2)
+                .build(&mut objs)
+                .unwrap(),
+                FunctionBuilder::new(
+                    "wasteful",
+                    1,
+                    &assemble(
+                        "CONSTANT_BYTE 0
+                        POP
+                        GET_LOCAL 0
+                        NEGATE
+                        NEGATE
+                        JUMP 0
+                        JUMP 1
+                        NEGATE
+                        RETURN",
+                    )
+                    .unwrap(),
+                )
+                .local_count(1)
+                .constants(vec![Value::Int(1)])
+                .build(&mut objs)
+                .unwrap(),
+                FunctionBuilder::new(
+                    "apply_twice",
+                    2,
+                    &assemble(
+                        "GET_LOCAL 0
+                        GET_LOCAL 0
+                        GET_LOCAL 1
+                        CALL args=1
+                        TAIL_CALL args=1",
+                    )
+                    .unwrap(),
+                )
+                .local_count(2)
+                .build(&mut objs)
+                .unwrap(),
+                FunctionBuilder::new("print", 1, &[ByteCode::Return as u8])
+                    .variadic()
+                    .build(&mut objs)
+                    .unwrap(),
+                FunctionBuilder::new("greet", 3, &[ByteCode::Return as u8])
+                    .defaults(vec![
+                        Value::from_string("world", &mut objs),
+                        Value::Bool(true),
+                    ])
+                    .signature(
+                        vec![TypeTag::Int, TypeTag::String, TypeTag::Bool],
+                        TypeTag::String,
+                    )
+                    .source("src/greet.lang")
+                    .build(&mut objs)
+                    .unwrap(),
             ]);
 
-            let mut f = fs::File::create(file_name).expect("Could not open file");
-            write_values_to_disk(&mut f, &values);
+            save(&values)?;
         }
-        s => panic!("Invalid '{s}'"),
-    }
-}
-
-fn write_values_to_disk(file: &mut File, values: &[Value]) {
-    file.write(&values.len().to_be_bytes()).unwrap();
-
-    for value in values {
-        value.write(file);
-    }
+        "r" => {
+            let mut compact = false;
+            let mut optimize = false;
+            let mut atomic = true;
+            let mut write_options = WriteOptions {
+                warnings: Some(warnings),
+                ..WriteOptions::default()
+            };
+            for flag in flags {
+                match flag.as_str() {
+                    "--compact-constants" => compact = true,
+                    "--optimize" => optimize = true,
+                    "--no-atomic" => atomic = false,
+                    "--verify" => write_options.verify = true,
+                    f => return Err(usage(format!("Invalid '{f}'"))),
+                }
+            }
 
-    println!("{} constants written to file", values.len());
-}
+            // Held from the read to the rewrite, so no other save lands in between
+            let _lock = cli_lock(file_name, LockKind::Exclusive, lock_wait)?;
+            let mut f = open_input(file_name)?;
+            let options = LoadOptions {
+                strict: true,
+                warnings: Some(warnings),
+                ..LoadOptions::default()
+            };
+            load_values_from_disk(&mut f, &mut values, &mut objs, &options)?;
+            report_warnings(warnings);
+            eprintln!("{} constants read from file", values.len());
 
-fn load_values_from_disk(file: &mut File, values: &mut Vec<Value>, pool: &mut ObjectPool) {
-    let constants_to_read = read_usize(file);
-    values.reserve(constants_to_read);
+            if compact {
+                values = values
+                    .iter()
+                    .map(|value| map_functions(value, &mut compact_constants, &mut objs))
+                    .collect();
+            }
 
-    for _ in 0..constants_to_read {
-        let byte_id = read_u8(file);
-        values.push(Value::read(file, byte_id, pool));
-    }
+            if optimize {
+                let mut stats = OptStats::default();
+                let mut rewrite = |function| optimize_function(function, &mut stats);
+                values = values
+                    .iter()
+                    .map(|value| map_functions(value, &mut rewrite, &mut objs))
+                    .collect();
+                eprintln!(
+                    "{} bytes saved by constant pops, {} by double negates, {} jumps threaded",
+                    stats.constant_pop_bytes, stats.double_negate_bytes, stats.threaded_jumps
+                );
+            }
 
-    println!("{} constants read from file", values.len());
-}
+            // Rewrites in place, so a failed save mustn't lose the file that was read
+            let values = dedupe_strings(&values);
+            let saved = if atomic {
+                save_atomic(file_name, &values, &write_options)
+            } else {
+                File::create(file_name)
+                    .map_err(open_error(file_name))
+                    .and_then(|mut f| write_values_to_disk(&mut f, &values, &write_options))
+                    .and_then(|()| {
+                        if write_options.verify {
+                            verify_saved(file_name, &[&values], &write_options)?;
+                        }
+                        Ok(())
+                    })
+            };
+            saved.map_err(CliError::save)?;
+            report_warnings(warnings);
+            eprintln!("{} constants written to file", values.len());
+            if write_options.verify {
+                eprintln!("{} constants verified", values.len());
+            }
+        }
+        "a" => {
+            let (flag, path) = match flags {
+                [flag, path] => (flag.as_str(), path),
+                _ => return Err(usage("Expected --json PATH or --text PATH")),
+            };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-enum ByteCode {
-    ConstantByte,
-    Return,
-}
+            // The constants to append are described in JSON or text dump form
+            let text = fs::read_to_string(path).map_err(open_error(path))?;
+            let values = match flag {
+                "--json" => from_json(&text, &mut objs).map_err(|e| e.to_string()),
+                "--text" => from_text(&text, &mut objs).map_err(|e| e.to_string()),
+                f => return Err(usage(format!("Invalid '{f}'"))),
+            }
+            .or_else(|e| invalid(format!("Invalid constants in '{path}': {e}")))?;
+            let _lock = cli_lock(file_name, LockKind::Exclusive, lock_wait)?;
+            append_values_to_disk(file_name, &values)
+                .map_err(|e| CliError::Write(format!("Could not append to '{file_name}': {e}")))?;
+            eprintln!("{} constants appended to file", values.len());
+        }
+        "u" => {
+            let mut at = None;
+            let mut changes = None;
+            let mut compact_file = false;
+            let mut flags = flags.iter();
+            while let Some(flag) = flags.next() {
+                match flag.as_str() {
+                    "--at" => {
+                        at = flags
+                            .next()
+                            .and_then(|index| index.parse::<usize>().ok())
+                            .map(Some)
+                            .ok_or_else(|| usage("Expected a constant index after --at"))?;
+                    }
+                    "--json" | "--text" => {
+                        let path = flags
+                            .next()
+                            .ok_or_else(|| usage(format!("Expected a file after {flag}")))?;
+                        changes = Some((flag.as_str(), path));
+                    }
+                    "--compact" => compact_file = true,
+                    path if !path.starts_with("--") => file_name = path,
+                    f => return Err(usage(format!("Invalid '{f}'"))),
+                }
+            }
 
-#[derive(Debug, Clone)]
-enum Value {
-    Int(i32),
-    Bool(bool),
-    Object(Rc<Object>),
-}
+            let _lock = cli_lock(file_name, LockKind::Exclusive, lock_wait)?;
+            // The constants in the file replace those from `--at n` onwards
+            let report = match (at, changes) {
+                (Some(at), Some((flag, path))) => {
+                    let text = fs::read_to_string(path).map_err(open_error(path))?;
+                    let values = if flag == "--json" {
+                        from_json(&text, &mut objs).map_err(|e| e.to_string())
+                    } else {
+                        from_text(&text, &mut objs).map_err(|e| e.to_string())
+                    }
+                    .or_else(|e| invalid(format!("Invalid constants in '{path}': {e}")))?;
+                    let changes = values
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, value)| (at + i, value))
+                        .collect::<Vec<_>>();
+                    update_values(file_name, &changes)
+                }
+                (None, None) if compact_file => compact(file_name),
+                _ => {
+                    return Err(usage(
+                        "Expected --at N with --json PATH or --text PATH, or --compact",
+                    ))
+                }
+            };
+            let report = report
+                .map_err(|e| CliError::Write(format!("Could not update '{file_name}': {e}")))?;
+            eprintln!("{report}");
+        }
+        s => return Err(usage(format!("Invalid '{s}'"))),
+    }
 
-#[derive(Debug, Clone)]
-enum Object {
-    String(String),
-    Function {
-        identifier: String,
-        param_count: u8,
-        code: Vec<u8>,
-    },
+    Ok(())
 }
 
-trait ConstantIO {
-    fn to_type_id(&self) -> u8;
-    fn read(file: &mut File, byte_id: u8, pool: &mut ObjectPool) -> Value;
-    fn write(&self, file: &mut File);
+/// How the command line failed. `main` reports it on stderr and exits with its
+/// `exit_code`, so scripts can tell a mistyped command from a bad file.
+#[derive(Debug)]
+enum CliError {
+    /// The arguments aren't a command, for the reason given.
+    Usage(String),
+    /// The input couldn't be opened, read or parsed.
+    Load(ConstantsError),
+    /// The output couldn't be written, for the reason given.
+    Write(String),
+    /// The input was read, but failed its checksum, signature or code verification.
+    Verify(ConstantsError),
 }
 
-impl Value {
-    fn from_string(str: &'static str, pool: &mut ObjectPool) -> Value {
-        let v = Rc::new(Object::String(str.to_string()));
-        pool.push(Rc::clone(&v));
-        Value::Object(v)
+impl CliError {
+    /// 1 for a usage error, 2 for a failed load, 3 for a failed write and 4 for a failed
+    /// verification; success is 0.
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 1,
+            CliError::Load(_) => 2,
+            CliError::Write(_) => 3,
+            CliError::Verify(_) => 4,
+        }
     }
 
-    fn from_function_literal(
-        id: &'static str,
-        param_count: u8,
-        code: &[u8],
-        pool: &mut ObjectPool,
-    ) -> Value {
-        let v = Rc::new(Object::Function {
-            identifier: id.to_string(),
-            param_count,
-            code: code.iter().map(|b| *b).collect(),
-        });
-        pool.push(Rc::clone(&v));
-        Value::Object(v)
+    /// A failed write, where `?` on `e` would take it for a failed load.
+    fn write(e: impl fmt::Display) -> CliError {
+        CliError::Write(e.to_string())
     }
 
-    fn display(&self) {
-        match self {
-            Value::Int(i) => println!("{i}"),
-            Value::Bool(b) => println!("{b}"),
-            Value::Object(o) => o.display(),
+    /// A failed save: a failed write, unless `WriteOptions::verify` found the file wrong.
+    fn save(e: ConstantsError) -> CliError {
+        match e {
+            ConstantsError::SaveUnreadable(_) | ConstantsError::SaveMismatch(_) => e.into(),
+            e => CliError::write(e),
         }
     }
 }
 
-impl Object {
-    fn display(&self) {
-        match &*self {
-            Object::String(s) => println!("{s}"),
-            Object::Function {
-                identifier,
-                param_count,
-                code,
-            } => {
-                println!("func<'{identifier}', {param_count}>");
-
-                let mut ip = 0;
-                while ip < code.len() {
-                    let op = unsafe { std::mem::transmute::<u8, ByteCode>(code[ip]) };
-                    print!("{ip:04} ");
-                    match op {
-                        ByteCode::ConstantByte => byte_instruction(&mut ip, "CONSTANT_BYTE", &code),
-                        ByteCode::Return => simple_instruction(&mut ip, "RETURN"),
-                    }
-                }
-            }
+/// Sorts an error of the load paths by whether the input failed to load or to verify.
+impl From<ConstantsError> for CliError {
+    fn from(e: ConstantsError) -> CliError {
+        match e {
+            ConstantsError::ChecksumMismatch { .. }
+            | ConstantsError::FailedVerification { .. }
+            | ConstantsError::FunctionIssue { .. }
+            | ConstantsError::SaveUnreadable(_)
+            | ConstantsError::SaveMismatch(_)
+            | ConstantsError::SignatureMismatch
+            | ConstantsError::Unsigned => CliError::Verify(e),
+            ConstantsError::Unwritable(message) => CliError::Write(message),
+            e => CliError::Load(e),
         }
     }
 }
 
-impl ConstantIO for Value {
-    fn to_type_id(&self) -> u8 {
-        match &*self {
-            Value::Int(_) => 0,
-            Value::Bool(_) => 1,
-            Value::Object(o) => o.to_type_id(),
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) | CliError::Write(message) => write!(f, "{message}"),
+            CliError::Load(e @ ConstantsError::MissingKey) => {
+                write!(f, "{e}: pass its key with --key or --key-file")
+            }
+            CliError::Load(e) | CliError::Verify(e) => write!(f, "{e}"),
         }
     }
+}
 
-    fn read(file: &mut File, byte_id: u8, pool: &mut ObjectPool) -> Self {
-        match byte_id {
-            0 => Value::Int(read_i32(file)),
-            1 => Value::Bool(read_u8(file) == 1),
-            _ => Object::read(file, byte_id, pool),
-        }
-    }
+/// The `CliError::Usage` for `message`.
+fn usage(message: impl Into<String>) -> CliError {
+    CliError::Usage(message.into())
+}
 
-    fn write(&self, file: &mut File) {
-        let byte_id = self.to_type_id();
-        file.write(&[byte_id]).unwrap();
+/// A constants file to load, or one read ahead into memory: stdin, since loading seeks,
+/// or the decoded contents of an armored file.
+enum Input {
+    File(File),
+    Memory(io::Cursor<Vec<u8>>),
+}
 
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
-            Value::Int(i) => _ = file.write(&i.to_be_bytes()).unwrap(),
-            Value::Bool(b) => _ = file.write(&[if *b { 1 } else { 0 }]).unwrap(),
-            Value::Object(o) => o.write(file),
+            Input::File(file) => file.read(buf),
+            Input::Memory(bytes) => bytes.read(buf),
         }
     }
 }
 
-impl ConstantIO for Object {
-    fn to_type_id(&self) -> u8 {
-        match &*self {
-            Object::String(_) => 2,
-            Object::Function { .. } => 3,
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Input::File(file) => file.seek(pos),
+            Input::Memory(bytes) => bytes.seek(pos),
         }
     }
+}
 
-    fn read(file: &mut File, byte_id: u8, pool: &mut ObjectPool) -> Value {
-        match byte_id {
-            // String
-            2 => {
-                let str = read_string(file);
-                let obj = Rc::new(Object::String(str));
-                pool.push(Rc::clone(&obj));
-                Value::Object(obj)
-            }
-            3 => {
-                let identifier = read_string(file);
-                let param_count = read_u8(file);
-                let code = read_bytes(file);
-
-                let obj = Rc::new(Object::Function {
-                    identifier,
-                    param_count,
-                    code,
-                });
-                pool.push(Rc::clone(&obj));
-                Value::Object(obj)
-            }
-            _ => unreachable!("Invalid ID"),
-        }
-    }
+/// Opens `path` to load from, where `-` is stdin. Armored input is decoded up front, so
+/// loaders only ever see the binary form.
+fn open_input(path: &str) -> Result<Input, ConstantsError> {
+    let mut input = if path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().lock().read_to_end(&mut bytes)?;
+        Input::Memory(io::Cursor::new(bytes))
+    } else {
+        Input::File(File::open(path).map_err(open_error(path))?)
+    };
 
-    fn write(&self, file: &mut File) {
-        match &*self {
-            Object::String(s) => write_string(file, s),
-            Object::Function {
-                identifier,
-                param_count,
-                code,
-            } => {
-                write_string(file, identifier);
-                file.write(&param_count.to_be_bytes()).unwrap();
-
-                file.write(&code.len().to_be_bytes()).unwrap();
-                file.write(&code).unwrap();
-            }
-        }
+    let mut start = Vec::new();
+    input
+        .by_ref()
+        .take(ARMOR_BEGIN.len() as u64)
+        .read_to_end(&mut start)?;
+    input.rewind()?;
+    if start == ARMOR_BEGIN.as_bytes() {
+        let mut text = Vec::new();
+        input.read_to_end(&mut text)?;
+        return Ok(Input::Memory(io::Cursor::new(dearmor(&text)?)));
     }
+    Ok(input)
 }
 
-fn write_string(file: &mut File, str: &String) {
-    file.write(&str.len().to_be_bytes()).unwrap();
-    file.write(str.as_bytes()).unwrap();
+/// Where a constants file is saved: a file, one that replaces `path` only once finished,
+/// or stdout.
+enum Output {
+    File(File),
+    Atomic(AtomicFile),
+    Stdout(io::StdoutLock<'static>),
 }
 
-fn read_bytes(file: &mut File) -> Vec<u8> {
-    let size = read_usize(file);
-    let mut buffer = (0..size).map(|_| 0).collect::<Vec<u8>>();
-    file.read_exact(&mut buffer).unwrap();
-
-    buffer
-}
-
-fn read_string(file: &mut File) -> String {
-    let buffer = read_bytes(file);
-    String::from_utf8(buffer).unwrap()
+impl Output {
+    /// Flushes everything written, and puts an atomic save in place.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::File(mut file) => file.flush(),
+            Output::Atomic(file) => file.commit(),
+            Output::Stdout(mut stdout) => stdout.flush(),
+        }
+    }
 }
 
-fn read_u8(file: &mut File) -> u8 {
-    let mut buffer = [0u8; 1];
-    file.read_exact(&mut buffer).unwrap();
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::File(file) => file.write(buf),
+            Output::Atomic(file) => file.write(buf),
+            Output::Stdout(stdout) => stdout.write(buf),
+        }
+    }
 
-    u8::from_be_bytes(buffer)
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::File(file) => file.flush(),
+            Output::Atomic(file) => file.flush(),
+            Output::Stdout(stdout) => stdout.flush(),
+        }
+    }
 }
 
-fn read_i32(file: &mut File) -> i32 {
-    let mut buffer = [0u8; 4];
-    file.read_exact(&mut buffer).unwrap();
-
-    i32::from_be_bytes(buffer)
+/// Creates `path` to save to, where `-` is stdout. An `atomic` save writes beside `path`
+/// and replaces it on `Output::finish`.
+fn create_output(path: &str, atomic: bool) -> io::Result<Output> {
+    if path == "-" {
+        return Ok(Output::Stdout(io::stdout().lock()));
+    }
+    if atomic {
+        return AtomicFile::create(path).map(Output::Atomic);
+    }
+    File::create(path).map(Output::File)
 }
 
-fn read_usize(file: &mut File) -> usize {
-    let mut buffer = [0u8; 8];
-    file.read_exact(&mut buffer).unwrap();
-
-    usize::from_be_bytes(buffer)
+/// Locks `path` for a command, waiting `wait` for it, or not at all with `--no-lock`.
+/// Stdin and stdout aren't locked, and nor is a read where no lock file can be made.
+/// Failing to lock for a read fails the load, and for a write the write.
+fn cli_lock(
+    path: &str,
+    kind: LockKind,
+    wait: Option<Duration>,
+) -> Result<Option<FileLock>, CliError> {
+    let Some(wait) = wait.filter(|_| path != "-") else {
+        return Ok(None);
+    };
+    match FileLock::acquire(path, kind, Some(wait)) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(e) if kind == LockKind::Shared && e.kind() == io::ErrorKind::WouldBlock => {
+            Err(CliError::Load(e.into()))
+        }
+        Err(_) if kind == LockKind::Shared => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(CliError::write(e)),
+        Err(e) => Err(CliError::Write(format!("Could not lock '{path}': {e}"))),
+    }
 }
 
-fn simple_instruction(ip: &mut usize, label: &'static str) {
-    println!("{label}");
-    *ip += 1;
+/// Reads the key given by `--key TEXT` or `--key-file PATH`.
+fn read_key_flag(flag: &str, value: Option<&String>) -> Result<Vec<u8>, CliError> {
+    if flag == "--key-file" {
+        return read_key_file(flag, value);
+    }
+    let value = value.ok_or_else(|| usage(format!("Expected a value after {flag}")))?;
+    Ok(value.as_bytes().to_vec())
 }
 
-fn byte_instruction(ip: &mut usize, label: &'static str, code: &[u8]) {
-    println!("{label} {}", code[*ip + 1]);
-    *ip += 2;
+/// Reads the key in the file named after `flag`.
+fn read_key_file(flag: &str, path: Option<&String>) -> Result<Vec<u8>, CliError> {
+    let path = path.ok_or_else(|| usage(format!("Expected a key file after {flag}")))?;
+    Ok(fs::read(path).map_err(open_error(path))?)
 }
@@ -0,0 +1,2631 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use crate::{bytecode::*, io::*, value::*};
+
+pub type ObjectPool = Vec<Rc<Object>>;
+/// Finds the constants of the module called `name` among `values`. Nested modules are
+/// reached by searching the returned constants in turn.
+pub fn find_module<'a>(values: &'a [Value], name: &str) -> Option<&'a [Value]> {
+    values.iter().find_map(|value| match value {
+        Value::Object(o) => match &**o {
+            Object::Module {
+                name: module_name,
+                constants,
+            } if module_name == name => Some(constants.as_slice()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// What `check_function` finds wrong with a function, though its code may well decode:
+/// the kind of thing that otherwise only shows up once a VM runs it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    EmptyIdentifier,
+    /// More parameters than `LoadLimits::max_params`.
+    TooManyParams {
+        count: u8,
+        max: usize,
+    },
+    EmptyCode,
+    /// The last instruction neither returns nor tail calls, so the code can run off its
+    /// end. Code that doesn't decode is left to `verify_function`.
+    NoFinalReturn,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::EmptyIdentifier => write!(f, "has an empty identifier"),
+            Issue::TooManyParams { count, max } => {
+                write!(
+                    f,
+                    "declares {count} parameters, more than the limit of {max}"
+                )
+            }
+            Issue::EmptyCode => write!(f, "has no code"),
+            Issue::NoFinalReturn => write!(f, "doesn't end with RETURN"),
+        }
+    }
+}
+
+/// Checks that the metadata of `function` agrees with its code, returning every issue
+/// found. Anything but a function has none. Parameters are held to the default
+/// `LoadLimits::max_params`.
+pub fn check_function(function: &Object) -> Vec<Issue> {
+    check_function_with(function, LoadLimits::default().max_params)
+}
+
+/// `check_function` with parameters held to `max_params`, as loads take it from
+/// `LoadOptions::limits`.
+pub fn check_function_with(function: &Object, max_params: usize) -> Vec<Issue> {
+    let Object::Function {
+        identifier,
+        param_count,
+        code,
+        ..
+    } = function
+    else {
+        return Vec::new();
+    };
+
+    let mut issues = Vec::new();
+    if identifier.is_empty() {
+        issues.push(Issue::EmptyIdentifier);
+    }
+    if *param_count as usize > max_params {
+        issues.push(Issue::TooManyParams {
+            count: *param_count,
+            max: max_params,
+        });
+    }
+    match Instructions::new(code).last() {
+        None => issues.push(Issue::EmptyCode),
+        Some((_, Ok(Instruction::Return | Instruction::TailCall(_)))) | Some((_, Err(_))) => {}
+        Some(_) => issues.push(Issue::NoFinalReturn),
+    }
+    issues
+}
+
+/// Runs `check_function` over every function inside `values`, warning about each issue
+/// unless `options.strict` makes the first fatal. A function shared between constants is
+/// checked once, and named by the first.
+pub(crate) fn check_functions(
+    values: &[Value],
+    options: &LoadOptions,
+) -> Result<(), ConstantsError> {
+    let mut checked = HashSet::new();
+    for (constant, value) in values.iter().enumerate() {
+        let mut issues = Vec::new();
+        visit_functions(value, &mut |function| {
+            if !checked.insert(Rc::as_ptr(function)) {
+                return;
+            }
+            let Object::Function { identifier, .. } = &**function else {
+                unreachable!("only functions are visited");
+            };
+            for issue in check_function_with(function, options.limits.max_params) {
+                issues.push((identifier.clone(), issue));
+            }
+        });
+        for (identifier, issue) in issues {
+            if options.strict {
+                return Err(ConstantsError::FunctionIssue {
+                    identifier,
+                    constant,
+                    issue,
+                });
+            }
+            options.warn(Warning::FunctionIssue {
+                identifier,
+                constant,
+                issue,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Calls `visit` with every function inside `value`, however deep in other objects,
+/// outermost first. Objects shared within `value` are walked once, and the walk keeps
+/// its own stack, so a long pair chain can't exhaust the thread's.
+fn visit_functions(value: &Value, visit: &mut dyn FnMut(&Rc<Object>)) {
+    fn push<'a>(stack: &mut Vec<&'a Rc<Object>>, mut value: &'a Value) {
+        while let Value::Optional(Some(inner)) = value {
+            value = inner;
+        }
+        if let Value::Object(o) = value {
+            stack.push(o);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut stack = Vec::new();
+    push(&mut stack, value);
+    while let Some(o) = stack.pop() {
+        if !seen.insert(Rc::as_ptr(o)) {
+            continue;
+        }
+        // Pushed in reverse, so they come off the stack in order
+        let start = stack.len();
+        match &**o {
+            Object::Function {
+                constants,
+                defaults,
+                ..
+            } => {
+                visit(o);
+                constants
+                    .iter()
+                    .chain(defaults)
+                    .for_each(|value| push(&mut stack, value));
+            }
+            Object::List(items) | Object::Tuple(items) | Object::Set(items) => {
+                items.iter().for_each(|value| push(&mut stack, value))
+            }
+            Object::Map(entries) => {
+                for (key, value) in entries {
+                    push(&mut stack, key);
+                    push(&mut stack, value);
+                }
+            }
+            Object::EnumVariant { payload, .. } => {
+                payload.iter().for_each(|value| push(&mut stack, value))
+            }
+            Object::Record { fields, .. } => {
+                fields.iter().for_each(|(_, value)| push(&mut stack, value))
+            }
+            Object::Pair(car, cdr) => {
+                push(&mut stack, car);
+                push(&mut stack, cdr);
+            }
+            Object::Class { methods, .. } => stack.extend(methods.iter().map(|(_, method)| method)),
+            Object::Module { constants, .. } => {
+                constants.iter().for_each(|value| push(&mut stack, value))
+            }
+            _ => {}
+        }
+        stack[start..].reverse();
+    }
+}
+
+/// A variable captured by a closure: a local slot of the enclosing function when
+/// `is_local`, otherwise one of the enclosing function's own upvalues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Upvalue {
+    pub is_local: bool,
+    pub index: u8,
+}
+
+/// Static type of a function parameter or result, for link-time signature checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum TypeTag {
+    Int,
+    Bool,
+    Float,
+    String,
+    Object,
+    #[default]
+    Any,
+}
+
+impl TypeTag {
+    fn from_byte(byte: u8) -> Option<TypeTag> {
+        match byte {
+            0 => Some(TypeTag::Int),
+            1 => Some(TypeTag::Bool),
+            2 => Some(TypeTag::Float),
+            3 => Some(TypeTag::String),
+            4 => Some(TypeTag::Object),
+            5 => Some(TypeTag::Any),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TypeTag> {
+        [
+            TypeTag::Int,
+            TypeTag::Bool,
+            TypeTag::Float,
+            TypeTag::String,
+            TypeTag::Object,
+            TypeTag::Any,
+        ]
+        .into_iter()
+        .find(|tag| tag.name() == name)
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            TypeTag::Int => "int",
+            TypeTag::Bool => "bool",
+            TypeTag::Float => "float",
+            TypeTag::String => "string",
+            TypeTag::Object => "object",
+            TypeTag::Any => "any",
+        }
+    }
+}
+
+/// Accepts any number of arguments beyond `param_count`.
+pub(crate) const FUNCTION_VARIADIC: u8 = 1 << 0;
+/// Set only in the encoding, marking that a source path follows the rest of the function.
+const FUNCTION_HAS_SOURCE: u8 = 1 << 1;
+const FUNCTION_KNOWN_FLAGS: u8 = FUNCTION_VARIADIC | FUNCTION_HAS_SOURCE;
+
+/// Limit on upvalues per function, checked on build and write so the count fits its u16,
+/// and on load so a corrupt count can't claim more.
+const MAX_UPVALUES: usize = 256;
+
+#[derive(Debug, Clone)]
+pub enum Object {
+    String(String),
+    Function {
+        identifier: String,
+        param_count: u8,
+        /// `FUNCTION_*` flag bits.
+        flags: u8,
+        local_count: u16,
+        code: Vec<u8>,
+        /// Constants referenced by the function's own `ConstantByte` operands, which may
+        /// include nested functions. These can't form a cycle: an `Rc` is only made from
+        /// a finished object, which can only hold `Rc`s made before it, and nothing here
+        /// has interior mutability to point an older object at a newer one. Files can't
+        /// encode one either, as an object only refers to earlier entries of its pool. So
+        /// walking nested functions always ends, and dropping them frees every one.
+        constants: Vec<Value>,
+        /// Source lines as `(code_offset, line)` runs: each line applies from its offset
+        /// up to the next entry's.
+        lines: Option<Vec<(u32, u32)>>,
+        upvalues: Vec<Upvalue>,
+        /// Values for the trailing parameters a call site may omit.
+        defaults: Vec<Value>,
+        /// Parameter types in order, or empty when untyped.
+        param_types: Vec<TypeTag>,
+        return_type: TypeTag,
+        /// Path of the source file the function was compiled from.
+        source: Option<String>,
+    },
+    List(Vec<Value>),
+    Tuple(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bytes(Vec<u8>),
+    EnumVariant {
+        type_name: String,
+        variant: String,
+        payload: Option<Box<Value>>,
+    },
+    Record {
+        type_name: String,
+        fields: Vec<(String, Value)>,
+    },
+    /// Sign and big-endian magnitude, kept canonical: no leading zero bytes, and zero is
+    /// an empty, non-negative magnitude.
+    BigInt {
+        negative: bool,
+        magnitude: Vec<u8>,
+    },
+    Set(Vec<Value>),
+    Pair(Value, Value),
+    /// Interned by name: every symbol with the same name shares one pool entry.
+    Symbol(String),
+    /// Row-major `rows × cols` matrix; a 1×N shape is a plain vector.
+    FloatArray {
+        rows: u16,
+        cols: u16,
+        data: Vec<f64>,
+    },
+    /// Pattern text plus a set of `REGEX_*` flag bits. The pattern is not validated.
+    Regex {
+        pattern: String,
+        flags: u8,
+    },
+    /// A host-provided function the VM links by name.
+    NativeRef {
+        identifier: String,
+        param_count: u8,
+    },
+    /// A class declaration; every method is an `Object::Function`.
+    Class {
+        name: String,
+        methods: Vec<(String, Rc<Object>)>,
+    },
+    /// Constants grouped under a namespace, see `find_module`.
+    Module {
+        name: String,
+        constants: Vec<Value>,
+    },
+}
+
+/// Structural equality, as for `Value`. Sets and maps ignore order, and sets repeats, as
+/// writers sort and drop those.
+impl PartialEq for Object {
+    fn eq(&self, other: &Object) -> bool {
+        // Every item of each side is among the other's
+        fn same_items<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+            a.iter().all(|item| b.contains(item)) && b.iter().all(|item| a.contains(item))
+        }
+
+        match (self, other) {
+            (Object::String(a), Object::String(b)) => a == b,
+            (
+                Object::Function {
+                    identifier,
+                    param_count,
+                    flags,
+                    local_count,
+                    code,
+                    constants,
+                    lines,
+                    upvalues,
+                    defaults,
+                    param_types,
+                    return_type,
+                    source,
+                },
+                Object::Function {
+                    identifier: other_identifier,
+                    param_count: other_param_count,
+                    flags: other_flags,
+                    local_count: other_local_count,
+                    code: other_code,
+                    constants: other_constants,
+                    lines: other_lines,
+                    upvalues: other_upvalues,
+                    defaults: other_defaults,
+                    param_types: other_param_types,
+                    return_type: other_return_type,
+                    source: other_source,
+                },
+            ) => {
+                identifier == other_identifier
+                    && param_count == other_param_count
+                    && flags == other_flags
+                    && local_count == other_local_count
+                    && code == other_code
+                    && constants == other_constants
+                    && lines == other_lines
+                    && upvalues == other_upvalues
+                    && defaults == other_defaults
+                    && param_types == other_param_types
+                    && return_type == other_return_type
+                    && source == other_source
+            }
+            (Object::List(a), Object::List(b)) | (Object::Tuple(a), Object::Tuple(b)) => a == b,
+            (Object::Map(a), Object::Map(b)) => same_items(a, b),
+            (Object::Bytes(a), Object::Bytes(b)) => a == b,
+            (
+                Object::EnumVariant {
+                    type_name,
+                    variant,
+                    payload,
+                },
+                Object::EnumVariant {
+                    type_name: other_type_name,
+                    variant: other_variant,
+                    payload: other_payload,
+                },
+            ) => (type_name, variant, payload) == (other_type_name, other_variant, other_payload),
+            (
+                Object::Record { type_name, fields },
+                Object::Record {
+                    type_name: other_type_name,
+                    fields: other_fields,
+                },
+            ) => (type_name, fields) == (other_type_name, other_fields),
+            (
+                Object::BigInt {
+                    negative,
+                    magnitude,
+                },
+                Object::BigInt {
+                    negative: other_negative,
+                    magnitude: other_magnitude,
+                },
+            ) => (negative, magnitude) == (other_negative, other_magnitude),
+            (Object::Set(a), Object::Set(b)) => same_items(a, b),
+            (Object::Pair(..), Object::Pair(..)) => {
+                // Walked rather than recursed into, as chains can be far longer than any limit
+                let (mut a, mut b) = (self, other);
+                loop {
+                    let (Object::Pair(car, cdr), Object::Pair(other_car, other_cdr)) = (a, b)
+                    else {
+                        return a == b;
+                    };
+                    if car != other_car {
+                        return false;
+                    }
+                    match (cdr, other_cdr) {
+                        (Value::Object(next), Value::Object(other_next))
+                            if !Rc::ptr_eq(next, other_next) =>
+                        {
+                            (a, b) = (next, other_next);
+                        }
+                        _ => return cdr == other_cdr,
+                    }
+                }
+            }
+            (Object::Symbol(a), Object::Symbol(b)) => a == b,
+            (
+                Object::FloatArray { rows, cols, data },
+                Object::FloatArray {
+                    rows: other_rows,
+                    cols: other_cols,
+                    data: other_data,
+                },
+            ) => {
+                (rows, cols) == (other_rows, other_cols)
+                    && data.len() == other_data.len()
+                    && data
+                        .iter()
+                        .zip(other_data)
+                        .all(|(a, b)| a.to_bits() == b.to_bits())
+            }
+            (
+                Object::Regex { pattern, flags },
+                Object::Regex {
+                    pattern: other_pattern,
+                    flags: other_flags,
+                },
+            ) => (pattern, flags) == (other_pattern, other_flags),
+            (
+                Object::NativeRef {
+                    identifier,
+                    param_count,
+                },
+                Object::NativeRef {
+                    identifier: other_identifier,
+                    param_count: other_param_count,
+                },
+            ) => (identifier, param_count) == (other_identifier, other_param_count),
+            (
+                Object::Class { name, methods },
+                Object::Class {
+                    name: other_name,
+                    methods: other_methods,
+                },
+            ) => (name, methods) == (other_name, other_methods),
+            (
+                Object::Module { name, constants },
+                Object::Module {
+                    name: other_name,
+                    constants: other_constants,
+                },
+            ) => (name, constants) == (other_name, other_constants),
+            _ => false,
+        }
+    }
+}
+
+/// Drops a chain of pairs one cell at a time. Left to the default, each cell would drop its
+/// cdr before returning, so a long enough list would run out of stack.
+impl Drop for Object {
+    fn drop(&mut self) {
+        let Object::Pair(_, cdr) = self else {
+            return;
+        };
+        let mut next = std::mem::replace(cdr, Value::Nil);
+        // A cell still shared elsewhere is left whole for its last owner to drop
+        while let Value::Object(o) = next {
+            let Ok(mut object) = Rc::try_unwrap(o) else {
+                break;
+            };
+            next = match &mut object {
+                Object::Pair(_, cdr) => std::mem::replace(cdr, Value::Nil),
+                _ => Value::Nil,
+            };
+        }
+    }
+}
+
+const REGEX_CASE_INSENSITIVE: u8 = 1 << 0;
+const REGEX_MULTILINE: u8 = 1 << 1;
+const REGEX_DOT_ALL: u8 = 1 << 2;
+
+/// Flag bits paired with the letter used for them after the closing `/`.
+pub(crate) const REGEX_FLAGS: [(u8, char); 3] = [
+    (REGEX_CASE_INSENSITIVE, 'i'),
+    (REGEX_MULTILINE, 'm'),
+    (REGEX_DOT_ALL, 's'),
+];
+
+/// Builds `Object::Function` constants. Everything beyond the identifier, parameter count
+/// and code starts out empty.
+pub struct FunctionBuilder {
+    pub(crate) identifier: String,
+    pub(crate) param_count: u8,
+    pub(crate) flags: u8,
+    pub(crate) local_count: u16,
+    pub(crate) code: Vec<u8>,
+    pub(crate) constants: Vec<Value>,
+    pub(crate) lines: Option<Vec<(u32, u32)>>,
+    pub(crate) upvalues: Vec<Upvalue>,
+    pub(crate) defaults: Vec<Value>,
+    pub(crate) param_types: Vec<TypeTag>,
+    pub(crate) return_type: TypeTag,
+    pub(crate) source: Option<String>,
+}
+
+impl FunctionBuilder {
+    pub fn new(identifier: &str, param_count: u8, code: &[u8]) -> FunctionBuilder {
+        FunctionBuilder {
+            identifier: identifier.to_string(),
+            param_count,
+            flags: 0,
+            local_count: 0,
+            code: code.to_vec(),
+            constants: Vec::new(),
+            lines: None,
+            upvalues: Vec::new(),
+            defaults: Vec::new(),
+            param_types: Vec::new(),
+            return_type: TypeTag::Any,
+            source: None,
+        }
+    }
+
+    pub fn variadic(mut self) -> FunctionBuilder {
+        self.flags |= FUNCTION_VARIADIC;
+        self
+    }
+
+    pub fn local_count(mut self, local_count: u16) -> FunctionBuilder {
+        self.local_count = local_count;
+        self
+    }
+
+    pub fn constants(mut self, constants: Vec<Value>) -> FunctionBuilder {
+        self.constants = constants;
+        self
+    }
+
+    pub fn lines(mut self, lines: Vec<(u32, u32)>) -> FunctionBuilder {
+        self.lines = Some(lines);
+        self
+    }
+
+    pub fn upvalue(mut self, is_local: bool, index: u8) -> FunctionBuilder {
+        self.upvalues.push(Upvalue { is_local, index });
+        self
+    }
+
+    pub fn defaults(mut self, defaults: Vec<Value>) -> FunctionBuilder {
+        self.defaults = defaults;
+        self
+    }
+
+    pub fn signature(mut self, param_types: Vec<TypeTag>, return_type: TypeTag) -> FunctionBuilder {
+        self.param_types = param_types;
+        self.return_type = return_type;
+        self
+    }
+
+    pub fn source(mut self, path: &str) -> FunctionBuilder {
+        self.source = Some(path.to_string());
+        self
+    }
+
+    /// Adds the function to `pool`, failing if its identifier is empty, it has more
+    /// defaults than parameters, more than `MAX_UPVALUES` upvalues, or more parameter
+    /// type tags than a byte can count.
+    pub fn build(self, pool: &mut ObjectPool) -> Result<Value, String> {
+        check_identifier(&self.identifier)?;
+        check_defaults(&self.identifier, self.param_count, self.defaults.len())?;
+        check_upvalues(&self.identifier, self.upvalues.len())?;
+        check_param_types(&self.identifier, self.param_types.len())?;
+
+        let v = Rc::new(Object::Function {
+            identifier: self.identifier,
+            param_count: self.param_count,
+            flags: self.flags,
+            local_count: self.local_count,
+            code: self.code,
+            constants: self.constants,
+            lines: self.lines,
+            upvalues: self.upvalues,
+            defaults: self.defaults,
+            param_types: self.param_types,
+            return_type: self.return_type,
+            source: self.source,
+        });
+        pool.push(Rc::clone(&v));
+        Ok(Value::Object(v))
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Would otherwise be a blank line in a listing
+            Object::String(s) if s.is_empty() => write!(f, "<empty>"),
+            Object::String(s) => write!(f, "{s}"),
+            Object::Function {
+                identifier,
+                param_count,
+                flags,
+                local_count,
+                ..
+            } => {
+                let variadic = if flags & FUNCTION_VARIADIC != 0 {
+                    "+"
+                } else {
+                    ""
+                };
+                write!(
+                    f,
+                    "func<'{identifier}', {param_count}{variadic}, {local_count}>"
+                )
+            }
+            Object::List(items) => {
+                write!(f, "[")?;
+                write_nested_values(f, items)?;
+                write!(f, "]")
+            }
+            Object::Tuple(items) => {
+                write!(f, "(")?;
+                write_nested_values(f, items)?;
+                // A trailing comma keeps a single-element tuple from reading as a bare value
+                if items.len() == 1 {
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+            Object::Map(entries) if entries.is_empty() => write!(f, "{{}}"),
+            Object::Map(entries) => {
+                write!(f, "{{ ")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_nested_value(f, key)?;
+                    write!(f, ": ")?;
+                    write_nested_value(f, value)?;
+                }
+                write!(f, " }}")
+            }
+            Object::Bytes(bytes) => {
+                const PREVIEW_LEN: usize = 16;
+
+                write!(f, "bytes<{}> [", bytes.len())?;
+                for (i, b) in bytes.iter().take(PREVIEW_LEN).enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{b:02x}")?;
+                }
+                if bytes.len() > PREVIEW_LEN {
+                    write!(f, " ...")?;
+                }
+                write!(f, "]")
+            }
+            Object::EnumVariant {
+                type_name,
+                variant,
+                payload,
+            } => {
+                write!(f, "{type_name}::{variant}")?;
+                if let Some(payload) = payload {
+                    write!(f, "(")?;
+                    write_nested_value(f, payload)?;
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Object::Record { type_name, fields } if fields.is_empty() => {
+                write!(f, "{type_name} {{}}")
+            }
+            Object::Record { type_name, fields } => {
+                write!(f, "{type_name} {{ ")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{name}: ")?;
+                    write_nested_value(f, value)?;
+                }
+                write!(f, " }}")
+            }
+            Object::BigInt {
+                negative,
+                magnitude,
+            } => {
+                if *negative {
+                    write!(f, "-")?;
+                }
+                write!(f, "{}", big_int_to_decimal(magnitude))
+            }
+            // Distinguishes an empty set from an empty map
+            Object::Set(items) if items.is_empty() => write!(f, "set{{}}"),
+            Object::Set(items) => {
+                write!(f, "{{")?;
+                write_nested_values(f, items)?;
+                write!(f, "}}")
+            }
+            Object::Pair(car, cdr) => {
+                // Walk the cdr chain iteratively so long lists don't recurse per cell
+                write!(f, "(")?;
+                write_nested_value(f, car)?;
+
+                let mut tail = cdr;
+                while let Some((car, cdr)) = tail.as_pair() {
+                    write!(f, " ")?;
+                    write_nested_value(f, car)?;
+                    tail = cdr;
+                }
+
+                if !matches!(tail, Value::Nil) {
+                    write!(f, " . ")?;
+                    write_nested_value(f, tail)?;
+                }
+                write!(f, ")")
+            }
+            Object::Symbol(name) => write!(f, ":{name}"),
+            Object::FloatArray { rows, cols, data } => {
+                const MAX_PRINTED_LEN: usize = 16;
+
+                write!(f, "float[{rows}x{cols}]")?;
+                // Built by hand, an array may not fill its shape, so has no rows to show
+                let fits = check_float_array_shape(*rows, *cols, data.len()).is_ok();
+                if data.is_empty() || data.len() > MAX_PRINTED_LEN || !fits {
+                    return Ok(());
+                }
+
+                write!(f, " [")?;
+                for (i, row) in data.chunks(*cols as usize).enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    for (j, x) in row.iter().enumerate() {
+                        if j > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{x:?}")?;
+                    }
+                }
+                write!(f, "]")
+            }
+            Object::Regex { pattern, flags } => {
+                write!(f, "/{pattern}/")?;
+                for (bit, letter) in REGEX_FLAGS {
+                    if flags & bit != 0 {
+                        write!(f, "{letter}")?;
+                    }
+                }
+                Ok(())
+            }
+            Object::NativeRef {
+                identifier,
+                param_count,
+            } => write!(f, "native<'{identifier}', {param_count}>"),
+            Object::Class { name, methods } => {
+                write!(f, "class<'{name}', {} methods>", methods.len())
+            }
+            Object::Module { name, constants } => {
+                write!(f, "module<'{name}', {} constants>", constants.len())
+            }
+        }
+    }
+}
+
+impl Object {
+    pub(crate) fn disassemble_at(
+        &self,
+        out: &mut impl Write,
+        depth: usize,
+        options: &DisassembleOptions,
+    ) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+        match self {
+            Object::Function { .. } => {
+                writeln!(out, "{indent}{self}")?;
+                self.disassemble_function_body(out, depth, options)?;
+            }
+            Object::Class { methods, .. } => {
+                writeln!(out, "{indent}{self}")?;
+                for (name, method) in methods {
+                    writeln!(out, "{indent}    {name}: {method}")?;
+                    method.disassemble_function_body(out, depth + 2, options)?;
+                }
+            }
+            Object::Module { constants, .. } => {
+                writeln!(out, "{indent}{self}")?;
+                for constant in constants {
+                    constant.disassemble_at(out, depth + 1, options)?;
+                }
+            }
+            _ => writeln!(out, "{indent}{self}")?,
+        }
+        Ok(())
+    }
+
+    /// Writes everything below a function's header, indented one level per `depth`.
+    /// Functions in the constant table are disassembled in place one level deeper.
+    fn disassemble_function_body(
+        &self,
+        out: &mut impl Write,
+        depth: usize,
+        options: &DisassembleOptions,
+    ) -> io::Result<()> {
+        let Object::Function {
+            identifier,
+            param_count,
+            local_count,
+            code,
+            constants,
+            lines,
+            upvalues,
+            defaults,
+            param_types,
+            return_type,
+            source,
+            ..
+        } = self
+        else {
+            return Ok(());
+        };
+        let indent = "    ".repeat(depth);
+
+        if let Some(source) = source {
+            writeln!(out, "{indent}// from {source}")?;
+        }
+
+        if !param_types.is_empty() || *return_type != TypeTag::Any {
+            let params = param_types
+                .iter()
+                .map(|tag| format!("{tag:?}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(
+                out,
+                "{indent}func {identifier}({params}) -> {return_type:?}"
+            )?;
+        }
+
+        // Files are checked for more defaults than parameters, but hand-built objects may
+        // still have them, numbered from zero here
+        let first_default = (*param_count as usize).saturating_sub(defaults.len());
+        for (i, default) in defaults.iter().enumerate() {
+            writeln!(
+                out,
+                "{indent}param[{}] = {}",
+                first_default + i,
+                NestedValue(default)
+            )?;
+        }
+
+        if !upvalues.is_empty() {
+            writeln!(out, "{indent}upvalues:")?;
+            for (i, upvalue) in upvalues.iter().enumerate() {
+                let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                writeln!(out, "{indent}    {i:04} {kind} {}", upvalue.index)?;
+            }
+        }
+
+        // Label every jump target that starts an instruction, numbered in code order
+        let labels = options.labels.then(|| {
+            let starts = Instructions::new(code)
+                .map(|(offset, _)| offset)
+                .collect::<Vec<_>>();
+            let mut targets = Instructions::new(code)
+                .filter_map(|(offset, instruction)| instruction.ok()?.jump_target(offset))
+                .filter_map(|target| usize::try_from(target).ok())
+                .filter(|target| starts.contains(target))
+                .collect::<Vec<_>>();
+            targets.sort_unstable();
+            targets.dedup();
+            targets
+        });
+
+        if code.is_empty() {
+            writeln!(out, "{indent}<empty>")?;
+        }
+        let mut last_line = None;
+        for (offset, instruction) in Instructions::new(code) {
+            if let Some(label) = labels.iter().flatten().position(|&t| t == offset) {
+                writeln!(out, "{indent}L{label}:")?;
+            }
+            write!(out, "{indent}{offset:04} ")?;
+            if let Some(lines) = lines {
+                let line = line_at(lines, offset);
+                match line {
+                    Some(line) if last_line != Some(line) => write!(out, "{line:4} ")?,
+                    Some(_) => write!(out, "   | ")?,
+                    None => write!(out, "   ? ")?,
+                }
+                last_line = line;
+            }
+
+            let instruction = match instruction {
+                Ok(instruction) => instruction,
+                Err(DecodeError::UnknownOpcode(byte)) => {
+                    writeln!(out, "UNKNOWN_OPCODE {byte:#04x}")?;
+                    continue;
+                }
+                Err(DecodeError::Truncated(op)) => {
+                    writeln!(out, "{} <truncated>", op.mnemonic())?;
+                    continue;
+                }
+            };
+
+            let info = instruction.op().info();
+            let (label, operand) = (info.name, instruction.operand());
+            match info.operand {
+                OperandKind::None => simple_instruction(out, label)?,
+                OperandKind::Constant => {
+                    constant_instruction(out, label, operand as usize, constants)?
+                }
+                OperandKind::Local => byte_instruction(out, label, operand as u8, *local_count)?,
+                OperandKind::ArgCount => call_instruction(out, label, operand as u8)?,
+                OperandKind::ForwardJump | OperandKind::BackwardJump => {
+                    let target = instruction.jump_target(offset).unwrap_or_default();
+                    jump_instruction(out, label, operand, target, labels.as_deref())?
+                }
+            }
+        }
+
+        if !constants.is_empty() {
+            writeln!(out, "{indent}constants:")?;
+            for (i, constant) in constants.iter().enumerate() {
+                writeln!(out, "{indent}    {i:04} {constant}")?;
+                if let Value::Object(o) = constant {
+                    o.disassemble_function_body(out, depth + 2, options)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Functions built here need a name, so `func<''>` never shows up in a disassembly. Files
+/// from elsewhere may still hold nameless functions, which load as they are.
+pub(crate) fn check_identifier(identifier: &str) -> Result<(), String> {
+    if identifier.is_empty() {
+        return Err("Function identifiers can't be empty".to_string());
+    }
+
+    Ok(())
+}
+
+pub(crate) fn check_defaults(
+    identifier: &str,
+    param_count: u8,
+    default_count: usize,
+) -> Result<(), String> {
+    if default_count > param_count as usize {
+        return Err(format!(
+            "Function '{identifier}' has {default_count} defaults for {param_count} parameters"
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_upvalues(identifier: &str, count: usize) -> Result<(), String> {
+    if count > MAX_UPVALUES {
+        return Err(format!(
+            "Function '{identifier}' declares {count} upvalues, more than {MAX_UPVALUES}"
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_param_types(identifier: &str, count: usize) -> Result<(), String> {
+    if count > u8::MAX as usize {
+        return Err(format!(
+            "Function '{identifier}' has {count} parameter type tags, more than {}",
+            u8::MAX
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_function(
+    file: &mut impl Read,
+    revision: usize,
+    layout: Layout,
+    pool: &mut ObjectPool,
+) -> Result<Value, ConstantsError> {
+    let identifier = read_string(file, layout)?;
+    let param_count = read_u8(file)?;
+    let mut flags = if revision >= 7 { read_u8(file)? } else { 0 };
+    if flags & !FUNCTION_KNOWN_FLAGS != 0 {
+        return invalid(format!(
+            "Function '{identifier}' has unknown flag bits {:#04x}",
+            flags & !FUNCTION_KNOWN_FLAGS
+        ));
+    }
+    let local_count = if revision >= 1 {
+        read_u16(file, layout)?
+    } else {
+        0
+    };
+    let code = read_bytes_within(file, layout, layout.limits.max_code_len, "Code length")?;
+    let constants = if revision >= 2 {
+        read_values(file, layout, pool)?
+    } else {
+        Vec::new()
+    };
+    let lines = if revision >= 3 && read_u8(file)? == 1 {
+        let count = read_usize(file, layout)?;
+        Some(
+            (0..count)
+                .map(|_| Ok((read_u32(file, layout)?, read_u32(file, layout)?)))
+                .collect::<Result<_, ConstantsError>>()?,
+        )
+    } else {
+        None
+    };
+    let upvalues = if revision >= 4 {
+        let count = read_u16(file, layout)? as usize;
+        check_upvalues(&identifier, count).or_else(invalid)?;
+
+        (0..count)
+            .map(|_| {
+                Ok(Upvalue {
+                    is_local: read_u8(file)? == 1,
+                    index: read_u8(file)?,
+                })
+            })
+            .collect::<Result<_, ConstantsError>>()?
+    } else {
+        Vec::new()
+    };
+    let defaults = if revision >= 5 {
+        read_values(file, layout, pool)?
+    } else {
+        Vec::new()
+    };
+    check_defaults(&identifier, param_count, defaults.len()).or_else(invalid)?;
+    let (param_types, return_type) = if revision >= 6 {
+        let type_tag = |byte| {
+            TypeTag::from_byte(byte)
+                .ok_or_else(|| ConstantsError::Invalid(format!("Unknown type tag {byte}")))
+        };
+        let return_type = type_tag(read_u8(file)?)?;
+        let count = read_u8(file)?;
+        let param_types = (0..count)
+            .map(|_| type_tag(read_u8(file)?))
+            .collect::<Result<_, ConstantsError>>()?;
+        (param_types, return_type)
+    } else {
+        (Vec::new(), TypeTag::Any)
+    };
+    let source = if flags & FUNCTION_HAS_SOURCE != 0 {
+        flags &= !FUNCTION_HAS_SOURCE;
+        Some(read_string(file, layout)?)
+    } else {
+        None
+    };
+
+    let obj = Rc::new(Object::Function {
+        identifier,
+        param_count,
+        flags,
+        local_count,
+        code,
+        constants,
+        lines,
+        upvalues,
+        defaults,
+        param_types,
+        return_type,
+        source,
+    });
+    pool.push(Rc::clone(&obj));
+    Ok(Value::Object(obj))
+}
+
+impl Object {
+    /// Type id as written with `options`, which differs from `to_type_id` for strings
+    /// stored in a non-default encoding.
+    pub(crate) fn encoded_type_id(&self, options: &WriteOptions) -> u8 {
+        match self {
+            Object::String(_) if options.string_encoding != StringEncoding::Utf8 => 26,
+            _ => self.to_type_id(),
+        }
+    }
+}
+
+/// Writes the payload of `function`, apart from `Object::write` so the frame objects
+/// nest through stays small.
+fn write_function(
+    file: &mut impl Write,
+    function: &Object,
+    options: &WriteState,
+) -> Result<(), ConstantsError> {
+    let Object::Function {
+        identifier,
+        param_count,
+        flags,
+        local_count,
+        code,
+        constants,
+        lines,
+        upvalues,
+        defaults,
+        param_types,
+        return_type,
+        source,
+    } = function
+    else {
+        unreachable!("only functions are written here");
+    };
+    check_defaults(identifier, *param_count, defaults.len()).or_else(unwritable)?;
+    check_upvalues(identifier, upvalues.len()).or_else(unwritable)?;
+    check_param_types(identifier, param_types.len()).or_else(unwritable)?;
+
+    let mut flags = *flags;
+    if source.is_some() {
+        flags |= FUNCTION_HAS_SOURCE;
+    }
+
+    write_string(file, identifier, options)?;
+    write_number(file, *param_count, options)?;
+    file.write_all(&[flags])?;
+    write_number(file, *local_count, options)?;
+
+    write_bytes(file, code, options)?;
+    write_values(file, constants, options)?;
+
+    match lines {
+        Some(lines) => {
+            file.write_all(&[1])?;
+            write_length(file, lines.len(), options)?;
+            for (offset, line) in lines {
+                write_number(file, *offset, options)?;
+                write_number(file, *line, options)?;
+            }
+        }
+        None => file.write_all(&[0])?,
+    }
+
+    write_number(file, upvalues.len() as u16, options)?;
+    for upvalue in upvalues {
+        file.write_all(&[upvalue.is_local as u8, upvalue.index])?;
+    }
+
+    write_values(file, defaults, options)?;
+
+    file.write_all(&[*return_type as u8])?;
+    file.write_all(&[param_types.len() as u8])?;
+    for tag in param_types {
+        file.write_all(&[*tag as u8])?;
+    }
+
+    if let Some(source) = source {
+        write_string(file, source, options)?;
+    }
+
+    Ok(())
+}
+
+impl ConstantIO for Object {
+    fn to_type_id(&self) -> u8 {
+        match self {
+            Object::String(_) => 2,
+            Object::Function { .. } => FUNCTION_TYPE_ID,
+            Object::List(_) => 11,
+            Object::Tuple(_) => 12,
+            Object::Map(_) => 13,
+            Object::Bytes(_) => 14,
+            Object::EnumVariant { .. } => 15,
+            Object::Record { .. } => 16,
+            Object::BigInt { .. } => 17,
+            Object::Set(_) => 19,
+            Object::Pair(..) => 20,
+            Object::Symbol(_) => 21,
+            Object::FloatArray { .. } => 28,
+            Object::Regex { .. } => 30,
+            Object::NativeRef { .. } => 38,
+            Object::Class { .. } => 39,
+            Object::Module { .. } => 40,
+        }
+    }
+
+    fn read(
+        file: &mut impl Read,
+        byte_id: u8,
+        layout: Layout,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, ConstantsError> {
+        if let Some(revision) = FUNCTION_TYPE_IDS.iter().position(|id| *id == byte_id) {
+            return read_function(file, revision, layout, pool);
+        }
+
+        Ok(match byte_id {
+            // String
+            2 => {
+                if let Some(strings) = layout.strings {
+                    return Ok(Value::Object(read_table_string(file, strings, layout)?));
+                }
+
+                let bytes = read_bytes(file, layout)?;
+                let obj =
+                    if layout.utf8 == Utf8Policy::Bytes && std::str::from_utf8(&bytes).is_err() {
+                        count_invalid_utf8(layout);
+                        Object::Bytes(bytes)
+                    } else {
+                        Object::String(decode_utf8(bytes, layout)?)
+                    };
+                let obj = Rc::new(obj);
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            11 => {
+                let items = read_values(file, layout, pool)?;
+                let obj = Rc::new(Object::List(items));
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            12 => {
+                let items = read_values(file, layout, pool)?;
+                let obj = Rc::new(Object::Tuple(items));
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            13 => {
+                let count = read_usize(file, layout)?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    let key_id = read_u8(file)?;
+                    let key = Value::read(file, key_id, layout, pool)?;
+                    let value_id = read_u8(file)?;
+                    let value = Value::read(file, value_id, layout, pool)?;
+                    entries.push((key, value));
+                }
+
+                let obj = Rc::new(Object::Map(entries));
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            14 => {
+                let obj = Rc::new(Object::Bytes(read_bytes(file, layout)?));
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            15 => {
+                let type_name = read_string(file, layout)?;
+                let variant = read_string(file, layout)?;
+                let payload = match read_u8(file)? {
+                    0 => None,
+                    1 => {
+                        let payload_id = read_u8(file)?;
+                        Some(Box::new(Value::read(file, payload_id, layout, pool)?))
+                    }
+                    flag => return invalid(format!("Invalid enum payload flag {flag}")),
+                };
+
+                let obj = Rc::new(Object::EnumVariant {
+                    type_name,
+                    variant,
+                    payload,
+                });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            16 => {
+                let type_name = read_string(file, layout)?;
+                let count = read_usize(file, layout)?;
+                let mut fields = Vec::new();
+                for _ in 0..count {
+                    let name = read_string(file, layout)?;
+                    let value_id = read_u8(file)?;
+                    fields.push((name, Value::read(file, value_id, layout, pool)?));
+                }
+
+                let obj = Rc::new(Object::Record { type_name, fields });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            17 => {
+                let negative = read_u8(file)? == 1;
+                let magnitude = read_bytes(file, layout)?;
+                if magnitude.first() == Some(&0) || (negative && magnitude.is_empty()) {
+                    return invalid("Non-canonical big integer encoding");
+                }
+
+                let obj = Rc::new(Object::BigInt {
+                    negative,
+                    magnitude,
+                });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            19 => {
+                let items = read_values(file, layout, pool)?;
+                let obj = Rc::new(Object::Set(items));
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            20 => {
+                // A cdr that is itself a pair is read in this loop rather than recursively,
+                // then the chain is linked back up from the innermost cell
+                let mut cars = Vec::new();
+                let tail = loop {
+                    let car_id = read_u8(file)?;
+                    cars.push(Value::read(file, car_id, layout, pool)?);
+
+                    let cdr_id = read_u8(file)?;
+                    if cdr_id != 20 {
+                        break Value::read(file, cdr_id, layout, pool)?;
+                    }
+                };
+
+                let mut value = tail;
+                for car in cars.into_iter().rev() {
+                    let obj = Rc::new(Object::Pair(car, value));
+                    pool.push(Rc::clone(&obj));
+                    value = Value::Object(obj);
+                }
+                value
+            }
+            21 => {
+                let name = read_string(file, layout)?;
+                Value::Object(match layout.symbols {
+                    Some(symbols) => symbols.borrow_mut().intern(name, pool),
+                    None => intern_symbol(name, pool),
+                })
+            }
+            28 => {
+                let rows = read_u16(file, layout)?;
+                let cols = read_u16(file, layout)?;
+                let len = rows as usize * cols as usize;
+                let data = (0..len)
+                    .map(|_| Ok(f64::from_bits(read_u64(file, layout)?)))
+                    .collect::<Result<Vec<_>, ConstantsError>>()?;
+
+                let obj = Rc::new(Object::FloatArray { rows, cols, data });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            30 => {
+                let pattern = read_string(file, layout)?;
+                let flags = read_u8(file)?;
+
+                let known = REGEX_FLAGS.iter().fold(0, |bits, (bit, _)| bits | bit);
+                if flags & !known != 0 {
+                    return invalid(format!(
+                        "Regex /{pattern}/ has unknown flag bits {:#04x}",
+                        flags & !known
+                    ));
+                }
+
+                let obj = Rc::new(Object::Regex { pattern, flags });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            38 => {
+                let identifier = read_string(file, layout)?;
+                let param_count = read_u8(file)?;
+
+                let obj = Rc::new(Object::NativeRef {
+                    identifier,
+                    param_count,
+                });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            39 => {
+                let name = read_string(file, layout)?;
+                let count = read_usize(file, layout)?;
+                let mut methods = Vec::new();
+                for _ in 0..count {
+                    let method_name = read_string(file, layout)?;
+                    let method_id = read_u8(file)?;
+                    match Value::read(file, method_id, layout, pool)? {
+                        Value::Object(o) if matches!(*o, Object::Function { .. }) => {
+                            methods.push((method_name, o))
+                        }
+                        method => {
+                            return invalid(format!(
+                            "Method '{method_name}' of class '{name}' is not a function: {method}"
+                        ))
+                        }
+                    }
+                }
+
+                let obj = Rc::new(Object::Class { name, methods });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            40 => {
+                let name = read_string(file, layout)?;
+                let constants = read_values(file, layout, pool)?;
+
+                let obj = Rc::new(Object::Module { name, constants });
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            // String with an explicit encoding
+            26 => {
+                let byte = read_u8(file)?;
+                let Some(encoding) = StringEncoding::from_byte(byte) else {
+                    return invalid(format!("Unknown string encoding {byte}"));
+                };
+                let str = read_string_encoded(file, encoding, layout)?;
+                let obj = Rc::new(Object::String(str));
+                pool.push(Rc::clone(&obj));
+                Value::Object(obj)
+            }
+            id => return Err(ConstantsError::UnknownTypeId { id, offset: None }),
+        })
+    }
+
+    fn write(&self, file: &mut impl Write, options: &WriteState) -> Result<(), ConstantsError> {
+        match self {
+            Object::String(s) if options.string_encoding != StringEncoding::Utf8 => {
+                file.write_all(&[options.string_encoding as u8])?;
+                write_string_encoded(file, s, options.string_encoding, options)?;
+            }
+            Object::String(s) => write_string(file, s, options)?,
+            Object::Function { .. } => write_function(file, self, options)?,
+            Object::List(items) | Object::Tuple(items) => write_values(file, items, options)?,
+            Object::Map(entries) => {
+                let mut entries = entries.iter().collect::<Vec<_>>();
+                if options.deterministic {
+                    entries.sort_by_cached_key(|(key, _)| canonical_encoding(key, options));
+                }
+
+                write_length(file, entries.len(), options)?;
+                for (key, value) in entries {
+                    key.write(file, options)?;
+                    value.write(file, options)?;
+                }
+            }
+            Object::Bytes(bytes) => write_bytes(file, bytes, options)?,
+            Object::EnumVariant {
+                type_name,
+                variant,
+                payload,
+            } => {
+                write_string(file, type_name, options)?;
+                write_string(file, variant, options)?;
+
+                match payload {
+                    Some(payload) => {
+                        file.write_all(&[1])?;
+                        payload.write(file, options)?;
+                    }
+                    None => file.write_all(&[0])?,
+                }
+            }
+            Object::Record { type_name, fields } => {
+                write_string(file, type_name, options)?;
+                write_length(file, fields.len(), options)?;
+                for (name, value) in fields {
+                    write_string(file, name, options)?;
+                    value.write(file, options)?;
+                }
+            }
+            Object::BigInt {
+                negative,
+                magnitude,
+            } => {
+                let magnitude = trim_leading_zeros(magnitude);
+                let negative = *negative && !magnitude.is_empty();
+                file.write_all(&[if negative { 1 } else { 0 }])?;
+                write_bytes(file, magnitude, options)?;
+            }
+            Object::Set(items) => {
+                let items = canonical_set_items(items, options);
+                write_length(file, items.len(), options)?;
+                for item in items {
+                    item.write(file, options)?;
+                }
+            }
+            Object::Pair(car, cdr) => {
+                car.write(file, options)?;
+
+                let mut tail = cdr;
+                while let Some((car, cdr)) = tail.as_pair() {
+                    file.write_all(&[self.to_type_id()])?;
+                    car.write(file, options)?;
+                    tail = cdr;
+                }
+                tail.write(file, options)?;
+            }
+            Object::Symbol(name) => write_string(file, name, options)?,
+            Object::FloatArray { rows, cols, data } => {
+                check_float_array_shape(*rows, *cols, data.len()).or_else(unwritable)?;
+
+                write_number(file, *rows, options)?;
+                write_number(file, *cols, options)?;
+                for x in data {
+                    write_number(file, x.to_bits(), options)?;
+                }
+            }
+            Object::Regex { pattern, flags } => {
+                write_string(file, pattern, options)?;
+                file.write_all(&[*flags])?;
+            }
+            Object::NativeRef {
+                identifier,
+                param_count,
+            } => {
+                write_string(file, identifier, options)?;
+                file.write_all(&[*param_count])?;
+            }
+            Object::Class { name, methods } => {
+                // Checked up front so a bad method can't leave a half-written class behind
+                for (method_name, method) in methods {
+                    if !matches!(**method, Object::Function { .. }) {
+                        return unwritable(format!(
+                            "Method '{method_name}' of class '{name}' is not a function: {method}"
+                        ));
+                    }
+                }
+
+                write_string(file, name, options)?;
+                write_length(file, methods.len(), options)?;
+                for (method_name, method) in methods {
+                    write_string(file, method_name, options)?;
+                    Value::Object(Rc::clone(method)).write(file, options)?;
+                }
+            }
+            Object::Module { name, constants } => {
+                write_string(file, name, options)?;
+                write_values(file, constants, options)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn check_float_array_shape(rows: u16, cols: u16, len: usize) -> Result<(), String> {
+    if rows as usize * cols as usize != len {
+        return Err(format!(
+            "Float array shape {rows}x{cols} does not match its {len} elements"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The symbols of a pool by name, so interning one is a lookup rather than a search of
+/// every object. It only knows of those in the pool when it was made and interned since.
+#[derive(Debug, Default)]
+pub(crate) struct SymbolTable {
+    symbols: HashMap<Rc<str>, Rc<Object>>,
+}
+
+impl SymbolTable {
+    pub(crate) fn of(pool: &ObjectPool) -> SymbolTable {
+        let mut table = SymbolTable::default();
+        for object in pool {
+            if let Object::Symbol(name) = &**object {
+                table
+                    .symbols
+                    .entry(name.as_str().into())
+                    .or_insert_with(|| Rc::clone(object));
+            }
+        }
+        table
+    }
+
+    /// The symbol called `name`, added to `pool` the first time it's met.
+    pub(crate) fn intern(&mut self, name: String, pool: &mut ObjectPool) -> Rc<Object> {
+        if let Some(symbol) = self.symbols.get(name.as_str()) {
+            return Rc::clone(symbol);
+        }
+        let key = Rc::from(name.as_str());
+        let symbol = Rc::new(Object::Symbol(name));
+        pool.push(Rc::clone(&symbol));
+        self.symbols.insert(key, Rc::clone(&symbol));
+        symbol
+    }
+}
+
+/// Interns one symbol in `pool`, searching it for an earlier one of the same name. Reads,
+/// which may meet thousands, go through a `SymbolTable` instead.
+pub(crate) fn intern_symbol(name: String, pool: &mut ObjectPool) -> Rc<Object> {
+    let existing = pool
+        .iter()
+        .find(|o| matches!(&***o, Object::Symbol(n) if *n == name));
+
+    match existing {
+        Some(symbol) => Rc::clone(symbol),
+        None => {
+            let symbol = Rc::new(Object::Symbol(name));
+            pool.push(Rc::clone(&symbol));
+            symbol
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A function built as `FunctionBuilder` would, then renamed to `identifier`, which
+    /// the builder itself may refuse.
+    fn function(identifier: &str, param_count: u8, code: &[u8]) -> Object {
+        let Value::Object(o) = FunctionBuilder::new("f", param_count, code)
+            .build(&mut ObjectPool::new())
+            .unwrap()
+        else {
+            unreachable!("the builder makes objects");
+        };
+        let mut function = (*o).clone();
+        if let Object::Function { identifier: id, .. } = &mut function {
+            *id = identifier.to_string();
+        }
+        function
+    }
+
+    #[test]
+    fn check_function_returns_every_issue() {
+        assert_eq!(
+            check_function(&function("", 200, &[])),
+            [
+                Issue::EmptyIdentifier,
+                Issue::TooManyParams {
+                    count: 200,
+                    max: 128
+                },
+                Issue::EmptyCode,
+            ]
+        );
+        assert_eq!(
+            check_function(&function("f", 0, &[ByteCode::Negate as u8])),
+            [Issue::NoFinalReturn]
+        );
+        assert_eq!(
+            check_function(&function("f", 2, &[ByteCode::Return as u8])),
+            []
+        );
+        assert_eq!(
+            check_function_with(&function("f", 2, &[ByteCode::Return as u8]), 1),
+            [Issue::TooManyParams { count: 2, max: 1 }]
+        );
+        assert_eq!(check_function(&Object::String(String::new())), []);
+    }
+
+    #[test]
+    fn upvalues_stop_at_the_limit_on_build_and_write() {
+        let code = [ByteCode::Return as u8];
+        let with_upvalues = |count: usize| {
+            (0..count).fold(FunctionBuilder::new("f", 0, &code), |builder, i| {
+                builder.upvalue(i % 2 == 0, i as u8)
+            })
+        };
+        let mut pool = ObjectPool::new();
+        let values = [with_upvalues(MAX_UPVALUES).build(&mut pool).unwrap()];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, values);
+
+        let message = "Function 'f' declares 257 upvalues, more than 256";
+        assert_eq!(
+            with_upvalues(MAX_UPVALUES + 1)
+                .build(&mut pool)
+                .unwrap_err(),
+            message
+        );
+        let mut function = function("f", 0, &code);
+        if let Object::Function { upvalues, .. } = &mut function {
+            let upvalue = Upvalue {
+                is_local: true,
+                index: 0,
+            };
+            *upvalues = vec![upvalue; MAX_UPVALUES + 1];
+        }
+        let e = serialize_to_vec(
+            &[Value::Object(Rc::new(function))],
+            &WriteOptions::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&e, ConstantsError::Unwritable(m) if m == message),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn signatures_list_their_tags_and_stop_at_a_byte_of_them() {
+        let code = [ByteCode::Return as u8];
+        let mut pool = ObjectPool::new();
+        let Value::Object(foo) = FunctionBuilder::new("foo", 2, &code)
+            .signature(vec![TypeTag::Int, TypeTag::String], TypeTag::Bool)
+            .build(&mut pool)
+            .unwrap()
+        else {
+            unreachable!("the builder makes objects");
+        };
+        let mut out = Vec::new();
+        foo.disassemble_function_body(&mut out, 0, &DisassembleOptions::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("func foo(Int, String) -> Bool\n"), "{out}");
+
+        let tags = |count| {
+            FunctionBuilder::new("f", 0, &code).signature(vec![TypeTag::Any; count], TypeTag::Any)
+        };
+        let values = [tags(255).build(&mut pool).unwrap()];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, values);
+
+        let message = "Function 'f' has 256 parameter type tags, more than 255";
+        assert_eq!(tags(256).build(&mut pool).unwrap_err(), message);
+        let mut function = function("f", 0, &code);
+        if let Object::Function { param_types, .. } = &mut function {
+            *param_types = vec![TypeTag::Int; 256];
+        }
+        let e = serialize_to_vec(
+            &[Value::Object(Rc::new(function))],
+            &WriteOptions::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&e, ConstantsError::Unwritable(m) if m == message),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn functions_anywhere_inside_a_constant_are_checked() {
+        let mut pool = ObjectPool::new();
+        let inner = FunctionBuilder::new("deep", 200, &[])
+            .build(&mut pool)
+            .unwrap();
+        let nested = [
+            Value::from_list(vec![Value::Int(1), inner.clone()], &mut pool),
+            Value::from_tuple(vec![inner.clone()], &mut pool),
+            Value::from_map(vec![(Value::Int(1), inner.clone())], &mut pool),
+            Value::from_set(vec![inner.clone()], &mut pool),
+            Value::from_record("R", vec![("f", inner.clone())], &mut pool),
+            Value::from_enum_variant("E", "V", Some(inner.clone()), &mut pool),
+            (0..1000).fold(inner.clone(), |cdr, i| {
+                Value::from_pair(Value::Int(i), cdr, &mut pool)
+            }),
+            Value::Optional(Some(Box::new(inner.clone()))),
+            FunctionBuilder::new("outer", 1, &[ByteCode::Return as u8])
+                .defaults(vec![inner.clone()])
+                .build(&mut pool)
+                .unwrap(),
+        ];
+        let issues = vec![
+            Issue::TooManyParams {
+                count: 200,
+                max: 128,
+            },
+            Issue::EmptyCode,
+        ];
+        for value in nested {
+            let mut found = Vec::new();
+            visit_functions(&value, &mut |function| found.push(check_function(function)));
+            assert_eq!(found.last(), Some(&issues), "{value}");
+
+            let bytes = serialize_to_vec(&[value], &WriteOptions::default()).unwrap();
+            let strict = LoadOptions {
+                strict: true,
+                ..LoadOptions::default()
+            };
+            let e = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &strict).unwrap_err();
+            assert!(
+                matches!(&e, ConstantsError::FunctionIssue { identifier, constant: 0, .. } if identifier == "deep"),
+                "{e}"
+            );
+        }
+    }
+
+    #[test]
+    fn labelled_disassembly_names_targets_and_marks_the_ones_that_miss() {
+        use ByteCode::*;
+        #[rustfmt::skip]
+        let code = [
+            JumpIfFalse as u8, 0, 3,
+            // Back onto itself
+            Loop as u8, 0, 3,
+            Return as u8,
+            // Past the end, and into the middle of the first instruction
+            Jump as u8, 0, 9,
+            Loop as u8, 0, 12,
+            Return as u8,
+        ];
+        let listing = |labels| {
+            let mut out = Vec::new();
+            function("f", 0, &code)
+                .disassemble_function_body(&mut out, 0, &DisassembleOptions { labels })
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            listing(true),
+            "0000 JUMP_IF_FALSE 3 -> L1 (0006)
+L0:
+0003 LOOP 3 -> L0 (0003)
+L1:
+0006 RETURN
+0007 JUMP 9 -> !! (0019)
+0010 LOOP 12 -> !! (0001)
+0013 RETURN
+"
+        );
+        assert_eq!(
+            listing(false),
+            "0000 JUMP_IF_FALSE 3 -> 0006
+0003 LOOP 3 -> 0003
+0006 RETURN
+0007 JUMP 9 -> 0019
+0010 LOOP 12 -> 0001
+0013 RETURN
+"
+        );
+    }
+
+    #[test]
+    fn repeated_symbols_load_as_one_object() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::from_symbol("init", &mut pool),
+            Value::from_string("init", &mut pool),
+            Value::from_list(vec![Value::from_symbol("init", &mut pool)], &mut pool),
+            Value::from_symbol("init", &mut pool),
+        ];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let mut pool = ObjectPool::new();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+
+        let symbol = |value: &Value| match value {
+            Value::Object(o) if matches!(**o, Object::Symbol(_)) => Rc::clone(o),
+            Value::Object(o) => match &**o {
+                Object::List(items) => match &items[0] {
+                    Value::Object(o) => Rc::clone(o),
+                    _ => panic!("expected a symbol in the list"),
+                },
+                _ => panic!("expected a symbol or a list"),
+            },
+            _ => panic!("expected an object"),
+        };
+        assert!(Rc::ptr_eq(&symbol(&read[0]), &symbol(&read[2])));
+        assert!(Rc::ptr_eq(&symbol(&read[0]), &symbol(&read[3])));
+        let symbols = pool
+            .iter()
+            .filter(|o| matches!(***o, Object::Symbol(_)))
+            .count();
+        assert_eq!(symbols, 1);
+    }
+
+    #[test]
+    fn many_distinct_symbols_load_without_searching_the_pool() {
+        let mut pool = ObjectPool::new();
+        let values = (0..100_000)
+            .map(|i| Value::Object(Rc::new(Object::Symbol(format!("s{i}")))))
+            .collect::<Vec<_>>();
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, values);
+        assert_eq!(pool.len(), 100_000);
+    }
+
+    #[test]
+    fn disassembling_more_defaults_than_params_numbers_them_from_zero() {
+        let mut function = function("f", 1, &[ByteCode::Return as u8]);
+        if let Object::Function { defaults, .. } = &mut function {
+            *defaults = vec![Value::Int(1), Value::Int(2)];
+        }
+        let mut out = Vec::new();
+        function
+            .disassemble_function_body(&mut out, 0, &DisassembleOptions::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("param[0] = 1\nparam[1] = 2\n"), "{out}");
+    }
+
+    #[test]
+    fn long_pair_chains_drop_without_recursing() {
+        let mut pool = ObjectPool::new();
+        let mut chain = Value::Nil;
+        for i in 0..100_000 {
+            chain = Value::from_pair(Value::Int(i), chain, &mut pool);
+        }
+        let bytes = serialize_to_vec(&[chain.clone()], &WriteOptions::default()).unwrap();
+        drop(pool);
+        drop(chain);
+
+        let mut pool = ObjectPool::new();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+        drop(read);
+        drop(pool);
+    }
+
+    #[test]
+    fn builds_refuse_what_files_would_too() {
+        let mut pool = ObjectPool::new();
+        let code = [ByteCode::Return as u8];
+        assert_eq!(
+            FunctionBuilder::new("", 0, &code)
+                .build(&mut pool)
+                .unwrap_err(),
+            "Function identifiers can't be empty"
+        );
+        assert_eq!(
+            FunctionBuilder::new("f", 1, &code)
+                .defaults(vec![Value::Nil, Value::Nil])
+                .build(&mut pool)
+                .unwrap_err(),
+            "Function 'f' has 2 defaults for 1 parameters"
+        );
+        assert!(Value::from_function_literal("", 0, &code, &mut pool).is_err());
+        assert!(Value::from_function_source("f", 0, "NOT_AN_OP", &mut pool).is_err());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn maps_keep_their_order_through_a_file() {
+        let mut pool = ObjectPool::new();
+        let entries = (0..1000)
+            .rev()
+            .map(|i| {
+                let key = Value::Object(Rc::new(Object::String(format!("k{i}"))));
+                (key, Value::Int(i))
+            })
+            .collect();
+        let map = Value::from_map(entries, &mut pool);
+        let bytes = serialize_to_vec(std::slice::from_ref(&map), &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, [map]);
+        let Value::Object(o) = &read[0] else {
+            panic!("expected an object");
+        };
+        let Object::Map(entries) = &**o else {
+            panic!("expected a map");
+        };
+        assert_eq!(entries[0].1, Value::Int(999));
+        assert_eq!(entries[999].1, Value::Int(0));
+
+        let small = Value::from_map(
+            vec![
+                (Value::from_string("a", &mut pool), Value::Int(1)),
+                (Value::from_string("b", &mut pool), Value::Bool(true)),
+            ],
+            &mut pool,
+        );
+        assert_eq!(small.to_string(), r#"{ "a": 1, "b": true }"#);
+    }
+
+    #[test]
+    fn blobs_load_any_bytes_and_display_a_preview() {
+        let mut pool = ObjectPool::new();
+        let edges = Value::from_bytes(&[0x00, 0xff, 0xc3, 0x28], &mut pool);
+        let big = (0..4 << 20).map(|i| i as u8).collect::<Vec<_>>();
+        let big = Value::from_bytes(&big, &mut pool);
+        let values = [edges, big];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+
+        assert_eq!(values[0].to_string(), "bytes<4> [00 ff c3 28]");
+        assert_eq!(
+            values[1].to_string(),
+            "bytes<4194304> [00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f ...]"
+        );
+    }
+
+    #[test]
+    fn big_ints_round_trip_canonically() {
+        const TWO_TO_200: &str = "1606938044258990275541962092341162602522202993782792835301376";
+
+        let mut pool = ObjectPool::new();
+        let values = [TWO_TO_200, "-12345678901234567890123", "0", "-0", "007"]
+            .map(|str| Value::from_big_int_str(str, &mut pool).unwrap());
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+        assert_eq!(
+            read.iter().map(Value::to_string).collect::<Vec<_>>(),
+            [TWO_TO_200, "-12345678901234567890123", "0", "0", "7"]
+        );
+
+        let magnitudes = read
+            .iter()
+            .map(|value| match value {
+                Value::Object(o) => match &**o {
+                    Object::BigInt { magnitude, .. } => magnitude.clone(),
+                    _ => panic!("expected a big integer"),
+                },
+                _ => panic!("expected an object"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(magnitudes[0].len(), 26);
+        assert_eq!(magnitudes[0][0], 1);
+        assert!(magnitudes[2].is_empty() && magnitudes[3].is_empty());
+        assert_eq!(magnitudes[4], [7]);
+
+        // The seven's one byte zeroed, as a writer that doesn't trim its magnitudes would
+        let at = bytes
+            .windows(7)
+            .position(|window| window == [17, 0, 0, 0, 0, 1, 7])
+            .unwrap();
+        let mut padded = bytes.clone();
+        padded[at + 6] = 0;
+        let options = LoadOptions {
+            skip_checksum: true,
+            ..LoadOptions::default()
+        };
+        let e = deserialize_from_slice(&padded, &mut ObjectPool::new(), &options).unwrap_err();
+        assert!(e.to_string().contains("Non-canonical"), "{e}");
+    }
+
+    #[test]
+    fn defaults_of_every_type_round_trip_and_pool_their_strings() {
+        let mut pool = ObjectPool::new();
+        let defaults = vec![
+            Value::Float(0.5),
+            Value::from_string("name", &mut pool),
+            Value::Nil,
+            Value::Bool(false),
+        ];
+        let function = FunctionBuilder::new("f", 5, &[ByteCode::Return as u8])
+            .defaults(defaults.clone())
+            .build(&mut pool)
+            .unwrap();
+        let bytes =
+            serialize_to_vec(std::slice::from_ref(&function), &WriteOptions::default()).unwrap();
+        let mut pool = ObjectPool::new();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, [function]);
+
+        let Value::Object(o) = &read[0] else {
+            panic!("expected an object");
+        };
+        let Object::Function { defaults: read, .. } = &**o else {
+            panic!("expected a function");
+        };
+        assert_eq!(*read, defaults);
+        let Value::Object(name) = &read[1] else {
+            panic!("expected a string");
+        };
+        assert!(pool.iter().any(|pooled| Rc::ptr_eq(pooled, name)));
+
+        let mut out = Vec::new();
+        o.disassemble_function_body(&mut out, 0, &DisassembleOptions::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with(
+                "param[1] = 0.5\nparam[2] = \"name\"\nparam[3] = nil\nparam[4] = false\n"
+            ),
+            "{out}"
+        );
+        assert!(FunctionBuilder::new("f", 3, &[ByteCode::Return as u8])
+            .defaults(defaults)
+            .build(&mut pool)
+            .is_err());
+    }
+
+    #[test]
+    fn deeply_nested_functions_round_trip_and_indent_each_level() {
+        let code = [ByteCode::ConstantByte as u8, 0, ByteCode::Return as u8];
+        let mut pool = ObjectPool::new();
+        let innermost = FunctionBuilder::new("f9", 0, &[ByteCode::Return as u8])
+            .build(&mut pool)
+            .unwrap();
+        let outermost = (1..9).rev().fold(innermost, |inner, level| {
+            FunctionBuilder::new(&format!("f{level}"), 0, &code)
+                .constants(vec![inner])
+                .build(&mut pool)
+                .unwrap()
+        });
+        let values = [outermost];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+
+        let mut expected = String::new();
+        for level in 1..9 {
+            let indent = "    ".repeat(2 * (level - 1));
+            expected.push_str(&format!(
+                "{indent}0000 CONSTANT_BYTE 0 ; func<'f{inner}', 0, 0>\n{indent}0002 RETURN\n\
+                 {indent}constants:\n{indent}    0000 func<'f{inner}', 0, 0>\n",
+                inner = level + 1
+            ));
+        }
+        expected.push_str(&format!("{}0000 RETURN\n", "    ".repeat(16)));
+        let mut out = Vec::new();
+        read[0]
+            .disassemble_at(&mut out, 0, &DisassembleOptions::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("func<'f1', 0, 0>\n{expected}")
+        );
+    }
+
+    #[test]
+    fn float_arrays_round_trip_and_print_only_small_shapes_in_full() {
+        let mut pool = ObjectPool::new();
+        let identity = (0..16)
+            .map(|i| if i % 5 == 0 { 1.0 } else { 0.0 })
+            .collect::<Vec<_>>();
+        let buffer = (0..10_000).map(|i| i as f64 / 8.0).collect::<Vec<_>>();
+        let values = [
+            Value::from_float_array(4, 4, identity, &mut pool).unwrap(),
+            Value::from_float_array(100, 100, buffer.clone(), &mut pool).unwrap(),
+            Value::from_float_array(1, 10_000, buffer, &mut pool).unwrap(),
+            Value::from_float_array(0, 3, vec![], &mut pool).unwrap(),
+        ];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+        assert_eq!(
+            read.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            [
+                "float[4x4] [1.0, 0.0, 0.0, 0.0; 0.0, 1.0, 0.0, 0.0; 0.0, 0.0, 1.0, 0.0; \
+                 0.0, 0.0, 0.0, 1.0]",
+                "float[100x100]",
+                "float[1x10000]",
+                "float[0x3]",
+            ]
+        );
+
+        // Shapes that don't fit their data are refused, or shown without rows if built
+        let e = Value::from_float_array(2, 0, vec![1.0], &mut pool).unwrap_err();
+        assert_eq!(e, "Float array shape 2x0 does not match its 1 elements");
+        let unfit = Object::FloatArray {
+            rows: 1,
+            cols: 0,
+            data: vec![1.0, 2.0],
+        };
+        assert_eq!(unfit.to_string(), "float[1x0]");
+        let e = Value::Object(Rc::new(unfit))
+            .write(&mut Vec::new(), &WriteState::new(&WriteOptions::default()))
+            .unwrap_err();
+        assert!(matches!(e, ConstantsError::Unwritable(_)), "{e}");
+    }
+
+    #[test]
+    fn pair_chains_read_write_and_display_as_lists() {
+        let mut pool = ObjectPool::new();
+        let pair = |car, cdr, pool: &mut ObjectPool| Value::from_pair(car, cdr, pool);
+        let list = pair(
+            Value::Int(1),
+            pair(Value::Int(2), Value::Nil, &mut pool),
+            &mut pool,
+        );
+        let dotted = pair(Value::Int(1), Value::Int(2), &mut pool);
+        let nested = pair(
+            dotted.clone(),
+            pair(Value::Int(3), Value::Nil, &mut pool),
+            &mut pool,
+        );
+        let single = pair(Value::Int(1), Value::Nil, &mut pool);
+        let improper = pair(
+            Value::Int(0),
+            pair(Value::Int(1), dotted.clone(), &mut pool),
+            &mut pool,
+        );
+        assert_eq!(
+            [&list, &dotted, &nested, &single, &improper].map(|v| v.to_string()),
+            ["(1 2)", "(1 . 2)", "((1 . 2) 3)", "(1)", "(0 1 1 . 2)"]
+        );
+
+        let long = (0..5000)
+            .rev()
+            .fold(Value::Nil, |cdr, i| pair(Value::Int(i), cdr, &mut pool));
+        let words = (0..5000).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(long.to_string(), format!("({})", words.join(" ")));
+
+        let values = [list, dotted, nested, single, improper, long];
+        for options in [
+            WriteOptions::default(),
+            WriteOptions {
+                varint: true,
+                endianness: Endianness::Little,
+                ..WriteOptions::default()
+            },
+        ] {
+            let bytes = serialize_to_vec(&values, &options).unwrap();
+            let read =
+                deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+                    .unwrap();
+            assert_eq!(read, values);
+            assert_eq!(read[5].to_string(), values[5].to_string());
+        }
+    }
+
+    #[test]
+    fn lists_round_trip_empty_and_ten_thousand_long() {
+        let mut pool = ObjectPool::new();
+        let empty = Value::from_list(vec![], &mut pool);
+        let ints = Value::from_list((0..10_000).map(Value::Int).collect(), &mut pool);
+        let holding_empty = Value::from_list(vec![empty.clone(), Value::Nil], &mut pool);
+        let values = [empty, ints, holding_empty];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+
+        let Value::Object(ints) = &read[1] else {
+            panic!("expected a list, read {}", read[1]);
+        };
+        let Object::List(items) = &**ints else {
+            panic!("expected a list, read {ints}");
+        };
+        assert!(items
+            .iter()
+            .enumerate()
+            .all(|(i, v)| *v == Value::Int(i as i32)));
+        assert_eq!(read[0].to_string(), "[]");
+        assert_eq!(read[2].to_string(), "[[], nil]");
+        assert!(read[1].to_string().starts_with("[0, 1, 2, "));
+        assert!(read[1].to_string().ends_with(", 9998, 9999]"));
+        // An empty list is still a list rather than any other empty container
+        assert_ne!(read[0], Value::from_tuple(vec![], &mut pool));
+    }
+
+    #[test]
+    fn tuples_of_no_or_one_element_stay_tuples() {
+        let mut pool = ObjectPool::new();
+        let unit = Value::from_tuple(vec![], &mut pool);
+        let single = Value::from_tuple(vec![Value::Int(1)], &mut pool);
+        let string = Value::from_string("one", &mut pool);
+        let single_string = Value::from_tuple(vec![string.clone()], &mut pool);
+        let pair = Value::from_tuple(vec![Value::Int(1), Value::Bool(false)], &mut pool);
+        let values = [unit, single, single_string, pair];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+
+        // A 1-tuple is not the value it holds, nor a list of it
+        assert_ne!(read[1], Value::Int(1));
+        assert_ne!(read[1], Value::from_list(vec![Value::Int(1)], &mut pool));
+        assert_ne!(read[2], string);
+        let Value::Object(single) = &read[1] else {
+            panic!("expected a tuple, read {}", read[1]);
+        };
+        assert!(matches!(&**single, Object::Tuple(items) if items == &[Value::Int(1)]));
+        assert_eq!(
+            read.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            ["()", "(1,)", "(\"one\",)", "(1, false)"]
+        );
+    }
+
+    #[test]
+    fn enum_variants_round_trip_with_and_without_a_payload() {
+        let mut pool = ObjectPool::new();
+        let message = Value::from_string("failed", &mut pool);
+        let values = [
+            Value::from_enum_variant("Option", "None", None, &mut pool),
+            Value::from_enum_variant("Option", "Some", Some(Value::Int(3)), &mut pool),
+            Value::from_enum_variant("Option", "Some", Some(Value::Nil), &mut pool),
+            Value::from_enum_variant("Result", "Err", Some(message.clone()), &mut pool),
+            Value::from_enum_variant("Result", "Err", Some(message), &mut pool),
+        ];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+        assert_eq!(
+            read.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            [
+                "Option::None",
+                "Option::Some(3)",
+                "Option::Some(nil)",
+                "Result::Err(\"failed\")",
+                "Result::Err(\"failed\")",
+            ]
+        );
+
+        // No payload differs from a nil one, and the shared string is read once
+        assert_ne!(
+            read[0],
+            Value::from_enum_variant("Option", "None", Some(Value::Nil), &mut pool)
+        );
+        let payload = |value: &Value| match value {
+            Value::Object(o) => match &**o {
+                Object::EnumVariant {
+                    payload: Some(payload),
+                    ..
+                } => match &**payload {
+                    Value::Object(payload) => Rc::clone(payload),
+                    _ => panic!("expected an object payload in {o}"),
+                },
+                _ => panic!("expected a variant with a payload, read {o}"),
+            },
+            _ => panic!("expected a variant, read {value}"),
+        };
+        assert!(Rc::ptr_eq(&payload(&read[3]), &payload(&read[4])));
+    }
+
+    #[test]
+    fn records_keep_their_field_order_nest_and_may_be_empty() {
+        let mut pool = ObjectPool::new();
+        let name = Value::from_string("ada", &mut pool);
+        let point = Value::from_record(
+            "Point",
+            vec![("y", Value::Int(2)), ("x", Value::Int(1))],
+            &mut pool,
+        );
+        let empty = Value::from_record("Unit", vec![], &mut pool);
+        let nested = Value::from_record(
+            "Person",
+            vec![
+                ("name", name),
+                ("home", point.clone()),
+                ("tag", empty.clone()),
+            ],
+            &mut pool,
+        );
+        let values = [point, empty, nested];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+        assert_eq!(
+            read.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            [
+                "Point { y: 2, x: 1 }",
+                "Unit {}",
+                "Person { name: \"ada\", home: Point { y: 2, x: 1 }, tag: Unit {} }",
+            ]
+        );
+
+        // Fields are written in order, and the same fields in another order are another record
+        let at = |field: &[u8]| bytes.windows(field.len()).position(|w| w == field).unwrap();
+        assert!(at(&[0, 0, 0, 1, b'y']) < at(&[0, 0, 0, 1, b'x']));
+        let swapped = Value::from_record(
+            "Point",
+            vec![("x", Value::Int(1)), ("y", Value::Int(2))],
+            &mut pool,
+        );
+        assert_ne!(read[0], swapped);
+        assert_ne!(read[1], Value::from_record("Other", vec![], &mut pool));
+    }
+
+    #[test]
+    fn sets_save_in_one_order_without_duplicates() {
+        let mut pool = ObjectPool::new();
+        let a = Value::from_string("a", &mut pool);
+        let b = Value::from_string("b", &mut pool);
+        let items = [
+            Value::Int(3),
+            b.clone(),
+            Value::Int(-1),
+            a.clone(),
+            Value::Bool(true),
+        ];
+        let forward = Value::from_set(items.to_vec(), &mut pool);
+        let backward = Value::from_set(items.iter().rev().cloned().collect(), &mut pool);
+        fn save(value: &Value, warnings: &Warnings) -> Vec<u8> {
+            let options = WriteOptions {
+                warnings: Some(warnings),
+                ..WriteOptions::default()
+            };
+            serialize_to_vec(std::slice::from_ref(value), &options).unwrap()
+        }
+        let warnings = Warnings::default();
+        assert_eq!(save(&forward, &warnings), save(&backward, &warnings));
+        assert_eq!(forward, backward);
+        assert!(warnings.take().is_empty());
+
+        // Duplicates are dropped on save with a warning, whichever copy came first
+        let repeated = [items.to_vec(), vec![Value::Int(3), a, b]].concat();
+        let duplicated = Value::from_set(repeated, &mut pool);
+        let bytes = save(&duplicated, &warnings);
+        assert_eq!(bytes, save(&forward, &Warnings::default()));
+        assert!(
+            matches!(&warnings.take()[..], [Warning::DuplicateSetItems(3)]),
+            "{warnings:?}"
+        );
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        let Value::Object(set) = &read[0] else {
+            panic!("expected a set, read {}", read[0]);
+        };
+        assert!(matches!(&**set, Object::Set(items) if items.len() == 5));
+        assert_eq!(read, [forward]);
+
+        let empty = Value::from_set(vec![], &mut pool);
+        let read = deserialize_from_slice(
+            &save(&empty, &warnings),
+            &mut ObjectPool::new(),
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read, [empty]);
+        assert_eq!(read[0].to_string(), "set{}");
+        assert_ne!(read[0], Value::from_map(vec![], &mut pool));
+    }
+
+    #[test]
+    fn functions_from_before_local_counts_load_with_none() {
+        // A V1 file of one function in the first layout, then in the one that added
+        // `local_count`
+        let old = |type_id, local_count: &[u8]| {
+            let mut bytes = b"CNST\x00\x01".to_vec();
+            bytes.extend(1u64.to_be_bytes());
+            bytes.push(type_id);
+            bytes.extend(1u64.to_be_bytes());
+            bytes.extend(b"f\x01");
+            bytes.extend(local_count);
+            bytes.extend(1u64.to_be_bytes());
+            bytes.push(ByteCode::Return as u8);
+            deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default()).unwrap()
+        };
+        let code = [ByteCode::Return as u8];
+        let read = old(FUNCTION_TYPE_IDS[0], &[]);
+        let expected = FunctionBuilder::new("f", 1, &code)
+            .build(&mut ObjectPool::new())
+            .unwrap();
+        assert_eq!(read, [expected]);
+        assert!(read[0].to_string().starts_with("func<'f', 1, 0>"));
+        let Value::Object(function) = &read[0] else {
+            panic!("expected a function, read {}", read[0]);
+        };
+        assert!(matches!(
+            &**function,
+            Object::Function { local_count: 0, .. }
+        ));
+
+        let read = old(FUNCTION_TYPE_IDS[1], &[0, 5]);
+        let expected = FunctionBuilder::new("f", 1, &code)
+            .local_count(5)
+            .build(&mut ObjectPool::new())
+            .unwrap();
+        assert!(read[0].to_string().starts_with("func<'f', 1, 5>"));
+        assert_eq!(read, std::slice::from_ref(&expected));
+
+        // Saved again, the count is kept in the newest layout
+        let bytes = serialize_to_vec(&read, &WriteOptions::default()).unwrap();
+        let again = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(again, [expected]);
+    }
+
+    #[test]
+    fn constant_tables_show_under_the_code_each_value_beside_its_load() {
+        let mut pool = ObjectPool::new();
+        let load = ByteCode::ConstantByte as u8;
+        let inner = FunctionBuilder::new("inner", 0, &[load, 0, ByteCode::Return as u8])
+            .constants(vec![Value::Float(0.5)])
+            .build(&mut pool)
+            .unwrap();
+        let name = Value::from_string("name", &mut pool);
+        let long = Value::from_string("a string too long to show whole beside its load", &mut pool);
+        let constants = vec![
+            Value::Int(3),
+            name.clone(),
+            Value::from_list(vec![Value::Int(1), name], &mut pool),
+            long,
+            inner,
+        ];
+        let code = [
+            load,
+            0,
+            load,
+            1,
+            load,
+            2,
+            load,
+            3,
+            load,
+            4,
+            ByteCode::Return as u8,
+        ];
+        let outer = FunctionBuilder::new("outer", 0, &code)
+            .constants(constants)
+            .build(&mut pool)
+            .unwrap();
+        let values = [outer];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(read, values);
+
+        let disassembly = |value: &Value| {
+            let mut out = Vec::new();
+            value
+                .disassemble_at(&mut out, 0, &DisassembleOptions::default())
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            disassembly(&read[0]),
+            "\
+func<'outer', 0, 0>
+0000 CONSTANT_BYTE 0 ; 3
+0002 CONSTANT_BYTE 1 ; \"name\"
+0004 CONSTANT_BYTE 2 ; [1, \"name\"]
+0006 CONSTANT_BYTE 3 ; \"a string too long to show whole beside ...
+0008 CONSTANT_BYTE 4 ; func<'inner', 0, 0>
+0010 RETURN
+constants:
+    0000 3
+    0001 name
+    0002 [1, \"name\"]
+    0003 a string too long to show whole beside its load
+    0004 func<'inner', 0, 0>
+        0000 CONSTANT_BYTE 0 ; 0.5
+        0002 RETURN
+        constants:
+            0000 0.5
+"
+        );
+
+        // A load past the table says so rather than failing the listing
+        let past = function("f", 0, &[load, 7, ByteCode::Return as u8]);
+        assert_eq!(
+            disassembly(&Value::Object(Rc::new(past))),
+            "func<'f', 0, 0>\n0000 CONSTANT_BYTE 7 ; <out of range>\n0002 RETURN\n"
+        );
+    }
+
+    #[test]
+    fn line_tables_save_again_as_they_were_read() {
+        let code = [
+            ByteCode::Negate as u8,
+            ByteCode::Negate as u8,
+            ByteCode::Pop as u8,
+            ByteCode::Return as u8,
+        ];
+        let save = |lines: Option<Vec<(u32, u32)>>| {
+            let mut builder = FunctionBuilder::new("f", 0, &code);
+            if let Some(lines) = lines {
+                builder = builder.lines(lines);
+            }
+            let values = [builder.build(&mut ObjectPool::new()).unwrap()];
+            let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+            let read =
+                deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+                    .unwrap();
+            assert_eq!(read, values);
+            assert_eq!(
+                serialize_to_vec(&read, &WriteOptions::default()).unwrap(),
+                bytes
+            );
+            let mut out = Vec::new();
+            read[0]
+                .disassemble_at(&mut out, 0, &DisassembleOptions::default())
+                .unwrap();
+            (bytes, String::from_utf8(out).unwrap())
+        };
+
+        let (bytes, disassembly) = save(Some(vec![(0, 10), (2, 12), (3, 12)]));
+        let mut table = vec![1, 0, 0, 0, 3];
+        for (offset, line) in [(0u32, 10u32), (2, 12), (3, 12)] {
+            table.extend(offset.to_be_bytes());
+            table.extend(line.to_be_bytes());
+        }
+        assert!(bytes.windows(table.len()).any(|w| w == table));
+        assert_eq!(
+            disassembly,
+            "func<'f', 0, 0>\n0000   10 NEGATE\n0001    | NEGATE\n0002   12 POP\n0003    | RETURN\n"
+        );
+
+        // Runs are kept as written, even out of order or repeated, rather than tidied
+        let odd = vec![(3, 7), (1, 2), (1, 2), (0, 0)];
+        let (bytes, _) = save(Some(odd.clone()));
+        let mut table = vec![1, 0, 0, 0, 4];
+        for (offset, line) in odd {
+            table.extend(offset.to_be_bytes());
+            table.extend(line.to_be_bytes());
+        }
+        assert!(bytes.windows(table.len()).any(|w| w == table));
+
+        // Without a table, the flag byte alone says so and no column is printed
+        let (bytes, disassembly) = save(None);
+        let (with, _) = save(Some(vec![]));
+        assert_eq!(with.len(), bytes.len() + 4);
+        assert_eq!(
+            disassembly,
+            "func<'f', 0, 0>\n0000 NEGATE\n0001 NEGATE\n0002 POP\n0003 RETURN\n"
+        );
+    }
+}
@@ -0,0 +1,1331 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use crate::{bytecode::*, io::*, object::*};
+
+/// Levels the contents of objects nest to, by address, as `check_depth` has measured them.
+pub(crate) type ObjectDepths = HashMap<*const Object, usize>;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(i32),
+    Bool(bool),
+    Float(f64),
+    Long(i64),
+    UInt(u64),
+    Char(char),
+    Nil,
+    Byte(u8),
+    Short(i16),
+    /// `mantissa / 10^scale`, so `1999` at scale 2 is `19.99`.
+    Decimal {
+        mantissa: i64,
+        scale: u8,
+    },
+    /// Seconds since the unix epoch in UTC, with `nanos` always below one second.
+    Timestamp {
+        seconds: i64,
+        nanos: u32,
+    },
+    Uuid([u8; 16]),
+    Optional(Option<Box<Value>>),
+    /// Index of an earlier top-level constant in the same file, resolved by
+    /// `load_values_from_disk` into a clone of that constant.
+    Ref(u32),
+    Complex {
+        re: f64,
+        im: f64,
+    },
+    Color {
+        r: u8,
+        g: u8,
+        b: u8,
+        a: u8,
+    },
+    Object(Rc<Object>),
+}
+
+/// Structural equality: objects compare by contents, not by `Rc`. Floats compare by bits,
+/// so a NaN equals itself and `-0.0` doesn't equal `0.0`, as the two encode differently.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Long(a), Value::Long(b)) => a == b,
+            (Value::UInt(a), Value::UInt(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Byte(a), Value::Byte(b)) => a == b,
+            (Value::Short(a), Value::Short(b)) => a == b,
+            (
+                Value::Decimal { mantissa, scale },
+                Value::Decimal {
+                    mantissa: other_mantissa,
+                    scale: other_scale,
+                },
+            ) => (mantissa, scale) == (other_mantissa, other_scale),
+            (
+                Value::Timestamp { seconds, nanos },
+                Value::Timestamp {
+                    seconds: other_seconds,
+                    nanos: other_nanos,
+                },
+            ) => (seconds, nanos) == (other_seconds, other_nanos),
+            (Value::Uuid(a), Value::Uuid(b)) => a == b,
+            (Value::Optional(a), Value::Optional(b)) => a == b,
+            (Value::Ref(a), Value::Ref(b)) => a == b,
+            (
+                Value::Complex { re, im },
+                Value::Complex {
+                    re: other_re,
+                    im: other_im,
+                },
+            ) => re.to_bits() == other_re.to_bits() && im.to_bits() == other_im.to_bits(),
+            (
+                Value::Color { r, g, b, a },
+                Value::Color {
+                    r: other_r,
+                    g: other_g,
+                    b: other_b,
+                    a: other_a,
+                },
+            ) => (r, g, b, a) == (other_r, other_g, other_b, other_a),
+            (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b) || a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Fails with `ConstantsError::MaxDepthExceeded` if `value` nests deeper than `limit`.
+pub(crate) fn check_depth(
+    value: &Value,
+    limit: usize,
+    depths: &mut ObjectDepths,
+) -> Result<(), ConstantsError> {
+    match nesting_depth(value, limit, depths) {
+        Some(_) => Ok(()),
+        None => Err(ConstantsError::MaxDepthExceeded { entry: None, limit }),
+    }
+}
+
+/// Runs `check_depth` over every constant in `values`, naming the first too deep.
+/// Writers pass `LoadLimits::DEFAULT.max_depth`, so nothing is written that couldn't
+/// be read back.
+pub(crate) fn check_depths(values: &[Value], limit: usize) -> Result<(), ConstantsError> {
+    let mut depths = ObjectDepths::new();
+    for (i, value) in values.iter().enumerate() {
+        check_depth(value, limit, &mut depths).map_err(|e| e.in_entry(Entry::Constant(i)))?;
+    }
+    Ok(())
+}
+
+/// Levels `value` nests to, as `LoadLimits::max_depth` counts them, or `None` past
+/// `limit`. Recursion stops there too, so measuring can't run out of stack either.
+fn nesting_depth(value: &Value, limit: usize, depths: &mut ObjectDepths) -> Option<usize> {
+    let limit = limit.checked_sub(1)?;
+    let inner = match value {
+        Value::Optional(Some(inner)) => nesting_depth(inner, limit, depths)?,
+        Value::Object(o) => contents_depth(o, limit, depths)?,
+        _ => 0,
+    };
+    Some(1 + inner)
+}
+
+/// Levels the values inside `o` nest to, or `None` past `limit`. Each object is measured
+/// once, however often it's shared. The rest of a pair chain is as deep as its head, as
+/// the chain is a flat list.
+fn contents_depth(o: &Rc<Object>, limit: usize, depths: &mut ObjectDepths) -> Option<usize> {
+    if let Some(&depth) = depths.get(&Rc::as_ptr(o)) {
+        return (depth <= limit).then_some(depth);
+    }
+
+    let mut deepest = 0;
+    let mut measure = |values: &mut dyn Iterator<Item = &Value>| {
+        for value in values {
+            deepest = deepest.max(nesting_depth(value, limit, depths)?);
+        }
+        Some(())
+    };
+    match &**o {
+        Object::Function {
+            constants,
+            defaults,
+            ..
+        } => measure(&mut constants.iter().chain(defaults))?,
+        Object::List(items) | Object::Tuple(items) | Object::Set(items) => {
+            measure(&mut items.iter())?
+        }
+        Object::Map(entries) => measure(&mut entries.iter().flat_map(|(key, value)| [key, value]))?,
+        Object::EnumVariant { payload, .. } => measure(&mut payload.as_deref().into_iter())?,
+        Object::Record { fields, .. } => measure(&mut fields.iter().map(|(_, value)| value))?,
+        Object::Module { constants, .. } => measure(&mut constants.iter())?,
+        Object::Class { methods, .. } => {
+            let values = methods
+                .iter()
+                .map(|(_, method)| Value::Object(Rc::clone(method)))
+                .collect::<Vec<_>>();
+            measure(&mut values.iter())?
+        }
+        Object::Pair(..) => {
+            // Walked rather than recursed into, as chains can be far longer than any limit
+            let mut chain = vec![o];
+            let mut tail = loop {
+                let Object::Pair(_, cdr) = &**chain[chain.len() - 1] else {
+                    unreachable!("the chain only holds pairs");
+                };
+                match cdr {
+                    Value::Object(next) if matches!(**next, Object::Pair(..)) => {
+                        if let Some(&depth) = depths.get(&Rc::as_ptr(next)) {
+                            break depth;
+                        }
+                        chain.push(next);
+                    }
+                    cdr => break nesting_depth(cdr, limit, depths)?,
+                }
+            };
+            for pair in chain.into_iter().rev() {
+                let Object::Pair(car, _) = &**pair else {
+                    unreachable!("the chain only holds pairs");
+                };
+                tail = tail.max(nesting_depth(car, limit, depths)?);
+                depths.insert(Rc::as_ptr(pair), tail);
+            }
+            deepest = tail;
+        }
+        _ => {}
+    }
+
+    if deepest > limit {
+        return None;
+    }
+    depths.insert(Rc::as_ptr(o), deepest);
+    Some(deepest)
+}
+
+impl Value {
+    pub fn from_int_fitting(i: i64) -> Value {
+        if let Ok(b) = u8::try_from(i) {
+            Value::Byte(b)
+        } else if let Ok(s) = i16::try_from(i) {
+            Value::Short(s)
+        } else if let Ok(i) = i32::try_from(i) {
+            Value::Int(i)
+        } else {
+            Value::Long(i)
+        }
+    }
+
+    pub fn from_decimal_str(str: &str) -> Result<Value, String> {
+        let invalid = || format!("Invalid decimal '{str}'");
+
+        let (negative, unsigned) = match str.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, str),
+        };
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+        if whole.is_empty() || (unsigned.contains('.') && fraction.is_empty()) {
+            return Err(invalid());
+        }
+
+        let scale = u8::try_from(fraction.len()).map_err(|_| invalid())?;
+        let mut mantissa: i64 = 0;
+        for c in whole.chars().chain(fraction.chars()) {
+            let digit = c.to_digit(10).ok_or_else(invalid)? as i64;
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| {
+                    if negative {
+                        m.checked_sub(digit)
+                    } else {
+                        m.checked_add(digit)
+                    }
+                })
+                .ok_or_else(|| format!("Decimal '{str}' does not fit in 64 bits"))?;
+        }
+
+        Ok(Value::Decimal { mantissa, scale })
+    }
+
+    pub fn from_timestamp(seconds: i64, nanos: u32) -> Result<Value, String> {
+        if nanos >= 1_000_000_000 {
+            return Err(format!("Timestamp nanos {nanos} must be below one second"));
+        }
+
+        Ok(Value::Timestamp { seconds, nanos })
+    }
+
+    pub fn from_uuid_str(str: &str) -> Result<Value, String> {
+        const HYPHENS: [usize; 4] = [8, 13, 18, 23];
+
+        let text = str.as_bytes();
+        if text.len() != 36 {
+            return Err(format!(
+                "Invalid UUID '{str}': expected 36 characters, found {}",
+                text.len()
+            ));
+        }
+
+        let mut bytes = [0u8; 16];
+        let mut digits = Vec::with_capacity(32);
+        for (i, c) in str.chars().enumerate() {
+            if HYPHENS.contains(&i) {
+                if c != '-' {
+                    return Err(format!("Invalid UUID '{str}': expected '-' at {i}"));
+                }
+                continue;
+            }
+
+            let digit = c
+                .to_digit(16)
+                .ok_or_else(|| format!("Invalid UUID '{str}': '{c}' is not a hex digit"))?;
+            digits.push(digit as u8);
+        }
+
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+            *byte = pair[0] << 4 | pair[1];
+        }
+
+        Ok(Value::Uuid(bytes))
+    }
+
+    pub fn from_hex_color(str: &str) -> Result<Value, String> {
+        let hex = str.strip_prefix('#').unwrap_or(str);
+        if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "Invalid color '{str}': expected 6 or 8 hex digits, optionally prefixed by '#'"
+            ));
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        Ok(Value::Color {
+            r: channel(0),
+            g: channel(1),
+            b: channel(2),
+            a: if hex.len() == 8 { channel(3) } else { 0xff },
+        })
+    }
+
+    pub fn from_string(str: &'static str, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::String(str.to_string()));
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_function_literal(
+        id: &'static str,
+        param_count: u8,
+        code: &[u8],
+        pool: &mut ObjectPool,
+    ) -> Result<Value, String> {
+        FunctionBuilder::new(id, param_count, code).build(pool)
+    }
+
+    pub fn from_function_source(
+        id: &str,
+        param_count: u8,
+        source: &str,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, String> {
+        let code = assemble(source).map_err(|e| e.to_string())?;
+        FunctionBuilder::new(id, param_count, &code).build(pool)
+    }
+
+    pub fn from_list(items: Vec<Value>, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::List(items));
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_tuple(items: Vec<Value>, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::Tuple(items));
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_map(entries: Vec<(Value, Value)>, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::Map(entries));
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_bytes(bytes: &[u8], pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::Bytes(bytes.to_vec()));
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_enum_variant(
+        type_name: &'static str,
+        variant: &'static str,
+        payload: Option<Value>,
+        pool: &mut ObjectPool,
+    ) -> Value {
+        let v = Rc::new(Object::EnumVariant {
+            type_name: type_name.to_string(),
+            variant: variant.to_string(),
+            payload: payload.map(Box::new),
+        });
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_record(
+        type_name: &'static str,
+        fields: Vec<(&'static str, Value)>,
+        pool: &mut ObjectPool,
+    ) -> Value {
+        let v = Rc::new(Object::Record {
+            type_name: type_name.to_string(),
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        });
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    fn from_big_int(negative: bool, magnitude: &[u8], pool: &mut ObjectPool) -> Value {
+        let magnitude = trim_leading_zeros(magnitude).to_vec();
+        let v = Rc::new(Object::BigInt {
+            negative: negative && !magnitude.is_empty(),
+            magnitude,
+        });
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_big_int_str(str: &str, pool: &mut ObjectPool) -> Result<Value, String> {
+        let (negative, digits) = match str.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, str),
+        };
+
+        if digits.is_empty() {
+            return Err(format!("Invalid big integer '{str}'"));
+        }
+
+        let mut magnitude = Vec::<u8>::new();
+        for c in digits.chars() {
+            let digit = c
+                .to_digit(10)
+                .ok_or_else(|| format!("Invalid digit '{c}' in big integer '{str}'"))?;
+
+            // magnitude = magnitude * 10 + digit
+            let mut carry = digit;
+            for byte in magnitude.iter_mut().rev() {
+                let n = *byte as u32 * 10 + carry;
+                *byte = n as u8;
+                carry = n >> 8;
+            }
+            while carry > 0 {
+                magnitude.insert(0, carry as u8);
+                carry >>= 8;
+            }
+        }
+
+        Ok(Value::from_big_int(negative, &magnitude, pool))
+    }
+
+    pub fn from_set(items: Vec<Value>, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::Set(items));
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_pair(car: Value, cdr: Value, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::Pair(car, cdr));
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_symbol(name: &str, pool: &mut ObjectPool) -> Value {
+        Value::Object(intern_symbol(name.to_string(), pool))
+    }
+
+    pub fn from_float_array(
+        rows: u16,
+        cols: u16,
+        data: Vec<f64>,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, String> {
+        check_float_array_shape(rows, cols, data.len())?;
+
+        let v = Rc::new(Object::FloatArray { rows, cols, data });
+        pool.push(Rc::clone(&v));
+        Ok(Value::Object(v))
+    }
+
+    pub fn from_regex(pattern: &str, flags: &str, pool: &mut ObjectPool) -> Result<Value, String> {
+        let mut bits = 0;
+        for c in flags.chars() {
+            let (bit, _) = REGEX_FLAGS
+                .iter()
+                .find(|(_, letter)| *letter == c)
+                .ok_or_else(|| format!("Unknown regex flag '{c}'"))?;
+            bits |= bit;
+        }
+
+        let v = Rc::new(Object::Regex {
+            pattern: pattern.to_string(),
+            flags: bits,
+        });
+        pool.push(Rc::clone(&v));
+        Ok(Value::Object(v))
+    }
+
+    pub fn from_native(identifier: &'static str, param_count: u8, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::NativeRef {
+            identifier: identifier.to_string(),
+            param_count,
+        });
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub fn from_class(
+        name: &'static str,
+        methods: Vec<(&'static str, Value)>,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, String> {
+        let methods = methods
+            .into_iter()
+            .map(|(method_name, method)| match method {
+                Value::Object(o) if matches!(*o, Object::Function { .. }) => {
+                    Ok((method_name.to_string(), o))
+                }
+                _ => Err(format!(
+                    "Method '{method_name}' of class '{name}' is not a function: {method}"
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let v = Rc::new(Object::Class {
+            name: name.to_string(),
+            methods,
+        });
+        pool.push(Rc::clone(&v));
+        Ok(Value::Object(v))
+    }
+
+    pub fn from_module(name: &'static str, constants: Vec<Value>, pool: &mut ObjectPool) -> Value {
+        let v = Rc::new(Object::Module {
+            name: name.to_string(),
+            constants,
+        });
+        pool.push(Rc::clone(&v));
+        Value::Object(v)
+    }
+
+    pub(crate) fn as_pair(&self) -> Option<(&Value, &Value)> {
+        match self {
+            Value::Object(o) => match &**o {
+                Object::Pair(car, cdr) => Some((car, cdr)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn display(&self, options: &DisassembleOptions) -> io::Result<()> {
+        self.disassemble(&mut io::stdout().lock(), options)
+    }
+
+    /// Writes the value on one line, followed by a disassembly for functions and the
+    /// members of classes and modules.
+    pub fn disassemble(
+        &self,
+        out: &mut impl Write,
+        options: &DisassembleOptions,
+    ) -> io::Result<()> {
+        self.disassemble_at(out, 0, options)
+    }
+
+    pub(crate) fn disassemble_at(
+        &self,
+        out: &mut impl Write,
+        depth: usize,
+        options: &DisassembleOptions,
+    ) -> io::Result<()> {
+        match self {
+            Value::Object(o) => o.disassemble_at(out, depth, options),
+            _ => writeln!(out, "{}{self}", "    ".repeat(depth)),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Float(x) => write!(f, "{x:?}"),
+            Value::Long(l) => write!(f, "{l}"),
+            Value::UInt(u) => write!(f, "{u}"),
+            Value::Char(c) => write!(f, "{c:?}"),
+            Value::Nil => write!(f, "nil"),
+            Value::Byte(b) => write!(f, "{b}"),
+            Value::Short(s) => write!(f, "{s}"),
+            Value::Decimal { mantissa, scale } => {
+                let scale = *scale as usize;
+                let digits = mantissa.unsigned_abs().to_string();
+
+                if *mantissa < 0 {
+                    write!(f, "-")?;
+                }
+                if scale == 0 {
+                    return write!(f, "{digits}");
+                }
+
+                // Pad so there is always at least one digit before the point
+                let digits = format!("{digits:0>width$}", width = scale + 1);
+                let (whole, fraction) = digits.split_at(digits.len() - scale);
+                write!(f, "{whole}.{fraction}")
+            }
+            Value::Timestamp { seconds, nanos } => write_timestamp(f, *seconds, *nanos),
+            Value::Uuid(bytes) => {
+                for (i, b) in bytes.iter().enumerate() {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        write!(f, "-")?;
+                    }
+                    write!(f, "{b:02x}")?;
+                }
+                Ok(())
+            }
+            Value::Optional(None) => write!(f, "none"),
+            Value::Optional(Some(inner)) => {
+                write!(f, "some(")?;
+                write_nested_value(f, inner)?;
+                write!(f, ")")
+            }
+            Value::Ref(index) => write!(f, "ref({index})"),
+            Value::Complex { re, im } => {
+                // Only a +0.0 real part is left out. The sign is written apart from the
+                // magnitude, as NaNs would otherwise display without theirs
+                let sign = if im.is_sign_negative() { "-" } else { "+" };
+                if re.to_bits() != 0 {
+                    write!(f, "{re}{sign}")?;
+                } else if sign == "-" {
+                    write!(f, "-")?;
+                }
+                write!(f, "{}i", im.abs())
+            }
+            Value::Color { r, g, b, a } => write!(f, "#{r:02X}{g:02X}{b:02X}{a:02X}"),
+            Value::Object(o) => write!(f, "{o}"),
+        }
+    }
+}
+
+/// Writes an RFC 3339 UTC timestamp, e.g. `1969-12-31T23:59:59.5Z`.
+fn write_timestamp(f: &mut fmt::Formatter, seconds: i64, nanos: u32) -> fmt::Result {
+    let days = seconds.div_euclid(86_400);
+    let secs_of_day = seconds.rem_euclid(86_400);
+
+    // Days to civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    write!(
+        f,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )?;
+    if nanos > 0 {
+        let fraction = format!("{nanos:09}");
+        write!(f, ".{}", fraction.trim_end_matches('0'))?;
+    }
+    write!(f, "Z")
+}
+
+/// Writes values separated by commas, quoting strings so they can be told apart
+/// from the surrounding punctuation.
+pub(crate) fn write_nested_values(f: &mut fmt::Formatter, values: &[Value]) -> fmt::Result {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write_nested_value(f, value)?;
+    }
+
+    Ok(())
+}
+
+/// Displays a value the way it appears inside another constant, with strings quoted.
+pub(crate) struct NestedValue<'a>(pub(crate) &'a Value);
+
+impl fmt::Display for NestedValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_nested_value(f, self.0)
+    }
+}
+
+pub(crate) fn write_nested_value(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+    match value {
+        Value::Object(o) => match &**o {
+            Object::String(s) => write!(f, "{s:?}"),
+            _ => write!(f, "{o}"),
+        },
+        _ => write!(f, "{value}"),
+    }
+}
+
+impl ConstantIO for Value {
+    fn to_type_id(&self) -> u8 {
+        match self {
+            Value::Int(_) => 0,
+            Value::Bool(_) => 1,
+            Value::Float(_) => 4,
+            Value::Long(_) => 5,
+            Value::UInt(_) => 6,
+            Value::Char(_) => 7,
+            Value::Nil => 8,
+            Value::Byte(_) => 9,
+            Value::Short(_) => 10,
+            Value::Decimal { .. } => 18,
+            Value::Timestamp { .. } => 22,
+            Value::Uuid(_) => 23,
+            Value::Optional(_) => 24,
+            Value::Ref(_) => 25,
+            Value::Complex { .. } => 27,
+            Value::Color { .. } => 29,
+            Value::Object(o) => o.to_type_id(),
+        }
+    }
+
+    fn read(
+        file: &mut impl Read,
+        byte_id: u8,
+        layout: Layout,
+        pool: &mut ObjectPool,
+    ) -> Result<Value, ConstantsError> {
+        let limit = layout.limits.max_depth;
+        if layout.depth >= limit {
+            return Err(ConstantsError::MaxDepthExceeded { entry: None, limit });
+        }
+        let layout = Layout {
+            depth: layout.depth + 1,
+            ..layout
+        };
+
+        Ok(match byte_id {
+            0 => Value::Int(read_int(file, layout)?),
+            1 => Value::Bool(read_u8(file)? == 1),
+            4 => Value::Float(f64::from_bits(read_u64(file, layout)?)),
+            5 => Value::Long(read_i64(file, layout)?),
+            6 => Value::UInt(read_u64(file, layout)?),
+            7 => {
+                let scalar = read_u32(file, layout)?;
+                match char::from_u32(scalar) {
+                    Some(c) => Value::Char(c),
+                    None => return invalid(format!("Invalid char scalar {scalar:#x}")),
+                }
+            }
+            8 => Value::Nil,
+            9 => Value::Byte(read_u8(file)?),
+            10 => Value::Short(read_i16(file, layout)?),
+            18 => Value::Decimal {
+                mantissa: read_i64(file, layout)?,
+                scale: read_u8(file)?,
+            },
+            22 => {
+                let seconds = read_i64(file, layout)?;
+                let nanos = read_u32(file, layout)?;
+                Value::from_timestamp(seconds, nanos).or_else(invalid)?
+            }
+            23 => {
+                let mut bytes = [0u8; 16];
+                file.read_exact(&mut bytes)?;
+                Value::Uuid(bytes)
+            }
+            24 => match read_u8(file)? {
+                0 => Value::Optional(None),
+                1 => {
+                    let inner_id = read_u8(file)?;
+                    Value::Optional(Some(Box::new(Value::read(file, inner_id, layout, pool)?)))
+                }
+                flag => return invalid(format!("Invalid optional flag {flag}")),
+            },
+            25 => Value::Ref(read_u32(file, layout)?),
+            POOL_REF_TYPE_ID => {
+                let Some(objects) = layout.objects else {
+                    return invalid("Object pool reference in a file without an object pool");
+                };
+                let index = read_usize(file, layout)?;
+                let Some(obj) = objects.get(index) else {
+                    return invalid(format!(
+                        "Object pool reference {index} is not an earlier entry of the {}-entry pool",
+                        objects.len()
+                    ));
+                };
+                Value::Object(Rc::clone(obj))
+            }
+            27 => Value::Complex {
+                re: f64::from_bits(read_u64(file, layout)?),
+                im: f64::from_bits(read_u64(file, layout)?),
+            },
+            29 => {
+                let mut rgba = [0u8; 4];
+                file.read_exact(&mut rgba)?;
+                let [r, g, b, a] = rgba;
+                Value::Color { r, g, b, a }
+            }
+            _ => return Object::read(file, byte_id, layout, pool),
+        })
+    }
+
+    fn write(&self, file: &mut impl Write, options: &WriteState) -> Result<(), ConstantsError> {
+        if let (Value::Object(o), Some(objects)) = (self, options.objects) {
+            return write_object_ref(file, o, objects, options);
+        }
+
+        let byte_id = match self {
+            Value::Object(o) => o.encoded_type_id(options),
+            _ => self.to_type_id(),
+        };
+        file.write_all(&[byte_id])?;
+
+        match self {
+            Value::Int(i) => write_int(file, *i, options)?,
+            Value::Bool(b) => file.write_all(&[if *b { 1 } else { 0 }])?,
+            Value::Float(f) => write_number(file, f.to_bits(), options)?,
+            Value::Long(l) => write_number(file, *l, options)?,
+            Value::UInt(u) => write_number(file, *u, options)?,
+            Value::Char(c) => write_number(file, *c as u32, options)?,
+            Value::Nil => {}
+            Value::Byte(b) => file.write_all(&[*b])?,
+            Value::Short(s) => write_number(file, *s, options)?,
+            Value::Decimal { mantissa, scale } => {
+                write_number(file, *mantissa, options)?;
+                file.write_all(&[*scale])?;
+            }
+            Value::Timestamp { seconds, nanos } => {
+                write_number(file, *seconds, options)?;
+                write_number(file, *nanos, options)?;
+            }
+            Value::Uuid(bytes) => file.write_all(bytes)?,
+            Value::Optional(None) => file.write_all(&[0])?,
+            Value::Optional(Some(inner)) => {
+                file.write_all(&[1])?;
+                inner.write(file, options)?;
+            }
+            Value::Ref(index) => write_number(file, *index, options)?,
+            Value::Complex { re, im } => {
+                write_number(file, re.to_bits(), options)?;
+                write_number(file, im.to_bits(), options)?;
+            }
+            Value::Color { r, g, b, a } => file.write_all(&[*r, *g, *b, *a])?,
+            Value::Object(o) => o.write(file, options)?,
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn trim_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+pub(crate) fn big_int_to_decimal(magnitude: &[u8]) -> String {
+    let mut remaining = trim_leading_zeros(magnitude).to_vec();
+    if remaining.is_empty() {
+        return "0".to_string();
+    }
+
+    let mut digits = Vec::new();
+    while !remaining.is_empty() {
+        // remaining = remaining / 10, keeping the remainder as the next digit
+        let mut rem = 0u32;
+        for byte in remaining.iter_mut() {
+            let n = (rem << 8) | *byte as u32;
+            *byte = (n / 10) as u8;
+            rem = n % 10;
+        }
+        digits.push(b'0' + rem as u8);
+        remaining = trim_leading_zeros(&remaining).to_vec();
+    }
+
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Saves `values` and loads them back, checking the loaded ones save to the same bytes.
+    fn round_trip(values: &[Value]) -> Vec<Value> {
+        let bytes = serialize_to_vec(values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        assert_eq!(
+            serialize_to_vec(&read, &WriteOptions::default()).unwrap(),
+            bytes
+        );
+        read
+    }
+
+    /// `bytes` with the first run of `from` in them starting with `to` instead.
+    fn patched(bytes: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+        let at = bytes
+            .windows(from.len())
+            .position(|window| window == from)
+            .expect("the bytes to patch");
+        let mut bytes = bytes.to_vec();
+        bytes[at..at + to.len()].copy_from_slice(to);
+        bytes
+    }
+
+    /// Loads `bytes` past their checksum, as a file patched by `patched` no longer has the
+    /// right one.
+    fn load_unchecked(bytes: &[u8]) -> Result<Vec<Value>, ConstantsError> {
+        let options = LoadOptions {
+            skip_checksum: true,
+            ..LoadOptions::default()
+        };
+        deserialize_from_slice(bytes, &mut ObjectPool::new(), &options)
+    }
+
+    #[test]
+    fn floats_round_trip_bit_for_bit() {
+        let floats = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.0, 0.0, -0.5];
+        let values = floats.map(Value::Float);
+        let read = round_trip(&values);
+        for (float, value) in floats.iter().zip(&read) {
+            let Value::Float(read) = value else {
+                panic!("expected a float, read {value}");
+            };
+            assert_eq!(read.to_bits(), float.to_bits());
+        }
+        assert_eq!(
+            read.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            ["NaN", "inf", "-inf", "-0.0", "0.0", "-0.5"]
+        );
+    }
+
+    #[test]
+    fn unsigned_ints_round_trip_and_unknown_ids_fail() {
+        let values = [Value::UInt(u64::MAX), Value::UInt(0)];
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(values[0].to_string(), "18446744073709551615");
+
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let from_a_newer_build = patched(&bytes, &[6, 0xff, 0xff], &[0xee]);
+        let e = load_unchecked(&from_a_newer_build).unwrap_err();
+        assert!(
+            matches!(e, ConstantsError::UnknownTypeId { id: 0xee, .. }),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn chars_round_trip_and_invalid_scalars_fail() {
+        let values = [Value::Char('a'), Value::Char('🦀'), Value::Char('\0')];
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(
+            values.each_ref().map(|c| c.to_string()),
+            ["'a'", "'🦀'", "'\\0'"]
+        );
+
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let surrogate = patched(&bytes, &[7, 0, 0, 0, b'a'], &[7, 0, 0, 0xd8, 0]);
+        let e = load_unchecked(&surrogate).unwrap_err();
+        assert!(e.to_string().contains("Invalid char scalar 0xd800"), "{e}");
+    }
+
+    #[test]
+    fn nils_between_constants_keep_them_aligned() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::Nil,
+            Value::Int(-1),
+            Value::Nil,
+            Value::Nil,
+            Value::from_string("after", &mut pool),
+            Value::Bool(true),
+            Value::Nil,
+        ];
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(Value::Nil.to_string(), "nil");
+    }
+
+    #[test]
+    fn bytes_and_shorts_round_trip_at_their_bounds() {
+        let values = [
+            Value::Byte(0),
+            Value::Byte(u8::MAX),
+            Value::Short(i16::MIN),
+            Value::Short(-128),
+            Value::Short(i16::MAX),
+        ];
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(values[2].to_string(), "-32768");
+
+        let fitted = [
+            -128,
+            255,
+            256,
+            i16::MIN as i64,
+            i16::MAX as i64 + 1,
+            i64::MIN,
+        ]
+        .map(Value::from_int_fitting);
+        assert_eq!(
+            fitted,
+            [
+                Value::Short(-128),
+                Value::Byte(255),
+                Value::Short(256),
+                Value::Short(i16::MIN),
+                Value::Int(32768),
+                Value::Long(i64::MIN),
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps_round_trip_and_display_as_rfc3339() {
+        let values = [
+            (0, 0),
+            (-1, 500_000_000),
+            (i32::MAX as i64 + 1, 0),
+            (4_102_444_800, 123),
+            (0, 999_999_999),
+        ]
+        .map(|(seconds, nanos)| Value::from_timestamp(seconds, nanos).unwrap());
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(
+            values.each_ref().map(|value| value.to_string()),
+            [
+                "1970-01-01T00:00:00Z",
+                "1969-12-31T23:59:59.5Z",
+                "2038-01-19T03:14:08Z",
+                "2100-01-01T00:00:00.000000123Z",
+                "1970-01-01T00:00:00.999999999Z",
+            ]
+        );
+
+        assert!(Value::from_timestamp(0, 1_000_000_000).is_err());
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let mut nanos = [0; 13];
+        nanos[0] = 22;
+        nanos[9..].copy_from_slice(&999_999_999u32.to_be_bytes());
+        let mut whole_second = nanos;
+        whole_second[9..].copy_from_slice(&1_000_000_000u32.to_be_bytes());
+        let e = load_unchecked(&patched(&bytes, &nanos, &whole_second)).unwrap_err();
+        assert!(e.to_string().contains("below one second"), "{e}");
+    }
+
+    #[test]
+    fn optionals_nest_and_register_their_objects() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::Optional(None),
+            Value::Optional(Some(Box::new(Value::Optional(Some(Box::new(Value::Int(
+                3,
+            ))))))),
+            Value::Optional(Some(Box::new(Value::from_string("hi", &mut pool)))),
+        ];
+        assert_eq!(
+            values.each_ref().map(|value| value.to_string()),
+            ["none", "some(some(3))", r#"some("hi")"#]
+        );
+
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let mut pool = ObjectPool::new();
+        let read = deserialize_from_slice(&bytes, &mut pool, &LoadOptions::default()).unwrap();
+        assert_eq!(read, values);
+        let Value::Optional(Some(inner)) = &read[2] else {
+            panic!("expected some");
+        };
+        let Value::Object(o) = &**inner else {
+            panic!("expected an object");
+        };
+        assert!(pool.iter().any(|pooled| Rc::ptr_eq(pooled, o)));
+    }
+
+    #[test]
+    fn colors_round_trip_and_parse_from_hex() {
+        let values = ["#ff8800", "ff880080", "#00000000", "FFFFFF"]
+            .map(|str| Value::from_hex_color(str).unwrap());
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(
+            values.each_ref().map(|value| value.to_string()),
+            ["#FF8800FF", "#FF880080", "#00000000", "#FFFFFFFF"]
+        );
+
+        for str in [
+            "",
+            "#",
+            "#fff",
+            "#ff88000",
+            "##ff8800",
+            "#ff880g",
+            "#ff8800ff00",
+        ] {
+            assert!(Value::from_hex_color(str).is_err(), "{str:?}");
+        }
+    }
+
+    #[test]
+    fn disassembly_writes_exact_text_to_any_sink() {
+        let mut pool = ObjectPool::new();
+        let inner = FunctionBuilder::new("inner", 0, &[ByteCode::Return as u8])
+            .build(&mut pool)
+            .unwrap();
+        let code = [
+            ByteCode::ConstantByte as u8,
+            0,
+            ByteCode::ConstantByte as u8,
+            1,
+            ByteCode::Loop as u8,
+            0,
+            7,
+        ];
+        let outer = FunctionBuilder::new("outer", 0, &code)
+            .constants(vec![Value::Int(3), inner])
+            .build(&mut pool)
+            .unwrap();
+
+        let mut out = Vec::new();
+        outer
+            .disassemble(&mut out, &DisassembleOptions::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+func<'outer', 0, 0>
+0000 CONSTANT_BYTE 0 ; 3
+0002 CONSTANT_BYTE 1 ; func<'inner', 0, 0>
+0004 LOOP 7 -> 0000
+constants:
+    0000 3
+    0001 func<'inner', 0, 0>
+        0000 RETURN
+"
+        );
+
+        let mut out = Vec::new();
+        Value::Int(3)
+            .disassemble(&mut out, &DisassembleOptions::default())
+            .unwrap();
+        assert_eq!(out, b"3\n");
+    }
+
+    #[test]
+    fn refs_share_their_target_and_must_point_back() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::from_string("shared", &mut pool),
+            Value::Int(1),
+            Value::Ref(0),
+        ];
+        let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+        let read = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &LoadOptions::default())
+            .unwrap();
+        let (Value::Object(first), Value::Object(again)) = (&read[0], &read[2]) else {
+            panic!("expected the ref resolved to its string, read {read:?}");
+        };
+        assert!(Rc::ptr_eq(first, again));
+
+        // Constant 2 pointing at itself, then past the end
+        for (target, error) in [(2, "constant 2"), (7, "constant 7")] {
+            let patched = patched(&bytes, &[25, 0, 0, 0, 0], &[25, 0, 0, 0, target]);
+            let e = load_unchecked(&patched).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("Constant 2 references {error}, which is not an earlier entry")
+            );
+
+            let mut forward = values.clone();
+            forward[2] = Value::Ref(target as u32);
+            let e = serialize_to_vec(&forward, &WriteOptions::default()).unwrap_err();
+            assert!(matches!(e, ConstantsError::Unwritable(_)), "{e}");
+        }
+    }
+
+    #[test]
+    fn complex_numbers_display_their_sign_and_round_trip_their_bits() {
+        let complex = |re, im| Value::Complex { re, im };
+        let negative_nan = f64::from_bits(0xfff8_0000_0000_1234);
+        let values = [
+            complex(3.0, 4.0),
+            complex(3.0, -4.0),
+            complex(0.0, 4.0),
+            complex(0.0, -4.0),
+            complex(-0.0, 4.0),
+            complex(1.5, -0.0),
+            complex(1.0, negative_nan),
+            complex(0.0, negative_nan),
+            complex(f64::NAN, f64::INFINITY),
+        ];
+        assert_eq!(
+            values.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            ["3+4i", "3-4i", "4i", "-4i", "-0+4i", "1.5-0i", "1-NaNi", "-NaNi", "NaN+infi"]
+        );
+
+        let read = round_trip(&values);
+        assert_eq!(read, values);
+        let Value::Complex { im, .. } = read[6] else {
+            panic!("expected a complex number, read {}", read[6]);
+        };
+        assert_eq!(im.to_bits(), negative_nan.to_bits());
+        // Equal bit for bit, so zeros and NaNs of another sign or payload differ
+        assert_ne!(complex(0.0, 1.0), complex(-0.0, 1.0));
+        assert_ne!(complex(1.0, negative_nan), complex(1.0, f64::NAN));
+    }
+
+    #[test]
+    fn longs_round_trip_at_their_bounds_beside_old_four_byte_ints() {
+        let values = [i64::MIN, -1, 0, i64::MAX].map(Value::Long);
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(values[0].to_string(), "-9223372036854775808");
+        assert_eq!(values[3].to_string(), "9223372036854775807");
+        let bytes = serialize_to_vec(&values[..1], &WriteOptions::default()).unwrap();
+        assert!(bytes
+            .windows(9)
+            .any(|w| w == [5, 0x80, 0, 0, 0, 0, 0, 0, 0]));
+
+        // Files from before longs hold ints of four bytes, which still load as ints
+        let mut ints = 2u64.to_be_bytes().to_vec();
+        ints.extend([0, 0xff, 0xff, 0xff, 0xff, 0, 0x7f, 0xff, 0xff, 0xff]);
+        let legacy = LoadOptions {
+            legacy: true,
+            ..LoadOptions::default()
+        };
+        let read = deserialize_from_slice(&ints, &mut ObjectPool::new(), &legacy).unwrap();
+        assert_eq!(read, [Value::Int(-1), Value::Int(i32::MAX)]);
+        let v1 = [&b"CNST\x00\x01"[..], &ints].concat();
+        let read =
+            deserialize_from_slice(&v1, &mut ObjectPool::new(), &LoadOptions::default()).unwrap();
+        assert_eq!(read, [Value::Int(-1), Value::Int(i32::MAX)]);
+        assert_ne!(read[0], Value::Long(-1));
+    }
+
+    #[test]
+    fn decimals_keep_their_sign_scale_and_leading_zeros() {
+        let decimal = |mantissa, scale| Value::Decimal { mantissa, scale };
+        let cases = [
+            ("0.0042", decimal(42, 4)),
+            ("-0.0042", decimal(-42, 4)),
+            ("-12.50", decimal(-1250, 2)),
+            ("7", decimal(7, 0)),
+            ("-7", decimal(-7, 0)),
+            ("0", decimal(0, 0)),
+            ("-9223372036854775808", decimal(i64::MIN, 0)),
+            ("-922337203.6854775808", decimal(i64::MIN, 10)),
+        ];
+        for (text, value) in &cases {
+            assert_eq!(&Value::from_decimal_str(text).unwrap(), value, "{text}");
+            assert_eq!(value.to_string(), *text);
+        }
+        let values = cases.map(|(_, value)| value);
+        assert_eq!(round_trip(&values), values);
+
+        // The scale is part of the value, so 1.0 and 1 differ
+        assert_ne!(Value::from_decimal_str("1.0"), Value::from_decimal_str("1"));
+        for invalid in [
+            "",
+            "-",
+            ".5",
+            "5.",
+            "1.2.3",
+            "1e3",
+            "+1",
+            "9223372036854775808",
+        ] {
+            assert!(Value::from_decimal_str(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn uuids_parse_strictly_and_display_lowercase() {
+        let uuid = Value::from_uuid_str("123E4567-E89B-12D3-A456-426614174000").unwrap();
+        assert_eq!(uuid.to_string(), "123e4567-e89b-12d3-a456-426614174000");
+        assert_eq!(Value::from_uuid_str(&uuid.to_string()).unwrap(), uuid);
+        let Value::Uuid(bytes) = uuid else {
+            panic!("expected a UUID, parsed {uuid}");
+        };
+        assert_eq!(bytes[..4], [0x12, 0x3e, 0x45, 0x67]);
+        assert_eq!(bytes[15], 0x00);
+        assert_eq!(round_trip(std::slice::from_ref(&uuid)), [uuid]);
+
+        let errors = [
+            (
+                "123e4567-e89b-12d3-a456-42661417400",
+                "expected 36 characters, found 35",
+            ),
+            (
+                "123e4567-e89b-12d3-a456-4266141740000",
+                "expected 36 characters, found 37",
+            ),
+            ("", "expected 36 characters, found 0"),
+            ("123e4567ae89b-12d3-a456-426614174000", "expected '-' at 8"),
+            (
+                "123e4567-e89b-12d3-a456-42661417400g",
+                "'g' is not a hex digit",
+            ),
+            (
+                "123e4567-e89b-12d3-a456-4266141740é",
+                "'é' is not a hex digit",
+            ),
+            (
+                "{23e4567-e89b-12d3-a456-426614174000",
+                "'{' is not a hex digit",
+            ),
+        ];
+        for (text, error) in errors {
+            assert_eq!(
+                Value::from_uuid_str(text).unwrap_err(),
+                format!("Invalid UUID '{text}': {error}")
+            );
+        }
+    }
+
+    #[test]
+    fn regexes_round_trip_their_flags_and_refuse_unknown_ones() {
+        let mut pool = ObjectPool::new();
+        let values = [
+            Value::from_regex("a.b", "", &mut pool).unwrap(),
+            Value::from_regex("^x$", "smi", &mut pool).unwrap(),
+            Value::from_regex("\\d+", "m", &mut pool).unwrap(),
+        ];
+        assert_eq!(round_trip(&values), values);
+        assert_eq!(
+            values.map(|v| v.to_string()),
+            ["/a.b/", "/^x$/ims", "/\\d+/m"]
+        );
+        assert_eq!(
+            Value::from_regex("a", "ix", &mut pool).unwrap_err(),
+            "Unknown regex flag 'x'"
+        );
+
+        // Bits from a newer build fail to load, rather than being dropped
+        let regex = [Value::from_regex("^x$", "i", &mut pool).unwrap()];
+        let bytes = serialize_to_vec(&regex, &WriteOptions::default()).unwrap();
+        for (bits, unknown) in [(0x09, "0x08"), (0x80, "0x80"), (0xff, "0xf8")] {
+            let newer = patched(&bytes, b"^x$\x01", &[b'^', b'x', b'$', bits]);
+            let e = load_unchecked(&newer).unwrap_err();
+            assert!(
+                e.to_string()
+                    .contains(&format!("Regex /^x$/ has unknown flag bits {unknown}")),
+                "{e}"
+            );
+        }
+    }
+}
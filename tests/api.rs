@@ -0,0 +1,104 @@
+use std::{fs, io::Cursor};
+
+use constants_generator_rs::{io::WriteState, *};
+
+/// Constants as a compiler would hand them over: scalars, a shared string and a function.
+fn compiled(pool: &mut ObjectPool) -> Vec<Value> {
+    let name = Value::from_string("main", pool);
+    let code = [ByteCode::Return as u8];
+    let main = Value::from_function_literal("main", 0, &code, pool).unwrap();
+    vec![
+        Value::Int(42),
+        Value::Float(0.5),
+        name.clone(),
+        Value::from_list(vec![name, Value::Bool(false)], pool),
+        main,
+    ]
+}
+
+fn strict() -> LoadOptions<'static> {
+    LoadOptions {
+        strict: true,
+        ..LoadOptions::default()
+    }
+}
+
+#[test]
+fn every_load_reads_what_every_save_writes() {
+    let values = compiled(&mut ObjectPool::new());
+    let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+    let mut written = Vec::new();
+    write_values_to_disk(&mut written, &values, &WriteOptions::default()).unwrap();
+    assert_eq!(written, bytes);
+
+    let mut read = Vec::new();
+    let mut pool = ObjectPool::new();
+    load_values_from_disk(&mut Cursor::new(&bytes), &mut read, &mut pool, &strict()).unwrap();
+    assert_eq!(read, values);
+    assert_eq!(
+        deserialize_from_slice(&bytes, &mut ObjectPool::new(), &strict()).unwrap(),
+        values
+    );
+    assert_eq!(
+        load_values_from_bytes(&bytes, &mut ObjectPool::new(), &strict()).unwrap(),
+        values
+    );
+
+    let dir = std::env::temp_dir().join(format!("constants-api-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("constants");
+    save_atomic(path.to_str().unwrap(), &values, &WriteOptions::default()).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+    let mut read = Vec::new();
+    let mut file = fs::File::open(&path).unwrap();
+    load_values_from_disk(&mut file, &mut read, &mut ObjectPool::new(), &strict()).unwrap();
+    assert_eq!(read, values);
+}
+
+#[test]
+fn constant_io_writes_one_value_inline() {
+    let options = WriteOptions::default();
+    let mut bytes = Vec::new();
+    Value::Int(7)
+        .write(&mut bytes, &WriteState::new(&options))
+        .unwrap();
+    assert_eq!(bytes, [Value::Int(7).to_type_id(), 0, 0, 0, 7]);
+
+    let mut pool = ObjectPool::new();
+    let Value::Object(string) = Value::from_string("hi", &mut pool) else {
+        panic!("expected an object");
+    };
+    let mut bytes = Vec::new();
+    string
+        .write(&mut bytes, &WriteState::new(&options))
+        .unwrap();
+    assert_eq!(bytes, [0, 0, 0, 2, b'h', b'i']);
+}
+
+#[test]
+fn failures_are_errors_to_match_on() {
+    let e = deserialize_from_slice(b"CNST", &mut ObjectPool::new(), &LoadOptions::default())
+        .unwrap_err();
+    assert!(matches!(e, ConstantsError::UnexpectedEof), "{e}");
+
+    let warnings = Warnings::default();
+    let code = [ByteCode::Return as u8, ByteCode::Pop as u8];
+    let mut pool = ObjectPool::new();
+    let values = [Value::from_function_literal("f", 0, &code, &mut pool).unwrap()];
+    let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+    let options = LoadOptions {
+        warnings: Some(&warnings),
+        ..LoadOptions::default()
+    };
+    // Code that runs off its end loads with a warning, unless asked to be strict
+    deserialize_from_slice(&bytes, &mut ObjectPool::new(), &options).unwrap();
+    assert!(
+        matches!(
+            &warnings.take()[..],
+            [Warning::FunctionIssue { constant: 0, .. }]
+        ),
+        "{warnings:?}"
+    );
+    let e = deserialize_from_slice(&bytes, &mut ObjectPool::new(), &strict()).unwrap_err();
+    assert!(matches!(e, ConstantsError::FunctionIssue { .. }), "{e}");
+}
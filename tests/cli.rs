@@ -0,0 +1,270 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use constants_generator_rs::{
+    io::{FileLock, LockKind},
+    object::FunctionBuilder,
+    save_atomic, ObjectPool, Value, WriteOptions,
+};
+
+/// An empty directory for one test to run the binary in.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("constants-cli-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn command(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_constants_generator_rs"));
+    command.args(args).current_dir(dir);
+    command
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    command(dir, args).output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn racing_saves_from_two_processes_leave_a_loadable_file() {
+    let dir = scratch("race");
+    for _ in 0..5 {
+        let saves = (0..2)
+            .map(|_| {
+                let mut save = command(&dir, &["s", "--wait", "30"]);
+                save.stderr(Stdio::null()).spawn().unwrap()
+            })
+            .collect::<Vec<_>>();
+        for mut save in saves {
+            assert!(save.wait().unwrap().success());
+        }
+        let load = run(&dir, &["l", "--strict"]);
+        assert!(load.status.success(), "{}", stderr(&load));
+    }
+    assert!(!dir.join("constants.lock").exists());
+}
+
+#[test]
+fn loading_a_missing_file_leaves_no_lock_file() {
+    let dir = scratch("missing");
+    let load = run(&dir, &["l", "missing.bin"]);
+    assert_eq!(load.status.code(), Some(2));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn loading_an_encrypted_file_without_its_key_names_the_flags() {
+    let dir = scratch("key");
+    assert!(run(&dir, &["s", "--key", "abc"]).status.success());
+    let load = run(&dir, &["l"]);
+    assert_eq!(load.status.code(), Some(2));
+    assert_eq!(
+        stderr(&load),
+        "error: File is encrypted and no key was supplied: pass its key with --key or --key-file\n"
+    );
+    assert!(run(&dir, &["l", "--key", "abc"]).status.success());
+}
+
+#[test]
+fn saves_and_loads_pipe_through_stdout_and_stdin() {
+    let dir = scratch("pipe");
+    let save = run(&dir, &["s", "-"]);
+    assert!(save.status.success(), "{}", stderr(&save));
+    assert!(save.stdout.starts_with(b"CNST"));
+    assert!(
+        stderr(&save).contains("constants written"),
+        "{}",
+        stderr(&save)
+    );
+
+    let mut load = command(&dir, &["l", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    load.stdin.take().unwrap().write_all(&save.stdout).unwrap();
+    let load = load.wait_with_output().unwrap();
+    assert!(load.status.success(), "{}", stderr(&load));
+    assert!(
+        stderr(&load).contains("constants read"),
+        "{}",
+        stderr(&load)
+    );
+    assert!(!String::from_utf8_lossy(&load.stdout).contains("constants read"));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    // The same file through the disk prints the same listing
+    assert!(run(&dir, &["s"]).status.success());
+    assert_eq!(fs::read(dir.join("constants")).unwrap(), save.stdout);
+    assert_eq!(run(&dir, &["l"]).stdout, load.stdout);
+}
+
+#[test]
+fn a_held_lock_makes_commands_wait_then_fail_unless_told_not_to_lock() {
+    let dir = scratch("held");
+    assert!(run(&dir, &["s"]).status.success());
+    let path = dir.join("constants");
+    let held = FileLock::acquire(path.to_str().unwrap(), LockKind::Exclusive, None).unwrap();
+
+    let save = run(&dir, &["s", "--wait", "0.1"]);
+    assert_eq!(save.status.code(), Some(3));
+    assert!(
+        stderr(&save).contains("is locked by another process"),
+        "{}",
+        stderr(&save)
+    );
+    let load = run(&dir, &["l", "--wait", "0.1"]);
+    assert_eq!(load.status.code(), Some(2));
+    assert!(run(&dir, &["s", "--no-lock"]).status.success());
+    assert!(run(&dir, &["l", "--no-lock"]).status.success());
+
+    drop(held);
+    assert!(run(&dir, &["s", "--wait", "0.1"]).status.success());
+    assert!(!dir.join("constants.lock").exists());
+}
+
+#[test]
+fn wait_takes_only_seconds_a_duration_can_hold() {
+    let dir = scratch("wait");
+    for secs in ["-1", "NaN", "inf", "1e30", "soon"] {
+        let output = run(&dir, &["s", "--wait", secs]);
+        assert_eq!(output.status.code(), Some(1), "{secs}: {}", stderr(&output));
+        assert!(
+            stderr(&output).contains("Expected a non-negative number of seconds after --wait"),
+            "{secs}: {}",
+            stderr(&output)
+        );
+    }
+    assert!(!dir.join("constants").exists());
+
+    // Longer than any deadline can be, so the lock is waited on without one
+    assert!(run(&dir, &["s", "--wait", "1e18"]).status.success());
+    assert!(run(&dir, &["l", "--wait", "1e18"]).status.success());
+}
+
+#[test]
+fn failures_exit_with_their_kind_and_leave_stdout_empty() {
+    let dir = scratch("codes");
+    let fails = |args: &[&str], code| {
+        let output = run(&dir, args);
+        assert_eq!(
+            output.status.code(),
+            Some(code),
+            "{args:?}: {}",
+            stderr(&output)
+        );
+        assert!(output.stdout.is_empty(), "{args:?}");
+        assert!(stderr(&output).starts_with("error: "), "{args:?}");
+        stderr(&output)
+    };
+
+    assert!(fails(&[], 1).contains("Expected a command"));
+    fails(&["x"], 1);
+    fails(&["l", "--max-depth", "deep"], 1);
+
+    assert_eq!(
+        fails(&["l", "missing.bin"], 2),
+        "error: Could not open 'missing.bin': No such file or directory (os error 2)\n"
+    );
+    assert!(run(&dir, &["s"]).status.success());
+    let bytes = fs::read(dir.join("constants")).unwrap();
+    fs::write(dir.join("truncated.bin"), &bytes[..bytes.len() / 2]).unwrap();
+    fails(&["l", "truncated.bin"], 2);
+
+    // The last byte of the body, just before the checksum and end marker that follow it
+    let mut flipped = bytes;
+    let at = flipped.len() - 9;
+    flipped[at] ^= 1;
+    fs::write(dir.join("flipped.bin"), flipped).unwrap();
+    assert!(fails(&["l", "flipped.bin"], 4).starts_with("error: Checksum mismatch"));
+
+    fails(&["s", "no/such/dir.bin"], 3);
+}
+
+#[test]
+fn a_function_inside_a_list_is_checked_like_a_top_level_one() {
+    let dir = scratch("nested-function");
+    let mut pool = ObjectPool::new();
+    let deep = FunctionBuilder::new("deep", 200, &[])
+        .build(&mut pool)
+        .unwrap();
+    let values = [Value::from_list(vec![Value::Int(1), deep], &mut pool)];
+    let path = dir.join("nested.bin");
+    save_atomic(path.to_str().unwrap(), &values, &WriteOptions::default()).unwrap();
+
+    let strict = run(&dir, &["l", "--strict", "nested.bin"]);
+    assert_eq!(strict.status.code(), Some(4), "{}", stderr(&strict));
+    assert!(
+        stderr(&strict).starts_with("error: Function 'deep' in constant 0 "),
+        "{}",
+        stderr(&strict)
+    );
+    let load = run(&dir, &["l", "nested.bin"]);
+    assert!(load.status.success(), "{}", stderr(&load));
+    assert!(
+        stderr(&load).contains("function 'deep' in constant 0 "),
+        "{}",
+        stderr(&load)
+    );
+}
+
+#[test]
+fn saving_a_ref_to_a_later_constant_fails_and_writes_nothing() {
+    let dir = scratch("forward-ref");
+    fs::write(
+        dir.join("refs.json"),
+        r#"[{"type":"int","value":1},{"type":"ref","index":5}]"#,
+    )
+    .unwrap();
+    let save = run(&dir, &["s", "--json", "refs.json"]);
+    assert_eq!(save.status.code(), Some(3), "{}", stderr(&save));
+    assert_eq!(
+        stderr(&save),
+        "error: Constant 1 references constant 5, which is not an earlier entry\n"
+    );
+    assert!(!dir.join("constants").exists());
+}
+
+#[test]
+fn metadata_leaves_out_the_creation_time_when_asked_or_deterministic() {
+    let dir = scratch("metadata");
+    let save = |args: &[&str]| {
+        let save = run(&dir, &[&["s"], args].concat());
+        assert!(save.status.success(), "{}", stderr(&save));
+    };
+    let written_by = |file| {
+        let load = run(&dir, &["l", file]);
+        assert!(load.status.success(), "{}", stderr(&load));
+        stderr(&load).lines().next().unwrap().to_string()
+    };
+    let generator = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+    save(&["--metadata", "timed.bin"]);
+    assert!(written_by("timed.bin").ends_with(" (unix time)"));
+    save(&["--meta", "build=42", "--no-timestamp", "untimed.bin"]);
+    assert_eq!(written_by("untimed.bin"), format!("Written by {generator}"));
+    let load = run(&dir, &["l", "untimed.bin"]);
+    assert!(
+        stderr(&load).contains("\n  build: 42\n"),
+        "{}",
+        stderr(&load)
+    );
+
+    // Two deterministic saves write the same bytes, which a timestamp would spoil
+    save(&["--metadata", "--deterministic", "first.bin"]);
+    save(&["--metadata", "--deterministic", "second.bin"]);
+    assert_eq!(written_by("first.bin"), format!("Written by {generator}"));
+    assert_eq!(
+        fs::read(dir.join("first.bin")).unwrap(),
+        fs::read(dir.join("second.bin")).unwrap()
+    );
+}
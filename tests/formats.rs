@@ -0,0 +1,101 @@
+use std::{fs, io::Cursor, path::Path, rc::Rc};
+
+use constants_generator_rs::*;
+
+/// A checked-in file from `tests/fixtures`, laid out as its format revision wrote files.
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    fs::read(path).unwrap()
+}
+
+/// What every fixture holds, whichever revision wrote it.
+fn expected() -> Vec<Value> {
+    let mut pool = ObjectPool::new();
+    let hi = Value::from_string("hi", &mut pool);
+    vec![
+        Value::Int(7),
+        Value::Bool(true),
+        hi.clone(),
+        Value::Float(1.5),
+        Value::from_list(vec![hi, Value::Long(-2)], &mut pool),
+    ]
+}
+
+fn load(bytes: &[u8], options: &LoadOptions) -> Vec<Value> {
+    deserialize_from_slice(bytes, &mut ObjectPool::new(), options).unwrap()
+}
+
+#[test]
+fn every_format_version_loads_the_same_constants() {
+    for version in 1..=8 {
+        let read = load(
+            &fixture(&format!("v{version}.bin")),
+            &LoadOptions::default(),
+        );
+        assert_eq!(read, expected(), "version {version}");
+
+        // Objects are only written once, and so shared on load, from version 6
+        let (Value::Object(string), Value::Object(list)) = (&read[2], &read[4]) else {
+            panic!("expected objects");
+        };
+        let Object::List(items) = &**list else {
+            panic!("expected a list");
+        };
+        let Value::Object(item) = &items[0] else {
+            panic!("expected a string in the list");
+        };
+        assert_eq!(Rc::ptr_eq(string, item), version >= 6, "version {version}");
+    }
+
+    let legacy = LoadOptions {
+        legacy: true,
+        ..LoadOptions::default()
+    };
+    assert_eq!(load(&fixture("legacy.bin"), &legacy), expected());
+}
+
+#[test]
+fn writes_target_the_newest_version() {
+    let bytes = serialize_to_vec(&expected(), &WriteOptions::default()).unwrap();
+    assert_eq!(bytes, fixture("v8.bin"));
+}
+
+#[test]
+fn spans_show_each_constants_bytes_as_checked_in() {
+    let mut values = expected();
+    values.push(Value::from_uuid_str("436f6e73-7461-6e74-73a0-0102037f7e20").unwrap());
+    let bytes = serialize_to_vec(&values, &WriteOptions::default()).unwrap();
+    let mut read = Vec::new();
+    let spans = io::read_constant_spans(
+        &mut Cursor::new(&bytes),
+        &mut read,
+        &mut ObjectPool::new(),
+        &LoadOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(read, values);
+
+    // Each span is exactly the bytes at its offset, and the spans follow one another
+    for pair in spans.windows(2) {
+        assert_eq!(pair[0].offset + pair[0].bytes.len() as u64, pair[1].offset);
+    }
+    for span in &spans {
+        assert!(span.in_file);
+        let at = span.offset as usize;
+        assert_eq!(bytes[at..at + span.bytes.len()], span.bytes);
+    }
+
+    let mut dump = Vec::new();
+    for (i, span) in spans.iter().enumerate() {
+        span.write(&mut dump, i).unwrap();
+    }
+    let dump = String::from_utf8(dump).unwrap();
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/spans.txt");
+    assert_eq!(
+        dump,
+        fs::read_to_string(golden).unwrap(),
+        "the span format changed; if that's intended, update tests/golden/spans.txt"
+    );
+}
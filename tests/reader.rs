@@ -0,0 +1,75 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use constants_generator_rs::{io::ConstantsReader, *};
+
+/// The system allocator, keeping count of the bytes live and the most there have been.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let live = LIVE.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(live, Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Ten thousand scalars, so nothing read goes to the pool.
+fn constants() -> Vec<Value> {
+    (0..10_000)
+        .map(|i| match i % 3 {
+            0 => Value::Int(i),
+            1 => Value::Long(i as i64 * -7),
+            _ => Value::Float(i as f64 / 4.0),
+        })
+        .collect()
+}
+
+#[test]
+fn each_step_holds_no_more_than_the_constant_it_reads() {
+    let values = constants();
+    let path = std::env::temp_dir().join(format!("constants-reader-{}", std::process::id()));
+    fs::write(
+        &path,
+        serialize_to_vec(&values, &WriteOptions::default()).unwrap(),
+    )
+    .unwrap();
+
+    let file = fs::File::open(&path).unwrap();
+    let mut pool = ObjectPool::new();
+    let options = LoadOptions::default();
+    let mut reader = ConstantsReader::new(file, &mut pool, &options).unwrap();
+    let before = LIVE.load(Ordering::SeqCst);
+    let mut read = 0;
+    loop {
+        let live = LIVE.load(Ordering::SeqCst);
+        PEAK.store(live, Ordering::SeqCst);
+        let Some(value) = reader.next() else {
+            break;
+        };
+        assert_eq!(value.unwrap(), values[read]);
+        let step = PEAK.load(Ordering::SeqCst) - live;
+        assert!(step <= 1024, "constant {read} took {step} bytes to read");
+        read += 1;
+    }
+    assert_eq!(read, values.len());
+    // Nothing read is kept between steps, where a full load would hold every constant
+    let kept = LIVE.load(Ordering::SeqCst).saturating_sub(before);
+    assert!(kept <= 1024, "{kept} bytes kept after reading");
+    assert!(values.len() * size_of::<Value>() > 100 * 1024);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,73 @@
+use std::{fs, path::Path, rc::Rc};
+
+use constants_generator_rs::{bytecode::assemble, io::*, object::*, value::*};
+
+fn golden(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    fs::read_to_string(path).unwrap()
+}
+
+/// A pool with a constant of most kinds, and strings that need every escape.
+fn representative(pool: &mut ObjectPool) -> Vec<Value> {
+    let code = assemble(
+        "GET_LOCAL 0
+         CONSTANT_BYTE 0
+         ADD
+         JUMP_IF_FALSE 1
+         NEGATE
+         RETURN",
+    )
+    .unwrap();
+    let function = FunctionBuilder::new("add_one", 1, &code)
+        .local_count(1)
+        .constants(vec![Value::Int(1)])
+        .defaults(vec![Value::Int(41)])
+        .lines(vec![(0, 3), (4, 4)])
+        .build(pool)
+        .unwrap();
+    let escapes = Value::Object(Rc::new(Object::String(
+        "tab\there \"quoted\" back\\slash\nbell\u{7} λ".to_string(),
+    )));
+    let name = Value::from_string("name", pool);
+    vec![
+        Value::Int(-7),
+        Value::Bool(true),
+        Value::Float(0.1),
+        Value::Float(f64::NEG_INFINITY),
+        Value::Long(i64::MIN),
+        Value::UInt(u64::MAX),
+        Value::Char('\''),
+        Value::Nil,
+        Value::Byte(255),
+        Value::Short(-300),
+        escapes,
+        Value::from_list(vec![name.clone(), Value::Int(2)], pool),
+        Value::from_map(vec![(name, Value::from_bytes(&[0, 0xff], pool))], pool),
+        Value::Optional(Some(Box::new(Value::Nil))),
+        Value::from_timestamp(-1, 5).unwrap(),
+        Value::from_hex_color("#ff880080").unwrap(),
+        Value::from_symbol("init", pool),
+        function,
+    ]
+}
+
+#[test]
+fn dumps_match_the_checked_in_text() {
+    let values = representative(&mut ObjectPool::new());
+    let text = to_text(&values);
+    assert_eq!(
+        text,
+        golden("pool.txt"),
+        "the dump format changed; if that's intended, update tests/golden/pool.txt"
+    );
+    assert_eq!(to_text(&values), text);
+}
+
+#[test]
+fn checked_in_text_parses_back_to_its_constants() {
+    let mut pool = ObjectPool::new();
+    let values = representative(&mut ObjectPool::new());
+    assert_eq!(from_text(&golden("pool.txt"), &mut pool).unwrap(), values);
+}